@@ -0,0 +1,90 @@
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    errors::FsCacheResult,
+    relative_backend::RelativeBackend,
+    save_strategy::SaveStrategy,
+    storage::{FileBackend, StorageBackend},
+};
+
+//An entry read again within this long of its last recorded access is not recorded again, so that
+//reading the same entries over and over does not keep modifying the log.
+const ACCESS_TIME_RESOLUTION: Duration = Duration::from_secs(60);
+
+/// When each entry was last read, stored beside the cache file rather than in it, so that reading
+/// entries does not make the whole cache file need saving again.
+pub(crate) struct AccessLog {
+    access_times: BaseFsCache<SystemTime>,
+}
+
+impl AccessLog {
+    /// Access times are stored relative to `relative_root`, if given, like the entries of the cache.
+    pub(crate) fn open(
+        cache_path: &Path,
+        save_strategy: Arc<dyn SaveStrategy>,
+        relative_root: Option<&Path>,
+    ) -> FsCacheResult<Self> {
+        let mut path = cache_path.to_path_buf().into_os_string();
+        path.push(".access");
+        let mut backend: Box<dyn StorageBackend<SystemTime>> = Box::new(FileBackend::new(path.into()));
+        if let Some(root) = relative_root {
+            backend = Box::new(RelativeBackend::new(backend, root.to_path_buf()));
+        }
+        let access_times = BaseFsCache::with_backend(save_strategy, backend)?;
+        Ok(Self { access_times })
+    }
+
+    pub(crate) fn set_save_on_drop(&mut self, save_on_drop: bool) {
+        self.access_times.set_save_on_drop(save_on_drop)
+    }
+
+    pub(crate) fn record(&self, key: &Path) -> FsCacheResult<()> {
+        let now = SystemTime::now();
+        let recent = |last: SystemTime| {
+            now.duration_since(last)
+                .is_ok_and(|since| since < ACCESS_TIME_RESOLUTION)
+        };
+        match self.access_times.get(key) {
+            Some(last) if recent(last) => Ok(()),
+            _ => self.access_times.insert(key.to_path_buf(), now),
+        }
+    }
+
+    pub(crate) fn last_access(&self, key: &Path) -> Option<SystemTime> {
+        self.access_times.get(key)
+    }
+
+    /// Forget the access times of entries which are no longer cached, such as those removed
+    /// while the log was not being kept.
+    pub(crate) fn retain_cached(&self, is_cached: impl Fn(&Path) -> bool) -> FsCacheResult<()> {
+        self.access_times.retain(|key, _| is_cached(key)).map(|_| ())
+    }
+
+    pub(crate) fn clear(&self) -> FsCacheResult<()> {
+        match self.access_times.is_empty() {
+            true => Ok(()),
+            false => self.access_times.clear(),
+        }
+    }
+
+    pub(crate) fn save(&self) -> FsCacheResult<()> {
+        self.access_times.save()
+    }
+
+    pub(crate) fn flush(&self) -> FsCacheResult<()> {
+        self.access_times.flush()
+    }
+
+    pub(crate) fn pending_modifications(&self) -> usize {
+        self.access_times.pending_modifications()
+    }
+
+    pub(crate) fn reset_on_disk(&self) -> FsCacheResult<()> {
+        self.access_times.reset_on_disk()
+    }
+}
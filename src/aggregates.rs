@@ -0,0 +1,153 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{cache_entry::MtimeCacheEntry, observer::CacheObserver};
+
+//Combines the values of two entries beneath a directory into one.
+pub(crate) type AggregateFn<T> = Arc<dyn Fn(&T, &T) -> T + Send + Sync>;
+
+//Calls the given function with every cached path and value.
+pub(crate) type ForEachEntry<'a, T> = &'a mut dyn FnMut(&Path, &T);
+
+//The value of every directory holding a cached entry, folded from the values of the entries
+//beneath it. A change to an entry only marks the directories above it as stale, and every stale
+//directory is folded again in a single pass over the cache when it is next refreshed.
+pub(crate) struct DirectoryAggregates<T> {
+    fold: AggregateFn<T>,
+    state: RwLock<AggregateState<T>>,
+}
+
+struct AggregateState<T> {
+    values: HashMap<PathBuf, T>,
+    stale: HashSet<PathBuf>,
+}
+
+impl<T> DirectoryAggregates<T>
+where
+    T: Clone,
+{
+    pub(crate) fn new(fold: AggregateFn<T>) -> Self {
+        Self {
+            fold,
+            state: RwLock::new(AggregateState {
+                values: HashMap::new(),
+                stale: HashSet::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn mark_stale(&self, path: &Path) {
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(_) => unreachable!(),
+        };
+        for dir in directories_above(path) {
+            //the directories above one already marked are marked too.
+            if !state.stale.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+    }
+
+    //Folds every stale directory again. The stale directories are taken before the cache is
+    //looked through, rather than holding the lock throughout, as entries are marked stale while
+    //the cache is locked. A directory marked stale again meanwhile is folded on the next refresh.
+    pub(crate) fn refresh(&self, entries: impl FnOnce(ForEachEntry<'_, T>)) {
+        let stale = match self.state.write() {
+            Ok(mut state) if !state.stale.is_empty() => std::mem::take(&mut state.stale),
+            Ok(_) => return,
+            Err(_) => unreachable!(),
+        };
+
+        let mut folded: HashMap<PathBuf, T> = HashMap::new();
+        entries(&mut |path, value| {
+            for dir in directories_above(path).filter(|dir| stale.contains(*dir)) {
+                match folded.get_mut(dir) {
+                    Some(acc) => *acc = (self.fold)(acc, value),
+                    None => {
+                        folded.insert(dir.to_path_buf(), value.clone());
+                    }
+                }
+            }
+        });
+
+        let mut state = match self.state.write() {
+            Ok(state) => state,
+            Err(_) => unreachable!(),
+        };
+        for dir in stale {
+            match folded.remove(&dir) {
+                Some(value) => state.values.insert(dir, value),
+                None => state.values.remove(&dir),
+            };
+        }
+    }
+
+    pub(crate) fn get(&self, dir: &Path) -> Option<T> {
+        match self.state.read() {
+            Ok(state) => state.values.get(dir).cloned(),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    pub(crate) fn is_stale(&self) -> bool {
+        match self.state.read() {
+            Ok(state) => !state.stale.is_empty(),
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+fn directories_above(path: &Path) -> impl Iterator<Item = &Path> {
+    path.ancestors().skip(1).filter(|dir| !dir.as_os_str().is_empty())
+}
+
+//Marks the directories above every changed entry as stale, then passes the change on to the
+//next observer if there is one.
+pub(crate) struct AggregatingObserver<T> {
+    aggregates: Arc<DirectoryAggregates<T>>,
+    next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+}
+
+impl<T> AggregatingObserver<T> {
+    pub(crate) fn new(
+        aggregates: Arc<DirectoryAggregates<T>>,
+        next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+    ) -> Self {
+        Self { aggregates, next }
+    }
+}
+
+impl<T> CacheObserver<MtimeCacheEntry<T>> for AggregatingObserver<T>
+where
+    T: Clone + Send + Sync,
+{
+    fn on_insert(&self, path: &Path, entry: &MtimeCacheEntry<T>) {
+        self.aggregates.mark_stale(path);
+        if let Some(next) = &self.next {
+            next.on_insert(path, entry)
+        }
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.aggregates.mark_stale(path);
+        if let Some(next) = &self.next {
+            next.on_remove(path)
+        }
+    }
+
+    fn on_save(&self) {
+        if let Some(next) = &self.next {
+            next.on_save()
+        }
+    }
+
+    fn on_load(&self, entries: usize) {
+        if let Some(next) = &self.next {
+            next.on_load(entries)
+        }
+    }
+}
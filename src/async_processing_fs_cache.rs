@@ -0,0 +1,1024 @@
+use std::{
+    borrow::Cow,
+    collections::{hash_map::RandomState, HashSet},
+    fs::Metadata,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use tokio::task::{spawn_blocking, JoinSet};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    cache_entry::{is_expired, legacy_file_migration, EntryMeta, MtimeCacheEntry, TimeToLiveFn, ENTRY_FORMAT_VERSION},
+    cache_interface::AsyncCacheInterface,
+    errors::{FsCacheErrorKind::*, FsCacheResult},
+    failures::{FailureLog, ProcessingFailure, RetryPolicy},
+    file_set::{Enumeration, FileSet},
+    format::ProcessorVersion,
+    hard_links::linked_groups,
+    invalidation::{source_changed, update_action, InvalidationStrategy, SourceMetadata, UpdateAction},
+    key_normalization::{KeyNormalizer, PathNormalization},
+    merge::{CacheDiff, MergeStrategy},
+    processing_fs_cache::{is_missing, missing_paths, sampled, take_renamed, unwalked_keys_removed},
+    progress::{Progress, UpdateProgress},
+    renames::find_renames,
+    save_strategy::SaveStrategy,
+    storage::FileBackend,
+    update_report::{UpdateOutcome, UpdatePlan, UpdateReport, VerifyReport},
+};
+
+//The number of files which update_from_fs will process at the same time unless told otherwise.
+const DEFAULT_MAX_CONCURRENCY: usize = 64;
+
+/// An async counterpart to [`crate::ProcessingFsCache`], for use inside a tokio runtime.
+///
+/// Filesystem metadata is read with `tokio::fs`, while loading, saving and modifying
+/// the cache (all of which may serialize the whole cache to disk) happen on tokio's
+/// blocking thread pool. Cloning is cheap, and clones share the same underlying cache.
+pub struct AsyncProcessingFsCache<I, S = RandomState>
+where
+    I: AsyncCacheInterface,
+    S: BuildHasher + Default + Send + Sync,
+{
+    base_cache: Arc<BaseFsCache<MtimeCacheEntry<I::T>, S>>,
+    interface: Arc<I>,
+    max_concurrency: usize,
+    invalidation_strategy: InvalidationStrategy,
+    key_normalizer: KeyNormalizer,
+    failures: Arc<FailureLog>,
+    retry_policy: RetryPolicy,
+    time_to_live: Option<TimeToLiveFn<I::T>>,
+    detect_renames: bool,
+    deduplicate_hard_links: bool,
+}
+
+impl<I, S> Clone for AsyncProcessingFsCache<I, S>
+where
+    I: AsyncCacheInterface,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        Self {
+            base_cache: self.base_cache.clone(),
+            interface: self.interface.clone(),
+            max_concurrency: self.max_concurrency,
+            invalidation_strategy: self.invalidation_strategy,
+            key_normalizer: self.key_normalizer.clone(),
+            failures: self.failures.clone(),
+            retry_policy: self.retry_policy,
+            time_to_live: self.time_to_live.clone(),
+            detect_renames: self.detect_renames,
+            deduplicate_hard_links: self.deduplicate_hard_links,
+        }
+    }
+}
+
+impl<I> AsyncProcessingFsCache<I>
+where
+    I: AsyncCacheInterface + Send + Sync + 'static,
+{
+    pub async fn new(
+        save_strategy: impl SaveStrategy + 'static,
+        cache_path: PathBuf,
+        interface: I,
+    ) -> FsCacheResult<Self> {
+        Self::with_hasher(save_strategy, cache_path, interface).await
+    }
+}
+
+impl<I, S> AsyncProcessingFsCache<I, S>
+where
+    I: AsyncCacheInterface + Send + Sync + 'static,
+    S: BuildHasher + Default + Send + Sync + 'static,
+{
+    /// As [`Self::new`], but the cache is held in a map using the hasher `S`. See
+    /// [`crate::ProcessingFsCacheBuilder::with_hasher`].
+    pub async fn with_hasher(
+        save_strategy: impl SaveStrategy + 'static,
+        cache_path: PathBuf,
+        interface: I,
+    ) -> FsCacheResult<Self> {
+        let save_strategy = Arc::new(save_strategy);
+        let version = ProcessorVersion {
+            version: interface.version(),
+            config_fingerprint: interface.config_fingerprint(),
+        };
+        let (base_cache, failures) = blocking(move || {
+            let failures = FailureLog::open(&cache_path, save_strategy.clone(), None)?;
+            failures.check_version(version)?;
+            let backend = FileBackend::new(cache_path)
+                .with_migration(legacy_file_migration::<I::T>(None))
+                .with_value_format_version(ENTRY_FORMAT_VERSION);
+            let base_cache = BaseFsCache::with_backend(save_strategy, Box::new(backend))?;
+            base_cache.check_version(version, |_, _, _| true)?;
+            Ok((base_cache, failures))
+        })
+        .await?;
+
+        Ok(Self {
+            base_cache: Arc::new(base_cache),
+            interface: Arc::new(interface),
+            max_concurrency: DEFAULT_MAX_CONCURRENCY,
+            invalidation_strategy: Default::default(),
+            key_normalizer: Default::default(),
+            failures: Arc::new(failures),
+            retry_policy: Default::default(),
+            time_to_live: None,
+            detect_renames: false,
+            deduplicate_hard_links: false,
+        })
+    }
+
+    /// The maximum number of files processed at the same time by [`Self::update_from_fs`].
+    pub fn with_max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self
+    }
+
+    /// How to decide whether a cached file has changed. Defaults to [`InvalidationStrategy::MtimeAndSize`].
+    pub fn with_invalidation_strategy(mut self, invalidation_strategy: InvalidationStrategy) -> Self {
+        self.invalidation_strategy = invalidation_strategy;
+        self
+    }
+
+    /// How paths are rewritten before being used as keys. See
+    /// [`crate::ProcessingFsCacheBuilder::path_normalization`].
+    pub fn with_path_normalization(mut self, path_normalization: PathNormalization) -> Self {
+        self.key_normalizer.paths = path_normalization;
+        self
+    }
+
+    /// Rewrite every path with `f` before using it as a key. See
+    /// [`crate::ProcessingFsCacheBuilder::key_rewrite`].
+    pub fn with_key_rewrite(mut self, f: impl Fn(&Path) -> PathBuf + Send + Sync + 'static) -> Self {
+        self.key_normalizer.rewrite = Some(Arc::new(f));
+        self
+    }
+
+    /// Lowercase every path before using it as a key. See
+    /// [`crate::ProcessingFsCacheBuilder::case_insensitive_keys`].
+    pub fn with_case_insensitive_keys(mut self, case_insensitive: bool) -> Self {
+        self.key_normalizer.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// When to retry files which failed to process. See [`crate::ProcessingFsCacheBuilder::retry_policy`].
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Treat every entry as stale once it has been cached for longer than `time_to_live`.
+    /// See [`crate::ProcessingFsCacheBuilder::time_to_live`].
+    pub fn with_time_to_live(self, time_to_live: Duration) -> Self {
+        self.with_entry_time_to_live(move |_, _| Some(time_to_live))
+    }
+
+    /// Choose how long each entry stays fresh from its path and value. Entries for which `f`
+    /// returns None never expire.
+    pub fn with_entry_time_to_live(
+        mut self,
+        f: impl Fn(&Path, &I::T) -> Option<Duration> + Send + Sync + 'static,
+    ) -> Self {
+        self.time_to_live = Some(Arc::new(f));
+        self
+    }
+
+    /// Move the entries of files which have been renamed rather than processing them again.
+    /// See [`crate::ProcessingFsCacheBuilder::detect_renames`].
+    pub fn with_detect_renames(mut self, detect_renames: bool) -> Self {
+        self.detect_renames = detect_renames;
+        self
+    }
+
+    /// Process a file with several hard links only once per update, sharing its value between
+    /// the links. See [`crate::ProcessingFsCacheBuilder::deduplicate_hard_links`].
+    pub fn with_deduplicate_hard_links(mut self, deduplicate_hard_links: bool) -> Self {
+        self.deduplicate_hard_links = deduplicate_hard_links;
+        self
+    }
+
+    pub async fn save(&self) -> FsCacheResult<()> {
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            base_cache.save()?;
+            failures.save()
+        })
+        .await
+    }
+
+    /// See [`crate::ProcessingFsCache::flush`].
+    pub async fn flush(&self) -> FsCacheResult<()> {
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            base_cache.flush()?;
+            failures.flush()
+        })
+        .await
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.pending_modifications() != 0
+    }
+
+    /// See [`crate::ProcessingFsCache::pending_modifications`].
+    pub fn pending_modifications(&self) -> usize {
+        self.base_cache.pending_modifications() + self.failures.pending_modifications()
+    }
+
+    pub async fn get(&self, key: impl AsRef<Path>) -> FsCacheResult<I::T> {
+        self.get_arc(key).await.map(Arc::unwrap_or_clone)
+    }
+
+    /// As [`Self::get`], but returns the cached value itself rather than a clone of it. See
+    /// [`crate::ProcessingFsCache::fetch_arc`].
+    pub async fn get_arc(&self, key: impl AsRef<Path>) -> FsCacheResult<Arc<I::T>> {
+        self.base_cache.fetch(&self.key(key.as_ref())).map(|entry| entry.value)
+    }
+
+    /// Call `f` with the cached value for a path, without cloning it. See
+    /// [`crate::ProcessingFsCache::with_value`]. This holds the cache's read lock, so `f`
+    /// should be quick, and must not modify the cache or it will deadlock.
+    pub fn with_value<R>(&self, key: &Path, f: impl FnOnce(&I::T) -> R) -> FsCacheResult<R> {
+        let key = self.key(key);
+        match self.base_cache.with_item(&key, |entry| f(&entry.value)) {
+            Some(ret) => Ok(ret),
+            None => Err(KeyMissing(key.into_owned())),
+        }
+    }
+
+    /// As [`Self::get`], also returning when the value was cached, how long it took to
+    /// process and the state of the file it was processed from.
+    pub async fn get_with_meta(&self, key: impl AsRef<Path>) -> FsCacheResult<(I::T, EntryMeta)> {
+        let entry = self.base_cache.fetch(&self.key(key.as_ref()))?;
+        let meta = entry.meta();
+        Ok((Arc::unwrap_or_clone(entry.value), meta))
+    }
+
+    /// Insert a value for a path without running the processing function. The path must
+    /// exist, as its modification time and length are recorded to detect future changes.
+    pub async fn insert(&self, key: PathBuf, value: I::T) -> FsCacheResult<()> {
+        let key = self.key(&key).into_owned();
+        let source = self.fs_metadata(&key).await.map_err(|e| CacheFileIo {
+            path: key.clone(),
+            src: e,
+        })?;
+
+        self.insert_entry(key, MtimeCacheEntry::inserted(source, value)).await
+    }
+
+    /// Insert already-processed values for many paths at once, counting as a single
+    /// modification towards the save strategy.
+    pub async fn insert_batch(&self, items: Vec<(PathBuf, I::T)>) -> FsCacheResult<()> {
+        let mut entries = Vec::with_capacity(items.len());
+        for (key, value) in items {
+            let key = self.key(&key).into_owned();
+            let source = self.fs_metadata(&key).await.map_err(|e| CacheFileIo {
+                path: key.clone(),
+                src: e,
+            })?;
+            entries.push((key, MtimeCacheEntry::inserted(source, value)));
+        }
+
+        let base_cache = self.base_cache.clone();
+        blocking(move || base_cache.insert_batch(entries)).await
+    }
+
+    /// Modify a cached value in place. See [`crate::ProcessingFsCache::update_with`].
+    pub async fn update_with<F>(&self, key: PathBuf, f: F) -> FsCacheResult<()>
+    where
+        F: FnOnce(Option<&mut I::T>) -> Option<I::T> + Send + 'static,
+    {
+        let key = self.key(&key).into_owned();
+        let base_cache = self.base_cache.clone();
+        let invalidation_strategy = self.invalidation_strategy;
+
+        blocking(move || {
+            let mut metadata_error = None;
+            base_cache.update_with(key.clone(), |entry| match entry {
+                Some(entry) => f(Some(Arc::make_mut(&mut entry.value))).map(|value| MtimeCacheEntry {
+                    source: entry.source,
+                    value: Arc::new(value),
+                    cached_at: entry.cached_at,
+                    processing_time: entry.processing_time,
+                    file_id: entry.file_id,
+                }),
+                None => {
+                    let value = f(None)?;
+                    match SourceMetadata::read(&key, invalidation_strategy) {
+                        Ok(source) => Some(MtimeCacheEntry::inserted(source, value)),
+                        Err(e) => {
+                            metadata_error = Some(e);
+                            None
+                        }
+                    }
+                }
+            })?;
+
+            match metadata_error {
+                Some(e) => Err(CacheFileIo { path: key, src: e }),
+                None => Ok(()),
+            }
+        })
+        .await
+    }
+
+    /// Remove every entry for which `f` returns false, returning the number of entries removed.
+    pub async fn retain<F>(&self, mut f: F) -> FsCacheResult<usize>
+    where
+        F: FnMut(&Path, &I::T) -> bool + Send + 'static,
+    {
+        let base_cache = self.base_cache.clone();
+        blocking(move || base_cache.retain(|key, entry| f(key, &entry.value))).await
+    }
+
+    /// Remove every entry for a file which no longer exists, without processing anything.
+    /// See [`crate::ProcessingFsCache::prune_missing_files`].
+    pub async fn prune_missing_files(&self) -> FsCacheResult<usize> {
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            let missing: HashSet<PathBuf> = base_cache.keys().into_iter().filter(|key| is_missing(key)).collect();
+            for key in &missing {
+                failures.forget(key)?;
+            }
+            base_cache.retain(|key, _| !missing.contains(key))
+        })
+        .await
+    }
+
+    /// Remove every entry which has outlived its time to live, returning the number of entries removed.
+    pub async fn prune_expired(&self) -> FsCacheResult<usize> {
+        let (base_cache, time_to_live) = (self.base_cache.clone(), self.time_to_live.clone());
+        blocking(move || base_cache.retain(|key, entry| !is_expired(&time_to_live, key, entry))).await
+    }
+
+    /// Remove every entry which was processed or inserted more than `age` ago.
+    /// See [`crate::ProcessingFsCache::prune_older_than`].
+    pub async fn prune_older_than(&self, age: Duration) -> FsCacheResult<usize> {
+        let base_cache = self.base_cache.clone();
+        blocking(move || base_cache.retain(|_, entry| !entry.expired(age))).await
+    }
+
+    /// Mark a file as needing processing without processing it now. See [`crate::ProcessingFsCache::invalidate`].
+    pub async fn invalidate(&self, key: PathBuf) -> FsCacheResult<bool> {
+        let key = self.key(&key).into_owned();
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            failures.forget(&key)?;
+            base_cache.remove(&key)
+        })
+        .await
+    }
+
+    /// Like [`Self::invalidate`], for every file beneath `dir`. Returns the number of entries removed.
+    pub async fn invalidate_dir(&self, dir: PathBuf) -> FsCacheResult<usize> {
+        let dir = self.key(&dir).into_owned();
+        let failures = self.failures.clone();
+        let forget_dir = dir.clone();
+        blocking(move || failures.forget_under(&forget_dir)).await?;
+        self.retain(move |key, _| !key.starts_with(&dir)).await
+    }
+
+    /// Remove every entry for a file which is not part of `file_set`, such as after removing
+    /// one of its roots. Returns the number of entries removed.
+    pub async fn remove_outside(&self, file_set: &FileSet) -> FsCacheResult<usize> {
+        let file_set = self.key_normalizer.file_set(file_set).into_owned();
+        self.retain(move |key, _| file_set.includes(key)).await
+    }
+
+    /// Merge in the entries of another cache file. See [`crate::ProcessingFsCache::merge_from`].
+    pub async fn merge_from(&self, path: PathBuf, strategy: MergeStrategy) -> FsCacheResult<usize> {
+        let version = ProcessorVersion {
+            version: self.interface.version(),
+            config_fingerprint: self.interface.config_fingerprint(),
+        };
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || crate::merge::merge_from(&base_cache, &failures, &path, version, strategy, None)).await
+    }
+
+    /// Compare this cache with another. See [`crate::ProcessingFsCache::diff`].
+    pub fn diff<J, S2>(&self, other: &AsyncProcessingFsCache<J, S2>) -> CacheDiff
+    where
+        J: AsyncCacheInterface<T = I::T> + Send + Sync + 'static,
+        S2: BuildHasher + Default + Send + Sync,
+        I::T: PartialEq,
+    {
+        crate::merge::diff(&self.base_cache, &other.base_cache)
+    }
+
+    /// Remove every entry. The on-disk cache is updated when the cache is next saved.
+    pub async fn clear(&self) -> FsCacheResult<()> {
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            base_cache.clear()?;
+            failures.clear()
+        })
+        .await
+    }
+
+    /// Remove every entry and immediately delete the cache file.
+    pub async fn reset_on_disk(&self) -> FsCacheResult<()> {
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            base_cache.reset_on_disk()?;
+            failures.reset_on_disk()
+        })
+        .await
+    }
+
+    /// Every recorded failure to process a file. See [`crate::ProcessingFsCache::failures`].
+    pub fn failures(&self) -> Vec<(PathBuf, ProcessingFailure)> {
+        self.failures.list()
+    }
+
+    /// Forget every recorded failure, so that the files are retried on the next update.
+    pub async fn clear_failures(&self) -> FsCacheResult<()> {
+        let failures = self.failures.clone();
+        blocking(move || failures.clear()).await
+    }
+
+    pub async fn remove(&self, key: PathBuf) -> FsCacheResult<()> {
+        let key = self.key(&key).into_owned();
+        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+        blocking(move || {
+            failures.forget(&key)?;
+            base_cache.remove(&key).map(|_| ())
+        })
+        .await
+    }
+
+    pub async fn fetch_update(&self, key: PathBuf) -> FsCacheResult<Option<I::T>> {
+        let key = self.key(&key).into_owned();
+        let cache_source = self.cache_source(&key);
+
+        match update_action(
+            &key,
+            self.invalidation_strategy,
+            self.fs_state(&key).await,
+            cache_source,
+        )? {
+            UpdateAction::NoChange => self.get(&key).await.map(Option::from),
+            UpdateAction::Update(source, metadata) => {
+                self.check_known_failure(&key, &source)?;
+                self.force_update_inner(key, source, *metadata).await.map(Option::from)
+            }
+            UpdateAction::Remove => self.remove(key).await.map(|_| None),
+        }
+    }
+
+    /// Process a file and cache the result, even if it is unchanged or a recorded failure
+    /// would otherwise stop it from being retried.
+    pub async fn force_update(&self, key: PathBuf) -> FsCacheResult<I::T> {
+        let key = self.key(&key).into_owned();
+        let (source, metadata) = self.fs_state(&key).await.map_err(|e| CacheFileIo {
+            path: key.clone(),
+            src: e,
+        })?;
+
+        self.force_update_inner(key, source, metadata).await
+    }
+
+    async fn force_update_inner(
+        &self,
+        key: PathBuf,
+        source: SourceMetadata,
+        metadata: Metadata,
+    ) -> FsCacheResult<I::T> {
+        let start = Instant::now();
+        let result = self.interface.try_load_with_metadata(key.clone(), metadata).await;
+        let elapsed = start.elapsed();
+
+        let failures = self.failures.clone();
+        let (retry_policy, strategy) = (self.retry_policy, self.invalidation_strategy);
+        let value = match result {
+            Ok(value) => value,
+            Err(src) => {
+                return blocking(move || {
+                    failures.record(&key, source, &src, retry_policy, strategy)?;
+                    Err(Processing { src, path: key })
+                })
+                .await
+            }
+        };
+
+        let cache_entry = MtimeCacheEntry::processed(source, value.clone(), elapsed);
+        let base_cache = self.base_cache.clone();
+        blocking(move || {
+            failures.forget(&key)?;
+            base_cache.insert(key, cache_entry)
+        })
+        .await?;
+
+        Ok(value)
+    }
+
+    //Fails if the file failed to process before, has not changed since, and the retry
+    //policy says not to try again yet.
+    fn check_known_failure(&self, key: &Path, source: &SourceMetadata) -> FsCacheResult<()> {
+        match self
+            .failures
+            .known_failure(key, source, self.retry_policy, self.invalidation_strategy)
+        {
+            Some(failure) => Err(KnownFailure {
+                src: failure.error,
+                path: key.to_path_buf(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Bring the cache up to date with every file in the file set. New or modified files
+    /// are processed concurrently, and entries for files which no longer exist are removed.
+    /// A failure to update one file does not stop the others, and is listed in the returned report.
+    /// So are any parts of the file set which could not be walked, unless the file set's
+    /// [`crate::WalkErrorPolicy`] is to abort, in which case the update fails before anything is processed.
+    pub async fn update_from_fs(&self, file_set: &FileSet) -> FsCacheResult<UpdateReport> {
+        self.update_from_fs_with_progress(file_set, &()).await
+    }
+
+    /// Like [`Self::update_from_fs`], reporting progress to `progress` as each file is done.
+    pub async fn update_from_fs_with_progress(
+        &self,
+        file_set: &FileSet,
+        progress: &dyn UpdateProgress,
+    ) -> FsCacheResult<UpdateReport> {
+        let (Enumeration { files, errors }, moved) = self.paths_to_update(file_set, true).await?;
+
+        let mut report = self.update_paths(files, progress, false).await?;
+        report.migrated = moved.len();
+        report.record_walk_errors(errors);
+        info!(target: "generic_cache_update", "{}: {}", self.interface.describe(), report);
+        Ok(report)
+    }
+
+    /// List what [`Self::update_from_fs`] would process, reprocess and remove, without
+    /// processing anything or modifying the cache. See [`crate::ProcessingFsCache::plan_update`].
+    pub async fn plan_update(&self, file_set: &FileSet) -> FsCacheResult<UpdatePlan> {
+        let (Enumeration { files, errors }, renames) = self.paths_to_update(file_set, false).await?;
+
+        //files are looked at concurrently, so are numbered to put the plan back in order.
+        let mut outcomes = Vec::with_capacity(files.len());
+        let mut tasks = JoinSet::new();
+        for (n, path) in files.into_iter().enumerate() {
+            if tasks.len() >= self.max_concurrency {
+                if let Some(result) = tasks.join_next().await {
+                    outcomes.push(flatten_join(result)?);
+                }
+            }
+
+            let this = self.clone();
+            tasks.spawn(async move {
+                let outcome = this.plan_entry(&path).await;
+                Ok((n, path, outcome))
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            outcomes.push(flatten_join(result)?);
+        }
+        outcomes.sort_unstable_by_key(|(n, _, _)| *n);
+
+        let mut plan = UpdatePlan::default();
+        for (_, path, outcome) in outcomes {
+            plan.record(path, outcome);
+        }
+        plan.migrate = renames;
+        plan.record_walk_errors(errors);
+        Ok(plan)
+    }
+
+    //Every file in the file set, and every cached file which was not found in it, in the order
+    //they should be visited. If `detect_renames` is set, files which have been renamed are not
+    //visited at either path, and are returned separately. Their entries are moved if
+    //`move_renamed` is set, in which case only the renames which were made are returned.
+    async fn paths_to_update(
+        &self,
+        file_set: &FileSet,
+        move_renamed: bool,
+    ) -> FsCacheResult<(Enumeration, Vec<(PathBuf, PathBuf)>)> {
+        let file_set = file_set.clone();
+        let (base_cache, key_normalizer) = (self.base_cache.clone(), self.key_normalizer.clone());
+        let (invalidation_strategy, time_to_live) = (self.invalidation_strategy, self.time_to_live.clone());
+        let detect_renames = self.detect_renames;
+        blocking(move || {
+            let file_set = key_normalizer.file_set(&file_set);
+            let Enumeration { files, errors } = file_set.enumerate()?;
+            let mut files = key_normalizer.keys(files);
+            let cached_keys = unwalked_keys_removed(base_cache.keys(), &errors);
+            let mut missing_paths = missing_paths(&file_set, &files, cached_keys);
+            let mut renames = match detect_renames {
+                true => find_renames(
+                    &base_cache,
+                    &files,
+                    &missing_paths,
+                    invalidation_strategy,
+                    &time_to_live,
+                ),
+                false => vec![],
+            };
+            take_renamed(&renames, &mut files, &mut missing_paths);
+            if move_renamed && !renames.is_empty() {
+                let moves = renames.clone();
+                let moved = base_cache.rename_many(moves)?;
+                //A file whose old entry was removed meanwhile was not moved, so must still be visited.
+                let (made, unmade): (Vec<_>, Vec<_>) =
+                    renames.into_iter().partition(|(_, to)| base_cache.contains_key(to));
+                files.extend(unmade.into_iter().map(|(_, to)| to));
+                renames = made;
+                info!(target: "generic_cache_update", "moved {} renamed entries", moved);
+            }
+            let mut paths: Vec<_> = files.into_iter().chain(missing_paths).collect();
+            file_set.order(&mut paths);
+            Ok((Enumeration { files: paths, errors }, renames))
+        })
+        .await
+    }
+
+    /// Reprocess a file even if it has not changed. See [`crate::ProcessingFsCache::force_refresh`].
+    pub async fn force_refresh(&self, key: &Path) -> FsCacheResult<()> {
+        self.update_entry(&self.key(key), true).await.map(|_| ())
+    }
+
+    /// Reprocess every cached file beneath `dir`, whether or not it has changed.
+    /// See [`crate::ProcessingFsCache::force_refresh_under`].
+    pub async fn force_refresh_under(&self, dir: &Path) -> FsCacheResult<UpdateReport> {
+        let mut keys = self.keys_under(dir);
+        keys.sort_unstable();
+
+        let report = self.update_paths(keys, &(), true).await?;
+        info!(target: "generic_cache_update", "{}: refreshed {}: {}", self.interface.describe(), dir.display(), report);
+        Ok(report)
+    }
+
+    /// Reprocess a randomly chosen `sample_rate` of the cached files which have not changed,
+    /// and report any whose value differs from the cached one. See [`crate::ProcessingFsCache::verify`].
+    pub async fn verify(&self, sample_rate: f64) -> FsCacheResult<VerifyReport>
+    where
+        I::T: PartialEq,
+    {
+        let sampler = RandomState::new();
+        let keys: Vec<PathBuf> = self
+            .keys()
+            .into_iter()
+            .filter(|key| sampled(&sampler, key, sample_rate))
+            .collect();
+
+        let mut report = VerifyReport::default();
+        let mut tasks = JoinSet::new();
+        for key in keys {
+            if tasks.len() >= self.max_concurrency {
+                if let Some(result) = tasks.join_next().await {
+                    let (key, result) = flatten_join(result)?;
+                    report.record(key, result);
+                }
+            }
+
+            let this = self.clone();
+            tasks.spawn(async move {
+                let result = this.verify_entry(&key).await;
+                Ok((key, result))
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            let (key, result) = flatten_join(result)?;
+            report.record(key, result);
+        }
+
+        info!(target: "generic_cache_update", "{}: verified: {}", self.interface.describe(), report);
+        Ok(report)
+    }
+
+    //Whether reprocessing an unchanged file gives the cached value, or None if the file has changed.
+    async fn verify_entry(&self, key: &Path) -> FsCacheResult<Option<bool>>
+    where
+        I::T: PartialEq,
+    {
+        let entry = match self.base_cache.fetch(key) {
+            Ok(entry) => entry,
+            //removed since the keys were listed.
+            Err(KeyMissing(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (source, metadata) = match self.fs_state(key).await {
+            Ok(state) => state,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(CacheFileIo {
+                    path: key.to_path_buf(),
+                    src: e,
+                })
+            }
+        };
+        if source_changed(self.invalidation_strategy, &source, &entry.source) {
+            return Ok(None);
+        }
+
+        match self.interface.try_load_with_metadata(key.to_path_buf(), metadata).await {
+            Ok(value) => Ok(Some(value == *entry.value)),
+            Err(src) => Err(Processing {
+                src,
+                path: key.to_path_buf(),
+            }),
+        }
+    }
+
+    //If forced, files are processed whether or not they have changed.
+    async fn update_paths(
+        &self,
+        paths: Vec<PathBuf>,
+        progress: &dyn UpdateProgress,
+        force: bool,
+    ) -> FsCacheResult<UpdateReport> {
+        let total = paths.len();
+        progress.discovered(total);
+        let mut done = 0;
+        let mut report = UpdateReport::default();
+        let mut record = |(path, result): (PathBuf, FsCacheResult<UpdateOutcome>)| {
+            done += 1;
+            progress.file_done(&Progress {
+                path: &path,
+                done,
+                total,
+            });
+            report.record(path, result);
+        };
+
+        //hard links to the same file are updated together, by a single task.
+        let groups = match self.deduplicate_hard_links {
+            true => blocking(move || Ok(linked_groups(&paths))).await?,
+            false => paths.into_iter().map(|path| vec![path]).collect(),
+        };
+        let mut tasks: JoinSet<FsCacheResult<Vec<_>>> = JoinSet::new();
+        for group in groups {
+            if tasks.len() >= self.max_concurrency {
+                if let Some(result) = tasks.join_next().await {
+                    flatten_join(result)?.into_iter().for_each(&mut record);
+                }
+            }
+
+            let this = self.clone();
+            tasks.spawn(async move {
+                let results = this.update_linked(&group, force).await;
+                Ok(group.into_iter().zip(results).collect::<Vec<_>>())
+            });
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            flatten_join(result)?.into_iter().for_each(&mut record);
+        }
+
+        Ok(report)
+    }
+
+    //Like fetch_update, but without cloning the value out of the cache. If forced, the file
+    //is processed even if it is unchanged or is a known failure.
+    async fn update_entry(&self, key: &Path, force: bool) -> FsCacheResult<UpdateOutcome> {
+        let was_cached = self.contains_key(key);
+        let action = self.action_for(key, force).await?;
+        self.apply_update(key, was_cached, action, force, &mut None).await
+    }
+
+    //Like update_entry for each of `keys`, which are hard links to the same file, processing the
+    //file at most once. See `ProcessingFsCache::update_linked`.
+    async fn update_linked(&self, keys: &[PathBuf], force: bool) -> Vec<FsCacheResult<UpdateOutcome>> {
+        if let [key] = keys {
+            return vec![self.update_entry(key, force).await];
+        }
+
+        let mut actions = Vec::with_capacity(keys.len());
+        for key in keys {
+            actions.push((self.contains_key(key), self.action_for(key, force).await));
+        }
+        let mut linked = keys.iter().zip(&actions).find_map(|(key, (_, action))| match action {
+            Ok(UpdateAction::NoChange) => self.base_cache.fetch(key).ok(),
+            _ => None,
+        });
+        let mut results = Vec::with_capacity(keys.len());
+        for (key, (was_cached, action)) in keys.iter().zip(actions) {
+            results.push(match action {
+                Ok(action) => self.apply_update(key, was_cached, action, force, &mut linked).await,
+                Err(e) => Err(e),
+            });
+        }
+        results
+    }
+
+    async fn action_for(&self, key: &Path, force: bool) -> FsCacheResult<UpdateAction> {
+        //with no cached state to compare against, an existing file always needs processing.
+        let cache_source = self.cache_source(key).filter(|_| !force);
+        update_action(key, self.invalidation_strategy, self.fs_state(key).await, cache_source)
+    }
+
+    //Carries out `action` for `key`. If `linked` holds the entry of another hard link to the
+    //file, its value is used rather than processing the file, and otherwise it is given the entry
+    //if the file is processed.
+    async fn apply_update(
+        &self,
+        key: &Path,
+        was_cached: bool,
+        action: UpdateAction,
+        force: bool,
+        linked: &mut Option<MtimeCacheEntry<I::T>>,
+    ) -> FsCacheResult<UpdateOutcome> {
+        match action {
+            UpdateAction::NoChange => Ok(UpdateOutcome::Unchanged),
+            UpdateAction::Update(source, _) if !force && self.check_known_failure(key, &source).is_err() => {
+                Ok(UpdateOutcome::Skipped)
+            }
+            UpdateAction::Update(source, metadata) => {
+                match linked {
+                    Some(linked) => {
+                        let (base_cache, failures) = (self.base_cache.clone(), self.failures.clone());
+                        let (key, cache_entry) = (key.to_path_buf(), linked.linked(source));
+                        blocking(move || {
+                            failures.forget(&key)?;
+                            base_cache.insert(key, cache_entry)
+                        })
+                        .await?;
+                    }
+                    None => {
+                        self.force_update_inner(key.to_path_buf(), source, *metadata).await?;
+                        *linked = self.base_cache.fetch(key).ok();
+                    }
+                }
+                Ok(match was_cached {
+                    true => UpdateOutcome::Reprocessed,
+                    false => UpdateOutcome::Processed,
+                })
+            }
+            //a file can disappear between being found and being looked at.
+            UpdateAction::Remove if !was_cached => {
+                let (failures, key) = (self.failures.clone(), key.to_path_buf());
+                blocking(move || failures.forget(&key))
+                    .await
+                    .map(|_| UpdateOutcome::Unchanged)
+            }
+            UpdateAction::Remove => self.remove(key.to_path_buf()).await.map(|_| UpdateOutcome::Removed),
+        }
+    }
+
+    //What update_entry would do, without doing it.
+    async fn plan_entry(&self, key: &Path) -> FsCacheResult<UpdateOutcome> {
+        let was_cached = self.contains_key(key);
+        let cache_source = self.cache_source(key);
+        let action = update_action(key, self.invalidation_strategy, self.fs_state(key).await, cache_source)?;
+
+        Ok(match action {
+            UpdateAction::NoChange => UpdateOutcome::Unchanged,
+            UpdateAction::Update(source, _) if self.check_known_failure(key, &source).is_err() => {
+                UpdateOutcome::Skipped
+            }
+            UpdateAction::Update(..) if was_cached => UpdateOutcome::Reprocessed,
+            UpdateAction::Update(..) => UpdateOutcome::Processed,
+            UpdateAction::Remove if was_cached => UpdateOutcome::Removed,
+            UpdateAction::Remove => UpdateOutcome::Unchanged,
+        })
+    }
+
+    /// Write every cached path and value to `writer` as JSON. See [`crate::ProcessingFsCache::export_json`].
+    #[cfg(feature = "json")]
+    pub fn export_json(&self, writer: impl std::io::Write) -> FsCacheResult<()> {
+        self.exported_entries()?.write_json(writer)
+    }
+
+    /// Write every cached path and value to `writer` as CSV. See [`crate::ProcessingFsCache::export_csv`].
+    #[cfg(feature = "json")]
+    pub fn export_csv(&self, writer: impl std::io::Write) -> FsCacheResult<()> {
+        self.exported_entries()?.write_csv(writer)
+    }
+
+    #[cfg(feature = "json")]
+    fn exported_entries(&self) -> FsCacheResult<crate::export::ExportedEntries> {
+        let mut entries = crate::export::ExportedEntries::new();
+        let mut result = Ok(());
+        self.base_cache.for_each(|key, entry| {
+            if result.is_ok() {
+                result = entries.push(key, &entry.value);
+            }
+        });
+        result.map(|_| entries)
+    }
+
+    pub fn contains_key(&self, key: &Path) -> bool {
+        self.base_cache.contains_key(&self.key(key))
+    }
+
+    pub fn keys(&self) -> Vec<PathBuf> {
+        self.base_cache.keys()
+    }
+
+    /// Like [`Self::keys`], but sorted, so that anything built from them is the same from run to run.
+    pub fn sorted_keys(&self) -> Vec<PathBuf> {
+        let mut keys = self.base_cache.keys();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Call `f` with every cached path and value, without cloning either. This holds the
+    /// cache's read lock, so `f` must not modify the cache or it will deadlock.
+    pub fn for_each(&self, mut f: impl FnMut(&Path, &I::T)) {
+        self.base_cache.for_each(|key, entry| f(key, &entry.value))
+    }
+
+    /// The cached paths beneath `dir`.
+    pub fn keys_under(&self, dir: &Path) -> Vec<PathBuf> {
+        let dir = self.key(dir);
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, _| {
+            if key.starts_with(&dir) {
+                ret.push(key.to_path_buf());
+            }
+        });
+        ret
+    }
+
+    /// The cached paths and values beneath `dir`.
+    pub fn entries_under(&self, dir: &Path) -> Vec<(PathBuf, I::T)> {
+        let dir = self.key(dir);
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, entry| {
+            if key.starts_with(&dir) {
+                ret.push((key.to_path_buf(), I::T::clone(&entry.value)));
+            }
+        });
+        ret
+    }
+
+    /// The cached paths whose path and value satisfy `f`.
+    pub fn find(&self, mut f: impl FnMut(&Path, &I::T) -> bool) -> Vec<PathBuf> {
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, entry| {
+            if f(key, &entry.value) {
+                ret.push(key.to_path_buf());
+            }
+        });
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        self.base_cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.base_cache.is_empty()
+    }
+
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        self.key_normalizer.key(path)
+    }
+
+    //An expired entry is compared as if it were not cached, so that it is always processed again.
+    fn cache_source(&self, key: &Path) -> Option<SourceMetadata> {
+        self.base_cache
+            .fetch(key)
+            .ok()
+            .filter(|entry| !is_expired(&self.time_to_live, key, entry))
+            .map(|entry| entry.source)
+    }
+
+    async fn insert_entry(&self, key: PathBuf, entry: MtimeCacheEntry<I::T>) -> FsCacheResult<()> {
+        let base_cache = self.base_cache.clone();
+        blocking(move || base_cache.insert(key, entry)).await
+    }
+
+    async fn fs_metadata(&self, key: &Path) -> Result<SourceMetadata, std::io::Error> {
+        self.fs_state(key).await.map(|(source, _)| source)
+    }
+
+    async fn fs_state(&self, key: &Path) -> Result<(SourceMetadata, Metadata), std::io::Error> {
+        //hashing reads the whole file, so is done on the blocking thread pool.
+        if self.invalidation_strategy.needs_content_hash() {
+            let (key, strategy) = (key.to_path_buf(), self.invalidation_strategy);
+            return match spawn_blocking(move || SourceMetadata::read_with_metadata(&key, strategy)).await {
+                Ok(result) => result,
+                Err(e) => Err(std::io::Error::other(e)),
+            };
+        }
+
+        let metadata = tokio::fs::metadata(key).await?;
+        Ok((SourceMetadata::from_fs(&metadata)?, metadata))
+    }
+}
+
+//Run a (potentially slow) synchronous cache operation on tokio's blocking thread pool.
+async fn blocking<F, R>(f: F) -> FsCacheResult<R>
+where
+    F: FnOnce() -> FsCacheResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    match spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => Err(AsyncTask(format!("{}", e))),
+    }
+}
+
+fn flatten_join<R>(result: Result<FsCacheResult<R>, tokio::task::JoinError>) -> FsCacheResult<R> {
+    match result {
+        Ok(result) => result,
+        Err(e) => Err(AsyncTask(format!("{}", e))),
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    hash::BuildHasher,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Weak,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::error;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::base_fs_cache::BaseFsCache;
+
+// A background thread which periodically saves a cache if it has unsaved changes. The thread
+// is stopped (and joined) when this is dropped.
+pub(crate) struct Autosave {
+    stop: Option<mpsc::Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Autosave {
+    pub(crate) fn spawn<T, S>(cache: Weak<BaseFsCache<T, S>>, interval: Duration) -> Self
+    where
+        T: DeserializeOwned + Serialize + Send + Sync + Clone + 'static,
+        S: BuildHasher + Default + Send + Sync + 'static,
+    {
+        let (stop, stop_rx) = mpsc::channel::<()>();
+
+        let handle = std::thread::spawn(move || loop {
+            match stop_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => (),
+                //either told to stop, or the owning cache has gone away.
+                _ => return,
+            }
+
+            let cache = match cache.upgrade() {
+                Some(cache) => cache,
+                None => return,
+            };
+
+            if let Err(e) = cache.save_if_dirty() {
+                error!(target: "generic_cache_transactions", "Background save failed: {}", e);
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Autosave {
+    fn drop(&mut self) {
+        //Dropping the sender wakes the thread up and tells it to stop.
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
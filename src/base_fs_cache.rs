@@ -1,44 +1,88 @@
 use std::{
-    fmt::Debug,
+    collections::{hash_map::RandomState, HashSet},
+    hash::BuildHasher,
     path::{Path, PathBuf},
-    sync::{
-        atomic::{AtomicU32, Ordering::Relaxed},
-        RwLock,
-    },
+    sync::{Arc, Mutex, MutexGuard},
+    time::Instant,
 };
 
-use log::info;
-use log::trace;
+use log::{error, info};
 use serde::{de::DeserializeOwned, Serialize};
 
-use crate::errors::{
-    FsCacheErrorKind::{self, *},
-    FsCacheResult,
+use crate::{
+    cache_key::{CacheKey, DisplayKey, StoredKey},
+    errors::{FsCacheErrorKind, FsCacheResult},
+    format::ProcessorVersion,
+    observer::CacheObserver,
+    save_strategy::{DirtyState, SaveStrategy},
+    sharded_map::ShardedMap,
+    storage::StorageBackend,
 };
 
-//Types defining the on-disk format of the filesystem cacher.
-type CacheDiskFormat<T> = std::collections::HashMap<PathBuf, T>;
-
-#[derive(Default, Debug)]
-pub struct BaseFsCache<T> {
+/// The map of entries underlying every cache in this crate, which is loaded from and saved to a
+/// [`StorageBackend`] as its [`SaveStrategy`] decides, without processing any files itself.
+/// Entries are kept under paths unless another [`CacheKey`] is chosen as `K`, such as to cache
+/// values for `(path, offset)` pairs.
+///
+/// The entries are split between several maps by a hash of their keys, each behind a lock of
+/// its own, so that threads inserting or removing different entries at once rarely wait for
+/// each other. Operations on many entries, such as [`Self::retain`], lock every map involved.
+pub struct BaseFsCache<T, S = RandomState, K = PathBuf>
+where
+    T: DeserializeOwned + Serialize + Send + Sync + Clone,
+    S: BuildHasher + Default + Send + Sync,
+    K: CacheKey,
+{
     loaded_from_disk: bool,
-    cache_save_threshold: u32,
-    cache_modified_count: AtomicU32,
-    cache_path: PathBuf,
-    cache: RwLock<CacheDiskFormat<T>>,
+    save_on_drop: bool,
+    save_strategy: Arc<dyn SaveStrategy>,
+    last_save: Mutex<Instant>,
+    backend: Box<dyn StorageBackend<T, S, K>>,
+    cache: ShardedMap<T, S, K>,
+    //Only modified while holding the write locks on the shards of the keys being recorded, so that
+    //it always agrees with the cache. When both are needed, the cache is locked first, then this,
+    //then `last_save`.
+    unsaved: Mutex<Unsaved<S, K>>,
+    observer: Option<Box<dyn CacheObserver<T, K::Ref>>>,
+    size_limit: Option<SizeLimit<T, K>>,
+    evicted: Mutex<Vec<K>>,
+    //Held while saving or otherwise writing to the backend, so that saves made at once by several
+    //threads (such as workers crossing the save threshold, autosave and explicit saves) never write
+    //the same files together, and an older snapshot is never written after a newer one. Locked
+    //before the cache.
+    saving: Mutex<()>,
 }
 
-impl<T> BaseFsCache<T>
+//Orders entries for eviction, lowest first, from their key, value and estimated encoded size.
+type EvictionRankFn<T, K> = Box<dyn Fn(&<K as CacheKey>::Ref, &T, u64) -> u64 + Send + Sync>;
+
+struct SizeLimit<T, K: CacheKey> {
+    max_bytes: u64,
+    rank: EvictionRankFn<T, K>,
+}
+
+impl<T, S, K> BaseFsCache<T, S, K>
 where
     T: DeserializeOwned + Serialize + Send + Sync + Clone,
+    S: BuildHasher + Default + Send + Sync,
+    K: CacheKey,
 {
-    pub fn new(cache_save_threshold: u32, cache_path: PathBuf) -> FsCacheResult<Self> {
+    pub fn with_backend(
+        save_strategy: Arc<dyn SaveStrategy>,
+        backend: Box<dyn StorageBackend<T, S, K>>,
+    ) -> FsCacheResult<Self> {
         let mut ret = Self {
             loaded_from_disk: false,
-            cache_save_threshold,
-            cache_modified_count: Default::default(),
-            cache_path,
+            save_on_drop: true,
+            save_strategy,
+            last_save: Mutex::new(Instant::now()),
+            backend,
             cache: Default::default(),
+            unsaved: Default::default(),
+            observer: None,
+            size_limit: None,
+            evicted: Default::default(),
+            saving: Mutex::new(()),
         };
 
         match ret.load_cache_from_disk() {
@@ -47,232 +91,635 @@ where
         }
     }
 
+    /// Whether to save any unsaved changes when the cache is dropped. Enabled by default.
+    pub fn set_save_on_drop(&mut self, save_on_drop: bool) {
+        self.save_on_drop = save_on_drop;
+    }
+
+    /// Whether the cache can be modified while it is being saved, which it can by default.
+    ///
+    /// Saving shares every map the entries are held in with a snapshot, which only takes as long
+    /// as locking each map in turn, and the backend encodes the entries of the snapshot one at a
+    /// time as they are written. A map modified before the save has finished is copied first,
+    /// so saving takes extra memory only for the maps modified meanwhile. Values held in an
+    /// `Arc`, as those of [`crate::ProcessingFsCache`] are, are shared with the copy rather than
+    /// copied. Changes made while saving are saved next time.
+    ///
+    /// If disabled, modifying a map waits until the save has finished instead, so that saving
+    /// takes no memory beyond the cache.
+    pub fn set_save_from_snapshot(&mut self, save_from_snapshot: bool) {
+        self.cache.set_copy_on_write(save_from_snapshot);
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn CacheObserver<T, K::Ref>>) {
+        self.observer = Some(observer);
+    }
+
+    /// Whenever the cache is about to be saved, evict entries until it is estimated to take no
+    /// more than `max_bytes` once encoded. Entries are evicted lowest `rank` first, which is given
+    /// the key and value of each entry and an estimate of the bytes it takes up. Sizes are
+    /// estimated as bincode would encode the entries, before any compression or encryption.
+    pub fn set_size_limit(&mut self, max_bytes: u64, rank: impl Fn(&K::Ref, &T, u64) -> u64 + Send + Sync + 'static) {
+        self.size_limit = Some(SizeLimit {
+            max_bytes,
+            rank: Box::new(rank),
+        });
+    }
+
+    /// The keys evicted to keep within [`Self::set_size_limit`] since this was last called, in
+    /// the order they were evicted.
+    pub fn take_evicted(&self) -> Vec<K> {
+        match self.evicted.lock() {
+            Ok(mut evicted) => std::mem::take(&mut *evicted),
+            Err(_) => unreachable!(),
+        }
+    }
+
+    fn notify(&self, f: impl FnOnce(&dyn CacheObserver<T, K::Ref>)) {
+        if let Some(observer) = &self.observer {
+            f(observer.as_ref())
+        }
+    }
+
     pub fn save(&self) -> FsCacheResult<()> {
-        let modified_count = self.cache_modified_count.load(Relaxed);
-        if modified_count != 0 {
-            self.save_inner()
+        self.save_if_dirty()
+    }
+
+    /// Save whether or not there are unsaved changes.
+    pub fn flush(&self) -> FsCacheResult<()> {
+        self.save_inner(SaveProgress::default())
+    }
+
+    /// Whether there are changes which have not been saved.
+    pub fn is_dirty(&self) -> bool {
+        !self.lock_unsaved().keys.is_empty()
+    }
+
+    /// The number of entries inserted, updated or removed since the last save.
+    pub fn pending_modifications(&self) -> usize {
+        self.lock_unsaved().keys.len()
+    }
+
+    pub fn save_if_dirty(&self) -> FsCacheResult<()> {
+        if self.is_dirty() {
+            self.save_inner(SaveProgress::default())
         } else {
             Ok(())
         }
     }
 
-    fn save_inner(&self) -> FsCacheResult<()> {
-        use std::io::BufWriter;
+    //`claimed` is the progress towards the save strategy already taken by the caller which
+    //decided to save. Whatever has been made since is taken here, as it is saved too.
+    fn save_inner(&self, claimed: SaveProgress) -> FsCacheResult<()> {
+        let _saving = self.lock_saving();
+        self.evict_to_size_limit();
+
+        //The unsaved keys are taken, and the backend told of the snapshot, while every shard is locked,
+        //so that both agree with the snapshot.
+        let (snapshot, changed_keys, taken) = {
+            let readable_cache = self.cache.read_all();
+            self.backend.taking_snapshot();
+            let mut unsaved = self.lock_unsaved();
+            let changed_keys: Vec<K> = std::mem::take(&mut unsaved.keys).into_iter().collect();
+            let taken = unsaved.take_progress();
+            drop(unsaved);
+            (readable_cache.into_snapshot(), changed_keys, taken)
+        };
+        let result = self.backend.save_changes(&snapshot.view(), &changed_keys);
+        drop(snapshot);
+
+        //If saving failed, the changes still need saving next time, and still count towards it.
+        match result {
+            Ok(()) => {
+                *match self.last_save.lock() {
+                    Ok(last_save) => last_save,
+                    Err(_) => unreachable!(),
+                } = Instant::now();
+                self.notify(|observer| observer.on_save());
+            }
+            Err(_) => {
+                let mut unsaved = self.lock_unsaved();
+                unsaved.keys.extend(changed_keys);
+                unsaved.add_progress(claimed);
+                unsaved.add_progress(taken);
+            }
+        }
+        result
+    }
 
-        //The cache file and its directory may not exist yet. So first create the directory
-        //first if necessary.
-        if !&self.cache_path.exists() {
-            if let Some(ref parent_dir) = self.cache_path.parent() {
-                if let Err(e) = std::fs::create_dir_all(parent_dir) {
-                    return Err(CacheFileIo {
-                        src: e,
-                        path: self.cache_path.clone(),
-                    });
-                }
+    //The evicted entries are saved as removed along with everything else, so unlike other
+    //removals they are not written to a journal first.
+    fn evict_to_size_limit(&self) {
+        let limit = match &self.size_limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let mut writeable_cache = self.cache.write_all();
+
+        let mut ranked: Vec<(u64, u64, &K)> = writeable_cache
+            .iter()
+            .map(|(key, item)| {
+                let size = bincode::serialized_size(&(StoredKey::<K>(key.borrow()), item)).unwrap_or(0);
+                ((limit.rank)(key.borrow(), item, size), size, key)
+            })
+            .collect();
+        let mut total: u64 = ranked.iter().map(|(_, size, _)| size).sum();
+        if total <= limit.max_bytes {
+            return;
+        }
+        ranked.sort_unstable_by_key(|(rank, _, _)| *rank);
+        let mut victims = vec![];
+        for (_, size, key) in ranked {
+            if total <= limit.max_bytes {
+                break;
             }
+            total -= size;
+            victims.push(key.clone());
         }
 
-        //If the application dies or gets killed while saving, we risk losing the cache.
-        //So we will first save the cache to a temporary file and rename it into the real
-        //cache file.
-        let temp_store_path = self.cache_path.with_extension("tmp");
+        info!(target: "generic_cache_remove",
+            "evicting {} entries to keep within {} bytes",
+            victims.len(),
+            limit.max_bytes
+        );
+        for key in &victims {
+            writeable_cache.remove(key.borrow());
+            self.notify(|observer| observer.on_remove(key.borrow()));
+        }
+        self.lock_unsaved().keys.extend(victims.iter().cloned());
+        match self.evicted.lock() {
+            Ok(mut evicted) => evicted.extend(victims),
+            Err(_) => unreachable!(),
+        }
+    }
 
-        info!(
-            target: "generic_cache_transactions",
-            "saving updated cache at {} of size {}",
+    fn lock_saving(&self) -> MutexGuard<'_, ()> {
+        match self.saving.lock() {
+            Ok(saving) => saving,
+            Err(_) => unreachable!(),
+        }
+    }
 
-            self.cache_path.display(),
-            match self.cache.read() {
-                Err(_) => unreachable!(),
-                Ok(cache) => cache.len()
-            }
-        );
+    fn lock_unsaved(&self) -> MutexGuard<'_, Unsaved<S, K>> {
+        match self.unsaved.lock() {
+            Ok(unsaved) => unsaved,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    //Called before any option is set, which would otherwise be lost with the old map.
+    fn load_cache_from_disk(&mut self) -> FsCacheResult<()> {
+        self.cache = ShardedMap::new(self.backend.load()?);
+        self.loaded_from_disk = true;
+        Ok(())
+    }
 
-        let temp_cache_file = match std::fs::File::create(&temp_store_path) {
-            Ok(temp_cache_file) => Ok(temp_cache_file),
-            Err(e) => Err(CacheFileIo {
-                src: e,
-                path: self.cache_path.to_path_buf(),
-            }),
-        }?;
+    /////////////////////////////
+    // Wrappers for HashMap.
+    /////////////////////////////
 
-        let mut cache_buf = BufWriter::new(temp_cache_file);
+    pub fn insert(&self, key: K, item: T) -> FsCacheResult<()> {
+        let bytes = self.dirty_bytes(&[(key.borrow(), Some(&item))]);
 
-        let readable_cache = match self.cache.read() {
-            Ok(cache) => cache,
-            Err(_) => unreachable!(),
+        info!(target: "generic_cache_insert",
+            "inserting : {}",
+            DisplayKey::<K>(key.borrow())
+        );
+        let cache_entry = item;
+        let save = {
+            let mut writeable_cache = self.cache.write([key.borrow()]);
+            self.backend.append(&[(key.borrow(), Some(&cache_entry))])?;
+            self.notify(|observer| observer.on_insert(key.borrow(), &cache_entry));
+            writeable_cache.insert(key.clone(), cache_entry);
+            self.record_modification(
+                std::iter::once(key),
+                SaveProgress {
+                    modifications: 1,
+                    bytes,
+                },
+            )
         };
+        self.save_if_claimed(save)
+    }
 
-        if let Err(e) = bincode::serialize_into(&mut cache_buf, &*readable_cache) {
-            return Err(Serialization {
-                src: format!("{}", e),
-                path: self.cache_path.to_path_buf(),
-            });
-        }
+    /// Insert many entries at once. The write lock of each map involved is only taken once, and
+    /// the whole batch counts as a single modification towards the save strategy.
+    pub fn insert_batch(&self, items: Vec<(K, T)>) -> FsCacheResult<()> {
+        self.insert_entries(items, 1)
+    }
 
-        let temp_cache_file = match cache_buf.into_inner() {
-            Err(e) => {
-                return Err(CacheFileIo {
-                    src: e.into_error(),
-                    path: self.cache_path.to_path_buf(),
-                })
-            }
-            Ok(x) => x,
-        };
+    /// As [`Self::insert_batch`], but every entry counts as a modification towards the save
+    /// strategy, as if they had been inserted one at a time.
+    pub fn insert_many(&self, items: Vec<(K, T)>) -> FsCacheResult<()> {
+        let modifications = items.len() as u32;
+        self.insert_entries(items, modifications)
+    }
+
+    //As `insert_many`, but the result of any save it triggers is returned separately, as the
+    //entries stay inserted even if saving fails. If the outer result is an error, nothing was inserted.
+    pub(crate) fn insert_many_reporting_save(&self, items: Vec<(K, T)>) -> FsCacheResult<FsCacheResult<()>> {
+        let modifications = items.len() as u32;
+        self.insert_entries_unsaved(items, modifications)
+            .map(|save| self.save_if_claimed(save))
+    }
 
-        if let Err(e) = temp_cache_file.sync_all() {
-            return Err(CacheFileIo {
-                src: e,
-                path: self.cache_path.to_path_buf(),
-            });
+    fn insert_entries(&self, items: Vec<(K, T)>, modifications: u32) -> FsCacheResult<()> {
+        let save = self.insert_entries_unsaved(items, modifications)?;
+        self.save_if_claimed(save)
+    }
+
+    //Returns the claimed progress if the cache should now be saved, like `record_modification`.
+    fn insert_entries_unsaved(&self, items: Vec<(K, T)>, modifications: u32) -> FsCacheResult<Option<SaveProgress>> {
+        if items.is_empty() {
+            return Ok(None);
         }
 
-        //now move the store to replace the old one.
-        if let Err(e) = std::fs::rename(temp_store_path, &self.cache_path) {
-            return Err(CacheFileIo {
-                src: e,
-                path: self.cache_path.to_path_buf(),
-            });
+        let records: Vec<(&K::Ref, Option<&T>)> = items.iter().map(|(key, item)| (key.borrow(), Some(item))).collect();
+        let bytes = self.dirty_bytes(&records);
+
+        info!(target: "generic_cache_insert", "inserting batch of {} entries", items.len());
+        let mut writeable_cache = self.cache.write(items.iter().map(|(key, _)| key.borrow()));
+        self.backend.append(&records)?;
+
+        let mut keys = Vec::with_capacity(items.len());
+        for (key, item) in items {
+            self.notify(|observer| observer.on_insert(key.borrow(), &item));
+            writeable_cache.insert(key.clone(), item);
+            keys.push(key);
         }
+        Ok(self.record_modification(keys, SaveProgress { modifications, bytes }))
+    }
 
-        Ok(())
+    /// Modify an entry in place. `f` is given the current value if there is one, and may
+    /// mutate it directly and/or return a replacement. If `f` returns a value for a key
+    /// which is not cached, it is inserted.
+    ///
+    /// As the value is modified in place, it cannot be put back if the backend then fails to
+    /// store the change. The modified value is kept and saved along with the next save, and the
+    /// error is returned.
+    pub fn update_with(&self, key: K, f: impl FnOnce(Option<&mut T>) -> Option<T>) -> FsCacheResult<()> {
+        let (appended, save) = {
+            let mut writeable_cache = self.cache.write([key.borrow()]);
+
+            if let Some(replacement) = f(writeable_cache.get_mut(key.borrow())) {
+                writeable_cache.insert(key.clone(), replacement);
+            }
+
+            //Nothing was cached, and nothing was inserted.
+            let item = match writeable_cache.get(key.borrow()) {
+                Some(item) => item,
+                None => return Ok(()),
+            };
+            info!(target: "generic_cache_insert", "updating : {}", DisplayKey::<K>(key.borrow()));
+            let record = [(key.borrow(), Some(item))];
+            let appended = self.backend.append(&record);
+            let bytes = self.dirty_bytes(&record);
+            self.notify(|observer| observer.on_insert(key.borrow(), item));
+            let save = self.record_modification(
+                std::iter::once(key),
+                SaveProgress {
+                    modifications: 1,
+                    bytes,
+                },
+            );
+            (appended, save)
+        };
+
+        //A claimed save must still happen, or the progress it took would go uncounted.
+        let saved = self.save_if_claimed(save);
+        appended.and(saved)
     }
 
-    fn load_cache_from_disk(&mut self) -> FsCacheResult<()> {
-        //Try and read from disk. If there is nothing  available, this is not an error.
-        //It just means that no cached values can be used. If so then go ahead and return early
-        //as there is no deserialization to do.
-        if !&self.cache_path.exists() {
-            info!(target: "generic_cache_startup",
-                "Creating new cache file: {}.", self.cache_path.display()
+    /// Remove every entry for which `f` returns false, with every map write locked throughout.
+    /// Any removals count as a single modification towards the save strategy. Returns the number
+    /// of entries removed.
+    pub fn retain(&self, mut f: impl FnMut(&K::Ref, &T) -> bool) -> FsCacheResult<usize> {
+        let (removed_count, save) = {
+            let mut writeable_cache = self.cache.write_all();
+
+            let removed: Vec<K> = writeable_cache
+                .iter()
+                .filter(|(key, item)| !f((*key).borrow(), item))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if removed.is_empty() {
+                return Ok(0);
+            }
+
+            info!(target: "generic_cache_remove", "Removing {} entries", removed.len());
+            let records: Vec<(&K::Ref, Option<&T>)> = removed.iter().map(|key| (key.borrow(), None)).collect();
+            self.backend.append(&records)?;
+            let bytes = self.dirty_bytes(&records);
+
+            for key in &removed {
+                writeable_cache.remove(key.borrow());
+                self.notify(|observer| observer.on_remove(key.borrow()));
+            }
+            let removed_count = removed.len();
+            let save = self.record_modification(
+                removed,
+                SaveProgress {
+                    modifications: 1,
+                    bytes,
+                },
             );
-            self.cache = Default::default();
-            self.loaded_from_disk = true;
-            return Ok(());
+            (removed_count, save)
+        };
+        self.save_if_claimed(save)?;
+        Ok(removed_count)
+    }
+
+    /// Compare the stored processor version with `version`. If only the version number differs,
+    /// remove the entries `is_stale` selects, given the stored version number. If the
+    /// configuration fingerprint differs, every entry is removed. The new version is then
+    /// recorded. Returns the number of entries removed.
+    pub fn check_version(
+        &self,
+        version: ProcessorVersion,
+        mut is_stale: impl FnMut(u32, &K::Ref, &T) -> bool,
+    ) -> FsCacheResult<usize> {
+        let stored_version = self.backend.stored_version()?;
+        if stored_version == Some(version) {
+            return Ok(0);
         }
 
-        let cache_file = match std::fs::File::open(&self.cache_path) {
-            Ok(f) => f,
-            Err(e) => {
-                return Err(CacheFileIo {
-                    src: e,
-                    path: self.cache_path.clone(),
-                })
+        let removed = match stored_version {
+            Some(stored) if stored.config_fingerprint != version.config_fingerprint => {
+                let removed = self.retain(|_, _| false)?;
+                info!(target: "generic_cache_startup",
+                    "Processor configuration changed. Removed {} stale entries", removed
+                );
+                removed
             }
+            Some(stored) => {
+                let removed = self.retain(|key, item| !is_stale(stored.version, key, item))?;
+                info!(target: "generic_cache_startup",
+                    "Processor version changed from {} to {}. Removed {} stale entries",
+                    stored.version, version.version, removed
+                );
+                removed
+            }
+            None => 0,
         };
 
-        let reader = std::io::BufReader::new(cache_file);
-        let decode_result = bincode::deserialize_from(reader);
+        let _saving = self.lock_saving();
+        let readable_cache = self.cache.read_all();
+        self.backend.taking_snapshot();
+        let snapshot = readable_cache.into_snapshot();
+        self.backend.store_version(version, &snapshot.view())?;
+        Ok(removed)
+    }
 
-        //we may fail to read the hash file. This most likely to occur in development if <T> is changed.
-        match decode_result {
-            Ok(cache_file_data) => {
-                self.cache = cache_file_data;
-                self.loaded_from_disk = true;
+    /// Remove every entry. The removals are saved like any other modification.
+    pub fn clear(&self) -> FsCacheResult<()> {
+        self.retain(|_, _| false).map(|_| ())
+    }
 
-                trace!(target: "generic_cache_startup",
-                    "Loaded cache. Path: {}, Entries: {}", self.cache_path.display(), self.len()
-                );
-                Ok(())
-            }
-            Err(e) => Err(Deserialization {
-                src: format!("{}", e),
-                path: self.cache_path.to_path_buf(),
-            }),
+    /// Remove every entry and delete everything stored by the backend, so the cache starts
+    /// again from nothing.
+    pub fn reset_on_disk(&self) -> FsCacheResult<()> {
+        let _saving = self.lock_saving();
+        let mut writeable_cache = self.cache.write_all();
+
+        self.backend.reset()?;
+        for (key, _) in writeable_cache.iter() {
+            self.notify(|observer| observer.on_remove(key.borrow()));
         }
+        writeable_cache.clear();
+        *self.lock_unsaved() = Unsaved::default();
+        Ok(())
     }
 
-    /////////////////////////////
-    // Wrappers for HashMap.
-    /////////////////////////////
+    //An estimate of how much would be lost if `records` were never saved: the bincode size of
+    //each key and value. Removals only count their key. Serializing every value is not free,
+    //so this is only worked out if the save strategy asks for it.
+    fn dirty_bytes(&self, records: &[(&K::Ref, Option<&T>)]) -> u64 {
+        if !self.save_strategy.needs_dirty_bytes() {
+            return 0;
+        }
+        records
+            .iter()
+            .map(|(key, item)| bincode::serialized_size(&(StoredKey::<K>(key), item)).unwrap_or(0))
+            .sum()
+    }
 
-    pub fn insert(&self, key: PathBuf, item: T) -> FsCacheResult<()> {
-        let cache_modified_count = self.cache_modified_count.fetch_add(1, Relaxed);
+    /// Remove the entry for a key, returning whether there was one. Nothing is modified if
+    /// there was not.
+    pub fn remove(&self, key: &K::Ref) -> FsCacheResult<bool> {
+        let bytes = self.dirty_bytes(&[(key, None)]);
+        let save = {
+            let mut writeable_cache = self.cache.write([key]);
+            if writeable_cache.get(key).is_none() {
+                return Ok(false);
+            }
+            info!(target: "generic_cache_remove", "Removing: {}", DisplayKey::<K>(key));
+            self.backend.append(&[(key, None)])?;
+            writeable_cache.remove(key);
+            self.notify(|observer| observer.on_remove(key));
+            self.record_modification(
+                std::iter::once(key.to_owned()),
+                SaveProgress {
+                    modifications: 1,
+                    bytes,
+                },
+            )
+        };
+        self.save_if_claimed(save).map(|_| true)
+    }
 
-        info!(target: "generic_cache_insert",
-            "inserting : {}",
-            key.display()
-        );
-        let cache_entry = item;
-        {
-            let mut writeable_cache = match self.cache.write() {
-                Ok(cache) => cache,
+    /// Move entries to new keys, with the maps holding both write locked throughout, replacing
+    /// anything cached for the new keys. The moves count as a single modification towards the
+    /// save strategy, and keys which are not cached are skipped. Returns the number of entries moved.
+    pub fn rename_many(&self, moves: Vec<(K, K)>) -> FsCacheResult<usize> {
+        let (moved, save) = {
+            let mut writeable_cache = self
+                .cache
+                .write(moves.iter().flat_map(|(from, to)| [from.borrow(), to.borrow()]));
+            let moved: Vec<(K, K, T)> = moves
+                .into_iter()
+                .filter_map(|(from, to)| writeable_cache.remove(from.borrow()).map(|item| (from, to, item)))
+                .collect();
+            if moved.is_empty() {
+                return Ok(0);
+            }
+
+            info!(target: "generic_cache_insert", "moving {} entries", moved.len());
+            let records: Vec<(&K::Ref, Option<&T>)> = moved
+                .iter()
+                .flat_map(|(from, to, item)| [(from.borrow(), None), (to.borrow(), Some(item))])
+                .collect();
+            let bytes = self.dirty_bytes(&records);
+            if let Err(e) = self.backend.append(&records) {
+                for (from, _, item) in moved {
+                    writeable_cache.insert(from, item);
+                }
+                return Err(e);
+            }
+
+            let count = moved.len();
+            let mut keys = Vec::with_capacity(count * 2);
+            for (from, to, item) in moved {
+                self.notify(|observer| observer.on_remove(from.borrow()));
+                self.notify(|observer| observer.on_insert(to.borrow(), &item));
+                writeable_cache.insert(to.clone(), item);
+                keys.push(from);
+                keys.push(to);
+            }
+            (
+                count,
+                self.record_modification(
+                    keys,
+                    SaveProgress {
+                        modifications: 1,
+                        bytes,
+                    },
+                ),
+            )
+        };
+        self.save_if_claimed(save).map(|_| moved)
+    }
+
+    //Must be called while holding the write locks on the shards of `keys`, once the modification
+    //has been made. Counting and checking the save strategy under one lock means every modification
+    //is counted, and only the one which crosses the threshold claims the save. Returns the claimed
+    //progress if the cache should now be saved, which must be passed to `save_if_claimed` once
+    //the write locks have been released.
+    fn record_modification(&self, keys: impl IntoIterator<Item = K>, progress: SaveProgress) -> Option<SaveProgress> {
+        let mut unsaved = self.lock_unsaved();
+        unsaved.keys.extend(keys);
+        unsaved.add_progress(progress);
+
+        let state = DirtyState {
+            modifications: unsaved.modifications,
+            dirty_bytes: unsaved.bytes,
+            since_last_save: match self.last_save.lock() {
+                Ok(last_save) => last_save.elapsed(),
                 Err(_) => unreachable!(),
-            };
-            writeable_cache.insert(key, cache_entry);
+            },
+        };
+        match self.save_strategy.should_save(&state) {
+            true => Some(unsaved.take_progress()),
+            false => None,
         }
-        self.update_transaction_count_and_save_if_necessary(cache_modified_count)
     }
 
-    pub fn remove(&self, key: impl AsRef<Path>) -> FsCacheResult<()> {
-        {
-            info!(target: "generic_cache_remove", "Removing: {}", key.as_ref().display());
-            let mut writeable_cache = match self.cache.write() {
-                Ok(cache) => cache,
-                Err(_) => unreachable!(),
-            };
-            writeable_cache.remove(key.as_ref());
+    fn save_if_claimed(&self, claimed: Option<SaveProgress>) -> FsCacheResult<()> {
+        match claimed {
+            Some(claimed) => self.save_inner(claimed),
+            None => Ok(()),
         }
-        let cache_modified_count = self.cache_modified_count.fetch_add(1, Relaxed);
-        self.update_transaction_count_and_save_if_necessary(cache_modified_count)
-    }
-
-    fn update_transaction_count_and_save_if_necessary(&self, prev_count: u32) -> FsCacheResult<()> {
-        // We need to defend against
-        // 1) multiple saves of data when only one should be performed
-        // 2) Failing to reset the cache_modified_count to 0. I think we
-        // can guarantee both of these things with Relaxed accesses.
-        //
-        // todo: I think the above two points are true, but we should probably
-        // guarantee better behaviour than that. I think at worst here, every
-        // operation could trigger a save of the cache as cache_modified_count
-        // isn't guaranteed to be sensibly propagated between threads.
-        if prev_count == self.cache_save_threshold - 1 {
-            self.cache_modified_count.store(0, Relaxed);
-            self.save_inner()
-        } else {
-            Ok(())
+    }
+
+    /// A clone of an entry, if there is one.
+    pub fn get(&self, key: &K::Ref) -> Option<T> {
+        self.cache.read(key).get(key).cloned()
+    }
+
+    /// Call `f` with an entry, without cloning it, if there is one. The read lock is held
+    /// while `f` runs, so it must not modify the cache.
+    pub fn with_item<R>(&self, key: &K::Ref, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.cache.read(key).get(key).map(f)
+    }
+
+    pub fn contains_key(&self, key: &K::Ref) -> bool {
+        self.cache.read(key).contains_key(key)
+    }
+
+    pub fn keys(&self) -> Vec<K> {
+        self.cache.read_all().iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Call `f` with every entry, without cloning. Every read lock is held throughout, so `f`
+    /// must not modify the cache.
+    pub fn for_each(&self, mut f: impl FnMut(&K::Ref, &T)) {
+        let readable_cache = self.cache.read_all();
+        for (key, item) in readable_cache.iter() {
+            f(key.borrow(), item);
         }
     }
 
+    /// The keys of every entry for which `f` returns true, searched in parallel on the
+    /// current rayon thread pool. Each map is only read locked while it is searched, so entries
+    /// modified meanwhile may or may not be found.
+    pub fn par_find(&self, f: impl Fn(&K::Ref, &T) -> bool + Sync) -> Vec<K> {
+        self.cache.par_find(f)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T, S> BaseFsCache<T, S>
+where
+    T: DeserializeOwned + Serialize + Send + Sync + Clone,
+    S: BuildHasher + Default + Send + Sync,
+{
+    /// A clone of an entry, failing with [`FsCacheErrorKind::KeyMissing`] if there is none.
     pub fn fetch(&self, key: &Path) -> Result<T, FsCacheErrorKind> {
-        match self.cache.read() {
-            Err(_) => unreachable!(),
-            Ok(readable_cache) => match readable_cache.get(key) {
-                Some(value) => Ok(value.clone()),
-                None => Err(FsCacheErrorKind::KeyMissing(key.to_path_buf())),
-            },
-        }
+        self.get(key)
+            .ok_or_else(|| FsCacheErrorKind::KeyMissing(key.to_path_buf()))
     }
+}
 
-    pub fn contains_key(&self, key: &Path) -> bool {
-        match self.cache.read() {
-            Err(_) => unreachable!(),
-            Ok(cache) => cache.contains_key(key),
+impl<T, S, K> Drop for BaseFsCache<T, S, K>
+where
+    T: DeserializeOwned + Serialize + Send + Sync + Clone,
+    S: BuildHasher + Default + Send + Sync,
+    K: CacheKey,
+{
+    fn drop(&mut self) {
+        if !self.save_on_drop || !self.is_dirty() {
+            return;
         }
-    }
 
-    pub fn keys(&self) -> Vec<PathBuf> {
-        match self.cache.read() {
-            Ok(cache) => cache,
-            Err(_) => unreachable!(),
+        //There is no way to report an error from here, so the best that can be done is to log it.
+        if let Err(e) = self.save_inner(SaveProgress::default()) {
+            error!(target: "generic_cache_transactions", "Failed to save cache on drop: {}", e);
         }
-        .keys()
-        .cloned()
-        .collect()
     }
+}
 
-    pub fn len(&self) -> usize {
-        match self.cache.read() {
-            Ok(cache) => cache.len(),
-            Err(_) => unreachable!(),
+//Changes which have not been saved yet.
+struct Unsaved<S, K> {
+    //Keys inserted or removed since the last save.
+    keys: HashSet<K, S>,
+    //SaveProgress towards the save strategy since the last save was triggered.
+    modifications: u32,
+    bytes: u64,
+}
+
+//Keys need not implement Default.
+impl<S: Default, K> Default for Unsaved<S, K> {
+    fn default() -> Self {
+        Self {
+            keys: Default::default(),
+            modifications: 0,
+            bytes: 0,
         }
     }
+}
 
-    pub fn is_empty(&self) -> bool {
-        match self.cache.read() {
-            Ok(cache) => cache.is_empty(),
-            Err(_) => unreachable!(),
+impl<S, K> Unsaved<S, K> {
+    fn add_progress(&mut self, progress: SaveProgress) {
+        self.modifications = self.modifications.saturating_add(progress.modifications);
+        self.bytes = self.bytes.saturating_add(progress.bytes);
+    }
+
+    fn take_progress(&mut self) -> SaveProgress {
+        SaveProgress {
+            modifications: std::mem::take(&mut self.modifications),
+            bytes: std::mem::take(&mut self.bytes),
         }
     }
 }
+
+#[derive(Default, Clone, Copy)]
+struct SaveProgress {
+    modifications: u32,
+    bytes: u64,
+}
@@ -0,0 +1,8 @@
+//Inspects cache files written with a self-describing codec. See generic_filesystem_cache::tool.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = generic_filesystem_cache::tool::run_untyped(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    hash::BuildHasher,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime},
+};
+
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cache_entry::MtimeCacheEntry,
+    errors::{
+        FsCacheErrorKind::{CacheFileIo, Serialization},
+        FsCacheResult,
+    },
+    format::{fnv1a, type_fingerprint, FileHeader, ProcessorVersion},
+    interning::shallow_clone,
+    invalidation::{FileId, SourceMetadata},
+    storage::{sync_parent_dir, temp_path, CacheView, Durability, FileBackend, StorageBackend},
+};
+
+//A blob file, named after the hash and length of the value it holds, so that a value which
+//has already been written is never written again.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct BlobId {
+    hash: u64,
+    len: u64,
+}
+
+impl BlobId {
+    fn of(bytes: &[u8]) -> Self {
+        Self {
+            hash: fnv1a(bytes.iter().copied()),
+            len: bytes.len() as u64,
+        }
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:016x}-{}", self.hash, self.len)
+    }
+}
+
+//How a cache which stores large values in blob files stores its entries. Entries appended to a
+//journal are stored with their value, because blobs are only written when the cache is saved.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum BlobEntry<T> {
+    Inline(MtimeCacheEntry<T>),
+    Blob {
+        source: SourceMetadata,
+        blob: BlobId,
+        cached_at: Option<SystemTime>,
+        processing_time: Option<Duration>,
+        file_id: Option<FileId>,
+    },
+}
+
+type ReadOnlyFn = Box<dyn Fn() -> bool + Send + Sync>;
+
+//Stores values larger than a threshold in files of their own in a directory beside the cache,
+//so that saving the cache only writes the values which have changed, and only refers to the
+//rest. Blob files are written with bincode whatever the codec of the cache.
+pub(crate) struct BlobBackend<T, S> {
+    backend: Box<dyn StorageBackend<BlobEntry<T>, S>>,
+    dir: PathBuf,
+    threshold: u64,
+    //Whether the cache file is read-only, in which case blobs are neither written nor removed.
+    is_read_only: ReadOnlyFn,
+    durability: Durability,
+    //The blob holding each value which is known to have been written, by the address of the
+    //value, so that values are not serialized again on every save to find their blob.
+    written: Mutex<HashMap<usize, (Weak<T>, BlobId)>>,
+}
+
+impl<T, S> BlobBackend<T, S>
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    pub(crate) fn new(
+        backend: Box<dyn StorageBackend<BlobEntry<T>, S>>,
+        file_backend: FileBackend,
+        threshold: u64,
+    ) -> Self {
+        let dir = blob_dir(file_backend.cache_path());
+        Self {
+            backend,
+            dir,
+            threshold,
+            durability: file_backend.durability(),
+            is_read_only: Box::new(move || file_backend.is_read_only()),
+            written: Mutex::new(HashMap::new()),
+        }
+    }
+
+    //Writes the blob of every large value which has not been written yet. The blobs are written
+    //on threads of their own rather than on rayon's, as the cache is usually saved from one of
+    //rayon's workers while no other save can start. A worker waiting for the blobs would pick up
+    //other work meanwhile, such as processing a file whose insertion then tries to save, and waits
+    //for the save it is part of.
+    fn stored(
+        &self,
+        cache: &CacheView<'_, MtimeCacheEntry<T>, S>,
+    ) -> FsCacheResult<HashMap<PathBuf, BlobEntry<T>, S>> {
+        let entries: Vec<(&PathBuf, &MtimeCacheEntry<T>)> = cache.iter().collect();
+        let thread_count = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let per_thread = entries.len().div_ceil(thread_count).max(1);
+        let stored: Vec<Vec<(PathBuf, BlobEntry<T>)>> = std::thread::scope(|scope| {
+            let threads: Vec<_> = entries
+                .chunks(per_thread)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|(key, entry)| self.stored_entry(key, entry))
+                            .collect()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| match thread.join() {
+                    Ok(result) => result,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                })
+                .collect::<FsCacheResult<_>>()
+        })?;
+        self.lock_written().retain(|_, (value, _)| value.strong_count() > 0);
+        Ok(stored.into_iter().flatten().collect())
+    }
+
+    fn stored_entry(&self, key: &Path, entry: &MtimeCacheEntry<T>) -> FsCacheResult<(PathBuf, BlobEntry<T>)> {
+        let entry = match self.blob_of(&entry.value)? {
+            Some(blob) => BlobEntry::Blob {
+                source: entry.source,
+                blob,
+                cached_at: entry.cached_at,
+                processing_time: entry.processing_time,
+                file_id: entry.file_id,
+            },
+            None => BlobEntry::Inline(shallow_clone(entry)),
+        };
+        Ok((key.to_path_buf(), entry))
+    }
+
+    //The blob holding `value`, or None if it is small enough to store in the cache file.
+    fn blob_of(&self, value: &Arc<T>) -> FsCacheResult<Option<BlobId>> {
+        if let Some((written, blob)) = self.lock_written().get(&(Arc::as_ptr(value) as usize)) {
+            if written.upgrade().is_some_and(|written| Arc::ptr_eq(&written, value)) {
+                return Ok(Some(*blob));
+            }
+        }
+
+        let len = bincode::serialized_size(value.as_ref()).unwrap_or(0);
+        if len <= self.threshold {
+            return Ok(None);
+        }
+        let bytes = bincode::serialize(value.as_ref()).map_err(|e| Serialization {
+            src: format!("{}", e),
+            path: self.dir.clone(),
+        })?;
+        let blob = BlobId::of(&bytes);
+        self.write_blob(blob, &bytes)?;
+        self.remember(value, blob);
+        Ok(Some(blob))
+    }
+
+    fn remember(&self, value: &Arc<T>, blob: BlobId) {
+        self.lock_written()
+            .insert(Arc::as_ptr(value) as usize, (Arc::downgrade(value), blob));
+    }
+
+    //Like the cache file, a blob is written to a temporary file first, so that a blob file is
+    //never left partly written, and is as durable as the cache file referring to it.
+    fn write_blob(&self, blob: BlobId, bytes: &[u8]) -> FsCacheResult<()> {
+        let path = self.dir.join(blob.file_name());
+        if path.exists() {
+            return Ok(());
+        }
+        let temp_path = temp_path(&path);
+        let write = || -> std::io::Result<()> {
+            fs::create_dir_all(&self.dir)?;
+            let mut file = fs::File::create(&temp_path)?;
+            file.write_all(bytes)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &path)?;
+            match self.durability {
+                Durability::FlushFile => Ok(()),
+                Durability::FlushFileAndDirectory => sync_parent_dir(&path),
+            }
+        };
+        write().map_err(|e| CacheFileIo { src: e, path })
+    }
+
+    fn read_blob(&self, blob: BlobId) -> Result<T, String> {
+        let path = self.dir.join(blob.file_name());
+        let bytes = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        bincode::deserialize(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    //Blobs which no entry refers to any more are deleted once the cache has been saved.
+    fn remove_unused_blobs(&self, stored: &HashMap<PathBuf, BlobEntry<T>, S>) -> FsCacheResult<()> {
+        let used: HashSet<String> = stored
+            .values()
+            .filter_map(|entry| match entry {
+                BlobEntry::Blob { blob, .. } => Some(blob.file_name()),
+                BlobEntry::Inline(_) => None,
+            })
+            .collect();
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.dir.clone(),
+                })
+            }
+        };
+
+        let mut removed = 0;
+        for file in dir {
+            let path = match file {
+                Ok(file) => file.path(),
+                Err(e) => {
+                    return Err(CacheFileIo {
+                        src: e,
+                        path: self.dir.clone(),
+                    })
+                }
+            };
+            let is_used = path
+                .file_name()
+                .is_some_and(|name| used.contains(&*name.to_string_lossy()));
+            if !is_used {
+                if let Err(e) = fs::remove_file(&path) {
+                    return Err(CacheFileIo { src: e, path });
+                }
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            info!(target: "generic_cache_transactions",
+                "removed {} unused blobs from {}", removed, self.dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    fn lock_written(&self) -> std::sync::MutexGuard<'_, HashMap<usize, (Weak<T>, BlobId)>> {
+        match self.written.lock() {
+            Ok(written) => written,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<T, S> StorageBackend<MtimeCacheEntry<T>, S> for BlobBackend<T, S>
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    //An entry whose blob cannot be read cannot be used, so is treated as if it had never been cached.
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, MtimeCacheEntry<T>, S>> {
+        let stored: Vec<(PathBuf, BlobEntry<T>)> = self.backend.load()?.into_iter().collect();
+        Ok(stored
+            .into_par_iter()
+            .filter_map(|(key, entry)| match entry {
+                BlobEntry::Inline(entry) => Some((key, entry)),
+                BlobEntry::Blob {
+                    source,
+                    blob,
+                    cached_at,
+                    processing_time,
+                    file_id,
+                } => match self.read_blob(blob) {
+                    Ok(value) => {
+                        let value = Arc::new(value);
+                        self.remember(&value, blob);
+                        let entry = MtimeCacheEntry {
+                            source,
+                            value,
+                            cached_at,
+                            processing_time,
+                            file_id,
+                        };
+                        Some((key, entry))
+                    }
+                    Err(e) => {
+                        warn!(target: "generic_cache_startup", "Skipping entry for {} with an unreadable blob: {}", key.display(), e);
+                        None
+                    }
+                },
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    fn save(&self, cache: &CacheView<'_, MtimeCacheEntry<T>, S>) -> FsCacheResult<()> {
+        if (self.is_read_only)() {
+            return Ok(());
+        }
+        let stored = self.stored(cache)?;
+        self.backend.save(&(&stored).into())?;
+        self.remove_unused_blobs(&stored)
+    }
+
+    fn save_changes(
+        &self,
+        cache: &CacheView<'_, MtimeCacheEntry<T>, S>,
+        changed_keys: &[PathBuf],
+    ) -> FsCacheResult<()> {
+        if (self.is_read_only)() {
+            return Ok(());
+        }
+        let stored = self.stored(cache)?;
+        self.backend.save_changes(&(&stored).into(), changed_keys)?;
+        self.remove_unused_blobs(&stored)
+    }
+
+    fn append(&self, changes: &[(&Path, Option<&MtimeCacheEntry<T>>)]) -> FsCacheResult<()> {
+        let inline: Vec<(&Path, Option<BlobEntry<T>>)> = changes
+            .iter()
+            .map(|(key, entry)| (*key, entry.map(|entry| BlobEntry::Inline(shallow_clone(entry)))))
+            .collect();
+        let changes: Vec<(&Path, Option<&BlobEntry<T>>)> =
+            inline.iter().map(|(key, entry)| (*key, entry.as_ref())).collect();
+        self.backend.append(&changes)
+    }
+
+    fn taking_snapshot(&self) {
+        self.backend.taking_snapshot()
+    }
+
+    fn reset(&self) -> FsCacheResult<()> {
+        if (self.is_read_only)() {
+            return Ok(());
+        }
+        self.backend.reset()?;
+        self.remove_unused_blobs(&HashMap::default())
+    }
+
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        self.backend.stored_version()
+    }
+
+    fn store_version(
+        &self,
+        version: ProcessorVersion,
+        cache: &CacheView<'_, MtimeCacheEntry<T>, S>,
+    ) -> FsCacheResult<()> {
+        if (self.is_read_only)() {
+            return Ok(());
+        }
+        self.backend.store_version(version, &(&self.stored(cache)?).into())
+    }
+}
+
+pub(crate) fn blob_dir(cache_path: &Path) -> PathBuf {
+    let mut path = cache_path.to_path_buf().into_os_string();
+    path.push(".blobs");
+    path.into()
+}
+
+/// Converts a bincode cache file of plain entries, as upgraded by `plain_migration`, to one
+/// whose entries may refer to blobs, for when blobs are turned on for an existing cache. Every
+/// value is kept in the cache file until the cache is next modified and saved.
+pub(crate) fn blob_file_migration<T>(
+    plain_migration: impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+) -> impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static
+where
+    T: DeserializeOwned + Serialize,
+{
+    move |header, payload| {
+        if header.fingerprint == Some(blob_fingerprint::<T>()) {
+            return Ok(payload);
+        }
+        let payload = plain_migration(header, payload)?;
+        let plain: HashMap<PathBuf, MtimeCacheEntry<T>> = bincode::deserialize(&payload).map_err(|e| {
+            format!(
+                "only a cache file encoded with bincode can be converted to store values in blobs: {}",
+                e
+            )
+        })?;
+        let inline: HashMap<PathBuf, BlobEntry<T>> = plain
+            .into_iter()
+            .map(|(key, entry)| (key, BlobEntry::Inline(entry)))
+            .collect();
+        bincode::serialize(&inline).map_err(|e| format!("{}", e))
+    }
+}
+
+pub(crate) fn blob_fingerprint<T>() -> u64 {
+    type_fingerprint::<BlobEntry<T>>()
+}
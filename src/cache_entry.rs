@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    blobs::blob_fingerprint,
+    format::{name_fingerprint, type_fingerprint, FileHeader, MigrationFn},
+    interning::{expanded_payload, interned_fingerprint},
+    invalidation::{FileId, SourceMetadata},
+};
+
+/// The format version of the files in which entries were last changed, by adding the
+/// identity of their file. Older files are upgraded by [`legacy_file_migration`].
+pub(crate) const ENTRY_FORMAT_VERSION: u32 = 5;
+
+/// A processed value as stored by a [`crate::ProcessingFsCache`], along with the state of
+/// the file it was processed from and when it was processed. With the `rkyv` feature, entries
+/// can also be stored by a `RkyvBackend`.
+//
+//The fingerprint of a cache file is taken from the full name of this type, which changed
+//when the type moved to this module and gained the processing fields. The fields are kept
+//last, so that entries in the old format fail to deserialize rather than being misread.
+//
+//The value is shared so that reading it need not clone it. An `Arc` is stored exactly as the
+//value inside it, so this does not change the format.
+//
+//Self-describing codecs can read entries from before the file identity was recorded as they
+//are, but bincode files need upgrading.
+#[derive(Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub struct MtimeCacheEntry<T> {
+    pub(crate) source: SourceMetadata,
+    pub(crate) value: Arc<T>,
+    #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::Map<rkyv::with::AsUnixTime>))]
+    pub(crate) cached_at: Option<SystemTime>,
+    pub(crate) processing_time: Option<Duration>,
+    #[serde(default)]
+    pub(crate) file_id: Option<FileId>,
+}
+
+#[cfg(feature = "rkyv")]
+impl<T: rkyv::Archive> ArchivedMtimeCacheEntry<T> {
+    /// The archived value, as processed from the file.
+    pub fn value(&self) -> &T::Archived {
+        &self.value
+    }
+}
+
+impl<T> MtimeCacheEntry<T> {
+    pub(crate) fn processed(source: SourceMetadata, value: T, processing_time: Duration) -> Self {
+        Self {
+            source,
+            value: Arc::new(value),
+            cached_at: Some(SystemTime::now()),
+            processing_time: Some(processing_time),
+            file_id: source.file_id,
+        }
+    }
+
+    pub(crate) fn inserted(source: SourceMetadata, value: T) -> Self {
+        Self {
+            source,
+            value: Arc::new(value),
+            cached_at: Some(SystemTime::now()),
+            processing_time: None,
+            file_id: source.file_id,
+        }
+    }
+
+    //The same value for another hard link to the file, whose state is `source`.
+    pub(crate) fn linked(&self, source: SourceMetadata) -> Self {
+        Self {
+            source,
+            value: self.value.clone(),
+            cached_at: self.cached_at,
+            processing_time: self.processing_time,
+            file_id: source.file_id,
+        }
+    }
+
+    //Entries cached before the time was recorded are of unknown age, so are treated as expired.
+    pub(crate) fn expired(&self, time_to_live: Duration) -> bool {
+        match self.cached_at {
+            Some(cached_at) => cached_at.elapsed().is_ok_and(|age| age > time_to_live),
+            None => true,
+        }
+    }
+
+    pub(crate) fn meta(&self) -> EntryMeta {
+        EntryMeta {
+            cached_at: self.cached_at,
+            processing_time: self.processing_time,
+            source_mtime: self.source.mtime,
+            source_len: self.source.len,
+            accessed_at: None,
+        }
+    }
+}
+
+//How long a cached value stays fresh, if it expires at all.
+pub(crate) type TimeToLiveFn<T> = Arc<dyn Fn(&Path, &T) -> Option<Duration> + Send + Sync>;
+
+pub(crate) fn is_expired<T>(time_to_live: &Option<TimeToLiveFn<T>>, key: &Path, entry: &MtimeCacheEntry<T>) -> bool {
+    match time_to_live {
+        Some(time_to_live) => time_to_live(key, &entry.value).is_some_and(|ttl| entry.expired(ttl)),
+        None => false,
+    }
+}
+
+/// Where a cached value came from, as returned by `get_with_meta`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// When the value was cached, or None if it was cached by a version of this crate which
+    /// did not record it.
+    pub cached_at: Option<SystemTime>,
+    /// How long processing the file took, or None if the value was inserted rather than processed.
+    pub processing_time: Option<Duration>,
+    /// The modification time of the file when it was processed.
+    pub source_mtime: SystemTime,
+    /// The length of the file when it was processed.
+    pub source_len: u64,
+    /// When the value was last read, to within a minute, or None if it has not been read or
+    /// access times are not tracked. See [`crate::ProcessingFsCacheBuilder::track_access_times`].
+    pub accessed_at: Option<SystemTime>,
+}
+
+impl EntryMeta {
+    /// How long ago the value was cached, if known.
+    pub fn age(&self) -> Option<Duration> {
+        self.cached_at.and_then(|cached_at| cached_at.elapsed().ok())
+    }
+}
+
+//An entry as stored before the processing fields were added.
+#[derive(Deserialize)]
+struct LegacyCacheEntry<T> {
+    source: SourceMetadata,
+    value: T,
+}
+
+impl<T> From<LegacyCacheEntry<T>> for MtimeCacheEntry<T> {
+    fn from(legacy: LegacyCacheEntry<T>) -> Self {
+        Self {
+            source: legacy.source,
+            value: Arc::new(legacy.value),
+            cached_at: None,
+            processing_time: None,
+            file_id: None,
+        }
+    }
+}
+
+//An entry as stored before the identity of its file was added.
+#[derive(Deserialize)]
+struct UnidentifiedCacheEntry<T> {
+    source: SourceMetadata,
+    value: T,
+    cached_at: Option<SystemTime>,
+    processing_time: Option<Duration>,
+}
+
+impl<T> From<UnidentifiedCacheEntry<T>> for MtimeCacheEntry<T> {
+    fn from(old: UnidentifiedCacheEntry<T>) -> Self {
+        Self {
+            source: old.source,
+            value: Arc::new(old.value),
+            cached_at: old.cached_at,
+            processing_time: old.processing_time,
+            file_id: None,
+        }
+    }
+}
+
+fn legacy_fingerprint<T>() -> u64 {
+    name_fingerprint(&format!(
+        "generic_filesystem_cache::processing_fs_cache::MtimeCacheEntry<{}>",
+        std::any::type_name::<T>()
+    ))
+}
+
+/// A migration for bincode cache files, which upgrades files holding entries in an old
+/// format (including files written before headers existed). Any other file is passed on to
+/// `fallback` if there is one. The backend must also have [`ENTRY_FORMAT_VERSION`] as its
+/// value format version, so that files with the current value type but an older format
+/// are passed here too.
+pub(crate) fn legacy_file_migration<T>(
+    fallback: Option<Arc<MigrationFn>>,
+) -> impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static
+where
+    T: DeserializeOwned + Serialize,
+{
+    move |header, payload| {
+        if header.fingerprint.is_none() || header.fingerprint == Some(legacy_fingerprint::<T>()) {
+            let legacy: HashMap<PathBuf, LegacyCacheEntry<T>> =
+                bincode::deserialize(&payload).map_err(|e| format!("{}", e))?;
+            let upgraded: HashMap<PathBuf, MtimeCacheEntry<T>> =
+                legacy.into_iter().map(|(key, entry)| (key, entry.into())).collect();
+            return bincode::serialize(&upgraded).map_err(|e| format!("{}", e));
+        }
+
+        //A payload which is not bincode is passed on as it is, as self-describing codecs can
+        //read the old format.
+        if header.fingerprint == Some(type_fingerprint::<MtimeCacheEntry<T>>()) {
+            return match bincode::deserialize::<HashMap<PathBuf, UnidentifiedCacheEntry<T>>>(&payload) {
+                Ok(old) => {
+                    let upgraded: HashMap<PathBuf, MtimeCacheEntry<T>> =
+                        old.into_iter().map(|(key, entry)| (key, entry.into())).collect();
+                    bincode::serialize(&upgraded).map_err(|e| format!("{}", e))
+                }
+                Err(_) => Ok(payload),
+            };
+        }
+
+        //A cache whose values were interned is read as plain entries when it is opened without
+        //interning, or lazily.
+        if header.fingerprint == Some(interned_fingerprint::<T>()) {
+            return expanded_payload::<T>(&payload);
+        }
+
+        //The values of a cache which stores them in blobs cannot be found from the cache file alone.
+        if header.fingerprint == Some(blob_fingerprint::<T>()) {
+            return Err(
+                "large values are stored in blob files, so the cache can only be opened with \
+                ProcessingFsCacheBuilder::blob_threshold"
+                    .to_string(),
+            );
+        }
+
+        match &fallback {
+            Some(fallback) => fallback(header, payload),
+            None => Err("the file holds a different value type".to_string()),
+        }
+    }
+}
+
+/// Upgrade a single bincode-encoded entry in an old format. See [`crate::SledBackend::with_migration`].
+//
+//An entry in the newer of the old formats would be misread as one in the oldest, with its
+//trailing fields ignored, but not the other way around, so the newer format is tried first.
+#[cfg(feature = "sled")]
+pub(crate) fn legacy_entry_migration<T>(bytes: &[u8]) -> Result<Vec<u8>, String>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let upgraded = match bincode::deserialize::<UnidentifiedCacheEntry<T>>(bytes) {
+        Ok(old) => MtimeCacheEntry::from(old),
+        Err(_) => {
+            MtimeCacheEntry::from(bincode::deserialize::<LegacyCacheEntry<T>>(bytes).map_err(|e| format!("{}", e))?)
+        }
+    };
+    bincode::serialize(&upgraded).map_err(|e| format!("{}", e))
+}
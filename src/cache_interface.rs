@@ -1,10 +1,131 @@
-use std::path::Path;
+use std::{fs::Metadata, path::Path};
 
 use serde::{de::DeserializeOwned, Serialize};
 
-// Users of the generic filesystem cache should implement this interface.
+// Users of the generic filesystem cache should implement this interface. Any configuration
+// the processing needs can be kept in the implementing struct. It is also implemented for
+// any closure taking a &Path, which is called directly rather than through a Box.
 pub trait CacheInterface {
     type T: Serialize + DeserializeOwned + Clone + Send + Sync;
 
     fn load(&self, src_path: impl AsRef<Path>) -> Self::T;
+
+    // A version number for what load produces. Defaults to 0. When it changes, cached values
+    // are treated as stale (see ProcessingFsCacheBuilder::stale_on_version_change).
+    fn version(&self) -> u32 {
+        0
+    }
+
+    // A fingerprint of any configuration which affects what load produces, such as one made
+    // with format::config_fingerprint. Defaults to 0. When it changes, every cached value is stale.
+    fn config_fingerprint(&self) -> u64 {
+        0
+    }
+
+    // A name for the processing, used when logging. Defaults to the name of the implementing type.
+    fn describe(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+
+    // Like load, but also given the file's metadata, which the cache has already read to
+    // decide whether the file needs processing. The cache always calls this rather than
+    // load, so overriding it saves statting every file a second time.
+    fn load_with_metadata(&self, src_path: &Path, _metadata: &Metadata) -> Self::T {
+        self.load(src_path)
+    }
+
+    // Like load_with_metadata, but able to fail. A failure is reported instead of caching a
+    // value, and may be remembered so that the file is not retried on every update (see
+    // ProcessingFsCacheBuilder::retry_policy). The cache always calls this rather than load_with_metadata.
+    fn try_load_with_metadata(&self, src_path: &Path, metadata: &Metadata) -> Result<Self::T, String> {
+        Ok(self.load_with_metadata(src_path, metadata))
+    }
+
+    // An estimate of the memory used by a value, including anything it holds on the heap, for
+    // keeping within a memory budget (see ProcessingFsCacheBuilder::memory_budget). Defaults to
+    // the size of the value itself, so should be overridden for values such as Vecs and Strings.
+    fn estimated_size(&self, _value: &Self::T) -> usize {
+        std::mem::size_of::<Self::T>()
+    }
+}
+
+impl<F, T> CacheInterface for F
+where
+    F: Fn(&Path) -> T,
+    T: Serialize + DeserializeOwned + Clone + Send + Sync,
+{
+    type T = T;
+
+    fn load(&self, src_path: impl AsRef<Path>) -> Self::T {
+        self(src_path.as_ref())
+    }
+
+    fn load_with_metadata(&self, src_path: &Path, _metadata: &Metadata) -> Self::T {
+        self(src_path)
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use async_interface::AsyncCacheInterface;
+
+#[cfg(feature = "tokio")]
+mod async_interface {
+    use std::{fs::Metadata, future::Future, path::PathBuf, pin::Pin};
+
+    use serde::{de::DeserializeOwned, Serialize};
+
+    // Users of the async filesystem cache should implement this interface. It is
+    // implemented for any closure taking a PathBuf and returning a future.
+    pub trait AsyncCacheInterface {
+        type T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static;
+
+        fn load(&self, src_path: PathBuf) -> Pin<Box<dyn Future<Output = Self::T> + Send + '_>>;
+
+        // A version number for what load produces. See CacheInterface::version.
+        fn version(&self) -> u32 {
+            0
+        }
+
+        // A fingerprint of the processing's configuration. See CacheInterface::config_fingerprint.
+        fn config_fingerprint(&self) -> u64 {
+            0
+        }
+
+        // A name for the processing, used when logging. See CacheInterface::describe.
+        fn describe(&self) -> &str {
+            std::any::type_name::<Self>()
+        }
+
+        // Like load, but also given the file's metadata. See CacheInterface::load_with_metadata.
+        fn load_with_metadata(
+            &self,
+            src_path: PathBuf,
+            _metadata: Metadata,
+        ) -> Pin<Box<dyn Future<Output = Self::T> + Send + '_>> {
+            self.load(src_path)
+        }
+
+        // Like load_with_metadata, but able to fail. See CacheInterface::try_load_with_metadata.
+        fn try_load_with_metadata(
+            &self,
+            src_path: PathBuf,
+            metadata: Metadata,
+        ) -> Pin<Box<dyn Future<Output = Result<Self::T, String>> + Send + '_>> {
+            let load = self.load_with_metadata(src_path, metadata);
+            Box::pin(async move { Ok(load.await) })
+        }
+    }
+
+    impl<F, Fut, T> AsyncCacheInterface for F
+    where
+        F: Fn(PathBuf) -> Fut,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    {
+        type T = T;
+
+        fn load(&self, src_path: PathBuf) -> Pin<Box<dyn Future<Output = Self::T> + Send + '_>> {
+            Box::pin(self(src_path))
+        }
+    }
 }
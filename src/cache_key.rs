@@ -0,0 +1,119 @@
+use std::{
+    borrow::Borrow,
+    fmt,
+    hash::Hash,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::stored_path::{LoadedPath, StoredPath};
+
+/// A type which entries of a [`crate::BaseFsCache`] can be kept under, and which a
+/// [`crate::FileBackend`] can store. Implemented for paths, which is what every other cache in
+/// this crate is keyed by, for strings, such as URL-like identifiers, and for pairs of a path
+/// and an offset, such as for caching parts of a file separately.
+///
+/// Entries are looked up by the borrowed form of the key, as with the keys of a `HashMap`.
+/// Another type of key can be supported by implementing this trait, which usually means
+/// passing its serialization on to serde.
+pub trait CacheKey: Borrow<Self::Ref> + Hash + Eq + Clone + Send + Sync + 'static {
+    /// The borrowed form of the key, such as `Path` for `PathBuf`.
+    type Ref: ?Sized + Hash + Eq + ToOwned<Owned = Self> + Send + Sync;
+
+    fn serialize_key<Se: Serializer>(key: &Self::Ref, serializer: Se) -> Result<Se::Ok, Se::Error>;
+
+    fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>;
+
+    /// Writes the key for log messages.
+    fn fmt_key(key: &Self::Ref, f: &mut fmt::Formatter<'_>) -> fmt::Result;
+
+    /// The length of the key in bytes, as limited by [`crate::LoadLimits::max_path_len`].
+    fn key_len(key: &Self::Ref) -> usize;
+}
+
+//Paths are stored so that those which are not valid unicode survive being saved.
+impl CacheKey for PathBuf {
+    type Ref = Path;
+
+    fn serialize_key<Se: Serializer>(key: &Path, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        StoredPath(key).serialize(serializer)
+    }
+
+    fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        LoadedPath::deserialize(deserializer).map(|LoadedPath(path)| path)
+    }
+
+    fn fmt_key(key: &Path, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", key.display())
+    }
+
+    fn key_len(key: &Path) -> usize {
+        key.as_os_str().len()
+    }
+}
+
+impl CacheKey for String {
+    type Ref = str;
+
+    fn serialize_key<Se: Serializer>(key: &str, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        serializer.serialize_str(key)
+    }
+
+    fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)
+    }
+
+    fn fmt_key(key: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(key)
+    }
+
+    fn key_len(key: &str) -> usize {
+        key.len()
+    }
+}
+
+impl CacheKey for (PathBuf, u64) {
+    type Ref = Self;
+
+    fn serialize_key<Se: Serializer>(key: &Self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        (StoredPath(&key.0), key.1).serialize(serializer)
+    }
+
+    fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <(LoadedPath, u64)>::deserialize(deserializer).map(|(LoadedPath(path), offset)| (path, offset))
+    }
+
+    fn fmt_key(key: &Self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", key.0.display(), key.1)
+    }
+
+    fn key_len(key: &Self) -> usize {
+        key.0.as_os_str().len()
+    }
+}
+
+//A key as it is written to the cache file.
+pub(crate) struct StoredKey<'a, K: CacheKey>(pub(crate) &'a K::Ref);
+
+impl<K: CacheKey> Serialize for StoredKey<'_, K> {
+    fn serialize<Se: Serializer>(&self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        K::serialize_key(self.0, serializer)
+    }
+}
+
+pub(crate) struct LoadedKey<K>(pub(crate) K);
+
+impl<'de, K: CacheKey> Deserialize<'de> for LoadedKey<K> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        K::deserialize_key(deserializer).map(LoadedKey)
+    }
+}
+
+pub(crate) struct DisplayKey<'a, K: CacheKey>(pub(crate) &'a K::Ref);
+
+impl<K: CacheKey> fmt::Display for DisplayKey<'_, K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        K::fmt_key(self.0, f)
+    }
+}
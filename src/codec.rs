@@ -0,0 +1,141 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::stored_path::{LoadedMap, LoadedPath};
+
+/// The on-disk encoding used by a [`crate::FileBackend`].
+pub trait Codec: Send + Sync {
+    fn serialize<T: Serialize>(&self, writer: &mut dyn Write, value: &T) -> Result<(), String>;
+
+    fn deserialize<T: DeserializeOwned>(&self, reader: &mut dyn Read) -> Result<T, String>;
+
+    /// Like `deserialize`, but failing rather than reading more than `limit` bytes, so that a
+    /// length read from a corrupt file cannot cause a larger allocation than the file itself.
+    /// The default implementation does not enforce the limit.
+    fn deserialize_limited<T: DeserializeOwned>(&self, reader: &mut dyn Read, _limit: u64) -> Result<T, String> {
+        self.deserialize(reader)
+    }
+
+    /// Decode as many entries as possible from the start of a serialized map, for recovering
+    /// a partly corrupt cache file. Returns the entries and the number of bytes they took up.
+    /// The default implementation only recovers a map which decodes in full.
+    fn deserialize_map_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> (HashMap<PathBuf, T>, usize) {
+        match self.deserialize::<LoadedMap<T, RandomState>>(&mut &bytes[..]) {
+            Ok(LoadedMap(map)) => (map, bytes.len()),
+            Err(_) => (HashMap::new(), 0),
+        }
+    }
+
+    /// Whether a map is encoded as its length, as a `u64`, followed by each key and value
+    /// encoded on their own, so that an entry can be found in a cache file and decoded alone.
+    /// Only the entries of such codecs can be indexed for reading one at a time from a
+    /// memory-mapped file (with the `mmap` feature). The default implementation returns false.
+    fn encodes_entries_separately(&self) -> bool {
+        false
+    }
+}
+
+/// Compact and fast, but opaque, and unable to read files written with a different `T`.
+/// This is the default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    fn serialize<T: Serialize>(&self, writer: &mut dyn Write, value: &T) -> Result<(), String> {
+        bincode::serialize_into(writer, value).map_err(|e| format!("{}", e))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, reader: &mut dyn Read) -> Result<T, String> {
+        bincode::deserialize_from(reader).map_err(|e| format!("{}", e))
+    }
+
+    //The same options as bincode::deserialize_from, but with a limit.
+    fn deserialize_limited<T: DeserializeOwned>(&self, reader: &mut dyn Read, limit: u64) -> Result<T, String> {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit)
+            .deserialize_from(reader)
+            .map_err(|e| format!("{}", e))
+    }
+
+    //A map is its length followed by each key and value in turn, so can be read entry by entry.
+    fn deserialize_map_prefix<T: DeserializeOwned>(&self, bytes: &[u8]) -> (HashMap<PathBuf, T>, usize) {
+        let mut remaining = bytes;
+        let mut ret = HashMap::new();
+        let len: u64 = match bincode::deserialize_from(&mut remaining) {
+            Ok(len) => len,
+            Err(_) => return (ret, 0),
+        };
+
+        let mut used = bytes.len() - remaining.len();
+        for _ in 0..len {
+            match bincode::deserialize_from::<_, (LoadedPath, T)>(&mut remaining) {
+                Ok((LoadedPath(key), value)) => {
+                    ret.insert(key, value);
+                    used = bytes.len() - remaining.len();
+                }
+                Err(_) => break,
+            }
+        }
+        (ret, used)
+    }
+
+    fn encodes_entries_separately(&self) -> bool {
+        true
+    }
+}
+
+/// Human readable, and tolerant of new optional fields in `T`. Paths which are not valid
+/// UTF-8 are stored percent-encoded, after a leading NUL character.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn serialize<T: Serialize>(&self, writer: &mut dyn Write, value: &T) -> Result<(), String> {
+        serde_json::to_writer(writer, value).map_err(|e| format!("{}", e))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, reader: &mut dyn Read) -> Result<T, String> {
+        serde_json::from_reader(reader).map_err(|e| format!("{}", e))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+#[cfg(feature = "cbor")]
+impl Codec for CborCodec {
+    fn serialize<T: Serialize>(&self, writer: &mut dyn Write, value: &T) -> Result<(), String> {
+        ciborium::ser::into_writer(value, writer).map_err(|e| format!("{}", e))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, reader: &mut dyn Read) -> Result<T, String> {
+        ciborium::de::from_reader(reader).map_err(|e| format!("{}", e))
+    }
+}
+
+/// Struct fields are stored by name, so that files survive fields being added or reordered.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    fn serialize<T: Serialize>(&self, mut writer: &mut dyn Write, value: &T) -> Result<(), String> {
+        rmp_serde::encode::write_named(&mut writer, value).map_err(|e| format!("{}", e))
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, reader: &mut dyn Read) -> Result<T, String> {
+        rmp_serde::decode::from_read(reader).map_err(|e| format!("{}", e))
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    fs::{self, Metadata},
+    io,
+    path::PathBuf,
+};
+
+use rayon::prelude::*;
+
+use crate::{
+    errors::FsCacheResult,
+    file_set::{Enumeration, FileSet, WalkError},
+    update_report::UpdateReport,
+};
+
+//The metadata read for each file found by a walk.
+pub(crate) type WalkedMetadata = HashMap<PathBuf, Metadata>;
+
+/// The files found by one walk of a [`FileSet`], along with their metadata, so that several
+/// caches of the same files can be brought up to date without each walking and looking at every
+/// file again. Usually used through [`CompositeCache`].
+pub struct SharedWalk {
+    files: Vec<PathBuf>,
+    errors: Vec<WalkError>,
+    metadata: WalkedMetadata,
+}
+
+impl SharedWalk {
+    /// Walk the file set, reading the metadata of every file found in parallel. Fails only if
+    /// the file set's [`crate::WalkErrorPolicy`] is to abort on an error.
+    pub fn new(file_set: &FileSet) -> FsCacheResult<Self> {
+        let Enumeration { files, errors } = file_set.enumerate()?;
+        //a file whose metadata cannot be read is looked at again by each cache, which reports why.
+        let metadata = files
+            .par_iter()
+            .filter_map(|path| fs::metadata(path).ok().map(|metadata| (path.clone(), metadata)))
+            .collect();
+        Ok(Self {
+            files,
+            errors,
+            metadata,
+        })
+    }
+
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// The parts of the file set which could not be walked.
+    pub fn errors(&self) -> &[WalkError] {
+        &self.errors
+    }
+
+    //Each cache is given its own copy, as the errors are moved into its update report.
+    pub(crate) fn enumeration(&self) -> Enumeration {
+        Enumeration {
+            files: self.files.clone(),
+            errors: self
+                .errors
+                .iter()
+                .map(|e| WalkError {
+                    path: e.path.clone(),
+                    error: io::Error::new(e.error.kind(), e.error.to_string()),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn metadata(&self) -> &WalkedMetadata {
+        &self.metadata
+    }
+}
+
+/// A cache which can be brought up to date from a [`SharedWalk`] of its file set, rather than
+/// walking the file set itself. Implemented by [`crate::ProcessingFsCache`].
+pub trait SharedWalkCache {
+    /// Like [`crate::ProcessingFsCache::update_from_fs`], with `walk` being a walk of `file_set`.
+    fn update_from_walk(&self, file_set: &FileSet, walk: &SharedWalk) -> FsCacheResult<UpdateReport>;
+}
+
+/// Several caches of the same files, such as of their perceptual hashes, durations and codecs,
+/// which are brought up to date from a single walk of the filesystem in which each file is only
+/// looked at once. Each cache still processes the files which have changed for it.
+#[derive(Default)]
+pub struct CompositeCache<'a> {
+    caches: Vec<&'a dyn SharedWalkCache>,
+}
+
+impl<'a> CompositeCache<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cache(mut self, cache: &'a dyn SharedWalkCache) -> Self {
+        self.caches.push(cache);
+        self
+    }
+
+    /// Walk the file set once, then bring each cache up to date with it in the order they were
+    /// added, returning the report of each. Stops at the first cache which fails to update.
+    pub fn update_from_fs(&self, file_set: &FileSet) -> FsCacheResult<Vec<UpdateReport>> {
+        let walk = SharedWalk::new(file_set)?;
+        self.caches
+            .iter()
+            .map(|cache| cache.update_from_walk(file_set, &walk))
+            .collect()
+    }
+}
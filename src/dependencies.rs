@@ -0,0 +1,147 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{cache_entry::MtimeCacheEntry, observer::CacheObserver};
+
+//The files which a file depends on, found from its path and processed value.
+pub(crate) type DependencyFn<T> = Arc<dyn Fn(&Path, &T) -> Vec<PathBuf> + Send + Sync>;
+
+//Which cached files depend on which others, and which entries have changed since the files
+//depending on them were last brought up to date.
+pub(crate) struct DependencyGraph<T> {
+    dependencies_of: DependencyFn<T>,
+    edges: RwLock<Edges>,
+}
+
+#[derive(Default)]
+struct Edges {
+    dependencies: HashMap<PathBuf, Vec<PathBuf>>,
+    dependents: HashMap<PathBuf, HashSet<PathBuf>>,
+    changed: HashSet<PathBuf>,
+}
+
+impl Edges {
+    fn forget(&mut self, path: &Path) {
+        for dependency in self.dependencies.remove(path).unwrap_or_default() {
+            if let Some(dependents) = self.dependents.get_mut(&dependency) {
+                dependents.remove(path);
+                if dependents.is_empty() {
+                    self.dependents.remove(&dependency);
+                }
+            }
+        }
+    }
+}
+
+impl<T> DependencyGraph<T> {
+    pub(crate) fn new(dependencies_of: DependencyFn<T>) -> Self {
+        Self {
+            dependencies_of,
+            edges: Default::default(),
+        }
+    }
+
+    //Replaces the dependencies of `path` with those of its new value.
+    pub(crate) fn insert(&self, path: &Path, value: &T) {
+        let dependencies = (self.dependencies_of)(path, value);
+        let mut edges = self.write();
+        edges.forget(path);
+        for dependency in &dependencies {
+            edges
+                .dependents
+                .entry(dependency.clone())
+                .or_default()
+                .insert(path.to_path_buf());
+        }
+        edges.dependencies.insert(path.to_path_buf(), dependencies);
+    }
+
+    //A removed file no longer depends on anything, but the files depending on it still do.
+    pub(crate) fn remove(&self, path: &Path) {
+        self.write().forget(path);
+    }
+
+    pub(crate) fn mark_changed(&self, path: &Path) {
+        self.write().changed.insert(path.to_path_buf());
+    }
+
+    //Every entry which has changed since this was last called.
+    pub(crate) fn take_changed(&self) -> HashSet<PathBuf> {
+        std::mem::take(&mut self.write().changed)
+    }
+
+    //The files which directly depend on any of `paths`, in order.
+    pub(crate) fn dependents_of(&self, paths: &HashSet<PathBuf>) -> Vec<PathBuf> {
+        let edges = match self.edges.read() {
+            Ok(edges) => edges,
+            Err(_) => unreachable!(),
+        };
+        let dependents: HashSet<&PathBuf> = paths
+            .iter()
+            .filter_map(|path| edges.dependents.get(path))
+            .flatten()
+            .collect();
+        let mut ret: Vec<PathBuf> = dependents.into_iter().cloned().collect();
+        ret.sort_unstable();
+        ret
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Edges> {
+        match self.edges.write() {
+            Ok(edges) => edges,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+//Keeps the dependency graph up to date with every change to the cache, and records which entries
+//have changed, then passes the change on to the next observer if there is one.
+pub(crate) struct DependencyObserver<T> {
+    graph: Arc<DependencyGraph<T>>,
+    next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+}
+
+impl<T> DependencyObserver<T> {
+    pub(crate) fn new(
+        graph: Arc<DependencyGraph<T>>,
+        next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+    ) -> Self {
+        Self { graph, next }
+    }
+}
+
+impl<T> CacheObserver<MtimeCacheEntry<T>> for DependencyObserver<T>
+where
+    T: Send + Sync,
+{
+    fn on_insert(&self, path: &Path, entry: &MtimeCacheEntry<T>) {
+        self.graph.insert(path, &entry.value);
+        self.graph.mark_changed(path);
+        if let Some(next) = &self.next {
+            next.on_insert(path, entry)
+        }
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.graph.remove(path);
+        self.graph.mark_changed(path);
+        if let Some(next) = &self.next {
+            next.on_remove(path)
+        }
+    }
+
+    fn on_save(&self) {
+        if let Some(next) = &self.next {
+            next.on_save()
+        }
+    }
+
+    fn on_load(&self, entries: usize) {
+        if let Some(next) = &self.next {
+            next.on_load(entries)
+        }
+    }
+}
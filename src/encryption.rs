@@ -0,0 +1,37 @@
+use std::path::Path;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::errors::{FsCacheErrorKind::*, FsCacheResult};
+
+const NONCE_LEN: usize = 24;
+
+//Encrypted cache files are laid out as a random nonce followed by the ciphertext.
+pub(crate) fn encrypt(key: &[u8; 32], plaintext: &[u8], path: &Path) -> FsCacheResult<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    match cipher.encrypt(&nonce, plaintext) {
+        Ok(ciphertext) => Ok(nonce.into_iter().chain(ciphertext).collect()),
+        Err(e) => Err(Serialization {
+            src: format!("encryption failed: {}", e),
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+pub(crate) fn decrypt(key: &[u8; 32], data: &[u8], path: &Path) -> FsCacheResult<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return Err(Decryption(path.to_path_buf()));
+    }
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+
+    cipher
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Decryption(path.to_path_buf()))
+}
@@ -0,0 +1,167 @@
+use std::{
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "mmap")]
+use log::warn;
+
+use crate::{
+    format::{fnv1a, PayloadChecksum},
+    storage::{replace_file, temp_path},
+};
+
+//An index of the entries of a cache file, kept beside it at `<cache_path>.index`, so that an entry
+//can be found and decoded without reading the rest of the file. Only written for codecs which
+//encode each entry on its own (see `Codec::encodes_entries_separately`).
+//
+//The index is `MAGIC`, the length and CRC32 of the payload of the cache file it was written for,
+//the number of entries and the CRC32 of the records which follow: the hash of each encoded key and
+//the offset of its entry within the payload, sorted by hash. An index whose checksum is not that of
+//the cache file is stale, and is ignored, so the two need not be replaced together. So is an index
+//whose records fail their checksum.
+const MAGIC: [u8; 8] = *b"GFSINDEX";
+#[cfg(feature = "mmap")]
+const HEADER_LEN: usize = 32;
+const RECORD_LEN: usize = 16;
+
+pub(crate) fn index_path(cache_path: &Path) -> PathBuf {
+    let mut path = cache_path.as_os_str().to_os_string();
+    path.push(".index");
+    path.into()
+}
+
+pub(crate) fn key_hash(key: &[u8]) -> u64 {
+    fnv1a(key.iter().copied())
+}
+
+//`records` are the hash of each encoded key and the offset of its entry. The index is renamed into
+//place like the cache file, but is not flushed to disk, as one cut short by a crash is too short
+//for the number of entries it claims, and is ignored.
+pub(crate) fn write(cache_path: &Path, checksum: PayloadChecksum, mut records: Vec<(u64, u64)>) -> std::io::Result<()> {
+    records.sort_unstable();
+    let path = index_path(cache_path);
+    let temp_path = temp_path(&path);
+
+    let mut encoded = Vec::with_capacity(records.len() * RECORD_LEN);
+    for (hash, offset) in &records {
+        encoded.extend_from_slice(&hash.to_le_bytes());
+        encoded.extend_from_slice(&offset.to_le_bytes());
+    }
+
+    let mut writer = BufWriter::new(std::fs::File::create(&temp_path)?);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&checksum.len.to_le_bytes())?;
+    writer.write_all(&checksum.crc32.to_le_bytes())?;
+    writer.write_all(&(records.len() as u64).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&encoded).to_le_bytes())?;
+    writer.write_all(&encoded)?;
+    writer.into_inner().map_err(|e| e.into_error())?;
+
+    replace_file(&temp_path, &path)
+}
+
+//A cache file mapped into memory along with its index, so that its entries can be found and
+//decoded one at a time.
+#[cfg(feature = "mmap")]
+pub(crate) struct MappedEntries {
+    cache: memmap2::Mmap,
+    payload_start: usize,
+    index: memmap2::Mmap,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedEntries {
+    //Maps `cache_file`, whose payload has `checksum`, along with its index. Returns None if the
+    //index is missing, incomplete or corrupt, or was written for another version of the cache file.
+    pub(crate) fn open(
+        cache_file: &std::fs::File,
+        cache_path: &Path,
+        checksum: PayloadChecksum,
+    ) -> std::io::Result<Option<Self>> {
+        let index_file = match std::fs::File::open(index_path(cache_path)) {
+            Ok(index_file) => index_file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        //SAFETY: a mapped file changing under us would change the slices read from it. This crate
+        //never modifies cache files or their indexes in place: they are always written beside
+        //them and renamed over them, which leaves files which are already open as they were.
+        let (cache, index) = unsafe { (memmap2::Mmap::map(cache_file)?, memmap2::Mmap::map(&index_file)?) };
+
+        let mut expected_header = MAGIC.to_vec();
+        expected_header.extend_from_slice(&checksum.len.to_le_bytes());
+        expected_header.extend_from_slice(&checksum.crc32.to_le_bytes());
+        if !index.starts_with(&expected_header) || index.len() < HEADER_LEN || checksum.len > cache.len() as u64 {
+            return Ok(None);
+        }
+
+        let entries = Self {
+            payload_start: cache.len() - checksum.len as usize,
+            cache,
+            index,
+        };
+        let index_len = entries.len().checked_mul(RECORD_LEN).and_then(|len| len.checked_add(HEADER_LEN));
+        if index_len != Some(entries.index.len()) {
+            return Ok(None);
+        }
+        match crc32fast::hash(&entries.index[HEADER_LEN..]) == read_u32(&entries.index[28..HEADER_LEN]) {
+            true => Ok(Some(entries)),
+            false => {
+                warn!(target: "generic_cache_startup",
+                    "Ignoring corrupt entry index {}", index_path(cache_path).display()
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        read_u64(&self.index[20..28]) as usize
+    }
+
+    //Returns the position in the index of the entry whose key is encoded as `key`, along with the
+    //encoding of its value, which runs on to the end of the file.
+    pub(crate) fn find(&self, key: &[u8]) -> Option<(usize, &[u8])> {
+        let hash = key_hash(key);
+
+        //the first record with this hash or later.
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.record(mid).0 < hash {
+                true => low = mid + 1,
+                false => high = mid,
+            }
+        }
+
+        //keys whose hashes collide are told apart by comparing them.
+        let payload = &self.cache[self.payload_start..];
+        (low..self.len())
+            .take_while(|n| self.record(*n).0 == hash)
+            .find_map(|n| {
+                let entry = payload.get(self.record(n).1 as usize..)?;
+                entry.strip_prefix(key).map(|value| (n, value))
+            })
+    }
+
+    fn record(&self, n: usize) -> (u64, u64) {
+        let record = &self.index[HEADER_LEN + n * RECORD_LEN..][..RECORD_LEN];
+        (read_u64(&record[..8]), read_u64(&record[8..]))
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(feature = "mmap")]
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
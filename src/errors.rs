@@ -12,6 +12,15 @@ pub enum FsCacheErrorKind {
     #[error("IO error accessing {src}: {path}")]
     CacheItemIo { src: String, path: PathBuf },
 
+    #[error("Failed to walk {path}: {src}")]
+    Traversal { src: std::io::Error, path: PathBuf },
+
+    #[error("Failed to process {path}: {src}")]
+    Processing { src: String, path: PathBuf },
+
+    #[error("Not retrying {path}, which failed to process: {src}")]
+    KnownFailure { src: String, path: PathBuf },
+
     #[error("Key missing from cache: {0}")]
     KeyMissing(PathBuf),
 
@@ -20,4 +29,52 @@ pub enum FsCacheErrorKind {
 
     #[error("Failed to deserialize items from cache file {path}: {src}")]
     Deserialization { src: String, path: PathBuf },
+
+    #[error("Cache file {path} is corrupt: {src}")]
+    CorruptedCache { src: String, path: PathBuf },
+
+    #[error("Cannot read cache file {path}: {src}")]
+    IncompatibleCacheFile { src: String, path: PathBuf },
+
+    #[error("Cache file {path} exceeds a load limit: {src}")]
+    LoadLimitExceeded { src: String, path: PathBuf },
+
+    #[cfg(feature = "encryption")]
+    #[error("Failed to decrypt cache file {0}: wrong key, or the file has been tampered with")]
+    Decryption(PathBuf),
+
+    #[error("Cache file is locked by another process: {0}")]
+    CacheLocked(PathBuf),
+
+    #[error("Storage backend error for {path}: {src}")]
+    Backend { src: String, path: PathBuf },
+
+    #[error("Failed to cache {path}, which was inserted in a batch with {failed}, whose error is reported instead")]
+    BatchInsertFailed { path: PathBuf, failed: PathBuf },
+
+    #[error("Unsupported cache configuration: {0}")]
+    UnsupportedConfiguration(String),
+
+    #[cfg(feature = "json")]
+    #[error("Failed to export cache contents: {0}")]
+    Export(String),
+
+    #[error("Failed to create worker thread pool: {0}")]
+    ThreadPool(String),
+
+    #[cfg(feature = "tokio")]
+    #[error("Background cache task failed: {0}")]
+    AsyncTask(String),
+
+    #[cfg(feature = "watch")]
+    #[error("Failed to watch {path} for changes: {src}")]
+    Watch { src: String, path: PathBuf },
+}
+
+impl FsCacheErrorKind {
+    //Whether this error means that the contents of the cache file could not be made sense of,
+    //as opposed to the file being inaccessible.
+    pub(crate) fn is_unreadable_contents(&self) -> bool {
+        matches!(self, Self::CorruptedCache { .. } | Self::Deserialization { .. })
+    }
 }
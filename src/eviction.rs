@@ -0,0 +1,121 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::{cache_entry::MtimeCacheEntry, observer::CacheObserver};
+
+/// Which entries are evicted first when the cache file would grow beyond
+/// [`crate::ProcessingFsCacheBuilder::max_disk_size`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Those whose values were read or cached longest ago.
+    #[default]
+    LeastRecentlyUsed,
+    /// Those processed longest ago, whether or not they have been used since.
+    OldestProcessed,
+    /// Those taking up the most space, so that as few entries as possible are evicted.
+    LargestFirst,
+}
+
+//The order in which entries were last used, so that the least recently used can be evicted.
+#[derive(Default)]
+pub(crate) struct Recency {
+    order: Mutex<AccessOrder>,
+}
+
+#[derive(Default)]
+struct AccessOrder {
+    ticks: HashMap<PathBuf, u64>,
+    by_tick: BTreeMap<u64, PathBuf>,
+    next_tick: u64,
+}
+
+impl Recency {
+    pub(crate) fn touch(&self, path: &Path) {
+        let mut guard = self.lock();
+        let order = &mut *guard;
+        let tick = order.next_tick;
+        order.next_tick += 1;
+        let path = match order.ticks.get_mut(path) {
+            Some(last) => {
+                let last = std::mem::replace(last, tick);
+                match order.by_tick.remove(&last) {
+                    Some(path) => path,
+                    None => unreachable!(),
+                }
+            }
+            None => {
+                order.ticks.insert(path.to_path_buf(), tick);
+                path.to_path_buf()
+            }
+        };
+        order.by_tick.insert(tick, path);
+    }
+
+    pub(crate) fn forget(&self, path: &Path) {
+        let mut order = self.lock();
+        if let Some(last) = order.ticks.remove(path) {
+            order.by_tick.remove(&last);
+        }
+    }
+
+    //When a path was last used, compared to the others. Lower is less recent.
+    pub(crate) fn tick(&self, path: &Path) -> Option<u64> {
+        self.lock().ticks.get(path).copied()
+    }
+
+    //The `count` least recently used paths, least recent first.
+    pub(crate) fn least_recent(&self, count: usize) -> Vec<PathBuf> {
+        self.lock().by_tick.values().take(count).cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AccessOrder> {
+        match self.order.lock() {
+            Ok(order) => order,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+//Counts an entry as used when it is inserted, and forgets it when it is removed, then passes
+//the change on to the next observer if there is one.
+pub(crate) struct RecencyObserver<T> {
+    recency: Arc<Recency>,
+    next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+}
+
+impl<T> RecencyObserver<T> {
+    pub(crate) fn new(recency: Arc<Recency>, next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>) -> Self {
+        Self { recency, next }
+    }
+}
+
+impl<T> CacheObserver<MtimeCacheEntry<T>> for RecencyObserver<T> {
+    fn on_insert(&self, path: &Path, entry: &MtimeCacheEntry<T>) {
+        self.recency.touch(path);
+        if let Some(next) = &self.next {
+            next.on_insert(path, entry)
+        }
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.recency.forget(path);
+        if let Some(next) = &self.next {
+            next.on_remove(path)
+        }
+    }
+
+    fn on_save(&self) {
+        if let Some(next) = &self.next {
+            next.on_save()
+        }
+    }
+
+    fn on_load(&self, entries: usize) {
+        if let Some(next) = &self.next {
+            next.on_load(entries)
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    errors::{FsCacheErrorKind::Export, FsCacheResult},
+    stored_path::lossless_string,
+};
+
+/// Cached paths and values, in path order, ready to be written out for analysis elsewhere.
+pub(crate) struct ExportedEntries(Vec<(PathBuf, Value)>);
+
+impl ExportedEntries {
+    pub(crate) fn new() -> Self {
+        Self(vec![])
+    }
+
+    pub(crate) fn push(&mut self, path: &Path, value: &impl Serialize) -> FsCacheResult<()> {
+        let value = serde_json::to_value(value).map_err(|e| Export(format!("{}: {}", path.display(), e)))?;
+        self.0.push((path.to_path_buf(), value));
+        Ok(())
+    }
+
+    //A JSON array with an object for each entry, holding its path and value.
+    pub(crate) fn write_json(mut self, writer: impl Write) -> FsCacheResult<()> {
+        self.0.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let entries: Vec<Value> = self
+            .0
+            .into_iter()
+            .map(|(path, value)| serde_json::json!({ "path": lossless_string(&path), "value": value }))
+            .collect();
+        serde_json::to_writer_pretty(writer, &entries).map_err(|e| Export(format!("{}", e)))
+    }
+
+    //A row for each entry. The first column is the path, followed by a column for each field
+    //found in any value, with the fields of nested structs named like `outer.inner`. A value
+    //which is not a struct or map goes in a single column named `value`.
+    pub(crate) fn write_csv(mut self, mut writer: impl Write) -> FsCacheResult<()> {
+        self.0.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        let rows: Vec<(PathBuf, BTreeMap<String, String>)> = self
+            .0
+            .into_iter()
+            .map(|(path, value)| {
+                let mut fields = BTreeMap::new();
+                flatten("", value, &mut fields);
+                (path, fields)
+            })
+            .collect();
+        let columns: BTreeSet<&String> = rows.iter().flat_map(|(_, fields)| fields.keys()).collect();
+
+        let io_err = |e: std::io::Error| Export(format!("{}", e));
+        let header: Vec<&str> = std::iter::once("path")
+            .chain(columns.iter().map(|column| column.as_str()))
+            .collect();
+        write_csv_row(&mut writer, &header).map_err(io_err)?;
+        for (path, fields) in &rows {
+            let path = lossless_string(path);
+            let row: Vec<&str> = std::iter::once(path.as_ref())
+                .chain(
+                    columns
+                        .iter()
+                        .map(|column| fields.get(*column).map_or("", String::as_str)),
+                )
+                .collect();
+            write_csv_row(&mut writer, &row).map_err(io_err)?;
+        }
+        Ok(())
+    }
+}
+
+fn flatten(prefix: &str, value: Value, fields: &mut BTreeMap<String, String>) {
+    let text = match value {
+        Value::Object(object) => {
+            for (key, value) in object {
+                let name = match prefix {
+                    "" => key,
+                    prefix => format!("{}.{}", prefix, key),
+                };
+                flatten(&name, value, fields);
+            }
+            return;
+        }
+        Value::Null => String::new(),
+        Value::String(s) => s,
+        //numbers and booleans as they are, and arrays as JSON.
+        value => value.to_string(),
+    };
+
+    let name = match prefix {
+        "" => "value",
+        prefix => prefix,
+    };
+    fields.insert(name.to_string(), text);
+}
+
+//Fields are quoted only if they need to be.
+fn write_csv_row(writer: &mut impl Write, fields: &[&str]) -> std::io::Result<()> {
+    for (n, field) in fields.iter().enumerate() {
+        if n > 0 {
+            writer.write_all(b",")?;
+        }
+        match field.contains([',', '"', '\n', '\r']) {
+            true => write!(writer, "\"{}\"", field.replace('"', "\"\""))?,
+            false => writer.write_all(field.as_bytes())?,
+        }
+    }
+    writer.write_all(b"\n")
+}
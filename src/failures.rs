@@ -0,0 +1,177 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    errors::FsCacheResult,
+    format::ProcessorVersion,
+    invalidation::{source_changed, InvalidationStrategy, SourceMetadata},
+    relative_backend::RelativeBackend,
+    save_strategy::SaveStrategy,
+    storage::{FileBackend, StorageBackend},
+};
+
+/// When to try processing a file again after it failed to process. A failure is forgotten
+/// as soon as the file changes, so a modified file is always retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RetryPolicy {
+    /// Retry on every update. Failures are not recorded.
+    #[default]
+    Always,
+    /// Retry once this long has passed since the file last failed.
+    After(Duration),
+    /// Stop retrying once the file has failed this many times.
+    MaxAttempts(u32),
+}
+
+/// A recorded failure to process a file.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ProcessingFailure {
+    pub(crate) source: SourceMetadata,
+    /// The error returned by the processing function the last time it failed.
+    pub error: String,
+    /// When the file last failed to process.
+    pub failed_at: SystemTime,
+    /// How many times in a row the file has failed to process.
+    pub attempts: u32,
+}
+
+impl RetryPolicy {
+    fn should_retry(&self, failure: &ProcessingFailure) -> bool {
+        match self {
+            Self::Always => true,
+            Self::After(retry_after) => failure
+                .failed_at
+                .elapsed()
+                .map_or(true, |elapsed| elapsed >= *retry_after),
+            Self::MaxAttempts(max_attempts) => failure.attempts < *max_attempts,
+        }
+    }
+}
+
+/// Failures to process files, stored beside the cache file so that they are remembered from run to run.
+pub(crate) struct FailureLog {
+    failures: BaseFsCache<ProcessingFailure>,
+}
+
+impl FailureLog {
+    /// Failures are stored relative to `relative_root`, if given, like the entries of the cache.
+    pub(crate) fn open(
+        cache_path: &Path,
+        save_strategy: Arc<dyn SaveStrategy>,
+        relative_root: Option<&Path>,
+    ) -> FsCacheResult<Self> {
+        let mut path = cache_path.to_path_buf().into_os_string();
+        path.push(".failures");
+        let mut backend: Box<dyn StorageBackend<ProcessingFailure>> = Box::new(FileBackend::new(path.into()));
+        if let Some(root) = relative_root {
+            backend = Box::new(RelativeBackend::new(backend, root.to_path_buf()));
+        }
+        let failures = BaseFsCache::with_backend(save_strategy, backend)?;
+        Ok(Self { failures })
+    }
+
+    /// A fixed processing function may no longer fail, so every failure is forgotten when
+    /// the processor version changes.
+    pub(crate) fn check_version(&self, version: ProcessorVersion) -> FsCacheResult<()> {
+        self.failures.check_version(version, |_, _, _| true).map(|_| ())
+    }
+
+    pub(crate) fn set_save_on_drop(&mut self, save_on_drop: bool) {
+        self.failures.set_save_on_drop(save_on_drop)
+    }
+
+    /// The failure recorded for a file, if the file has not changed since and `policy` says
+    /// not to retry it yet.
+    pub(crate) fn known_failure(
+        &self,
+        key: &Path,
+        source: &SourceMetadata,
+        policy: RetryPolicy,
+        strategy: InvalidationStrategy,
+    ) -> Option<ProcessingFailure> {
+        if policy == RetryPolicy::Always {
+            return None;
+        }
+
+        self.failures
+            .fetch(key)
+            .ok()
+            .filter(|failure| !source_changed(strategy, source, &failure.source) && !policy.should_retry(failure))
+    }
+
+    pub(crate) fn record(
+        &self,
+        key: &Path,
+        source: SourceMetadata,
+        error: &str,
+        policy: RetryPolicy,
+        strategy: InvalidationStrategy,
+    ) -> FsCacheResult<()> {
+        if policy == RetryPolicy::Always {
+            return Ok(());
+        }
+
+        self.failures.update_with(key.to_path_buf(), |failure| {
+            //only failures of the file as it is now count towards the policy.
+            let attempts = match failure {
+                Some(failure) if !source_changed(strategy, &source, &failure.source) => failure.attempts + 1,
+                _ => 1,
+            };
+            Some(ProcessingFailure {
+                source,
+                error: error.to_string(),
+                failed_at: SystemTime::now(),
+                attempts,
+            })
+        })
+    }
+
+    pub(crate) fn forget(&self, key: &Path) -> FsCacheResult<()> {
+        self.failures.remove(key).map(|_| ())
+    }
+
+    pub(crate) fn forget_under(&self, dir: &Path) -> FsCacheResult<()> {
+        let mut any_under = false;
+        self.failures.for_each(|key, _| any_under |= key.starts_with(dir));
+        match any_under {
+            true => self.failures.retain(|key, _| !key.starts_with(dir)).map(|_| ()),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn list(&self) -> Vec<(PathBuf, ProcessingFailure)> {
+        let mut ret = vec![];
+        self.failures
+            .for_each(|key, failure| ret.push((key.to_path_buf(), failure.clone())));
+        ret
+    }
+
+    pub(crate) fn clear(&self) -> FsCacheResult<()> {
+        match self.failures.is_empty() {
+            true => Ok(()),
+            false => self.failures.clear(),
+        }
+    }
+
+    pub(crate) fn save(&self) -> FsCacheResult<()> {
+        self.failures.save()
+    }
+
+    pub(crate) fn flush(&self) -> FsCacheResult<()> {
+        self.failures.flush()
+    }
+
+    pub(crate) fn pending_modifications(&self) -> usize {
+        self.failures.pending_modifications()
+    }
+
+    pub(crate) fn reset_on_disk(&self) -> FsCacheResult<()> {
+        self.failures.reset_on_disk()
+    }
+}
@@ -0,0 +1,760 @@
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::Metadata,
+    io::{BufRead, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use log::warn;
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+use crate::{
+    errors::{FsCacheErrorKind::Traversal, FsCacheResult},
+    key_normalization::plain_prefix,
+};
+
+/// Exclusions for common kinds of directory trees, for use with [`FileSet::with_preset`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// Skip version control metadata, dependency directories and build output.
+    SourceTree,
+    /// Skip thumbnail caches, OS metadata files and sidecar files.
+    MediaLibrary,
+}
+
+/// A set of files on disk, described by a list of starting paths and a list of
+/// paths to exclude. Enumerating the set recursively walks each starting path.
+///
+/// On Windows, extended-length paths such as `\\?\C:\foo` are taken to mean the same as
+/// their usual spelling, here `C:\foo`, whichever is given to the set or checked against it.
+#[derive(Debug, Clone, Default)]
+pub struct FileSet {
+    roots: Vec<PathBuf>,
+    //if set, exactly these files are in the set (subject to any filters), and nothing is walked.
+    listed_paths: Option<HashSet<PathBuf>>,
+    exclusions: Vec<PathBuf>,
+    //lowercase, without the leading dot. None means any extension.
+    extensions: Option<Vec<String>>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_since: Option<SystemTime>,
+    one_file_system: bool,
+    skip_hidden: bool,
+    skip_cache_dirs: bool,
+    excluded_names: Vec<OsString>,
+    //lowercase, without the leading dot.
+    excluded_extensions: Vec<String>,
+    parallel_walk: bool,
+    walk_error_policy: WalkErrorPolicy,
+    sorted: bool,
+    #[cfg(feature = "gitignore")]
+    respect_ignore_files: bool,
+}
+
+impl FileSet {
+    pub fn new<P, Q>(roots: impl IntoIterator<Item = P>, exclusions: impl IntoIterator<Item = Q>) -> Self
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Self {
+            roots: roots
+                .into_iter()
+                .map(|p| plain_prefix(p.as_ref()).into_owned())
+                .collect(),
+            listed_paths: None,
+            exclusions: exclusions
+                .into_iter()
+                .map(|p| plain_prefix(p.as_ref()).into_owned())
+                .collect(),
+            extensions: None,
+            min_size: None,
+            max_size: None,
+            modified_since: None,
+            one_file_system: false,
+            skip_hidden: false,
+            skip_cache_dirs: false,
+            excluded_names: vec![],
+            excluded_extensions: vec![],
+            parallel_walk: false,
+            walk_error_policy: WalkErrorPolicy::Continue,
+            sorted: false,
+            #[cfg(feature = "gitignore")]
+            respect_ignore_files: false,
+        }
+    }
+
+    /// A set of exactly the given files, rather than everything found beneath some roots.
+    /// Listed paths which do not exist are treated as deleted files.
+    pub fn from_paths<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            listed_paths: Some(
+                paths
+                    .into_iter()
+                    .map(|p| plain_prefix(p.as_ref()).into_owned())
+                    .collect(),
+            ),
+            ..Self::new(Vec::<PathBuf>::new(), Vec::<PathBuf>::new())
+        }
+    }
+
+    /// Like [`Self::from_paths`], reading one path per line, such as the output of `find`.
+    /// Empty lines are ignored.
+    pub fn from_reader(reader: impl BufRead) -> std::io::Result<Self> {
+        let mut paths = vec![];
+        for line in reader.split(b'\n') {
+            let mut line = line?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            if !line.is_empty() {
+                paths.push(path_from_bytes(line));
+            }
+        }
+        Ok(Self::from_paths(paths))
+    }
+
+    /// Only include files with one of these extensions, which are compared case-insensitively
+    /// and may be given with or without a leading dot.
+    pub fn with_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        self.extensions = Some(normalize_extensions(extensions));
+        self
+    }
+
+    /// Only include files of at least this many bytes. Use 1 to skip empty files.
+    pub fn with_min_size(mut self, min_size: u64) -> Self {
+        self.min_size = Some(min_size);
+        self
+    }
+
+    /// Only include files of at most this many bytes.
+    pub fn with_max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Only include files modified after `time`. Cached entries for older files are left as
+    /// they are by `update_from_fs`, which makes for quick incremental updates when most
+    /// files are known to be old and already cached.
+    pub fn modified_since(mut self, time: SystemTime) -> Self {
+        self.modified_since = Some(time);
+        self
+    }
+
+    /// Don't descend into directories on a different filesystem from their root, such as
+    /// network mounts or `/proc`.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Whether to include hidden files and directories, whose names start with a dot.
+    /// Included by default. Roots are always walked, even if they are hidden.
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.skip_hidden = !include_hidden;
+        self
+    }
+
+    /// Skip directories marked as caches by a valid `CACHEDIR.TAG` file, as described at
+    /// <https://bford.info/cachedir/>.
+    pub fn skip_cache_dirs(mut self, skip_cache_dirs: bool) -> Self {
+        self.skip_cache_dirs = skip_cache_dirs;
+        self
+    }
+
+    /// Skip files and directories with any of these names, wherever they are below the roots.
+    pub fn exclude_names<S: AsRef<OsStr>>(mut self, names: &[S]) -> Self {
+        self.excluded_names
+            .extend(names.iter().map(|name| name.as_ref().to_os_string()));
+        self
+    }
+
+    /// Skip files with any of these extensions, which are compared case-insensitively and
+    /// may be given with or without a leading dot.
+    pub fn exclude_extensions<S: AsRef<str>>(mut self, extensions: &[S]) -> Self {
+        self.excluded_extensions.extend(normalize_extensions(extensions));
+        self
+    }
+
+    /// Add the exclusions for a common kind of directory tree.
+    pub fn with_preset(self, preset: Preset) -> Self {
+        let (names, extensions): (&[&str], &[&str]) = match preset {
+            Preset::SourceTree => (
+                &[
+                    ".git",
+                    ".hg",
+                    ".svn",
+                    "node_modules",
+                    "target",
+                    "__pycache__",
+                    ".venv",
+                    ".tox",
+                    ".mypy_cache",
+                    ".gradle",
+                ],
+                &["o", "obj", "pyc", "class"],
+            ),
+            Preset::MediaLibrary => (
+                &[
+                    ".thumbnails",
+                    "@eaDir",
+                    ".AppleDouble",
+                    ".Trashes",
+                    ".DS_Store",
+                    "Thumbs.db",
+                    "desktop.ini",
+                    ".picasa.ini",
+                ],
+                &["xmp", "thm", "aae", "nfo"],
+            ),
+        };
+        self.exclude_names(names).exclude_extensions(extensions)
+    }
+
+    /// Walk directories in parallel on rayon's thread pool. This is much faster for large
+    /// trees on storage which handles many requests at once, such as SSDs and network shares,
+    /// but files are found in no particular order. Not used with `respect_ignore_files`.
+    pub fn parallel_walk(mut self, parallel_walk: bool) -> Self {
+        self.parallel_walk = parallel_walk;
+        self
+    }
+
+    /// Whether [`Self::enumerate`] (and so `update_from_fs`) should carry on or stop if part
+    /// of the set cannot be walked. Defaults to [`WalkErrorPolicy::Continue`].
+    pub fn walk_error_policy(mut self, policy: WalkErrorPolicy) -> Self {
+        self.walk_error_policy = policy;
+        self
+    }
+
+    /// Skip files matched by `.gitignore`, `.ignore` and git's other exclude files, as git
+    /// would. `.gitignore` files are honored even outside of a git repository.
+    #[cfg(feature = "gitignore")]
+    pub fn respect_ignore_files(mut self, respect_ignore_files: bool) -> Self {
+        self.respect_ignore_files = respect_ignore_files;
+        self
+    }
+
+    /// Enumerate files in sorted order, so that they are processed in the same order on every
+    /// run. `update_from_fs` processes files in parallel, so for a strictly reproducible order
+    /// also limit it to a single worker thread (or an async cache to a concurrency of one).
+    pub fn sorted(mut self, sorted: bool) -> Self {
+        self.sorted = sorted;
+        self
+    }
+
+    //Puts paths in the order requested by `sorted`.
+    pub(crate) fn order(&self, paths: &mut [PathBuf]) {
+        if self.sorted {
+            paths.sort_unstable();
+        }
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    pub fn exclusions(&self) -> &[PathBuf] {
+        &self.exclusions
+    }
+
+    /// Add another root to walk, returning false if it was already a root. To avoid walking
+    /// the whole set again, bring just the new root up to date by passing
+    /// `file_set.subtree(root)` to `update_from_fs`.
+    pub fn add_root(&mut self, root: impl AsRef<Path>) -> bool {
+        add_path(&mut self.roots, root.as_ref())
+    }
+
+    /// Stop walking a root, returning false if it was not a root. Entries already cached for
+    /// files beneath it are left alone by `update_from_fs`; use `remove_outside` to drop them.
+    pub fn remove_root(&mut self, root: impl AsRef<Path>) -> bool {
+        remove_path(&mut self.roots, root.as_ref())
+    }
+
+    /// Exclude another path, returning false if it was already excluded. As with
+    /// [`Self::remove_root`], cached entries for newly excluded files are left alone.
+    pub fn add_exclusion(&mut self, exclusion: impl AsRef<Path>) -> bool {
+        add_path(&mut self.exclusions, exclusion.as_ref())
+    }
+
+    /// Stop excluding a path, returning false if it was not excluded. Files which are now
+    /// part of the set can be brought up to date with `file_set.subtree(exclusion)`.
+    pub fn remove_exclusion(&mut self, exclusion: impl AsRef<Path>) -> bool {
+        remove_path(&mut self.exclusions, exclusion.as_ref())
+    }
+
+    /// The same set of files, but only those beneath `root`.
+    pub fn subtree(&self, root: &Path) -> Self {
+        Self {
+            roots: vec![plain_prefix(root).into_owned()],
+            ..self.clone()
+        }
+    }
+
+    //The same set with every root, listed path and exclusion rewritten by `f`.
+    pub(crate) fn map_paths(&self, f: impl Fn(&Path) -> PathBuf) -> Self {
+        Self {
+            roots: self.roots.iter().map(|path| f(path)).collect(),
+            listed_paths: self
+                .listed_paths
+                .as_ref()
+                .map(|listed_paths| listed_paths.iter().map(|path| f(path)).collect()),
+            exclusions: self.exclusions.iter().map(|path| f(path)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns true if the path lies beneath one of the roots (or for a set made from a list
+    /// of paths, is listed), is not excluded and passes
+    /// any filters. Paths which do not exist pass any filters on file size (and the like),
+    /// so that deleted files are still included.
+    pub fn includes(&self, path: &Path) -> bool {
+        let path = &*plain_prefix(path);
+        let in_set = match &self.listed_paths {
+            Some(listed_paths) => listed_paths.contains(path) && !self.skips_entry(path, 1, false),
+            None => {
+                self.roots
+                    .iter()
+                    .any(|root| path.starts_with(root) && !self.skips_name_below(root, path))
+                    && !self.is_excluded(path)
+            }
+        };
+        in_set
+            && self.accepts_file(path)
+            && (!self.has_metadata_filters() || std::fs::metadata(path).map_or(true, |m| self.accepts_metadata(&m)))
+    }
+
+    fn has_metadata_filters(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.modified_since.is_some()
+    }
+
+    fn accepts_metadata(&self, metadata: &Metadata) -> bool {
+        self.min_size.is_none_or(|min_size| metadata.len() >= min_size)
+            && self.max_size.is_none_or(|max_size| metadata.len() <= max_size)
+            && self
+                .modified_since
+                .is_none_or(|since| metadata.modified().is_ok_and(|mtime| mtime > since))
+    }
+
+    //Checks the metadata filters using metadata from a walk, only fetching it if needed.
+    fn accepts_walked_file(
+        &self,
+        path: &Path,
+        metadata: impl FnOnce() -> std::io::Result<Metadata>,
+        errors: &WalkErrors,
+    ) -> bool {
+        if !self.accepts_file(path) {
+            return false;
+        }
+        if !self.has_metadata_filters() {
+            return true;
+        }
+        match metadata() {
+            Ok(metadata) => self.accepts_metadata(&metadata),
+            Err(e) => {
+                errors.record(path, e);
+                false
+            }
+        }
+    }
+
+    //Filters which apply to files only, not to the directories containing them.
+    fn accepts_file(&self, path: &Path) -> bool {
+        let ext = path.extension().map(|ext| ext.to_string_lossy().to_lowercase());
+        let wanted = match (&self.extensions, &ext) {
+            (None, _) => true,
+            (Some(extensions), Some(ext)) => extensions.contains(ext),
+            (Some(_), None) => false,
+        };
+        wanted && ext.is_none_or(|ext| !self.excluded_extensions.contains(&ext))
+    }
+
+    fn skips_name(&self, name: &OsStr) -> bool {
+        (self.skip_hidden && name.to_string_lossy().starts_with('.')) || self.excluded_names.iter().any(|n| n == name)
+    }
+
+    fn skips_name_below(&self, root: &Path, path: &Path) -> bool {
+        match path.strip_prefix(root) {
+            Ok(relative) => relative.components().any(|c| self.skips_name(c.as_os_str())),
+            Err(_) => false,
+        }
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.exclusions.iter().any(|excl| path.starts_with(excl))
+    }
+
+    //Whether a walk should skip a file or directory (and so everything in it). `depth` is 0 for the root.
+    fn skips_entry(&self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        self.is_excluded(path)
+            || (depth > 0 && path.file_name().is_some_and(|name| self.skips_name(name)))
+            || (self.skip_cache_dirs && is_dir && is_tagged_cache_dir(path))
+    }
+
+    /// Recursively walk the roots and return every file found that is not excluded. Any
+    /// errors are logged and skipped over, whatever the [`WalkErrorPolicy`]. Use
+    /// [`Self::enumerate`] to find out about them.
+    pub fn enumerate_from_fs(&self) -> Vec<PathBuf> {
+        self.walk(false).0
+    }
+
+    /// Like [`Self::enumerate_from_fs`], but also returns any errors encountered, such as
+    /// unreadable directories and broken symlinks. With [`WalkErrorPolicy::Abort`], the
+    /// first error stops the walk and is returned instead.
+    pub fn enumerate(&self) -> FsCacheResult<Enumeration> {
+        let abort_on_error = self.walk_error_policy == WalkErrorPolicy::Abort;
+        let (files, mut errors) = self.walk(abort_on_error);
+
+        match abort_on_error && !errors.is_empty() {
+            true => {
+                let WalkError { path, error } = errors.swap_remove(0);
+                Err(Traversal { src: error, path })
+            }
+            false => Ok(Enumeration { files, errors }),
+        }
+    }
+
+    fn walk(&self, abort_on_error: bool) -> (Vec<PathBuf>, Vec<WalkError>) {
+        let errors = WalkErrors::new(abort_on_error);
+        let mut ret = vec![];
+
+        if let Some(listed_paths) = &self.listed_paths {
+            ret.extend(
+                listed_paths
+                    .iter()
+                    .filter(|path| !errors.aborted() && self.includes_listed_file(path, &errors))
+                    .cloned(),
+            );
+            self.order(&mut ret);
+            return (ret, errors.into_inner());
+        }
+
+        for root in &self.roots {
+            if errors.aborted() {
+                break;
+            }
+
+            #[cfg(feature = "gitignore")]
+            if self.respect_ignore_files {
+                self.walk_respecting_ignore_files(root, &mut ret, &errors);
+                continue;
+            }
+
+            //the device of a file can only be checked on unix.
+            if self.parallel_walk && (cfg!(unix) || !self.one_file_system) {
+                self.walk_parallel(root, &mut ret, &errors);
+                continue;
+            }
+
+            let walker = WalkDir::new(root)
+                .same_file_system(self.one_file_system)
+                .into_iter()
+                .filter_entry(|entry| !self.skips_entry(entry.path(), entry.depth(), entry.file_type().is_dir()));
+
+            for entry in walker {
+                match entry {
+                    Ok(entry) if entry.file_type().is_file() => {
+                        if self.accepts_walked_file(entry.path(), || Ok(entry.metadata()?), &errors) {
+                            ret.push(entry.into_path())
+                        }
+                    }
+                    Ok(entry) if entry.path_is_symlink() => check_symlink(entry.path(), &errors),
+                    Ok(_) => (),
+                    Err(e) => {
+                        let path = e.path().unwrap_or(root).to_path_buf();
+                        errors.record(&path, e.into());
+                    }
+                }
+                if errors.aborted() {
+                    break;
+                }
+            }
+        }
+
+        self.order(&mut ret);
+        (ret, errors.into_inner())
+    }
+
+    fn includes_listed_file(&self, path: &Path, errors: &WalkErrors) -> bool {
+        match std::fs::metadata(path) {
+            Ok(metadata) => {
+                metadata.is_file()
+                    && !self.skips_entry(path, 1, false)
+                    && self.accepts_walked_file(path, || Ok(metadata), errors)
+            }
+            //listed files which don't exist are as good as deleted, and not an error.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => {
+                errors.record(path, e);
+                false
+            }
+        }
+    }
+
+    //Behaves like the WalkDir walk, except for the order in which files are found.
+    fn walk_parallel(&self, root: &Path, ret: &mut Vec<PathBuf>, errors: &WalkErrors) {
+        //like WalkDir, a symlinked root is followed.
+        let metadata = match std::fs::metadata(root) {
+            Ok(metadata) => metadata,
+            Err(e) => return errors.record(root, e),
+        };
+
+        if self.skips_entry(root, 0, metadata.is_dir()) {
+            return;
+        }
+        if metadata.is_dir() {
+            ret.extend(self.walk_dir_parallel(root, 1, device_id(&metadata), errors));
+        } else if metadata.is_file() && self.accepts_walked_file(root, || Ok(metadata), errors) {
+            ret.push(root.to_path_buf());
+        }
+    }
+
+    fn walk_dir_parallel(
+        &self,
+        dir: &Path,
+        depth: usize,
+        root_device: Option<u64>,
+        errors: &WalkErrors,
+    ) -> Vec<PathBuf> {
+        if errors.aborted() {
+            return vec![];
+        }
+
+        let entries: Vec<_> = match std::fs::read_dir(dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        errors.record(dir, e);
+                        None
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                errors.record(dir, e);
+                return vec![];
+            }
+        };
+
+        entries
+            .into_par_iter()
+            .flat_map_iter(|entry| {
+                let path = entry.path();
+                let file_type = match entry.file_type() {
+                    Ok(file_type) => file_type,
+                    Err(e) => {
+                        errors.record(&path, e);
+                        return vec![];
+                    }
+                };
+
+                if self.skips_entry(&path, depth, file_type.is_dir()) {
+                    vec![]
+                } else if file_type.is_dir() {
+                    let crosses_filesystem = self.one_file_system
+                        && entry.metadata().ok().and_then(|metadata| device_id(&metadata)) != root_device;
+                    match crosses_filesystem {
+                        true => vec![],
+                        false => self.walk_dir_parallel(&path, depth + 1, root_device, errors),
+                    }
+                } else if file_type.is_file() && self.accepts_walked_file(&path, || entry.metadata(), errors) {
+                    vec![path]
+                } else {
+                    if file_type.is_symlink() {
+                        check_symlink(&path, errors);
+                    }
+                    vec![]
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "gitignore")]
+    fn walk_respecting_ignore_files(&self, root: &Path, ret: &mut Vec<PathBuf>, errors: &WalkErrors) {
+        let this = self.clone();
+        let walker = ignore::WalkBuilder::new(root)
+            .standard_filters(false)
+            .git_ignore(true)
+            .git_global(true)
+            .git_exclude(true)
+            .ignore(true)
+            .parents(true)
+            .require_git(false)
+            .same_file_system(self.one_file_system)
+            .filter_entry(move |entry| {
+                let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+                !this.skips_entry(entry.path(), entry.depth(), is_dir)
+            })
+            .build();
+
+        for entry in walker {
+            match entry {
+                Ok(entry) if entry.file_type().is_some_and(|ft| ft.is_file()) => {
+                    let metadata = || entry.metadata().map_err(std::io::Error::other);
+                    if self.accepts_walked_file(entry.path(), metadata, errors) {
+                        ret.push(entry.into_path())
+                    }
+                }
+                Ok(entry) if entry.path_is_symlink() => check_symlink(entry.path(), errors),
+                Ok(_) => (),
+                Err(e) => {
+                    let path = match &e {
+                        ignore::Error::WithPath { path, .. } => path.clone(),
+                        _ => root.to_path_buf(),
+                    };
+                    errors.record(&path, std::io::Error::other(e));
+                }
+            }
+            if errors.aborted() {
+                break;
+            }
+        }
+    }
+}
+
+/// Something which went wrong while walking a [`FileSet`].
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: std::io::Error,
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
+    }
+}
+
+/// What [`FileSet::enumerate`] should do when part of the set cannot be walked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WalkErrorPolicy {
+    /// Skip over the problem, and report it alongside the files found.
+    #[default]
+    Continue,
+    /// Stop walking, and return the error.
+    Abort,
+}
+
+/// The files in a [`FileSet`], along with anything which could not be walked.
+#[derive(Debug, Default)]
+pub struct Enumeration {
+    pub files: Vec<PathBuf>,
+    pub errors: Vec<WalkError>,
+}
+
+//Collects errors from a walk, which may be running on several threads.
+struct WalkErrors {
+    errors: Mutex<Vec<WalkError>>,
+    abort_on_error: bool,
+    aborted: AtomicBool,
+}
+
+impl WalkErrors {
+    fn new(abort_on_error: bool) -> Self {
+        Self {
+            errors: Default::default(),
+            abort_on_error,
+            aborted: AtomicBool::new(false),
+        }
+    }
+
+    fn record(&self, path: &Path, error: std::io::Error) {
+        warn!(target: "generic_cache_enumerate", "skipping {}: {}", path.display(), error);
+        self.lock().push(WalkError {
+            path: path.to_path_buf(),
+            error,
+        });
+        if self.abort_on_error {
+            self.aborted.store(true, Relaxed);
+        }
+    }
+
+    fn aborted(&self) -> bool {
+        self.aborted.load(Relaxed)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<WalkError>> {
+        match self.errors.lock() {
+            Ok(errors) => errors,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    fn into_inner(self) -> Vec<WalkError> {
+        match self.errors.into_inner() {
+            Ok(errors) => errors,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+//Symlinks are not followed, but one which points nowhere is probably a mistake worth knowing about.
+fn check_symlink(path: &Path, errors: &WalkErrors) {
+    if let Err(e) = std::fs::metadata(path) {
+        errors.record(path, e);
+    }
+}
+
+fn add_path(paths: &mut Vec<PathBuf>, path: &Path) -> bool {
+    let path = &*plain_prefix(path);
+    let added = !paths.iter().any(|p| p == path);
+    if added {
+        paths.push(path.to_path_buf());
+    }
+    added
+}
+
+fn remove_path(paths: &mut Vec<PathBuf>, path: &Path) -> bool {
+    let path = &*plain_prefix(path);
+    let len = paths.len();
+    paths.retain(|p| p != path);
+    paths.len() != len
+}
+
+//A cache directory tag must start with this, to avoid accidentally skipping directories
+//which just happen to contain a file with that name.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+fn is_tagged_cache_dir(dir: &Path) -> bool {
+    let mut signature = [0; CACHEDIR_TAG_SIGNATURE.len()];
+    match std::fs::File::open(dir.join("CACHEDIR.TAG")) {
+        Ok(mut tag) => tag.read_exact(&mut signature).is_ok() && signature == CACHEDIR_TAG_SIGNATURE,
+        Err(_) => false,
+    }
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(unix)]
+fn device_id(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+fn normalize_extensions<S: AsRef<str>>(extensions: &[S]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|ext| ext.as_ref().trim_start_matches('.').to_lowercase())
+        .collect()
+}
@@ -0,0 +1,234 @@
+use std::{
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+
+//Every cache file written by this crate starts with these bytes. Files written by older
+//versions of the crate have no header at all, and are treated as format version 0.
+const MAGIC: [u8; 8] = *b"GFSCACHE";
+
+//Offset of the payload checksum within the header, which is filled in after the payload is written.
+const CHECKSUM_OFFSET: u64 = 20;
+
+/// The version of the on-disk format written by this version of the crate.
+///
+/// * 0: no header.
+/// * 1: header containing a value type fingerprint.
+/// * 2: header additionally contains the length and CRC32 of the payload.
+/// * 3: header additionally contains the version of the processing which produced the values.
+/// * 4: header additionally contains a fingerprint of the configuration of the processing.
+/// * 5: the header is unchanged, but the entries of a [`crate::ProcessingFsCache`] additionally
+///   contain the identity of their file.
+pub const FORMAT_VERSION: u32 = 5;
+
+/// The header at the start of a cache file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+    /// 0 for files written before headers were introduced.
+    pub format_version: u32,
+    /// A fingerprint of the type of the values stored in the file, or None if unknown.
+    pub fingerprint: Option<u64>,
+    /// The length and CRC32 of the payload, for files with format version 2 or later.
+    pub checksum: Option<PayloadChecksum>,
+    /// The version of the processing which produced the values, for files with format
+    /// version 3 or later. Files with format version 3 have a configuration fingerprint of 0.
+    pub processor_version: Option<ProcessorVersion>,
+}
+
+/// Identifies the processing which produced a cache's values, so that values produced by
+/// different processing are not mixed. See [`crate::CacheInterface::version`] and
+/// [`crate::CacheInterface::config_fingerprint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProcessorVersion {
+    pub version: u32,
+    pub config_fingerprint: u64,
+}
+
+/// The length and CRC32 of the (possibly encrypted) payload following the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadChecksum {
+    pub len: u64,
+    pub crc32: u32,
+}
+
+//A reader over the payload of a cache file, which may first yield bytes consumed while
+//looking for a header.
+pub(crate) type PayloadReader<R> = std::io::Chain<Cursor<Vec<u8>>, R>;
+
+impl FileHeader {
+    pub(crate) fn current<T>(processor_version: ProcessorVersion) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            fingerprint: Some(type_fingerprint::<T>()),
+            checksum: None,
+            processor_version: Some(processor_version),
+        }
+    }
+
+    /// Write the header. The checksum is written as zeroes and must be filled in with
+    /// [`write_checksum`] once the payload has been written.
+    pub(crate) fn write(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&self.format_version.to_le_bytes())?;
+        writer.write_all(&self.fingerprint.unwrap_or_default().to_le_bytes())?;
+        writer.write_all(&[0; 12])?;
+        let processor_version = self.processor_version.unwrap_or_default();
+        writer.write_all(&processor_version.version.to_le_bytes())?;
+        writer.write_all(&processor_version.config_fingerprint.to_le_bytes())
+    }
+
+    /// Read the header from the start of a cache file, returning it along with a reader
+    /// positioned at the start of the payload.
+    pub(crate) fn read<R: Read>(mut reader: R) -> std::io::Result<(Self, PayloadReader<R>)> {
+        let mut prefix = vec![];
+        (&mut reader).take(MAGIC.len() as u64).read_to_end(&mut prefix)?;
+
+        if prefix != MAGIC {
+            let legacy = Self {
+                format_version: 0,
+                fingerprint: None,
+                checksum: None,
+                processor_version: None,
+            };
+            return Ok((legacy, Cursor::new(prefix).chain(reader)));
+        }
+
+        let mut version = [0; 4];
+        let mut fingerprint = [0; 8];
+        reader.read_exact(&mut version)?;
+        reader.read_exact(&mut fingerprint)?;
+        let format_version = u32::from_le_bytes(version);
+
+        let checksum = if format_version >= 2 {
+            let mut len = [0; 8];
+            let mut crc32 = [0; 4];
+            reader.read_exact(&mut len)?;
+            reader.read_exact(&mut crc32)?;
+            Some(PayloadChecksum {
+                len: u64::from_le_bytes(len),
+                crc32: u32::from_le_bytes(crc32),
+            })
+        } else {
+            None
+        };
+
+        let processor_version = if format_version >= 3 {
+            let mut version = [0; 4];
+            let mut config_fingerprint = [0; 8];
+            reader.read_exact(&mut version)?;
+            if format_version >= 4 {
+                reader.read_exact(&mut config_fingerprint)?;
+            }
+            Some(ProcessorVersion {
+                version: u32::from_le_bytes(version),
+                config_fingerprint: u64::from_le_bytes(config_fingerprint),
+            })
+        } else {
+            None
+        };
+
+        let header = Self {
+            format_version,
+            fingerprint: Some(u64::from_le_bytes(fingerprint)),
+            checksum,
+            processor_version,
+        };
+        Ok((header, Cursor::new(vec![]).chain(reader)))
+    }
+}
+
+/// Fill in the payload checksum of a header written by [`FileHeader::write`].
+pub(crate) fn write_checksum(file: &mut (impl Write + Seek), checksum: PayloadChecksum) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(CHECKSUM_OFFSET))?;
+    file.write_all(&checksum.len.to_le_bytes())?;
+    file.write_all(&checksum.crc32.to_le_bytes())?;
+    file.seek(SeekFrom::End(0)).map(|_| ())
+}
+
+/// Wraps a reader or writer, computing the checksum of all bytes passing through it.
+pub(crate) struct Checksummed<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+    len: u64,
+}
+
+impl<T> Checksummed<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            len: 0,
+        }
+    }
+
+    pub(crate) fn finish(self) -> (T, PayloadChecksum) {
+        let checksum = PayloadChecksum {
+            len: self.len,
+            crc32: self.hasher.finalize(),
+        };
+        (self.inner, checksum)
+    }
+
+    //The number of bytes which have passed through so far.
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A hook to upgrade the payload of an old or incompatible cache file. It receives the
+/// header and the decoded (but still serialized) payload of the file, and must return a
+/// payload which can be deserialized as the current value type.
+pub type MigrationFn = dyn Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync;
+
+// The fingerprint is a hash of the value type's name. It is not guaranteed to be stable
+// between compiler versions, but will catch the common case of the cached type being changed.
+pub(crate) fn type_fingerprint<T>() -> u64 {
+    name_fingerprint(std::any::type_name::<T>())
+}
+
+pub(crate) fn name_fingerprint(type_name: &str) -> u64 {
+    fnv1a(type_name.bytes())
+}
+
+//Stable between runs, unlike hashing with std's hashers.
+pub(crate) fn path_fingerprint(path: &Path) -> u64 {
+    fnv1a(path.as_os_str().as_encoded_bytes().iter().copied())
+}
+
+/// A fingerprint of some configuration, for use as [`crate::CacheInterface::config_fingerprint`].
+/// It is a hash of the configuration serialized with bincode, so is stable between runs and
+/// compiler versions. Configurations which cannot be serialized all have the same fingerprint.
+pub fn config_fingerprint(config: &impl Serialize) -> u64 {
+    fnv1a(bincode::serialize(config).unwrap_or_default().into_iter())
+}
+
+//FNV-1a, chosen because it is trivial and (unlike std's hashers) stable between releases.
+pub(crate) fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
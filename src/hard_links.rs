@@ -0,0 +1,29 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use rayon::prelude::*;
+
+use crate::invalidation::FileId;
+
+//Groups paths which are hard links to the same file, so that the file only needs processing
+//once. Groups are in the order their first path appears in `paths`, and each path which is
+//not linked to any other is in a group by itself.
+pub(crate) fn linked_groups(paths: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    let ids: Vec<Option<FileId>> = paths
+        .par_iter()
+        .map(|path| {
+            fs::metadata(path)
+                .ok()
+                .and_then(|metadata| FileId::of_linked(&metadata))
+        })
+        .collect();
+
+    let mut groups: Vec<Vec<PathBuf>> = Vec::with_capacity(paths.len());
+    let mut group_of: HashMap<FileId, usize> = HashMap::new();
+    for (path, id) in paths.iter().zip(ids) {
+        match id.map(|id| *group_of.entry(id).or_insert(groups.len())) {
+            Some(n) if n < groups.len() => groups[n].push(path.clone()),
+            _ => groups.push(vec![path.clone()]),
+        }
+    }
+    groups
+}
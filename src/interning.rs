@@ -0,0 +1,295 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, Hash},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cache_entry::MtimeCacheEntry,
+    errors::FsCacheResult,
+    format::{type_fingerprint, FileHeader, ProcessorVersion},
+    invalidation::{FileId, SourceMetadata},
+    storage::{CacheView, StorageBackend},
+};
+
+//Shares identical values between entries, so that each is only held in memory once.
+pub(crate) trait Intern<T>: Send + Sync {
+    //The shared copy of `value`, which becomes the shared copy if there is none yet.
+    fn intern(&self, value: Arc<T>) -> Arc<T>;
+}
+
+pub(crate) struct Interner<T> {
+    values: Mutex<InternedValues<T>>,
+}
+
+struct InternedValues<T> {
+    values: HashSet<Arc<T>>,
+    //Values which are no longer cached are forgotten whenever the number of values remembered
+    //reaches this, which then doubles, so that forgetting them costs little per value.
+    prune_at: usize,
+}
+
+const MIN_PRUNE_AT: usize = 1024;
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Self {
+            values: Mutex::new(InternedValues {
+                values: HashSet::new(),
+                prune_at: MIN_PRUNE_AT,
+            }),
+        }
+    }
+}
+
+impl<T> Intern<T> for Interner<T>
+where
+    T: Eq + Hash + Send + Sync,
+{
+    fn intern(&self, value: Arc<T>) -> Arc<T> {
+        let mut interned = match self.values.lock() {
+            Ok(interned) => interned,
+            Err(_) => unreachable!(),
+        };
+        if let Some(shared) = interned.values.get(&value) {
+            return shared.clone();
+        }
+
+        if interned.values.len() >= interned.prune_at {
+            interned.values.retain(|value| Arc::strong_count(value) > 1);
+            interned.prune_at = (interned.values.len() * 2).max(MIN_PRUNE_AT);
+        }
+        interned.values.insert(value.clone());
+        value
+    }
+}
+
+//How a cache which interns its values stores its entries. Every distinct value is stored once,
+//in a list kept under the empty path, which no file can have, and entries refer to their value
+//by its position in the list. Entries appended to a journal are stored with their value,
+//because the list is only written when everything is saved.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum InternedEntry<T> {
+    Values(Vec<Arc<T>>),
+    Shared {
+        source: SourceMetadata,
+        value: usize,
+        cached_at: Option<SystemTime>,
+        processing_time: Option<Duration>,
+        file_id: Option<FileId>,
+    },
+    Inline(MtimeCacheEntry<T>),
+}
+
+//Stores the entries of a cache whose values are interned, so that entries with the same value
+//share one copy of it on disk as they do in memory.
+pub(crate) struct InterningBackend<T, S> {
+    backend: Box<dyn StorageBackend<InternedEntry<T>, S>>,
+    interner: Arc<dyn Intern<T>>,
+}
+
+impl<T, S> InterningBackend<T, S>
+where
+    S: BuildHasher + Default,
+{
+    pub(crate) fn new(backend: Box<dyn StorageBackend<InternedEntry<T>, S>>, interner: Arc<dyn Intern<T>>) -> Self {
+        Self { backend, interner }
+    }
+}
+
+impl<T, S> StorageBackend<MtimeCacheEntry<T>, S> for InterningBackend<T, S>
+where
+    T: Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, MtimeCacheEntry<T>, S>> {
+        let mut stored = self.backend.load()?;
+        let values: Vec<Arc<T>> = match stored.remove(Path::new("")) {
+            Some(InternedEntry::Values(values)) => {
+                values.into_iter().map(|value| self.interner.intern(value)).collect()
+            }
+            _ => vec![],
+        };
+        Ok(expanded(stored, &values)
+            .into_iter()
+            .map(|(key, mut entry)| {
+                entry.value = self.interner.intern(entry.value);
+                (key, entry)
+            })
+            .collect())
+    }
+
+    //The list of values is rewritten from scratch, after which the position of a value may have
+    //changed, so entries are always saved all together rather than only those which changed.
+    fn save(&self, cache: &CacheView<'_, MtimeCacheEntry<T>, S>) -> FsCacheResult<()> {
+        self.backend.save(&(&interned(cache)).into())
+    }
+
+    fn save_changes(
+        &self,
+        cache: &CacheView<'_, MtimeCacheEntry<T>, S>,
+        _changed_keys: &[PathBuf],
+    ) -> FsCacheResult<()> {
+        self.save(cache)
+    }
+
+    fn append(&self, changes: &[(&Path, Option<&MtimeCacheEntry<T>>)]) -> FsCacheResult<()> {
+        let inline: Vec<(&Path, Option<InternedEntry<T>>)> = changes
+            .iter()
+            .map(|(key, entry)| (*key, entry.map(|entry| InternedEntry::Inline(shallow_clone(entry)))))
+            .collect();
+        let changes: Vec<(&Path, Option<&InternedEntry<T>>)> =
+            inline.iter().map(|(key, entry)| (*key, entry.as_ref())).collect();
+        self.backend.append(&changes)
+    }
+
+    fn taking_snapshot(&self) {
+        self.backend.taking_snapshot()
+    }
+
+    fn reset(&self) -> FsCacheResult<()> {
+        self.backend.reset()
+    }
+
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        self.backend.stored_version()
+    }
+
+    fn store_version(
+        &self,
+        version: ProcessorVersion,
+        cache: &CacheView<'_, MtimeCacheEntry<T>, S>,
+    ) -> FsCacheResult<()> {
+        self.backend.store_version(version, &(&interned(cache)).into())
+    }
+}
+
+//Values need not be Clone, but are shared rather than cloned anyway.
+pub(crate) fn shallow_clone<T>(entry: &MtimeCacheEntry<T>) -> MtimeCacheEntry<T> {
+    MtimeCacheEntry {
+        source: entry.source,
+        value: entry.value.clone(),
+        cached_at: entry.cached_at,
+        processing_time: entry.processing_time,
+        file_id: entry.file_id,
+    }
+}
+
+//Entries whose values are shared in memory are given the same position in the list of values.
+fn interned<T, S>(cache: &CacheView<'_, MtimeCacheEntry<T>, S>) -> HashMap<PathBuf, InternedEntry<T>, S>
+where
+    S: BuildHasher + Default,
+{
+    let mut values = vec![];
+    let mut positions: HashMap<*const T, usize> = HashMap::new();
+    let mut ret: HashMap<PathBuf, InternedEntry<T>, S> = cache
+        .iter()
+        .map(|(key, entry)| {
+            let value = *positions.entry(Arc::as_ptr(&entry.value)).or_insert_with(|| {
+                values.push(entry.value.clone());
+                values.len() - 1
+            });
+            let shared = InternedEntry::Shared {
+                source: entry.source,
+                value,
+                cached_at: entry.cached_at,
+                processing_time: entry.processing_time,
+                file_id: entry.file_id,
+            };
+            (key.clone(), shared)
+        })
+        .collect();
+    ret.insert(PathBuf::new(), InternedEntry::Values(values));
+    ret
+}
+
+//An entry referring to a value beyond the end of the list cannot be used, so is treated as if it
+//had never been cached.
+fn expanded<T, S>(
+    stored: HashMap<PathBuf, InternedEntry<T>, S>,
+    values: &[Arc<T>],
+) -> HashMap<PathBuf, MtimeCacheEntry<T>, S>
+where
+    S: BuildHasher + Default,
+{
+    stored
+        .into_iter()
+        .filter_map(|(key, entry)| match entry {
+            InternedEntry::Inline(entry) => Some((key, entry)),
+            InternedEntry::Shared {
+                source,
+                value,
+                cached_at,
+                processing_time,
+                file_id,
+            } => match values.get(value) {
+                Some(value) => Some((
+                    key,
+                    MtimeCacheEntry {
+                        source,
+                        value: value.clone(),
+                        cached_at,
+                        processing_time,
+                        file_id,
+                    },
+                )),
+                None => {
+                    warn!(target: "generic_cache_startup", "Skipping entry for {} with a missing value", key.display());
+                    None
+                }
+            },
+            InternedEntry::Values(_) => None,
+        })
+        .collect()
+}
+
+/// Converts a bincode cache file of plain entries, as upgraded by `plain_migration`, to one of
+/// interned entries, for when interning is turned on for an existing cache.
+pub(crate) fn interning_file_migration<T>(
+    plain_migration: impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+) -> impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static
+where
+    T: DeserializeOwned + Serialize,
+{
+    move |header, payload| {
+        if header.fingerprint == Some(interned_fingerprint::<T>()) {
+            return Ok(payload);
+        }
+        let payload = plain_migration(header, payload)?;
+        let plain: HashMap<PathBuf, MtimeCacheEntry<T>> = bincode::deserialize(&payload).map_err(|e| {
+            format!(
+                "only a cache file encoded with bincode can be converted to interned values: {}",
+                e
+            )
+        })?;
+        bincode::serialize(&interned(&(&plain).into())).map_err(|e| format!("{}", e))
+    }
+}
+
+/// Converts a bincode cache file of interned entries to one of plain entries, for when
+/// interning is turned off for an existing cache, or it is opened lazily.
+pub(crate) fn expanded_payload<T>(payload: &[u8]) -> Result<Vec<u8>, String>
+where
+    T: DeserializeOwned + Serialize,
+{
+    let mut stored: HashMap<PathBuf, InternedEntry<T>> = bincode::deserialize(payload).map_err(|e| {
+        format!(
+            "only a cache file encoded with bincode can be converted from interned values: {}",
+            e
+        )
+    })?;
+    let values = match stored.remove(Path::new("")) {
+        Some(InternedEntry::Values(values)) => values,
+        _ => return Err("the file has no list of interned values".to_string()),
+    };
+    bincode::serialize(&expanded(stored, &values)).map_err(|e| format!("{}", e))
+}
+
+pub(crate) fn interned_fingerprint<T>() -> u64 {
+    type_fingerprint::<InternedEntry<T>>()
+}
@@ -0,0 +1,232 @@
+use std::{
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::{FsCacheErrorKind::*, FsCacheResult};
+
+/// How the cache decides whether a file has changed since it was last processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidationStrategy {
+    /// Reprocess a file if its modification time has changed.
+    Mtime,
+    /// Reprocess a file if its length has changed.
+    Size,
+    /// Reprocess a file if either its modification time or length has changed.
+    #[default]
+    MtimeAndSize,
+    /// Reprocess a file if a hash of its contents has changed. Every file must be read
+    /// in full on every update, but this is robust on filesystems with unreliable timestamps.
+    #[cfg(feature = "blake3")]
+    ContentHash,
+    /// Always reprocess files.
+    Always,
+    /// Never reprocess a file once it has been cached.
+    Never,
+}
+
+impl InvalidationStrategy {
+    pub(crate) fn needs_content_hash(&self) -> bool {
+        #[cfg(feature = "blake3")]
+        if let Self::ContentHash = self {
+            return true;
+        }
+
+        false
+    }
+}
+
+/// How a file on disk may have changed since the last time the cache was updated
+pub(crate) enum UpdateAction {
+    NoChange,
+    //the full metadata is passed on to the processing function, so it need not stat the file again.
+    Update(SourceMetadata, Box<fs::Metadata>),
+    Remove,
+}
+
+/// The state of a file on disk at the time it was processed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub(crate) struct SourceMetadata {
+    #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::AsUnixTime))]
+    pub(crate) mtime: SystemTime,
+    pub(crate) len: u64,
+    pub(crate) content_hash: Option<[u8; 32]>,
+    //Stored in cache entries rather than here, so that the stored format of failures is unchanged.
+    #[serde(skip)]
+    #[cfg_attr(feature = "rkyv", rkyv(with = rkyv::with::Skip))]
+    pub(crate) file_id: Option<FileId>,
+}
+
+/// Identifies a file whatever its path, so that it can be recognised after being renamed.
+/// Only known on unix, where it is the device and inode of the file.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "rkyv", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize))]
+pub(crate) struct FileId {
+    pub(crate) dev: u64,
+    pub(crate) ino: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    pub(crate) fn from_fs(metadata: &fs::Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn from_fs(_metadata: &fs::Metadata) -> Option<Self> {
+        None
+    }
+
+    //The identity of a file which has more than one hard link, or None if it has only one.
+    #[cfg(unix)]
+    pub(crate) fn of_linked(metadata: &fs::Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        match metadata.nlink() > 1 {
+            true => Self::from_fs(metadata),
+            false => None,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn of_linked(_metadata: &fs::Metadata) -> Option<Self> {
+        None
+    }
+}
+
+impl SourceMetadata {
+    pub(crate) fn from_fs(metadata: &fs::Metadata) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            mtime: metadata.modified()?,
+            len: metadata.len(),
+            content_hash: None,
+            file_id: FileId::from_fs(metadata),
+        })
+    }
+
+    /// Read the state of a file, including a hash of its contents if the strategy requires it.
+    pub(crate) fn read(path: &Path, strategy: InvalidationStrategy) -> Result<Self, std::io::Error> {
+        Self::read_with_metadata(path, strategy).map(|(source, _)| source)
+    }
+
+    /// Like [`Self::read`], also returning the metadata the state was read from.
+    pub(crate) fn read_with_metadata(
+        path: &Path,
+        strategy: InvalidationStrategy,
+    ) -> Result<(Self, fs::Metadata), std::io::Error> {
+        Self::read_from_metadata(path, fs::metadata(path)?, strategy)
+    }
+
+    /// Like [`Self::read_with_metadata`], given metadata which has already been read.
+    pub(crate) fn read_from_metadata(
+        path: &Path,
+        metadata: fs::Metadata,
+        strategy: InvalidationStrategy,
+    ) -> Result<(Self, fs::Metadata), std::io::Error> {
+        let mut ret = Self::from_fs(&metadata)?;
+        if strategy.needs_content_hash() {
+            ret.content_hash = Some(content_hash(path)?);
+        }
+        Ok((ret, metadata))
+    }
+}
+
+#[cfg(feature = "blake3")]
+fn content_hash(path: &Path) -> Result<[u8; 32], std::io::Error> {
+    let mut hasher = blake3::Hasher::new();
+    let mut file = fs::File::open(path)?;
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(not(feature = "blake3"))]
+fn content_hash(_path: &Path) -> Result<[u8; 32], std::io::Error> {
+    unreachable!()
+}
+
+// helper function to get whether a particular path has been updated in the filesystem.
+// Contains a hacky workaround for a problem where SSHFS (and presumably FUSE underneath)
+// reports different mtimes for files compared to a backing BTRFS filesystem (FUSE/sshfs probably
+// reports less granular mtimes?), where a file will only be considered stale if the mtime
+// is different by more than DURATION_TOLERANCE.
+pub(crate) fn update_action(
+    key: &Path,
+    strategy: InvalidationStrategy,
+    fs_source: Result<(SourceMetadata, fs::Metadata), std::io::Error>,
+    cache_source: Option<SourceMetadata>,
+) -> FsCacheResult<UpdateAction> {
+    //If the path is not present on the filesystem, then remove it from the cache
+    //(it may have never existed in the cache but this is OK)
+    let (fs_source, fs_metadata) = match fs_source {
+        Ok(fs_source) => fs_source,
+        Err(e) => match e.kind() {
+            std::io::ErrorKind::NotFound => return Ok(UpdateAction::Remove),
+            _ => {
+                return Err(CacheFileIo {
+                    path: key.to_path_buf(),
+                    src: e,
+                })
+            }
+        },
+    };
+
+    //if the file exists on the filesystem but not in the cache, we will insert it.
+    let cache_source = match cache_source {
+        Some(cache_source) => cache_source,
+        None => return Ok(UpdateAction::Update(fs_source, Box::new(fs_metadata))),
+    };
+
+    //otherwise, see if the file is changed...
+    if source_changed(strategy, &fs_source, &cache_source) {
+        Ok(UpdateAction::Update(fs_source, Box::new(fs_metadata)))
+    } else {
+        Ok(UpdateAction::NoChange)
+    }
+}
+
+/// Whether the configured strategy considers a file to have changed between two readings of its state.
+pub(crate) fn source_changed(
+    strategy: InvalidationStrategy,
+    fs_source: &SourceMetadata,
+    cache_source: &SourceMetadata,
+) -> bool {
+    match strategy {
+        InvalidationStrategy::Mtime => mtime_changed(fs_source.mtime, cache_source.mtime),
+        InvalidationStrategy::Size => fs_source.len != cache_source.len,
+        InvalidationStrategy::MtimeAndSize => {
+            fs_source.len != cache_source.len || mtime_changed(fs_source.mtime, cache_source.mtime)
+        }
+        //entries cached before hashing was enabled have no hash, so must be reprocessed.
+        #[cfg(feature = "blake3")]
+        InvalidationStrategy::ContentHash => {
+            cache_source.content_hash.is_none() || fs_source.content_hash != cache_source.content_hash
+        }
+        InvalidationStrategy::Always => true,
+        InvalidationStrategy::Never => false,
+    }
+}
+
+fn mtime_changed(fs_mtime: SystemTime, cache_mtime: SystemTime) -> bool {
+    // debug: switch between ignoring nanos and not (current  workaround for nanos-difference might be causing issues?)
+    let include_nanos = false;
+
+    if include_nanos {
+        //original implementation used the following code, which produced errors as SystemTime::duration_since
+        //appears to return an error if only the nanos portion of the fields differ
+        fs_mtime != cache_mtime
+    } else {
+        // To fix the problem the durations are converted seconds since unix epoch.
+        const DURATION_TOLERANCE_SECS: i64 = 2;
+        let cache_mtime_secs = cache_mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+        let fs_mtime_secs = fs_mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+
+        (cache_mtime_secs - fs_mtime_secs).abs() > DURATION_TOLERANCE_SECS
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+
+use crate::storage::{replace_file, temp_path};
+
+// An append-only log of changes made to the cache since it was last saved in full.
+//
+// Each record is stored as its length and CRC32 (both little-endian u32s) followed by the
+// record itself. If the application dies while appending, the last record may be torn, so
+// replay stops at the first record which is incomplete or fails its checksum.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    state: Mutex<JournalState>,
+    compact_after: usize,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    file: Option<File>,
+    record_count: usize,
+    len: u64,
+    //The length and record count of the journal when a snapshot of the cache was last taken.
+    marked: Option<(u64, usize)>,
+}
+
+impl Journal {
+    pub(crate) fn new(path: PathBuf, compact_after: usize) -> Self {
+        Self {
+            path,
+            state: Default::default(),
+            compact_after,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether enough records have been appended that the journal should be folded into
+    /// the main cache file.
+    pub(crate) fn wants_compaction(&self) -> bool {
+        self.lock().record_count >= self.compact_after
+    }
+
+    pub(crate) fn append(&self, records: &[Vec<u8>]) -> std::io::Result<()> {
+        let mut state = self.lock();
+        if state.file.is_none() {
+            state.file = Some(self.open()?);
+        }
+
+        let mut buf = vec![];
+        for record in records {
+            buf.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&crc32fast::hash(record).to_le_bytes());
+            buf.extend_from_slice(record);
+        }
+
+        if let Some(file) = state.file.as_mut() {
+            file.write_all(&buf)?;
+        }
+        state.record_count += records.len();
+        state.len += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Flush appended records to disk.
+    pub(crate) fn sync(&self) -> std::io::Result<()> {
+        match &self.lock().file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    /// Remember how much of the journal a snapshot of the cache being taken now holds, for
+    /// `clear_saved`. Must be called while nothing can be appended.
+    pub(crate) fn mark(&self) {
+        let mut state = self.lock();
+        state.marked = Some((state.len, state.record_count));
+    }
+
+    /// Empty the journal of the records held by the snapshot last passed to `mark`, once it has
+    /// been saved elsewhere, keeping any appended since. Every record is dropped if there has
+    /// been no snapshot since the journal was last emptied.
+    pub(crate) fn clear_saved(&self) -> std::io::Result<()> {
+        let mut state = self.lock();
+        let (saved_len, saved_count) = match state.marked {
+            Some(marked) if marked.0 < state.len => marked,
+            _ => return self.clear_locked(&mut state),
+        };
+
+        //The records appended since the snapshot, which are at the end of the journal, are copied
+        //to a new journal which replaces this one.
+        let mut kept = vec![];
+        let mut reader = File::open(&self.path)?;
+        reader.seek(SeekFrom::End(-((state.len - saved_len) as i64)))?;
+        reader.read_to_end(&mut kept)?;
+
+        let temp_path = temp_path(&self.path);
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&kept)?;
+        file.sync_data()?;
+        drop(file);
+        state.file = None;
+        replace_file(&temp_path, &self.path)?;
+
+        state.len = kept.len() as u64;
+        state.record_count -= saved_count;
+        state.marked = None;
+        Ok(())
+    }
+
+    /// Empty the journal, once all of its records have been saved elsewhere or are unwanted.
+    pub(crate) fn clear(&self) -> std::io::Result<()> {
+        self.clear_locked(&mut self.lock())
+    }
+
+    fn clear_locked(&self, state: &mut JournalState) -> std::io::Result<()> {
+        match &state.file {
+            Some(file) => file.set_len(0)?,
+            None if self.path.exists() => std::fs::remove_file(&self.path)?,
+            None => (),
+        }
+        state.record_count = 0;
+        state.len = 0;
+        state.marked = None;
+        Ok(())
+    }
+
+    /// Read every intact record in the journal, discarding any torn record at the end.
+    pub(crate) fn replay(&self) -> std::io::Result<Vec<Vec<u8>>> {
+        let mut records = vec![];
+        if !self.path.exists() {
+            return Ok(records);
+        }
+
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut valid_len = 0;
+        loop {
+            let mut prefix = [0; 8];
+            match read_full(&mut reader, &mut prefix)? {
+                0 => break,
+                8 => (),
+                _ => {
+                    warn!(target: "generic_cache_startup", "Discarding torn record at end of {}", self.path.display());
+                    break;
+                }
+            }
+
+            let len = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]) as usize;
+            let crc = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+            let mut record = vec![0; len];
+            if read_full(&mut reader, &mut record)? != len || crc32fast::hash(&record) != crc {
+                warn!(target: "generic_cache_startup", "Discarding torn record at end of {}", self.path.display());
+                break;
+            }
+
+            valid_len += 8 + len as u64;
+            records.push(record);
+        }
+
+        //Chop off any torn record, so that new records are not appended after garbage.
+        let mut state = self.lock();
+        let file = self.open()?;
+        file.set_len(valid_len)?;
+        state.file = Some(file);
+        state.record_count = records.len();
+        state.len = valid_len;
+        state.marked = None;
+
+        Ok(records)
+    }
+
+    fn open(&self) -> std::io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(&self.path)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, JournalState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+//Like read_exact, but returns how many bytes were read instead of failing at end of file.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..])? {
+            0 => break,
+            n => total += n,
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,287 @@
+use std::{
+    borrow::Cow,
+    path::{Component, Path, PathBuf, Prefix},
+    sync::Arc,
+};
+
+use crate::file_set::FileSet;
+
+/// How a path is rewritten before it is used as a key, so that different spellings of the
+/// same path, such as `./foo/bar` and `foo//bar`, share one entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathNormalization {
+    /// Paths are used exactly as given.
+    #[default]
+    None,
+    /// Remove `.` components and duplicate or trailing separators, and resolve `..` against
+    /// the component before it, without looking at the filesystem. If that component is a
+    /// symlink, the result may not be the same file. On Windows, extended-length paths such as
+    /// `\\?\C:\foo` are also written in their usual form.
+    Lexical,
+    /// Resolve symlinks and make paths absolute, as with [`std::fs::canonicalize`]. Costs a
+    /// filesystem lookup per path. A path which does not exist, such as a deleted file, is
+    /// resolved through its parent directory, or else normalized lexically. On Windows, the
+    /// result is written as `C:\foo` or `\\server\share\foo`, rather than as the
+    /// extended-length path `canonicalize` returns, so that it matches the paths found by walking.
+    Canonical,
+}
+
+//A user-supplied rewrite of keys, such as to a canonical unicode form.
+pub(crate) type KeyRewriteFn = Arc<dyn Fn(&Path) -> PathBuf + Send + Sync>;
+
+//Turns paths into keys of the cache, and file sets into sets of keys.
+#[derive(Clone, Default)]
+pub(crate) struct KeyNormalizer {
+    pub(crate) paths: PathNormalization,
+    pub(crate) rewrite: Option<KeyRewriteFn>,
+    pub(crate) case_insensitive: bool,
+}
+
+impl KeyNormalizer {
+    fn is_identity(&self) -> bool {
+        self.paths == PathNormalization::None && self.rewrite.is_none() && !self.case_insensitive
+    }
+
+    pub(crate) fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let key = match self.paths {
+            PathNormalization::None => Cow::Borrowed(path),
+            PathNormalization::Lexical => match plain_prefix(path) {
+                //`.` and `..` are ordinary names in extended-length paths.
+                Cow::Borrowed(path) if is_verbatim(path) => Cow::Borrowed(path),
+                Cow::Borrowed(path) => lexically_normalized(path),
+                Cow::Owned(path) => Cow::Owned(lexically_normalized(&path).into_owned()),
+            },
+            PathNormalization::Canonical => Cow::Owned(plain_prefix(&canonicalized(path)).into_owned()),
+        };
+        let key = match &self.rewrite {
+            Some(rewrite) => Cow::Owned(rewrite(&key)),
+            None => key,
+        };
+        match self.case_insensitive {
+            true => Cow::Owned(lowercased(&key)),
+            false => key,
+        }
+    }
+
+    //The same set of files, given as keys, so that walking it finds keys and that keys can be
+    //checked against it.
+    pub(crate) fn file_set<'a>(&self, file_set: &'a FileSet) -> Cow<'a, FileSet> {
+        match self.is_identity() {
+            true => Cow::Borrowed(file_set),
+            false => Cow::Owned(file_set.map_paths(|path| self.key(path).into_owned())),
+        }
+    }
+
+    pub(crate) fn keys(&self, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        match self.is_identity() {
+            true => paths,
+            false => paths.into_iter().map(|path| self.key(&path).into_owned()).collect(),
+        }
+    }
+}
+
+fn lexically_normalized(path: &Path) -> Cow<'_, Path> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                //There is nothing above the root.
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                Some(Component::ParentDir) | Some(Component::CurDir) | None => normalized.push(".."),
+            },
+            component => normalized.push(component),
+        }
+    }
+    if normalized.as_os_str().is_empty() {
+        normalized.push(".");
+    }
+
+    match normalized.as_os_str() == path.as_os_str() {
+        true => Cow::Borrowed(path),
+        false => Cow::Owned(normalized),
+    }
+}
+
+fn canonicalized(path: &Path) -> PathBuf {
+    if let Ok(canonical) = std::fs::canonicalize(path) {
+        return canonical;
+    }
+
+    let path = lexically_normalized(path);
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return path.into_owned(),
+    };
+    match (std::fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+//Paths which are not unicode can only have their ASCII letters lowercased.
+fn lowercased(path: &Path) -> PathBuf {
+    match path.to_str() {
+        Some(path) => path.to_lowercase().into(),
+        None => path.as_os_str().to_ascii_lowercase().into(),
+    }
+}
+
+/// Windows has several spellings of the same path: `\\?\C:\foo` means `C:\foo`, and
+/// `\\?\UNC\server\share\foo` means `\\server\share\foo`. Paths are rewritten to the
+/// usual spelling, with an upper case drive letter, unless they depend on the extended-length
+/// form, such as to name a file `aux` or `foo.`. Elsewhere paths have no prefix, so are
+/// returned as they are.
+pub(crate) fn plain_prefix(path: &Path) -> Cow<'_, Path> {
+    let mut components = path.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix,
+        _ => return Cow::Borrowed(path),
+    };
+    let plain = match prefix.kind() {
+        Prefix::VerbatimDisk(drive) => format!("{}:", drive.to_ascii_uppercase() as char),
+        Prefix::Disk(drive) if drive.is_ascii_lowercase() => format!("{}:", drive.to_ascii_uppercase() as char),
+        Prefix::VerbatimUNC(server, share) => match (server.to_str(), share.to_str()) {
+            (Some(server), Some(share)) => format!(r"\\{}\{}", server, share),
+            _ => return Cow::Borrowed(path),
+        },
+        _ => return Cow::Borrowed(path),
+    };
+
+    if prefix.kind().is_verbatim() && !components.clone().all(is_plain_component) {
+        return Cow::Borrowed(path);
+    }
+    let mut ret = PathBuf::from(plain);
+    ret.extend(components);
+    Cow::Owned(ret)
+}
+
+fn is_verbatim(path: &Path) -> bool {
+    matches!(path.components().next(), Some(Component::Prefix(prefix)) if prefix.kind().is_verbatim())
+}
+
+//Whether a component of an extended-length path means the same in an ordinary path, which
+//would not be the case for `..`, names containing `/`, names which Windows would trim, and
+//device names.
+fn is_plain_component(component: Component<'_>) -> bool {
+    let name = match component {
+        Component::RootDir => return true,
+        Component::Normal(name) => name,
+        _ => return false,
+    };
+    let name = match name.to_str() {
+        Some(name) => name,
+        None => return false,
+    };
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end()
+        .to_ascii_uppercase();
+    let is_device = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem[3..].chars().all(|c| c.is_ascii_digit()));
+    !name.contains('/') && !name.ends_with(['.', ' ']) && !is_device
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+
+    use super::*;
+
+    fn assert_rewritten(path: &str, expected: &str) {
+        match plain_prefix(Path::new(path)) {
+            Cow::Owned(rewritten) => assert_eq!(rewritten.as_os_str(), expected, "rewriting {}", path),
+            Cow::Borrowed(_) => panic!("{} was not rewritten, expected {}", path, expected),
+        }
+    }
+
+    fn assert_unchanged(path: &str) {
+        let unchanged = plain_prefix(Path::new(path));
+        assert!(matches!(unchanged, Cow::Borrowed(_)), "{} was rewritten to {:?}", path, unchanged);
+    }
+
+    #[test]
+    fn verbatim_disk_paths_are_rewritten() {
+        assert_rewritten(r"\\?\C:\foo\bar", r"C:\foo\bar");
+        assert_rewritten(r"\\?\c:\foo\bar", r"C:\foo\bar");
+        assert_rewritten(r"\\?\C:\", r"C:\");
+    }
+
+    #[test]
+    fn lowercase_drive_letters_are_uppercased() {
+        assert_rewritten(r"c:\foo\bar", r"C:\foo\bar");
+        assert_rewritten(r"c:foo", r"C:foo");
+        assert_unchanged(r"C:\foo\bar");
+        assert_unchanged(r"C:foo");
+    }
+
+    #[test]
+    fn verbatim_unc_paths_are_rewritten() {
+        assert_rewritten(r"\\?\UNC\server\share\foo", r"\\server\share\foo");
+        assert_rewritten(r"\\?\UNC\server\share", r"\\server\share");
+        assert_unchanged(r"\\server\share\foo");
+
+        let mut not_unicode = OsString::from(r"\\?\UNC\");
+        not_unicode.push(OsString::from_wide(&[0xd800]));
+        not_unicode.push(r"\share\foo");
+        assert!(matches!(plain_prefix(Path::new(&not_unicode)), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn other_prefixes_are_unchanged() {
+        assert_unchanged(r"\\?\pipe\foo");
+        assert_unchanged(r"\\.\COM1");
+        assert_unchanged(r"\\.\pipe\foo");
+        assert_unchanged(r"\foo\bar");
+        assert_unchanged(r"foo\bar");
+    }
+
+    #[test]
+    fn verbatim_paths_needing_their_prefix_are_unchanged() {
+        //`.` and `..` are ordinary names in extended-length paths.
+        assert_unchanged(r"\\?\C:\foo\..\bar");
+        assert_unchanged(r"\\?\C:\foo\.\bar");
+        //`/` is not a separator in extended-length paths.
+        assert_unchanged(r"\\?\C:\foo/bar");
+        //trailing dots and spaces are trimmed from ordinary paths.
+        assert_unchanged(r"\\?\C:\foo.");
+        assert_unchanged(r"\\?\C:\foo ");
+        assert_unchanged(r"\\?\UNC\server\share\foo.");
+
+        let mut not_unicode = OsString::from(r"\\?\C:\");
+        not_unicode.push(OsString::from_wide(&[0xd800]));
+        assert!(matches!(plain_prefix(Path::new(&not_unicode)), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn verbatim_paths_naming_devices_are_unchanged() {
+        for device in [
+            "con", "PRN", "aux", "nul", "CONIN$", "conout$", "com1", "COM9", "lpt1", "LPT9", "aux.txt", "nul .txt",
+            "com1.tar.gz",
+        ] {
+            assert_unchanged(&format!(r"\\?\C:\foo\{}", device));
+        }
+        for name in ["console", "com", "com10", "lptx", "auxiliary", "nul_"] {
+            assert_rewritten(&format!(r"\\?\C:\foo\{}", name), &format!(r"C:\foo\{}", name));
+        }
+    }
+
+    #[test]
+    fn lexical_keys_of_verbatim_paths() {
+        let normalizer = KeyNormalizer {
+            paths: PathNormalization::Lexical,
+            ..Default::default()
+        };
+        assert_eq!(normalizer.key(Path::new(r"\\?\c:\foo\bar")).as_os_str(), r"C:\foo\bar");
+        assert_eq!(normalizer.key(Path::new(r"c:\foo\.\baz\..\bar")).as_os_str(), r"C:\foo\bar");
+        assert_eq!(normalizer.key(Path::new(r"\\?\C:\foo\..\bar")).as_os_str(), r"\\?\C:\foo\..\bar");
+    }
+}
@@ -0,0 +1,241 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use log::{info, trace};
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "mmap")]
+use crate::entry_index::MappedEntries;
+use crate::{
+    cache_entry::MtimeCacheEntry,
+    codec::{BincodeCodec, Codec},
+    errors::FsCacheResult,
+    format::{path_fingerprint, ProcessorVersion},
+    invalidation::{source_changed, InvalidationStrategy, SourceMetadata},
+    key_normalization::KeyNormalizer,
+    storage::{FileBackend, StorageBackend},
+};
+
+/// A read-only view of a cache which only reads the part of the cache holding an entry when
+/// that entry is first looked up, so that opening it takes no time however large the cache.
+/// Made by [`crate::ProcessingFsCacheBuilder::build_lazy`].
+///
+/// The cache is read a shard at a time (see [`crate::ProcessingFsCacheBuilder::shards`]), so
+/// the more shards the less is read for each lookup. An unsharded cache is read in full on the
+/// first lookup. Shards which have been read stay in memory until the cache is dropped.
+///
+/// With the `mmap` feature, a shard which was saved with an index of its entries (see
+/// `ProcessingFsCacheBuilder::entry_index`) is instead mapped into memory, and only
+/// the entries which are looked up are decoded, so that a lookup reads little more than the
+/// entry itself however large the shard. Decoded entries stay in memory until the cache is dropped.
+pub struct LazyCache<T, C = BincodeCodec> {
+    shards: Vec<FileBackend<C>>,
+    loaded: Vec<OnceLock<Shard<T>>>,
+    version: ProcessorVersion,
+    invalidation_strategy: InvalidationStrategy,
+    key_normalizer: KeyNormalizer,
+    relative_root: Option<PathBuf>,
+}
+
+//A shard which has been opened, either by reading it in full or by mapping it into memory.
+enum Shard<T> {
+    Read(HashMap<PathBuf, MtimeCacheEntry<T>>),
+    #[cfg(feature = "mmap")]
+    Mapped(MappedShard<T>),
+}
+
+//The number of entries of a mapped shard whose decoded entries are allocated together.
+#[cfg(feature = "mmap")]
+const CHUNK_LEN: usize = 1024;
+
+#[cfg(feature = "mmap")]
+type DecodedChunk<T> = Box<[OnceLock<MtimeCacheEntry<T>>]>;
+
+#[cfg(feature = "mmap")]
+struct MappedShard<T> {
+    entries: MappedEntries,
+    //The entries decoded so far, by position in the index, in chunks which are only allocated
+    //once one of their entries is decoded, so that a shard of millions of entries costs little
+    //until they are looked up.
+    decoded: Vec<OnceLock<DecodedChunk<T>>>,
+}
+
+impl<T> Shard<T> {
+    fn decoded_entries(&self) -> usize {
+        match self {
+            Shard::Read(entries) => entries.len(),
+            #[cfg(feature = "mmap")]
+            Shard::Mapped(shard) => shard
+                .decoded
+                .iter()
+                .filter_map(|chunk| chunk.get())
+                .flat_map(|chunk| chunk.iter())
+                .filter(|entry| entry.get().is_some())
+                .count(),
+        }
+    }
+}
+
+impl<T, C> LazyCache<T, C>
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    C: Codec,
+{
+    pub(crate) fn new(
+        shards: Vec<FileBackend<C>>,
+        version: ProcessorVersion,
+        invalidation_strategy: InvalidationStrategy,
+        key_normalizer: KeyNormalizer,
+        relative_root: Option<PathBuf>,
+    ) -> Self {
+        let loaded = shards.iter().map(|_| OnceLock::new()).collect();
+        Self {
+            shards,
+            loaded,
+            version,
+            invalidation_strategy,
+            key_normalizer,
+            relative_root,
+        }
+    }
+
+    /// Returns the cached value for a path without checking whether the file has changed on
+    /// disk, or None if it is not cached. Fails only if the shard holding it cannot be read.
+    pub fn get(&self, key: &Path) -> FsCacheResult<Option<&T>> {
+        let key = self.key_normalizer.key(key);
+        Ok(self.entry(&key)?.map(|entry| entry.value.as_ref()))
+    }
+
+    /// As [`Self::get`], but also returns None if the configured [`InvalidationStrategy`]
+    /// considers the file to have changed since it was cached, or if it no longer exists.
+    pub fn get_fresh(&self, key: &Path) -> FsCacheResult<Option<&T>> {
+        let key = self.key_normalizer.key(key);
+        let entry = match self.entry(&key)? {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        match SourceMetadata::read(&key, self.invalidation_strategy) {
+            Ok(fs_source) if !source_changed(self.invalidation_strategy, &fs_source, &entry.source) => {
+                Ok(Some(&entry.value))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &Path) -> FsCacheResult<bool> {
+        let key = self.key_normalizer.key(key);
+        Ok(self.entry(&key)?.is_some())
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// How many shards have been read (or mapped) so far.
+    pub fn loaded_shards(&self) -> usize {
+        self.loaded.iter().filter(|shard| shard.get().is_some()).count()
+    }
+
+    /// How many entries have been decoded so far. Every entry of a shard which is read in full
+    /// is decoded at once.
+    pub fn decoded_entries(&self) -> usize {
+        self.loaded.iter().filter_map(|shard| shard.get()).map(Shard::decoded_entries).sum()
+    }
+
+    //Shards are read as they are stored, so keys must be looked up as they are stored too.
+    fn entry(&self, key: &Path) -> FsCacheResult<Option<&MtimeCacheEntry<T>>> {
+        let key = match &self.relative_root {
+            Some(root) => key.strip_prefix(root).unwrap_or(key),
+            None => key,
+        };
+        let n = (path_fingerprint(key) % self.shards.len() as u64) as usize;
+        match self.shard(n)? {
+            Shard::Read(entries) => Ok(entries.get(key)),
+            #[cfg(feature = "mmap")]
+            Shard::Mapped(shard) => self.mapped_entry(&self.shards[n], shard, key),
+        }
+    }
+
+    //Decodes the entry under `key`, unless it has been already. As with shards, threads which
+    //look up the same entry at once may each decode it, but only one copy is kept.
+    #[cfg(feature = "mmap")]
+    fn mapped_entry<'a>(
+        &self,
+        backend: &FileBackend<C>,
+        shard: &'a MappedShard<T>,
+        key: &Path,
+    ) -> FsCacheResult<Option<&'a MtimeCacheEntry<T>>> {
+        let (position, value) = match backend.find_mapped::<PathBuf>(&shard.entries, key)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let chunk =
+            shard.decoded[position / CHUNK_LEN].get_or_init(|| (0..CHUNK_LEN).map(|_| OnceLock::new()).collect());
+        let decoded = &chunk[position % CHUNK_LEN];
+        if decoded.get().is_none() {
+            let _ = decoded.set(backend.decode_mapped(value)?);
+        }
+        Ok(decoded.get())
+    }
+
+    //Returns the nth shard, opening it if it has not been opened yet. Threads which look up the
+    //same unopened shard at once may each open it, but only one copy is kept.
+    fn shard(&self, n: usize) -> FsCacheResult<&Shard<T>> {
+        if let Some(shard) = self.loaded[n].get() {
+            return Ok(shard);
+        }
+
+        let shard = self.open_shard(&self.shards[n])?;
+        let _ = self.loaded[n].set(shard);
+        match self.loaded[n].get() {
+            Some(shard) => Ok(shard),
+            None => unreachable!(),
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    fn open_shard(&self, backend: &FileBackend<C>) -> FsCacheResult<Shard<T>> {
+        let (entries, stored_version) = match backend.map_entries::<MtimeCacheEntry<T>>()? {
+            Some(mapped) => mapped,
+            None => return self.read_shard(backend),
+        };
+        if stored_version != self.version {
+            self.log_stale_shard(backend);
+            return Ok(Shard::Read(HashMap::new()));
+        }
+
+        let decoded = (0..entries.len().div_ceil(CHUNK_LEN)).map(|_| OnceLock::new()).collect();
+        Ok(Shard::Mapped(MappedShard { entries, decoded }))
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn open_shard(&self, backend: &FileBackend<C>) -> FsCacheResult<Shard<T>> {
+        self.read_shard(backend)
+    }
+
+    fn read_shard(&self, backend: &FileBackend<C>) -> FsCacheResult<Shard<T>> {
+        let mut entries = backend.read::<MtimeCacheEntry<T>>()?;
+        //Entries made by a different version of the processing are all stale.
+        if let Some(stored_version) = StorageBackend::<MtimeCacheEntry<T>>::stored_version(backend)? {
+            if stored_version != self.version {
+                self.log_stale_shard(backend);
+                entries.clear();
+            }
+        }
+        trace!(target: "generic_cache_startup",
+            "Loaded shard. Path: {}, Entries: {}", backend.cache_path().display(), entries.len()
+        );
+        Ok(Shard::Read(entries))
+    }
+
+    fn log_stale_shard(&self, backend: &FileBackend<C>) {
+        info!(target: "generic_cache_startup",
+            "{} was made by a different version of the processing, so none of its entries are used",
+            backend.cache_path().display()
+        );
+    }
+}
@@ -1,10 +1,91 @@
+mod access_times;
+mod aggregates;
+#[cfg(feature = "tokio")]
+mod async_processing_fs_cache;
+mod autosave;
 mod base_fs_cache;
+mod blobs;
+mod cache_entry;
 mod cache_interface;
+mod cache_key;
+pub mod codec;
+mod composite;
+mod dependencies;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod entry_index;
 pub mod errors;
+mod eviction;
+#[cfg(feature = "json")]
+mod export;
+mod failures;
+mod file_set;
+pub mod format;
+mod hard_links;
+mod interning;
+mod invalidation;
+mod journal;
+mod key_normalization;
+mod lazy_cache;
+mod lock;
+mod merge;
+mod namespaces;
+mod observer;
 mod processing_fs_cache;
-//mod file_set;
+mod progress;
+mod relative_backend;
+mod renames;
+#[cfg(feature = "rkyv")]
+mod rkyv_backend;
+pub mod save_strategy;
+mod sharded_backend;
+mod sharded_map;
+#[cfg(feature = "sled")]
+mod sled_backend;
+mod stats;
+mod storage;
+mod stored_path;
+mod tiered_cache;
+#[cfg(feature = "tool")]
+pub mod tool;
+mod update_report;
+mod value_index;
+#[cfg(feature = "watch")]
+mod watch;
 //Exports
+#[cfg(feature = "tokio")]
+pub use async_processing_fs_cache::AsyncProcessingFsCache;
+pub use base_fs_cache::BaseFsCache;
+#[cfg(feature = "rkyv")]
+pub use cache_entry::ArchivedMtimeCacheEntry;
+pub use cache_entry::{EntryMeta, MtimeCacheEntry};
+#[cfg(feature = "tokio")]
+pub use cache_interface::AsyncCacheInterface;
 pub use cache_interface::CacheInterface;
+pub use cache_key::CacheKey;
+pub use composite::{CompositeCache, SharedWalk, SharedWalkCache};
 pub use errors::FsCacheErrorKind;
-pub use processing_fs_cache::ProcessingFsCache;
-//pub use file_set::FileSet;
+pub use eviction::EvictionPolicy;
+pub use failures::{ProcessingFailure, RetryPolicy};
+pub use file_set::{Enumeration, FileSet, Preset, WalkError, WalkErrorPolicy};
+pub use invalidation::InvalidationStrategy;
+pub use key_normalization::PathNormalization;
+pub use lazy_cache::LazyCache;
+pub use lock::LockPolicy;
+pub use merge::{CacheDiff, MergeStrategy};
+pub use namespaces::{Namespace, NamespacedCache};
+pub use observer::CacheObserver;
+pub use processing_fs_cache::{ProcessingFsCache, ProcessingFsCacheBuilder};
+pub use progress::{Progress, UpdateProgress};
+#[cfg(feature = "rkyv")]
+pub use rkyv_backend::{ArchivedCache, RkyvBackend, RkyvValue};
+pub use save_strategy::SaveStrategy;
+pub use sharded_backend::ShardedFileBackend;
+#[cfg(feature = "sled")]
+pub use sled_backend::SledBackend;
+pub use stats::CacheStats;
+pub use storage::{CacheView, Durability, FileBackend, LoadLimits, RepairReport, StorageBackend};
+pub use tiered_cache::TieredCache;
+pub use update_report::{UpdatePlan, UpdateReport, VerifyReport};
+#[cfg(feature = "watch")]
+pub use watch::FsWatcher;
@@ -0,0 +1,109 @@
+use std::{
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+
+use crate::errors::{FsCacheErrorKind::*, FsCacheResult};
+
+/// What to do if another process is already using the cache file.
+///
+/// Locking is advisory, and only coordinates between processes which both use this crate
+/// with locking enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LockPolicy {
+    /// Do not lock the cache file.
+    #[default]
+    None,
+    /// Fail with [`crate::FsCacheErrorKind::CacheLocked`].
+    FailFast,
+    /// Block until the other process releases the cache file.
+    Wait,
+    /// Load the cache, but never write to it.
+    ReadOnly,
+}
+
+// An exclusive lock on a cache file, held from when the cache is loaded until it is dropped. The
+// lock is taken on a separate file, as the cache file itself is replaced on every save.
+#[derive(Debug)]
+pub(crate) struct CacheLock {
+    path: PathBuf,
+    policy: LockPolicy,
+    state: Mutex<LockState>,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    file: Option<File>,
+    read_only: bool,
+}
+
+impl CacheLock {
+    pub(crate) fn new(path: PathBuf, policy: LockPolicy) -> Self {
+        Self {
+            path,
+            policy,
+            state: Default::default(),
+        }
+    }
+
+    pub(crate) fn acquire(&self) -> FsCacheResult<()> {
+        let mut state = self.lock_state();
+        if self.policy == LockPolicy::None || state.file.is_some() || state.read_only {
+            return Ok(());
+        }
+
+        let io_err = |e| CacheFileIo {
+            src: e,
+            path: self.path.clone(),
+        };
+
+        if let Some(parent_dir) = self.path.parent() {
+            std::fs::create_dir_all(parent_dir).map_err(io_err)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)
+            .map_err(io_err)?;
+
+        if self.policy == LockPolicy::Wait {
+            file.lock().map_err(io_err)?;
+            state.file = Some(file);
+            return Ok(());
+        }
+
+        match file.try_lock() {
+            Ok(()) => state.file = Some(file),
+            Err(std::fs::TryLockError::WouldBlock) if self.policy == LockPolicy::ReadOnly => {
+                warn!(target: "generic_cache_startup",
+                    "{} is in use by another process. Opening read-only.", self.path.display()
+                );
+                state.read_only = true;
+            }
+            Err(std::fs::TryLockError::WouldBlock) => return Err(CacheLocked(self.path.clone())),
+            Err(std::fs::TryLockError::Error(e)) => return Err(io_err(e)),
+        }
+
+        Ok(())
+    }
+
+    /// Whether the cache could not be locked, so must not be written to.
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.lock_state().read_only
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, LockState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => unreachable!(),
+        }
+    }
+}
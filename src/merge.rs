@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    cache_entry::{legacy_file_migration, MtimeCacheEntry, ENTRY_FORMAT_VERSION},
+    errors::{FsCacheErrorKind::IncompatibleCacheFile, FsCacheResult},
+    failures::FailureLog,
+    format::ProcessorVersion,
+    storage::{FileBackend, StorageBackend},
+};
+
+/// Which entry to keep when merging another cache file into a cache which already holds an
+/// entry for the same path. Entries for paths only in the other file are always taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep whichever entry was processed from the most recently modified version of the
+    /// file, preferring the existing entry if they are the same.
+    PreferNewest,
+    /// Keep the existing entry.
+    PreferSelf,
+    /// Replace the existing entry with the other file's.
+    PreferOther,
+}
+
+impl MergeStrategy {
+    fn prefers_other<T>(&self, ours: &MtimeCacheEntry<T>, theirs: &MtimeCacheEntry<T>) -> bool {
+        match self {
+            Self::PreferNewest => theirs.source.mtime > ours.source.mtime,
+            Self::PreferSelf => false,
+            Self::PreferOther => true,
+        }
+    }
+}
+
+/// How two caches differ, as returned by `diff`. Each list is sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheDiff {
+    /// Paths cached only in the cache `diff` was called on.
+    pub only_in_self: Vec<PathBuf>,
+    /// Paths cached only in the other cache.
+    pub only_in_other: Vec<PathBuf>,
+    /// Paths cached in both, with different values.
+    pub changed: Vec<PathBuf>,
+}
+
+impl CacheDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_self.is_empty() && self.only_in_other.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for CacheDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} only here, {} only in other, {} changed",
+            self.only_in_self.len(),
+            self.only_in_other.len(),
+            self.changed.len()
+        )
+    }
+}
+
+//Compares values without cloning them, holding the read locks of both caches.
+pub(crate) fn diff<T, S1, S2>(
+    ours: &BaseFsCache<MtimeCacheEntry<T>, S1>,
+    theirs: &BaseFsCache<MtimeCacheEntry<T>, S2>,
+) -> CacheDiff
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + PartialEq + 'static,
+    S1: BuildHasher + Default + Send + Sync,
+    S2: BuildHasher + Default + Send + Sync,
+{
+    let mut diff = CacheDiff::default();
+    //the read lock cannot safely be taken twice over.
+    if std::ptr::addr_eq(ours, theirs) {
+        return diff;
+    }
+
+    ours.for_each(
+        |key, ours| match theirs.with_item(key, |theirs| ours.value == theirs.value) {
+            Some(true) => (),
+            Some(false) => diff.changed.push(key.to_path_buf()),
+            None => diff.only_in_self.push(key.to_path_buf()),
+        },
+    );
+    theirs.for_each(|key, _| {
+        if !ours.contains_key(key) {
+            diff.only_in_other.push(key.to_path_buf());
+        }
+    });
+
+    diff.only_in_self.sort_unstable();
+    diff.only_in_other.sort_unstable();
+    diff.changed.sort_unstable();
+    diff
+}
+
+/// Insert the entries of the cache file at `path` which `strategy` chooses over those already
+/// in `base_cache`, returning how many were taken. The other file is left untouched, and must
+/// have been written by the same version of the processing.
+pub(crate) fn merge_from<T, S>(
+    base_cache: &BaseFsCache<MtimeCacheEntry<T>, S>,
+    failures: &FailureLog,
+    path: &Path,
+    version: ProcessorVersion,
+    strategy: MergeStrategy,
+    relative_root: Option<&Path>,
+) -> FsCacheResult<usize>
+where
+    T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+    S: BuildHasher + Default + Send + Sync,
+{
+    let other = FileBackend::new(path.to_path_buf())
+        .with_migration(legacy_file_migration::<T>(None))
+        .with_value_format_version(ENTRY_FORMAT_VERSION);
+    let entries = other.read::<MtimeCacheEntry<T>>()?;
+
+    //the file's entries would otherwise be treated as up to date when they are not.
+    let other_version = StorageBackend::<MtimeCacheEntry<T>>::stored_version(&other)?.unwrap_or_default();
+    if other_version != version {
+        return Err(IncompatibleCacheFile {
+            src: format!(
+                "the file was written by a different version of the processing ({:?}, expected {:?})",
+                other_version, version
+            ),
+            path: path.to_path_buf(),
+        });
+    }
+
+    //the other file is taken to have been stored relative to a root in the same way as this cache.
+    let taken: Vec<(PathBuf, MtimeCacheEntry<T>)> = entries
+        .into_iter()
+        .map(|(key, entry)| match relative_root {
+            Some(root) => (root.join(key), entry),
+            None => (key, entry),
+        })
+        .filter(|(key, theirs)| {
+            base_cache
+                .with_item(key, |ours| strategy.prefers_other(ours, theirs))
+                .unwrap_or(true)
+        })
+        .collect();
+    for (key, _) in &taken {
+        failures.forget(key)?;
+    }
+
+    let merged = taken.len();
+    base_cache.insert_batch(taken)?;
+    info!(target: "generic_cache_update", "Merged {} entries from {}", merged, path.display());
+    Ok(merged)
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeSet},
+    fmt,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    cache_key::CacheKey,
+    codec::Codec,
+    errors::{FsCacheErrorKind::*, FsCacheResult},
+    format::type_fingerprint,
+    invalidation::{update_action, InvalidationStrategy, SourceMetadata, UpdateAction},
+    save_strategy::SaveStrategy,
+    storage::FileBackend,
+    stored_path::{LoadedPath, StoredPath},
+};
+
+//Every namespace is kept in one map, so that they are saved to one file, as one save strategy
+//decides. Values are stored encoded with bincode, as each namespace holds a different type.
+type NamespacedMap = BaseFsCache<Vec<u8>, RandomState, NamespacedKey>;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(crate) enum NamespacedKey {
+    //The fingerprint of the type of value a namespace holds, so that it is not read as another.
+    Type(String),
+    Entry(String, PathBuf),
+}
+
+impl NamespacedKey {
+    fn namespace(&self) -> &str {
+        match self {
+            Self::Type(namespace) | Self::Entry(namespace, _) => namespace,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename = "NamespacedKey")]
+enum StoredNamespacedKey<'a> {
+    Type(&'a str),
+    Entry(&'a str, StoredPath<'a>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "NamespacedKey")]
+enum LoadedNamespacedKey {
+    Type(String),
+    Entry(String, LoadedPath),
+}
+
+impl CacheKey for NamespacedKey {
+    type Ref = Self;
+
+    fn serialize_key<Se: Serializer>(key: &Self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        match key {
+            Self::Type(namespace) => StoredNamespacedKey::Type(namespace),
+            Self::Entry(namespace, path) => StoredNamespacedKey::Entry(namespace, StoredPath(path)),
+        }
+        .serialize(serializer)
+    }
+
+    fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match LoadedNamespacedKey::deserialize(deserializer)? {
+            LoadedNamespacedKey::Type(namespace) => Self::Type(namespace),
+            LoadedNamespacedKey::Entry(namespace, LoadedPath(path)) => Self::Entry(namespace, path),
+        })
+    }
+
+    fn fmt_key(key: &Self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match key {
+            Self::Type(namespace) => write!(f, "the type of {}", namespace),
+            Self::Entry(namespace, path) => write!(f, "{} in {}", path.display(), namespace),
+        }
+    }
+
+    fn key_len(key: &Self) -> usize {
+        match key {
+            Self::Type(namespace) => namespace.len(),
+            Self::Entry(_, path) => path.as_os_str().len(),
+        }
+    }
+}
+
+//A value along with the state of the file it was derived from, so that it is only used while
+//the file is unchanged.
+#[derive(Serialize, Deserialize)]
+struct NamespacedEntry<V> {
+    source: SourceMetadata,
+    value: V,
+}
+
+/// A single cache file holding several namespaces, each of which maps paths to a different type
+/// of value, such as the thumbnails, hashes and durations of the same files. Every namespace
+/// is saved together, as one save strategy decides.
+///
+/// Like a [`crate::ProcessingFsCache`], a value is only returned while the file it was cached
+/// for is unchanged, but nothing is processed by the cache itself: values are inserted with
+/// [`Namespace::insert`] or [`Namespace::get_or_insert_with`].
+pub struct NamespacedCache {
+    base_cache: Arc<NamespacedMap>,
+    cache_path: PathBuf,
+    invalidation_strategy: InvalidationStrategy,
+}
+
+impl NamespacedCache {
+    /// `save_strategy` decides when the cache is automatically saved. Passing a `u32` saves after
+    /// that many modifications, made to any namespace.
+    pub fn new(save_strategy: impl SaveStrategy + 'static, cache_path: PathBuf) -> FsCacheResult<Self> {
+        Self::with_file_backend(save_strategy, FileBackend::new(cache_path))
+    }
+
+    /// As [`Self::new`], but stored by `backend`, such as to choose its codec or use a journal.
+    pub fn with_file_backend<C>(
+        save_strategy: impl SaveStrategy + 'static,
+        backend: FileBackend<C>,
+    ) -> FsCacheResult<Self>
+    where
+        C: Codec + 'static,
+    {
+        let cache_path = backend.cache_path().to_path_buf();
+        let base_cache = BaseFsCache::with_backend(Arc::new(save_strategy), Box::new(backend))?;
+        Ok(Self {
+            base_cache: Arc::new(base_cache),
+            cache_path,
+            invalidation_strategy: Default::default(),
+        })
+    }
+
+    /// How every namespace decides whether a file has changed since its value was cached.
+    pub fn with_invalidation_strategy(mut self, invalidation_strategy: InvalidationStrategy) -> Self {
+        self.invalidation_strategy = invalidation_strategy;
+        self
+    }
+
+    /// The namespace called `name`, which is created if it does not exist yet. Fails if the
+    /// namespace already holds another type of value.
+    pub fn namespace<T>(&self, name: &str) -> FsCacheResult<Namespace<T>>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let key = NamespacedKey::Type(name.to_string());
+        let fingerprint = type_fingerprint::<T>().to_le_bytes().to_vec();
+        match self.base_cache.get(&key) {
+            Some(stored) if stored != fingerprint => {
+                return Err(IncompatibleCacheFile {
+                    src: format!("the namespace {} holds a different value type", name),
+                    path: self.cache_path.clone(),
+                })
+            }
+            Some(_) => {}
+            None => self.base_cache.insert(key, fingerprint)?,
+        }
+
+        Ok(Namespace {
+            base_cache: self.base_cache.clone(),
+            name: name.to_string(),
+            cache_path: self.cache_path.clone(),
+            invalidation_strategy: self.invalidation_strategy,
+            value: PhantomData,
+        })
+    }
+
+    /// The names of every namespace, in order.
+    pub fn namespaces(&self) -> Vec<String> {
+        let mut ret = BTreeSet::new();
+        self.base_cache.for_each(|key, _| {
+            if let NamespacedKey::Type(namespace) = key {
+                ret.insert(namespace.clone());
+            }
+        });
+        ret.into_iter().collect()
+    }
+
+    /// Remove a namespace and all of its entries, so that its name can hold another type of
+    /// value. Returns the number of entries removed.
+    pub fn remove_namespace(&self, name: &str) -> FsCacheResult<usize> {
+        let mut entries = 0;
+        self.base_cache.retain(|key, _| match key.namespace() == name {
+            true => {
+                entries += matches!(key, NamespacedKey::Entry(..)) as usize;
+                false
+            }
+            false => true,
+        })?;
+        Ok(entries)
+    }
+
+    /// Save any changes which have not been saved yet. Does nothing if there are none.
+    pub fn save(&self) -> FsCacheResult<()> {
+        self.base_cache.save()
+    }
+
+    /// Save whether or not there are unsaved changes.
+    pub fn flush(&self) -> FsCacheResult<()> {
+        self.base_cache.flush()
+    }
+
+    /// Whether any namespace has changes which have not been saved.
+    pub fn is_dirty(&self) -> bool {
+        self.base_cache.is_dirty()
+    }
+}
+
+/// The values of one type kept in a [`NamespacedCache`], as returned by
+/// [`NamespacedCache::namespace`]. Cloning is cheap, and clones share the same cache.
+pub struct Namespace<T> {
+    base_cache: Arc<NamespacedMap>,
+    name: String,
+    cache_path: PathBuf,
+    invalidation_strategy: InvalidationStrategy,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Namespace<T> {
+    fn clone(&self) -> Self {
+        Self {
+            base_cache: self.base_cache.clone(),
+            name: self.name.clone(),
+            cache_path: self.cache_path.clone(),
+            invalidation_strategy: self.invalidation_strategy,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T> Namespace<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The value cached for a path, or None if there is none or the file has changed since it
+    /// was cached.
+    pub fn get(&self, path: impl AsRef<Path>) -> FsCacheResult<Option<T>> {
+        let path = path.as_ref();
+        let stored = match self.base_cache.get(&self.key(path)) {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+        let entry: NamespacedEntry<T> = bincode::deserialize(&stored).map_err(|e| Deserialization {
+            src: format!("{}", e),
+            path: self.cache_path.clone(),
+        })?;
+
+        let fs_source = SourceMetadata::read_with_metadata(path, self.invalidation_strategy);
+        match update_action(path, self.invalidation_strategy, fs_source, Some(entry.source))? {
+            UpdateAction::NoChange => Ok(Some(entry.value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Cache a value for a path, along with the current state of its file, which must exist.
+    pub fn insert(&self, path: impl AsRef<Path>, value: T) -> FsCacheResult<()> {
+        self.insert_ref(path.as_ref(), &value)
+    }
+
+    /// The value cached for a path, or otherwise the value `f` derives from the file, which is
+    /// then cached.
+    pub fn get_or_insert_with(&self, path: impl AsRef<Path>, f: impl FnOnce(&Path) -> T) -> FsCacheResult<T> {
+        let path = path.as_ref();
+        if let Some(value) = self.get(path)? {
+            return Ok(value);
+        }
+        let value = f(path);
+        self.insert_ref(path, &value)?;
+        Ok(value)
+    }
+
+    pub fn remove(&self, path: impl AsRef<Path>) -> FsCacheResult<()> {
+        self.base_cache.remove(&self.key(path.as_ref())).map(|_| ())
+    }
+
+    /// Whether a value is cached for a path, whether or not its file has changed since.
+    pub fn contains_key(&self, path: impl AsRef<Path>) -> bool {
+        self.base_cache.contains_key(&self.key(path.as_ref()))
+    }
+
+    /// The paths with a value cached in this namespace. Every namespace is looked through.
+    pub fn keys(&self) -> Vec<PathBuf> {
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, _| match key {
+            NamespacedKey::Entry(namespace, path) if *namespace == self.name => ret.push(path.clone()),
+            _ => {}
+        });
+        ret
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove every entry of this namespace, leaving the others alone.
+    pub fn clear(&self) -> FsCacheResult<()> {
+        self.base_cache
+            .retain(|key, _| !matches!(key, NamespacedKey::Entry(namespace, _) if *namespace == self.name))
+            .map(|_| ())
+    }
+
+    fn insert_ref(&self, path: &Path, value: &T) -> FsCacheResult<()> {
+        let source = SourceMetadata::read(path, self.invalidation_strategy).map_err(|e| CacheFileIo {
+            src: e,
+            path: path.to_path_buf(),
+        })?;
+        let stored = bincode::serialize(&NamespacedEntry { source, value }).map_err(|e| Serialization {
+            src: format!("{}", e),
+            path: self.cache_path.clone(),
+        })?;
+        self.base_cache.insert(self.key(path), stored)
+    }
+
+    fn key(&self, path: &Path) -> NamespacedKey {
+        NamespacedKey::Entry(self.name.clone(), path.to_path_buf())
+    }
+}
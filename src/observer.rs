@@ -0,0 +1,20 @@
+use std::path::Path;
+
+/// Callbacks for changes to a cache, so that applications can mirror them elsewhere.
+///
+/// Insert and remove callbacks are made while the cache is locked, so they must not call
+/// back into the cache. `K` is the borrowed form of the keys, which are paths for every cache
+/// but a [`crate::BaseFsCache`] made with another [`crate::CacheKey`].
+pub trait CacheObserver<T, K: ?Sized = Path>: Send + Sync {
+    /// A value was inserted or replaced.
+    fn on_insert(&self, _path: &K, _value: &T) {}
+
+    /// An entry was removed. This may be called for paths which were not cached.
+    fn on_remove(&self, _path: &K) {}
+
+    /// The cache was saved.
+    fn on_save(&self) {}
+
+    /// The cache was loaded from disk, with this many entries.
+    fn on_load(&self, _entries: usize) {}
+}
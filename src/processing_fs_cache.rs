@@ -1,109 +1,2040 @@
 use std::{
-    borrow::Borrow,
-    fs,
+    borrow::{Borrow, Cow},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fs::Metadata,
+    hash::{BuildHasher, Hash},
     path::{Path, PathBuf},
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use serde::{Deserialize, Serialize};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
 use FsCacheErrorKind::*;
 
 use super::{
     base_fs_cache::BaseFsCache,
     errors::{FsCacheErrorKind, FsCacheResult},
 };
-use crate::cache_interface::CacheInterface;
+use crate::{
+    access_times::AccessLog,
+    aggregates::{AggregatingObserver, DirectoryAggregates},
+    autosave::Autosave,
+    blobs::{blob_file_migration, BlobBackend, BlobEntry},
+    cache_entry::{is_expired, legacy_file_migration, EntryMeta, MtimeCacheEntry, TimeToLiveFn, ENTRY_FORMAT_VERSION},
+    cache_interface::CacheInterface,
+    composite::{SharedWalk, SharedWalkCache, WalkedMetadata},
+    dependencies::{DependencyGraph, DependencyObserver},
+    eviction::{EvictionPolicy, Recency, RecencyObserver},
+    failures::{FailureLog, ProcessingFailure, RetryPolicy},
+    file_set::{Enumeration, FileSet, WalkError},
+    format::{FileHeader, MigrationFn, ProcessorVersion},
+    hard_links::linked_groups,
+    interning::{interning_file_migration, Intern, InternedEntry, Interner, InterningBackend},
+    invalidation::{source_changed, update_action, InvalidationStrategy, SourceMetadata, UpdateAction},
+    key_normalization::{KeyNormalizer, PathNormalization},
+    lazy_cache::LazyCache,
+    lock::LockPolicy,
+    merge::{CacheDiff, MergeStrategy},
+    observer::CacheObserver,
+    progress::{Progress, UpdateProgress},
+    relative_backend::RelativeBackend,
+    renames::find_renames,
+    save_strategy::SaveStrategy,
+    sharded_backend::ShardedFileBackend,
+    stats::{CacheStats, StatsCounters},
+    storage::{Durability, FileBackend, LoadLimits, StorageBackend},
+    tiered_cache::TieredCache,
+    update_report::{UpdateOutcome, UpdatePlan, UpdateReport, VerifyReport},
+    value_index::{IndexingObserver, ReverseIndex, ValueIndex},
+};
+#[cfg(feature = "watch")]
+use {crate::watch::FsWatcher, std::sync::atomic::AtomicBool};
 
-/// How a file on disk may have changed since the last time the cache was updated
-enum UpdateAction {
-    NoChange,
-    Update(SystemTime),
-    Remove,
-}
+//The most processed files each worker holds on to during an update before inserting them.
+const INSERT_BATCH_SIZE: usize = 32;
 
-#[derive(Serialize, Deserialize, Clone)]
-struct MtimeCacheEntry<T> {
-    cache_mtime: SystemTime,
-    value: T,
+pub struct ProcessingFsCache<I, S = RandomState>
+where
+    I: CacheInterface,
+    S: BuildHasher + Default + Send + Sync,
+{
+    //Declared first so that the autosave thread is stopped before the cache is dropped.
+    _autosave: Option<Autosave>,
+    base_cache: Arc<BaseFsCache<MtimeCacheEntry<I::T>, S>>,
+    interface: I,
+    thread_pool: Option<rayon::ThreadPool>,
+    invalidation_strategy: InvalidationStrategy,
+    key_normalizer: KeyNormalizer,
+    relative_root: Option<PathBuf>,
+    failures: FailureLog,
+    retry_policy: RetryPolicy,
+    time_to_live: Option<TimeToLiveFn<I::T>>,
+    detect_renames: bool,
+    deduplicate_hard_links: bool,
+    interner: Option<Arc<dyn Intern<I::T>>>,
+    value_index: Option<Arc<dyn ValueIndex<I::T>>>,
+    aggregates: Option<Arc<DirectoryAggregates<I::T>>>,
+    dependencies: Option<Arc<DependencyGraph<I::T>>>,
+    lru_capacity: Option<usize>,
+    recency: Option<Arc<Recency>>,
+    access_log: Option<AccessLog>,
+    stats: StatsCounters,
 }
 
-pub struct ProcessingFsCache<I>
+/// Builder for a [`ProcessingFsCache`], for when the defaults chosen by
+/// [`ProcessingFsCache::new`] are not suitable.
+pub struct ProcessingFsCacheBuilder<I, S = RandomState>
 where
     I: CacheInterface,
 {
-    base_cache: BaseFsCache<MtimeCacheEntry<I::T>>,
+    save_strategy: Arc<dyn SaveStrategy>,
+    file_backend: FileBackend,
+    migration: Option<Arc<MigrationFn>>,
     interface: I,
+    worker_threads: Option<usize>,
+    invalidation_strategy: InvalidationStrategy,
+    key_normalizer: KeyNormalizer,
+    relative_root: Option<PathBuf>,
+    backend: Option<Box<dyn StorageBackend<MtimeCacheEntry<I::T>, S>>>,
+    shard_count: Option<usize>,
+    save_on_drop: bool,
+    save_from_snapshot: bool,
+    autosave_interval: Option<Duration>,
+    observer: Option<Box<dyn CacheObserver<I::T>>>,
+    stale_on_version_change: Option<StaleFn<I::T>>,
+    retry_policy: RetryPolicy,
+    time_to_live: Option<TimeToLiveFn<I::T>>,
+    detect_renames: bool,
+    deduplicate_hard_links: bool,
+    interner: Option<Arc<dyn Intern<I::T>>>,
+    value_index: Option<Arc<dyn ValueIndex<I::T>>>,
+    aggregates: Option<Arc<DirectoryAggregates<I::T>>>,
+    dependencies: Option<Arc<DependencyGraph<I::T>>>,
+    lru_capacity: Option<usize>,
+    max_disk_size: Option<(u64, EvictionPolicy)>,
+    track_access_times: bool,
+    memory_budget: Option<usize>,
+    blob_threshold: Option<u64>,
 }
 
-impl<I> ProcessingFsCache<I>
+//What to do if the cache file exists but cannot be read.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OnUnreadable {
+    Fail,
+    RestoreBackup,
+    Repair,
+}
+
+//The entries removed by `invalidate`, `invalidate_dir` or `remove_outside`, with the key, directory
+//or file set already normalized.
+enum Invalidation<'a> {
+    Key(&'a Path),
+    Dir(&'a Path),
+    Outside(&'a FileSet),
+}
+
+//Decides which entries made by an older version of the processing are stale.
+type StaleFn<T> = Box<dyn Fn(u32, &Path, &T) -> bool>;
+
+//What bringing a file up to date did, and the entry to cache for it if it was processed.
+type EntryUpdate<T> = FsCacheResult<(UpdateOutcome, Option<MtimeCacheEntry<T>>)>;
+
+//Receives the outcome of bringing each file up to date, from any of the workers.
+type RecordFn<'a> = &'a (dyn Fn(Vec<(PathBuf, FsCacheResult<UpdateOutcome>)>) + Sync);
+
+impl<I> ProcessingFsCacheBuilder<I>
 where
     I: CacheInterface + Send + Sync,
+    I::T: 'static,
 {
-    pub fn new(cache_save_threshold: u32, cache_path: PathBuf, interface: I) -> FsCacheResult<Self> {
-        match BaseFsCache::new(cache_save_threshold, cache_path) {
-            Ok(base_cache) => Ok(Self { base_cache, interface }),
-            Err(e) => Err(e),
+    /// `save_strategy` decides when the cache is automatically saved. Passing a `u32` saves after
+    /// that many modifications.
+    pub fn new(save_strategy: impl SaveStrategy + 'static, cache_path: PathBuf, interface: I) -> Self {
+        Self::with_hasher(save_strategy, cache_path, interface)
+    }
+}
+
+impl<I, S> ProcessingFsCacheBuilder<I, S>
+where
+    I: CacheInterface + Send + Sync,
+    I::T: 'static,
+    S: BuildHasher + Default + Send + Sync + 'static,
+{
+    /// As [`Self::new`], but the cache is held in a map using the hasher `S`, such as a faster
+    /// hasher than std's default, which is resistant to collisions but slow to hash paths with.
+    /// The hasher is chosen by naming the type, as in
+    /// `ProcessingFsCacheBuilder::<_, FxBuildHasher>::with_hasher(...)`.
+    pub fn with_hasher(save_strategy: impl SaveStrategy + 'static, cache_path: PathBuf, interface: I) -> Self {
+        Self {
+            save_strategy: Arc::new(save_strategy),
+            file_backend: FileBackend::new(cache_path),
+            migration: None,
+            interface,
+            worker_threads: None,
+            invalidation_strategy: Default::default(),
+            key_normalizer: Default::default(),
+            relative_root: None,
+            backend: None,
+            shard_count: None,
+            save_on_drop: true,
+            save_from_snapshot: true,
+            autosave_interval: None,
+            observer: None,
+            stale_on_version_change: None,
+            retry_policy: Default::default(),
+            time_to_live: None,
+            detect_renames: false,
+            deduplicate_hard_links: false,
+            interner: None,
+            value_index: None,
+            aggregates: None,
+            dependencies: None,
+            lru_capacity: None,
+            max_disk_size: None,
+            track_access_times: false,
+            memory_budget: None,
+            blob_threshold: None,
+        }
+    }
+
+    /// The number of threads used to process files during [`ProcessingFsCache::update_from_fs`].
+    /// If not set, rayon's global thread pool is used.
+    pub fn worker_threads(mut self, worker_threads: usize) -> Self {
+        self.worker_threads = Some(worker_threads);
+        self
+    }
+
+    /// How to decide whether a cached file has changed. Defaults to [`InvalidationStrategy::MtimeAndSize`].
+    pub fn invalidation_strategy(mut self, invalidation_strategy: InvalidationStrategy) -> Self {
+        self.invalidation_strategy = invalidation_strategy;
+        self
+    }
+
+    /// How paths are rewritten before being used as keys, so that different spellings of the
+    /// same path share one entry. Every path given to the cache is rewritten, including those
+    /// of file sets, and keys are returned as rewritten. Defaults to [`PathNormalization::None`].
+    /// Entries cached before this was changed keep the keys they were cached with, so
+    /// [`ProcessingFsCache::clear`] the cache if a different normalization is chosen for it.
+    pub fn path_normalization(mut self, path_normalization: PathNormalization) -> Self {
+        self.key_normalizer.paths = path_normalization;
+        self
+    }
+
+    /// Rewrite every path with `f` before using it as a key, after any [`Self::path_normalization`],
+    /// so that paths which are spelled differently but name the same file share one entry. For
+    /// example, macOS gives file names in unicode normalization form D while typed paths are
+    /// usually in form C, which `f` can convert to the same form using a crate such as
+    /// `unicode-normalization`. Files are opened by their rewritten paths, so `f` must not
+    /// change which file a path names. As with [`Self::path_normalization`], entries cached
+    /// before this was changed keep the keys they were cached with.
+    pub fn key_rewrite(mut self, f: impl Fn(&Path) -> PathBuf + Send + Sync + 'static) -> Self {
+        self.key_normalizer.rewrite = Some(Arc::new(f));
+        self
+    }
+
+    /// Lowercase every path before using it as a key, after any [`Self::path_normalization`]
+    /// and [`Self::key_rewrite`], so that `Foo.txt` and `foo.txt` share one entry, as they are the same file on Windows
+    /// and on macOS by default. Keys are returned lowercased, and files are opened by their
+    /// lowercased paths, so this must only be used for files on case-insensitive filesystems.
+    /// Disabled by default. As with [`Self::path_normalization`], entries cached before this
+    /// was changed keep the keys they were cached with.
+    pub fn case_insensitive_keys(mut self, case_insensitive: bool) -> Self {
+        self.key_normalizer.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Store the paths of files beneath `root` relative to it, so that the cache can be moved
+    /// along with the files, such as when a tree built at `/mnt/data` is later mounted at
+    /// `/srv/data`: open the cache with the root it now has, and every entry is found under it.
+    /// Paths outside `root` are stored as they are, so should be absolute. Applies to any
+    /// [`Self::backend`], and to the recorded failures.
+    pub fn relative_to(mut self, root: impl Into<PathBuf>) -> Self {
+        self.relative_root = Some(root.into());
+        self
+    }
+
+    /// Keep this many previous versions of the cache file. See [`FileBackend::with_backups`].
+    pub fn backups(mut self, backup_count: usize) -> Self {
+        self.file_backend = self.file_backend.with_backups(backup_count);
+        self
+    }
+
+    /// How sure saving is that the cache file has reached the disk. See [`Durability`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.file_backend = self.file_backend.with_durability(durability);
+        self
+    }
+
+    /// Refuse to load cache files which are too large. See [`FileBackend::with_load_limits`].
+    pub fn load_limits(mut self, limits: LoadLimits) -> Self {
+        self.file_backend = self.file_backend.with_load_limits(limits);
+        self
+    }
+
+    /// Split the cache file into `shard_count` files, so that saving only rewrites those holding
+    /// changed entries, and the files are read and written on several threads at once rather
+    /// than one, which makes loading a large cache many times faster. See [`ShardedFileBackend`]. Journals are not used with shards, and
+    /// [`Self::build_or_restore_backup`] and [`Self::build_or_repair`] cannot recover them.
+    pub fn shards(mut self, shard_count: usize) -> Self {
+        self.shard_count = Some(shard_count);
+        self
+    }
+
+    /// Journal every change, only rewriting the cache file after `compact_after` changes.
+    /// See [`FileBackend::with_journal`].
+    pub fn journal(mut self, compact_after: usize) -> Self {
+        self.file_backend = self.file_backend.with_journal(compact_after);
+        self
+    }
+
+    /// Only write changed entries when saving, merging them into the cache file after
+    /// `compact_after` changes. See [`FileBackend::with_delta_saves`].
+    pub fn delta_saves(mut self, compact_after: usize) -> Self {
+        self.file_backend = self.file_backend.with_delta_saves(compact_after);
+        self
+    }
+
+    /// Lock the cache file against use by other processes. See [`FileBackend::with_lock_policy`].
+    pub fn lock_policy(mut self, policy: LockPolicy) -> Self {
+        self.file_backend = self.file_backend.with_lock_policy(policy);
+        self
+    }
+
+    /// Supply a hook to upgrade old or incompatible cache files. See [`FileBackend::with_migration`].
+    pub fn migration(
+        mut self,
+        migration: impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.migration = Some(Arc::new(migration));
+        self
+    }
+
+    /// Encrypt the cache file with the given 256-bit key. See [`FileBackend::with_encryption_key`].
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.file_backend = self.file_backend.with_encryption_key(key);
+        self
+    }
+
+    /// Write an index of the entries of the cache file (or of each shard) beside it, so that
+    /// [`Self::build_lazy`] decodes only the entries it looks up. See [`FileBackend::with_entry_index`].
+    #[cfg(feature = "mmap")]
+    pub fn entry_index(mut self, entry_index: bool) -> Self {
+        self.file_backend = self.file_backend.with_entry_index(entry_index);
+        self
+    }
+
+    /// Whether unsaved changes are saved when the cache is dropped. Enabled by default. If
+    /// disabled, any changes since the save strategy last triggered are lost unless
+    /// [`ProcessingFsCache::save`] is called.
+    pub fn save_on_drop(mut self, save_on_drop: bool) -> Self {
+        self.save_on_drop = save_on_drop;
+        self
+    }
+
+    /// Save from a snapshot of the cache taken when saving starts, so that the cache can be
+    /// modified while the file is written, rather than modifications waiting until it has been.
+    /// Only the parts of the cache modified meanwhile are copied, sharing their values with the
+    /// snapshot. See [`BaseFsCache::set_save_from_snapshot`]. Enabled by default.
+    pub fn save_from_snapshot(mut self, save_from_snapshot: bool) -> Self {
+        self.save_from_snapshot = save_from_snapshot;
+        self
+    }
+
+    /// Save the cache from a background thread every `interval` if it has unsaved changes,
+    /// regardless of the save strategy.
+    pub fn autosave_interval(mut self, interval: Duration) -> Self {
+        self.autosave_interval = Some(interval);
+        self
+    }
+
+    /// Receive callbacks whenever the cache is loaded, saved or modified.
+    pub fn observer(mut self, observer: impl CacheObserver<I::T> + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Choose which entries are stale when [`CacheInterface::version`] differs from the version
+    /// the cache was saved with. `f` is given the old version, and stale entries are removed
+    /// so they are processed again. By default, every entry is stale after a change of version.
+    /// A change of [`CacheInterface::config_fingerprint`] always makes every entry stale.
+    pub fn stale_on_version_change(mut self, f: impl Fn(u32, &Path, &I::T) -> bool + 'static) -> Self {
+        self.stale_on_version_change = Some(Box::new(f));
+        self
+    }
+
+    /// When to retry files which failed to process (see [`CacheInterface::try_load_with_metadata`]).
+    /// Unless the policy is [`RetryPolicy::Always`] (the default), failures are recorded in a
+    /// file beside the cache file, and unchanged files are not retried until the policy allows.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Treat every entry as stale once it has been cached for longer than `time_to_live`,
+    /// such as when values are looked up from somewhere which changes independently of the
+    /// file. Expired entries are reprocessed by [`ProcessingFsCache::update_from_fs`] and
+    /// [`ProcessingFsCache::fetch_update`], and can be removed with [`ProcessingFsCache::prune_expired`].
+    pub fn time_to_live(self, time_to_live: Duration) -> Self {
+        self.entry_time_to_live(move |_, _| Some(time_to_live))
+    }
+
+    /// Like [`Self::time_to_live`], choosing how long each entry stays fresh from its path and
+    /// value. Entries for which `f` returns None never expire.
+    pub fn entry_time_to_live(mut self, f: impl Fn(&Path, &I::T) -> Option<Duration> + Send + Sync + 'static) -> Self {
+        self.time_to_live = Some(Arc::new(f));
+        self
+    }
+
+    /// Recognise files which have been renamed or moved within the file set since they were
+    /// cached, and move their entries to the new path in [`ProcessingFsCache::update_from_fs`]
+    /// rather than processing them again. The number moved is reported as
+    /// [`UpdateReport::migrated`]. A file is recognised by its device and inode on unix, or
+    /// otherwise by its content hash if the invalidation strategy hashes content, or else by
+    /// its size and mtime, and only if it has not otherwise changed. A size and mtime shared by
+    /// several files does not identify any of them. Only enable this if values do not depend on
+    /// the path of their file. Disabled by default.
+    pub fn detect_renames(mut self, detect_renames: bool) -> Self {
+        self.detect_renames = detect_renames;
+        self
+    }
+
+    /// Process a file with several hard links within the file set only once per update, and
+    /// cache its value for every linked path, rather than processing each link separately. A
+    /// link which needs updating also shares the value cached for another link to the file if
+    /// that has not changed. Links are recognised by their device and inode, so only on unix,
+    /// and recognising them costs an extra lookup of each file. Only enable this if values do
+    /// not depend on the path of their file. Disabled by default.
+    pub fn deduplicate_hard_links(mut self, deduplicate_hard_links: bool) -> Self {
+        self.deduplicate_hard_links = deduplicate_hard_links;
+        self
+    }
+
+    /// Hold each distinct value only once, both in memory and in the cache file, with every
+    /// entry which has that value referring to the one copy. Worthwhile when many files have
+    /// equal values, such as checksums of a tree holding many copies of the same files.
+    ///
+    /// Every save then rewrites the whole cache file, or every shard, rather than only the
+    /// changed entries. An existing cache file is converted when interning is turned on or off.
+    /// With a [`Self::backend`], values are only shared in memory. A cache whose values are
+    /// interned cannot be opened with [`Self::build_lazy`] or [`Self::build_tiered`]. Disabled by default.
+    pub fn intern_values(mut self, intern_values: bool) -> Self
+    where
+        I::T: Eq + Hash,
+    {
+        self.interner = match intern_values {
+            true => Some(Arc::new(Interner::<I::T>::default())),
+            false => None,
+        };
+        self
+    }
+
+    /// Store each value which takes more than `min_bytes` to encode in a file of its own, in a
+    /// directory named `<cache_path>.blobs`, with the cache file only referring to it. A blob
+    /// is named after a hash of its contents, so is only written when a value changes, and
+    /// saving no longer rewrites every large value along with the rest of the cache. Blobs no
+    /// longer referred to are deleted after each save. Worthwhile when a few values are much
+    /// larger than the rest.
+    ///
+    /// Blob files are written with bincode, whatever the codec, and are not encrypted. An
+    /// existing cache file is converted when blobs are turned on, but a cache whose values are in
+    /// blobs can only be opened with them, so not with [`Self::build_lazy`] or [`Self::build_tiered`].
+    /// Ignored if values are interned (see [`Self::intern_values`]) or with a [`Self::backend`].
+    /// Disabled by default.
+    pub fn blob_threshold(mut self, min_bytes: u64) -> Self {
+        self.blob_threshold = Some(min_bytes);
+        self
+    }
+
+    /// Index entries by their value, so that [`ProcessingFsCache::paths_with_value`] finds the
+    /// paths with a value without looking at every entry, such as to find the other copies of a
+    /// file when values are checksums. The index is built when the cache is opened and kept up
+    /// to date with every change, and costs memory for a second copy of every path. Disabled by
+    /// default.
+    pub fn index_values(mut self, index_values: bool) -> Self
+    where
+        I::T: Eq + Hash,
+    {
+        self.value_index = match index_values {
+            true => Some(Arc::new(ReverseIndex::<I::T>::default())),
+            false => None,
+        };
+        self
+    }
+
+    /// Give every directory holding a cached file a value, folded from the values of the files
+    /// beneath it with `fold`, such as their total size, as returned by
+    /// [`ProcessingFsCache::directory_value`]. As the files are folded in no particular order,
+    /// `fold` should give the same result whichever way round it is called. Directory values
+    /// are kept in memory only: they are folded when the cache is opened, and again for the
+    /// directories above any entry which changes when the cache is next updated.
+    pub fn aggregate_directories(mut self, fold: impl Fn(&I::T, &I::T) -> I::T + Send + Sync + 'static) -> Self {
+        self.aggregates = Some(Arc::new(DirectoryAggregates::new(Arc::new(fold))));
+        self
+    }
+
+    /// Declare which files each file depends on, such as a source file on the files it includes,
+    /// from its path and processed value. Whenever an update processes a file again or removes
+    /// it, every file depending on it, directly or through others, is processed again too, even
+    /// if it has not changed itself. Dependencies are only followed through files which are
+    /// cached themselves, so must be given as they are cached. They are found for every entry
+    /// when the cache is opened, and kept in memory only.
+    pub fn dependencies(mut self, f: impl Fn(&Path, &I::T) -> Vec<PathBuf> + Send + Sync + 'static) -> Self {
+        self.dependencies = Some(Arc::new(DependencyGraph::new(Arc::new(f))));
+        self
+    }
+
+    /// Keep at most `max_entries` entries, evicting those least recently used once there are
+    /// more, such as for a cache of a scratch tree whose files keep changing. Evicted entries
+    /// are removed from the cache file too when it is next saved. An entry is used when it is
+    /// inserted or its value is read, but not when an update finds its file unchanged. Entries
+    /// are ordered by when they were cached when the cache is opened, as the order in which they
+    /// were used is kept in memory only. Disabled by default.
+    pub fn lru_capacity(mut self, max_entries: usize) -> Self {
+        self.lru_capacity = Some(max_entries);
+        self
+    }
+
+    /// Keep the cache file within roughly `max_bytes`. Whenever saving would make it larger,
+    /// entries are evicted first, in the order `policy` chooses, until it fits. Sizes are
+    /// estimated as bincode would encode the entries, before any compression or encryption, and
+    /// every entry is measured on every save. The evicted paths are listed by
+    /// [`ProcessingFsCache::take_evicted`]. Unlimited by default.
+    pub fn max_disk_size(mut self, max_bytes: u64, policy: EvictionPolicy) -> Self {
+        self.max_disk_size = Some((max_bytes, policy));
+        self
+    }
+
+    /// Record when each entry was last read, as returned in [`EntryMeta::accessed_at`], and order
+    /// entries for [`Self::lru_capacity`] by it when the cache is opened. Access times are kept in
+    /// a file beside the cache file, so that reading entries does not make the cache file itself
+    /// need saving, and an entry read again within a minute of its recorded access is not
+    /// recorded again. Disabled by default.
+    pub fn track_access_times(mut self, track_access_times: bool) -> Self {
+        self.track_access_times = track_access_times;
+        self
+    }
+
+    /// Keep the estimated memory used by the shards a [`TieredCache`] holds in memory within
+    /// `max_bytes`, writing out and dropping the least recently used shards whenever it is
+    /// exceeded, such as part way through an update. Values are measured with
+    /// [`CacheInterface::estimated_size`]. The shard in use is always kept, however large. Only
+    /// used by [`Self::build_tiered`]. Unlimited by default.
+    pub fn memory_budget(mut self, max_bytes: usize) -> Self {
+        self.memory_budget = Some(max_bytes);
+        self
+    }
+
+    /// Where to persist the cache. Defaults to a [`FileBackend`] storing the cache at `cache_path`.
+    /// If a backend is supplied, `cache_path` and any options for the default backend are ignored.
+    pub fn backend(mut self, backend: impl StorageBackend<MtimeCacheEntry<I::T>, S> + 'static) -> Self {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    pub fn build(self) -> FsCacheResult<ProcessingFsCache<I, S>> {
+        self.build_inner(OnUnreadable::Fail)
+    }
+
+    /// As [`Self::build`], but if the cache file is corrupt then it is replaced with the
+    /// newest readable backup (see [`Self::backups`]) before trying again.
+    pub fn build_or_restore_backup(self) -> FsCacheResult<ProcessingFsCache<I, S>> {
+        self.build_inner(OnUnreadable::RestoreBackup)
+    }
+
+    /// As [`Self::build`], but if the cache file is corrupt then as many entries as possible
+    /// are recovered from it before trying again. See [`FileBackend::repair`].
+    pub fn build_or_repair(self) -> FsCacheResult<ProcessingFsCache<I, S>> {
+        self.build_inner(OnUnreadable::Repair)
+    }
+
+    /// Open the cache as a [`LazyCache`], which reads nothing until it is first used. This
+    /// uses the cache file, shards, codec options, invalidation strategy, normalization of keys
+    /// and relative root chosen for the builder, and ignores everything else. Fails with
+    /// [`crate::FsCacheErrorKind::UnsupportedConfiguration`] if a [`Self::backend`] is supplied or
+    /// values are interned or kept in blobs, as their entries are not stored as a lazy cache reads them.
+    pub fn build_lazy(self) -> FsCacheResult<LazyCache<I::T>> {
+        self.check_plain_entries("build_lazy")?;
+        let file_backend = self
+            .file_backend
+            .with_migration(legacy_file_migration::<I::T>(self.migration))
+            .with_value_format_version(ENTRY_FORMAT_VERSION);
+        let shards = match self.shard_count {
+            Some(shard_count) => ShardedFileBackend::new(file_backend, shard_count).into_shards()?,
+            None => vec![file_backend],
+        };
+        let version = ProcessorVersion {
+            version: self.interface.version(),
+            config_fingerprint: self.interface.config_fingerprint(),
+        };
+        Ok(LazyCache::new(
+            shards,
+            version,
+            self.invalidation_strategy,
+            self.key_normalizer,
+            self.relative_root,
+        ))
+    }
+
+    /// Open the cache as a [`TieredCache`], which holds at most `max_resident_shards` of its
+    /// shards (see [`Self::shards`]) in memory at once, for caches of more files than fit in
+    /// memory. Like [`Self::build_lazy`], this uses the cache file, shards, codec options,
+    /// invalidation strategy, normalization of keys, relative root, [`Self::save_on_drop`] and
+    /// [`Self::memory_budget`] chosen for the builder, and ignores everything else. Fails like
+    /// [`Self::build_lazy`] if a [`Self::backend`] is supplied or values are interned or kept in blobs.
+    pub fn build_tiered(self, max_resident_shards: usize) -> FsCacheResult<TieredCache<I>> {
+        self.check_plain_entries("build_tiered")?;
+        let file_backend = self
+            .file_backend
+            .with_migration(legacy_file_migration::<I::T>(self.migration))
+            .with_value_format_version(ENTRY_FORMAT_VERSION);
+        file_backend.acquire_lock()?;
+        let shards = match self.shard_count {
+            Some(shard_count) => ShardedFileBackend::new(file_backend.clone(), shard_count).into_shards()?,
+            None => vec![file_backend.clone()],
+        };
+        let version = ProcessorVersion {
+            version: self.interface.version(),
+            config_fingerprint: self.interface.config_fingerprint(),
+        };
+        Ok(TieredCache::new(
+            self.interface,
+            file_backend,
+            shards,
+            max_resident_shards,
+            self.memory_budget,
+            version,
+            self.invalidation_strategy,
+            self.key_normalizer,
+            self.relative_root,
+            self.save_on_drop,
+        ))
+    }
+
+    //The lazy and tiered caches read and write shards of plain entries straight from the cache
+    //files, so cannot use anything which changes how entries are stored, or where.
+    fn check_plain_entries(&self, build: &str) -> FsCacheResult<()> {
+        let unsupported = if self.backend.is_some() {
+            "a custom backend"
+        } else if self.interner.is_some() {
+            "interned values"
+        } else if self.blob_threshold.is_some() {
+            "values in blobs"
+        } else {
+            return Ok(());
+        };
+        Err(UnsupportedConfiguration(format!("{} cannot be opened with {}", unsupported, build)))
+    }
+
+    fn build_inner(self, on_unreadable: OnUnreadable) -> FsCacheResult<ProcessingFsCache<I, S>> {
+        let thread_pool = match self.worker_threads {
+            None => None,
+            Some(num_threads) => match rayon::ThreadPoolBuilder::new().num_threads(num_threads).build() {
+                Ok(pool) => Some(pool),
+                Err(e) => return Err(ThreadPool(format!("{}", e))),
+            },
+        };
+
+        //Files from before entries recorded when they were processed are upgraded first,
+        //falling back to the user's migration for anything else.
+        let interner = self.interner;
+        let migration = legacy_file_migration::<I::T>(self.migration);
+        let file_backend = match (&interner, self.blob_threshold) {
+            (Some(_), _) => self
+                .file_backend
+                .with_migration(interning_file_migration::<I::T>(migration)),
+            (None, Some(_)) => self.file_backend.with_migration(blob_file_migration::<I::T>(migration)),
+            (None, None) => self.file_backend.with_migration(migration),
+        }
+        .with_value_format_version(ENTRY_FORMAT_VERSION);
+        let blobs = self.blob_threshold.map(|threshold| (threshold, file_backend.clone()));
+        let strategy = self.save_strategy;
+        let relative_root = self.relative_root;
+        let mut failures = FailureLog::open(file_backend.cache_path(), strategy.clone(), relative_root.as_deref())?;
+        failures.set_save_on_drop(self.save_on_drop);
+        let access_log = match self.track_access_times {
+            true => {
+                let mut access_log =
+                    AccessLog::open(file_backend.cache_path(), strategy.clone(), relative_root.as_deref())?;
+                access_log.set_save_on_drop(self.save_on_drop);
+                Some(access_log)
+            }
+            false => None,
+        };
+        let relative = |backend: Box<dyn StorageBackend<MtimeCacheEntry<I::T>, S>>| match &relative_root {
+            Some(root) => Box::new(RelativeBackend::new(backend, root.clone())),
+            None => backend,
+        };
+        //Interning is innermost, so that no key is stored as the empty path holding the values.
+        let stored = |backend| relative(entry_backend(backend, &interner, &blobs));
+        let mut base_cache = match (self.backend, self.shard_count) {
+            (Some(backend), _) => BaseFsCache::with_backend(strategy, relative(backend))?,
+            (None, Some(shard_count)) => {
+                let backend = ShardedFileBackend::new(file_backend, shard_count);
+                BaseFsCache::with_backend(strategy, relative(entry_backend(backend, &interner, &blobs)))?
+            }
+            (None, None) => match BaseFsCache::with_backend(strategy.clone(), stored(file_backend.clone())) {
+                Err(e) if on_unreadable == OnUnreadable::RestoreBackup && e.is_unreadable_contents() => {
+                    warn!(target: "generic_cache_startup", "{}. Trying backups.", e);
+                    let restored = match (&interner, &blobs) {
+                        (Some(_), _) => file_backend.restore_newest_backup::<InternedEntry<I::T>>()?,
+                        (None, Some(_)) => file_backend.restore_newest_backup::<BlobEntry<I::T>>()?,
+                        (None, None) => file_backend.restore_newest_backup::<MtimeCacheEntry<I::T>>()?,
+                    };
+                    match restored {
+                        Some(_) => BaseFsCache::with_backend(strategy, stored(file_backend))?,
+                        None => return Err(e),
+                    }
+                }
+                Err(e) if on_unreadable == OnUnreadable::Repair && e.is_unreadable_contents() => {
+                    warn!(target: "generic_cache_startup", "{}. Repairing.", e);
+                    match (&interner, &blobs) {
+                        (Some(_), _) => file_backend.repair::<InternedEntry<I::T>>()?,
+                        (None, Some(_)) => file_backend.repair::<BlobEntry<I::T>>()?,
+                        (None, None) => file_backend.repair::<MtimeCacheEntry<I::T>>()?,
+                    };
+                    BaseFsCache::with_backend(strategy, stored(file_backend))?
+                }
+                result => result?,
+            },
+        };
+        base_cache.set_save_on_drop(self.save_on_drop);
+        base_cache.set_save_from_snapshot(self.save_from_snapshot);
+        let stale_on_version_change = self.stale_on_version_change;
+        let version = ProcessorVersion {
+            version: self.interface.version(),
+            config_fingerprint: self.interface.config_fingerprint(),
+        };
+        base_cache.check_version(version, |old_version, key, entry| match &stale_on_version_change {
+            Some(is_stale) => is_stale(old_version, key, &entry.value),
+            None => true,
+        })?;
+        failures.check_version(version)?;
+        if let Some(access_log) = &access_log {
+            access_log.retain_cached(|key| base_cache.contains_key(key))?;
+        }
+        let mut observer = self.observer.map(|observer| {
+            observer.on_load(base_cache.len());
+            Box::new(ValueObserver(observer)) as Box<dyn CacheObserver<MtimeCacheEntry<I::T>>>
+        });
+        let value_index = self.value_index;
+        if let Some(index) = &value_index {
+            base_cache.for_each(|key, entry| index.insert(key, &entry.value));
+            observer = Some(Box::new(IndexingObserver::new(index.clone(), observer)));
+        }
+        let aggregates = self.aggregates;
+        if let Some(aggregates) = &aggregates {
+            base_cache.for_each(|key, _| aggregates.mark_stale(key));
+            aggregates.refresh(|f| base_cache.for_each(|key, entry| f(key, &entry.value)));
+            observer = Some(Box::new(AggregatingObserver::new(aggregates.clone(), observer)));
+        }
+        let dependencies = self.dependencies;
+        if let Some(dependencies) = &dependencies {
+            base_cache.for_each(|key, entry| dependencies.insert(key, &entry.value));
+            observer = Some(Box::new(DependencyObserver::new(dependencies.clone(), observer)));
+        }
+        let tracks_recency =
+            self.lru_capacity.is_some() || matches!(self.max_disk_size, Some((_, EvictionPolicy::LeastRecentlyUsed)));
+        let recency = tracks_recency.then(|| Arc::new(Recency::default()));
+        if let Some(recency) = &recency {
+            let mut keys = vec![];
+            base_cache.for_each(|key, entry| {
+                let last_access = access_log.as_ref().and_then(|access_log| access_log.last_access(key));
+                keys.push((last_access.max(entry.cached_at), key.to_path_buf()))
+            });
+            keys.sort_unstable();
+            for (_, key) in keys {
+                recency.touch(&key);
+            }
+            observer = Some(Box::new(RecencyObserver::new(recency.clone(), observer)));
+        }
+        if let Some(observer) = observer {
+            base_cache.set_observer(observer);
         }
+        if let Some((max_bytes, policy)) = self.max_disk_size {
+            match (policy, &recency) {
+                (EvictionPolicy::LeastRecentlyUsed, Some(recency)) => {
+                    let recency = recency.clone();
+                    base_cache.set_size_limit(max_bytes, move |key, _, _| recency.tick(key).unwrap_or(0))
+                }
+                (EvictionPolicy::OldestProcessed, _) => base_cache.set_size_limit(max_bytes, |_, entry, _| {
+                    entry
+                        .cached_at
+                        .and_then(|cached_at| cached_at.duration_since(SystemTime::UNIX_EPOCH).ok())
+                        .map_or(0, |since_epoch| since_epoch.as_nanos() as u64)
+                }),
+                (EvictionPolicy::LargestFirst, _) => base_cache.set_size_limit(max_bytes, |_, _, size| u64::MAX - size),
+                //recency is always tracked for this policy.
+                (EvictionPolicy::LeastRecentlyUsed, None) => unreachable!(),
+            }
+        }
+
+        let base_cache = Arc::new(base_cache);
+        let autosave = self
+            .autosave_interval
+            .map(|interval| Autosave::spawn(Arc::downgrade(&base_cache), interval));
+
+        Ok(ProcessingFsCache {
+            _autosave: autosave,
+            base_cache,
+            interface: self.interface,
+            thread_pool,
+            invalidation_strategy: self.invalidation_strategy,
+            key_normalizer: self.key_normalizer,
+            relative_root,
+            failures,
+            retry_policy: self.retry_policy,
+            time_to_live: self.time_to_live,
+            detect_renames: self.detect_renames,
+            deduplicate_hard_links: self.deduplicate_hard_links,
+            interner,
+            value_index,
+            aggregates,
+            dependencies,
+            lru_capacity: self.lru_capacity,
+            recency,
+            access_log,
+            stats: Default::default(),
+        })
+    }
+}
+
+impl<I> ProcessingFsCache<I>
+where
+    I: CacheInterface + Send + Sync,
+    I::T: 'static,
+{
+    pub fn new(save_strategy: impl SaveStrategy + 'static, cache_path: PathBuf, interface: I) -> FsCacheResult<Self> {
+        ProcessingFsCacheBuilder::new(save_strategy, cache_path, interface).build()
+    }
+
+    /// Create a cache stored in a sled database at `cache_path`, which durably stores each
+    /// change as it happens rather than only when the save strategy triggers.
+    #[cfg(feature = "sled")]
+    pub fn new_sled(
+        save_strategy: impl SaveStrategy + 'static,
+        cache_path: PathBuf,
+        interface: I,
+    ) -> FsCacheResult<Self> {
+        let backend = crate::sled_backend::SledBackend::open(cache_path.clone())?
+            .with_migration(crate::cache_entry::legacy_entry_migration::<I::T>);
+        ProcessingFsCacheBuilder::new(save_strategy, cache_path, interface)
+            .backend(backend)
+            .build()
     }
 
+    pub fn builder(
+        save_strategy: impl SaveStrategy + 'static,
+        cache_path: PathBuf,
+        interface: I,
+    ) -> ProcessingFsCacheBuilder<I> {
+        ProcessingFsCacheBuilder::new(save_strategy, cache_path, interface)
+    }
+}
+
+impl<I, S> ProcessingFsCache<I, S>
+where
+    I: CacheInterface + Send + Sync,
+    I::T: 'static,
+    S: BuildHasher + Default + Send + Sync + 'static,
+{
+    /// Save any changes which have not been saved yet. Does nothing if there are none.
     pub fn save(&self) -> FsCacheResult<()> {
-        self.base_cache.save()
+        self.base_cache.save()?;
+        if let Some(access_log) = &self.access_log {
+            access_log.save()?;
+        }
+        self.failures.save()
+    }
+
+    /// Save the cache, and the failures and access times recorded beside it, even if nothing
+    /// has changed since they were last saved.
+    pub fn flush(&self) -> FsCacheResult<()> {
+        self.base_cache.flush()?;
+        if let Some(access_log) = &self.access_log {
+            access_log.flush()?;
+        }
+        self.failures.flush()
+    }
+
+    /// Whether there are changes which would be lost if the cache were dropped without saving.
+    pub fn is_dirty(&self) -> bool {
+        self.pending_modifications() != 0
+    }
+
+    /// The number of entries, and recorded failures and access times, inserted, updated or
+    /// removed since they were last saved.
+    pub fn pending_modifications(&self) -> usize {
+        let access_times = self.access_log.as_ref().map_or(0, AccessLog::pending_modifications);
+        self.base_cache.pending_modifications() + self.failures.pending_modifications() + access_times
     }
 
     pub fn remove(&self, key: impl AsRef<Path>) -> FsCacheResult<()> {
-        self.base_cache.remove(key)
+        let key = self.key(key.as_ref());
+        self.failures.forget(&key)?;
+        let removed = self.base_cache.remove(&key)?;
+        self.stats.removed(removed as usize);
+        Ok(())
     }
 
+    /// Returns the cached value for a path without checking whether the file has changed
+    /// on disk. Use [`Self::fetch_update`] to reprocess stale entries.
     pub fn fetch(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<I::T> {
-        match self.base_cache.fetch(key.borrow()) {
-            Ok(MtimeCacheEntry { cache_mtime: _, value }) => Ok(value),
+        self.fetch_arc(key).map(Arc::unwrap_or_clone)
+    }
+
+    /// As [`Self::fetch`], but returns the cached value itself rather than a clone of it, which
+    /// is cheaper for large values. The value is not affected by later changes to the cache.
+    pub fn fetch_arc(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<Arc<I::T>> {
+        let key = self.key(key.borrow());
+        match self.base_cache.fetch(&key) {
+            Ok(MtimeCacheEntry { value, .. }) => {
+                self.accessed(&key);
+                Ok(value)
+            }
             Err(e) => Err(e),
         }
     }
 
+    /// Call `f` with the cached value for a path, without cloning it, and return its result.
+    /// Fails with [`FsCacheErrorKind::KeyMissing`] if the path is not cached. This holds the
+    /// cache's read lock while `f` runs, so `f` must not modify the cache or it will deadlock.
+    pub fn with_value<R>(&self, key: &Path, f: impl FnOnce(&I::T) -> R) -> FsCacheResult<R> {
+        let key = self.key(key);
+        match self.base_cache.with_item(&key, |entry| f(&entry.value)) {
+            Some(ret) => {
+                self.accessed(&key);
+                Ok(ret)
+            }
+            None => Err(KeyMissing(key.into_owned())),
+        }
+    }
+
+    /// As [`Self::fetch`], also returning when the value was cached, how long it took to
+    /// process and the state of the file it was processed from.
+    pub fn get_with_meta(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<(I::T, EntryMeta)> {
+        let key = self.key(key.borrow());
+        let entry = self.base_cache.fetch(&key)?;
+        let mut meta = entry.meta();
+        meta.accessed_at = self
+            .access_log
+            .as_ref()
+            .and_then(|access_log| access_log.last_access(&key));
+        self.accessed(&key);
+        Ok((Arc::unwrap_or_clone(entry.value), meta))
+    }
+
+    /// Returns the cached value for a path, first reprocessing the file if the configured
+    /// [`InvalidationStrategy`] considers it to have changed since it was cached. Returns None (and removes any cached
+    /// entry) if the file no longer exists, or fails with [`FsCacheErrorKind::KnownFailure`] if
+    /// the file failed to process before and the retry policy says not to retry it yet.
     pub fn fetch_update(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<Option<I::T>> {
         //insertion required if:
         // * Item is not in cache.
         // * Cached item is out of date.
 
-        match self.get_update_action(key.borrow())? {
-            UpdateAction::NoChange => self.fetch(key).map(Option::from),
-            UpdateAction::Update(fs_mtime) => self.force_update_inner(key, fs_mtime).map(Option::from),
-            UpdateAction::Remove => self.remove(key.borrow().as_path()).map(|_| None),
+        let key = self.key(key.borrow());
+        match self.get_update_action(&key)? {
+            UpdateAction::NoChange => {
+                self.stats.hit();
+                let value = self.fetch_key(&key)?;
+                self.accessed(&key);
+                Ok(Some(value))
+            }
+            UpdateAction::Update(source, metadata) => {
+                self.check_known_failure(&key, &source)?;
+                self.stats.miss();
+                self.force_update_inner(&key, source, &metadata).map(Option::from)
+            }
+            UpdateAction::Remove => self.remove(&key).map(|_| None),
+        }
+    }
+
+    /// Returns the cached value for a path if there is one, otherwise processes the file and
+    /// caches the result. Unlike [`Self::fetch_update`], cached entries are not checked for staleness.
+    pub fn get_or_compute(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<I::T> {
+        let key = self.key(key.borrow());
+        match self.base_cache.fetch(&key) {
+            Ok(MtimeCacheEntry { value, .. }) => {
+                self.stats.hit();
+                self.accessed(&key);
+                Ok(Arc::unwrap_or_clone(value))
+            }
+            Err(KeyMissing(_)) => {
+                let (source, metadata) = self.fs_state(&key).map_err(|e| CacheFileIo {
+                    path: key.to_path_buf(),
+                    src: e,
+                })?;
+                self.check_known_failure(&key, &source)?;
+                self.stats.miss();
+                self.force_update_inner(&key, source, &metadata)
+            }
+            Err(e) => Err(e),
         }
     }
 
+    /// Process a file and cache the result, even if it is unchanged or a recorded failure
+    /// would otherwise stop it from being retried.
     pub fn force_update(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<I::T> {
-        self.force_update_inner(
-            key.borrow(),
-            Self::fs_mtime(key.borrow()).map_err(|e| FsCacheErrorKind::CacheFileIo {
-                path: key.borrow().to_path_buf(),
+        let key = self.key(key.borrow());
+        let (source, metadata) = self.fs_state(&key).map_err(|e| FsCacheErrorKind::CacheFileIo {
+            path: key.to_path_buf(),
+            src: e,
+        })?;
+        self.force_update_inner(&key, source, &metadata)
+    }
+
+    fn force_update_inner(&self, key: &Path, source: SourceMetadata, metadata: &Metadata) -> FsCacheResult<I::T> {
+        self.process(key, source, metadata)?;
+        self.fetch_key(key)
+    }
+
+    //As `fetch`, for a path which is already a key.
+    fn fetch_key(&self, key: &Path) -> FsCacheResult<I::T> {
+        self.base_cache
+            .fetch(key)
+            .map(|MtimeCacheEntry { value, .. }| Arc::unwrap_or_clone(value))
+    }
+
+    //Processes a file and caches the result, without cloning the value back out.
+    fn process(&self, key: &Path, source: SourceMetadata, metadata: &Metadata) -> FsCacheResult<()> {
+        let cache_entry = self.process_entry(key, source, metadata)?;
+        self.base_cache.insert(key.to_path_buf(), cache_entry)?;
+        self.stats.inserted(1);
+        self.evict_excess().map(|_| ())
+    }
+
+    //Processes a file, returning the entry to cache for it.
+    fn process_entry(
+        &self,
+        key: &Path,
+        source: SourceMetadata,
+        metadata: &Metadata,
+    ) -> FsCacheResult<MtimeCacheEntry<I::T>> {
+        let start = Instant::now();
+        let result = self.interface.try_load_with_metadata(key, metadata);
+        let elapsed = start.elapsed();
+        self.stats.processed(elapsed);
+        let value = match result {
+            Ok(value) => value,
+            Err(src) => {
+                let (retry_policy, strategy) = (self.retry_policy, self.invalidation_strategy);
+                self.failures.record(key, source, &src, retry_policy, strategy)?;
+                return Err(Processing {
+                    src,
+                    path: key.to_path_buf(),
+                });
+            }
+        };
+        self.failures.forget(key)?;
+        Ok(self.interned(MtimeCacheEntry::processed(source, value, elapsed)))
+    }
+
+    /// Insert already-processed values for many paths at once. The current state of each file
+    /// is read from disk so that later calls to [`Self::fetch_update`] can tell whether it has
+    /// changed. The whole batch counts as a single modification towards the save strategy.
+    pub fn insert_batch(&self, items: Vec<(PathBuf, I::T)>) -> FsCacheResult<()> {
+        let entries = items
+            .into_iter()
+            .map(|(key, value)| (self.key(&key).into_owned(), value))
+            .map(|(key, value)| match self.fs_metadata(&key) {
+                Ok(source) => Ok((key, self.interned(MtimeCacheEntry::inserted(source, value)))),
+                Err(e) => Err(FsCacheErrorKind::CacheFileIo { path: key, src: e }),
+            })
+            .collect::<FsCacheResult<Vec<_>>>()?;
+
+        let count = entries.len();
+        self.base_cache.insert_batch(entries)?;
+        self.stats.inserted(count);
+        self.evict_excess().map(|_| ())
+    }
+
+    /// Modify a cached value in place, without cloning it out of the cache and reinserting it.
+    ///
+    /// `f` is given the cached value if there is one, and may mutate it directly and/or return
+    /// a replacement. If `f` returns a value for a path which is not cached, it is inserted,
+    /// in which case the file must exist so that its current state can be recorded.
+    pub fn update_with<F>(&self, key: impl Borrow<PathBuf>, f: F) -> FsCacheResult<()>
+    where
+        F: FnOnce(Option<&mut I::T>) -> Option<I::T>,
+    {
+        let key = self.key(key.borrow());
+        let mut metadata_error = None;
+
+        self.base_cache.update_with(key.to_path_buf(), |entry| match entry {
+            Some(entry) => f(Some(Arc::make_mut(&mut entry.value))).map(|value| MtimeCacheEntry {
+                source: entry.source,
+                value: self.interned_value(value),
+                cached_at: entry.cached_at,
+                processing_time: entry.processing_time,
+                file_id: entry.file_id,
+            }),
+            None => {
+                let value = f(None)?;
+                match self.fs_metadata(&key) {
+                    Ok(source) => Some(self.interned(MtimeCacheEntry::inserted(source, value))),
+                    Err(e) => {
+                        metadata_error = Some(e);
+                        None
+                    }
+                }
+            }
+        })?;
+
+        match metadata_error {
+            Some(e) => Err(FsCacheErrorKind::CacheFileIo {
+                path: key.to_path_buf(),
                 src: e,
-            })?,
-        )
+            }),
+            None => {
+                self.accessed(&key);
+                self.evict_excess().map(|_| ())
+            }
+        }
+    }
+
+    /// Remove every entry for which `f` returns false, returning the number of entries removed.
+    /// The whole pruning counts as a single modification towards the save strategy.
+    pub fn retain(&self, mut f: impl FnMut(&Path, &I::T) -> bool) -> FsCacheResult<usize> {
+        let removed = self.base_cache.retain(|key, entry| f(key, &entry.value))?;
+        self.stats.removed(removed);
+        Ok(removed)
+    }
+
+    /// Remove every entry for a file which no longer exists, without processing anything or
+    /// walking a file set, so that cleanup can be scheduled separately from [`Self::update_from_fs`].
+    /// Files are looked at in parallel, like in [`Self::par_find`]. Returns the number of entries removed.
+    pub fn prune_missing_files(&self) -> FsCacheResult<usize> {
+        let keys = self.keys();
+        let find_missing = || {
+            keys.into_par_iter()
+                .filter(|key| is_missing(key))
+                .collect::<HashSet<_>>()
+        };
+        let missing = match &self.thread_pool {
+            Some(pool) => pool.install(find_missing),
+            None => find_missing(),
+        };
+
+        for key in &missing {
+            self.failures.forget(key)?;
+        }
+        self.retain(|key, _| !missing.contains(key))
+    }
+
+    /// Remove every entry which has outlived its time to live (see
+    /// [`ProcessingFsCacheBuilder::time_to_live`]), returning the number of entries removed.
+    pub fn prune_expired(&self) -> FsCacheResult<usize> {
+        let removed = self
+            .base_cache
+            .retain(|key, entry| !is_expired(&self.time_to_live, key, entry))?;
+        self.stats.removed(removed);
+        Ok(removed)
+    }
+
+    /// Remove every entry which was neither processed, inserted nor read within `age`, such as to
+    /// stop a cache of a tree full of short-lived files from growing without bound. Reads are only
+    /// known of with [`ProcessingFsCacheBuilder::track_access_times`], and otherwise entries are
+    /// removed by when they were processed or inserted alone. Files which still exist are processed
+    /// again by the next update. Entries cached by versions of this crate which did not record when
+    /// are removed unless they have been read since. Returns the number of entries removed.
+    pub fn prune_older_than(&self, age: Duration) -> FsCacheResult<usize> {
+        let read_within_age = |key: &Path| {
+            self.access_log
+                .as_ref()
+                .and_then(|access_log| access_log.last_access(key))
+                .is_some_and(|last_access| last_access.elapsed().is_ok_and(|since| since <= age))
+        };
+        let removed = self
+            .base_cache
+            .retain(|key, entry| !entry.expired(age) || read_within_age(key))?;
+        self.stats.removed(removed);
+        Ok(removed)
     }
 
-    fn force_update_inner(&self, key: impl Borrow<PathBuf>, mtime: SystemTime) -> FsCacheResult<I::T> {
-        let k = key.borrow().clone();
+    /// Mark a file as needing processing without processing it now. Its entry is removed,
+    /// so that the next [`Self::update_from_fs`] or [`Self::get_or_compute`] processes it
+    /// again, and any recorded failure is forgotten. Returns whether the file was cached.
+    pub fn invalidate(&self, key: &Path) -> FsCacheResult<bool> {
+        self.invalidate_normalized(Invalidation::Key(&self.key(key))).map(|removed| removed != 0)
+    }
+
+    /// Like [`Self::invalidate`], for every file beneath `dir`. Returns the number of entries
+    /// removed. The whole invalidation counts as a single modification towards the save strategy.
+    pub fn invalidate_dir(&self, dir: &Path) -> FsCacheResult<usize> {
+        self.invalidate_normalized(Invalidation::Dir(&self.key(dir)))
+    }
 
-        let value = self.interface.load(k.clone());
-        let cache_entry = MtimeCacheEntry {
-            cache_mtime: mtime,
-            value,
+    /// Remove every entry for a file which is not part of `file_set`, such as after removing
+    /// one of its roots. Returns the number of entries removed.
+    pub fn remove_outside(&self, file_set: &FileSet) -> FsCacheResult<usize> {
+        self.invalidate_normalized(Invalidation::Outside(&self.key_normalizer.file_set(file_set)))
+    }
+
+    //Keys are normalized by the callers, once, as normalizing a key again may not leave it as it was.
+    //Failures are forgotten for invalidated files, which are to be processed again. Returns the
+    //number of entries removed.
+    fn invalidate_normalized(&self, invalidation: Invalidation<'_>) -> FsCacheResult<usize> {
+        let removed = match invalidation {
+            Invalidation::Key(key) => {
+                self.failures.forget(key)?;
+                self.base_cache.remove(key)? as usize
+            }
+            Invalidation::Dir(dir) => {
+                self.failures.forget_under(dir)?;
+                self.base_cache.retain(|key, _| !key.starts_with(dir))?
+            }
+            Invalidation::Outside(file_set) => self.base_cache.retain(|key, _| file_set.includes(key))?,
+        };
+        self.stats.removed(removed);
+        Ok(removed)
+    }
+
+    /// Merge in the entries of another cache file, such as one built on another machine, using
+    /// `strategy` to choose between entries for the same path. Returns the number of entries
+    /// taken from the other file, which is not modified. The other file must use the default
+    /// codec and have been written by the same version of the processing.
+    pub fn merge_from(&self, path: &Path, strategy: MergeStrategy) -> FsCacheResult<usize> {
+        let version = ProcessorVersion {
+            version: self.interface.version(),
+            config_fingerprint: self.interface.config_fingerprint(),
         };
-        self.base_cache.insert(k, cache_entry)?;
+        let merged = crate::merge::merge_from(
+            &self.base_cache,
+            &self.failures,
+            path,
+            version,
+            strategy,
+            self.relative_root.as_deref(),
+        )?;
+        self.evict_excess()?;
+        Ok(merged)
+    }
 
-        self.fetch(key)
+    /// Compare this cache with another, such as one loaded from an older copy of the cache
+    /// file, listing the paths cached in only one of them and those whose values differ.
+    pub fn diff<J, S2>(&self, other: &ProcessingFsCache<J, S2>) -> CacheDiff
+    where
+        J: CacheInterface<T = I::T> + Send + Sync,
+        S2: BuildHasher + Default + Send + Sync,
+        I::T: PartialEq,
+    {
+        crate::merge::diff(&self.base_cache, &other.base_cache)
+    }
+
+    /// Remove every entry. The on-disk cache is updated when the cache is next saved.
+    pub fn clear(&self) -> FsCacheResult<()> {
+        let removed = self.base_cache.len();
+        self.base_cache.clear()?;
+        self.failures.clear()?;
+        if let Some(access_log) = &self.access_log {
+            access_log.clear()?;
+        }
+        self.stats.removed(removed);
+        Ok(())
+    }
+
+    /// Remove every entry and immediately delete the cache file (and journal, if any).
+    /// Backups made with [`ProcessingFsCacheBuilder::backups`] are kept.
+    pub fn reset_on_disk(&self) -> FsCacheResult<()> {
+        self.base_cache.reset_on_disk()?;
+        if let Some(access_log) = &self.access_log {
+            access_log.reset_on_disk()?;
+        }
+        self.failures.reset_on_disk()
+    }
+
+    /// Every recorded failure to process a file. Failures are only recorded if the
+    /// [`ProcessingFsCacheBuilder::retry_policy`] is not [`RetryPolicy::Always`].
+    pub fn failures(&self) -> Vec<(PathBuf, ProcessingFailure)> {
+        self.failures.list()
+    }
+
+    /// Forget every recorded failure, so that the files are retried on the next update.
+    pub fn clear_failures(&self) -> FsCacheResult<()> {
+        self.failures.clear()
+    }
+
+    /// Bring the cache up to date with every file in the file set. New or modified files
+    /// are processed, and entries for files which no longer exist are removed.
+    ///
+    /// Files are processed in parallel, either on rayon's global thread pool or on a
+    /// dedicated pool if one was configured with [`ProcessingFsCacheBuilder::worker_threads`].
+    /// A failure to update one file does not stop the others, and is listed in the returned report.
+    /// So are any parts of the file set which could not be walked, unless the file set's
+    /// [`crate::WalkErrorPolicy`] is to abort, in which case the update fails before anything is processed.
+    ///
+    /// Each worker inserts the files it has processed in batches, so that it takes the write locks
+    /// of the cache once per batch rather than once per file. The entries are held in separately
+    /// locked shards, and a worker locks only the shards its batch touches, so workers inserting
+    /// into different shards do not wait for each other, nor do readers of other shards. If saving
+    /// fails part way through the update, the processed files stay cached, and the failure is
+    /// logged rather than reported against any file, as the next save tries again.
+    pub fn update_from_fs(&self, file_set: &FileSet) -> FsCacheResult<UpdateReport> {
+        self.update_from_fs_with_progress(file_set, &())
+    }
+
+    /// Like [`Self::update_from_fs`], reporting progress to `progress` as each file is done.
+    pub fn update_from_fs_with_progress(
+        &self,
+        file_set: &FileSet,
+        progress: &dyn UpdateProgress,
+    ) -> FsCacheResult<UpdateReport> {
+        let walked = self.walk(file_set)?;
+        self.update_walked(file_set, walked, progress, &WalkedMetadata::new())
+    }
+
+    //Updates from the files found by a walk of the file set. Files whose metadata was read
+    //during the walk are not looked at again to tell whether they have changed.
+    fn update_walked(
+        &self,
+        file_set: &FileSet,
+        (Enumeration { mut files, errors }, mut missing): (Enumeration, Vec<PathBuf>),
+        progress: &dyn UpdateProgress,
+        walked: &WalkedMetadata,
+    ) -> FsCacheResult<UpdateReport> {
+        let migrated = match self.detect_renames {
+            true => self.move_renamed(&mut files, &mut missing)?,
+            false => 0,
+        };
+        files.extend(missing);
+        file_set.order(&mut files);
+
+        let mut report = self.update_paths(&files, progress, false, walked);
+        report.migrated = migrated;
+        report.record_walk_errors(errors);
+        info!(target: "generic_cache_update", "{}: {}", self.interface.describe(), report);
+        Ok(report)
+    }
+
+    /// List what [`Self::update_from_fs`] would process, reprocess and remove, without
+    /// processing anything or modifying the cache. The filesystem is still walked and every
+    /// file looked at, so this costs as much as an update in which nothing has changed.
+    pub fn plan_update(&self, file_set: &FileSet) -> FsCacheResult<UpdatePlan> {
+        let (Enumeration { mut files, errors }, mut missing) = self.walk(file_set)?;
+        let renames = match self.detect_renames {
+            true => self.find_renames(&files, &missing),
+            false => vec![],
+        };
+        take_renamed(&renames, &mut files, &mut missing);
+        files.extend(missing);
+        file_set.order(&mut files);
+
+        let plan_all = || files.par_iter().map(|path| self.plan_entry(path)).collect::<Vec<_>>();
+        let outcomes = match &self.thread_pool {
+            Some(pool) => pool.install(plan_all),
+            None => plan_all(),
+        };
+
+        let mut plan = UpdatePlan::default();
+        for (path, outcome) in files.into_iter().zip(outcomes) {
+            plan.record(path, outcome);
+        }
+        plan.migrate = renames;
+        plan.record_walk_errors(errors);
+        Ok(plan)
+    }
+
+    //Every file in the file set, and separately every cached file which was not found in it.
+    fn walk(&self, file_set: &FileSet) -> FsCacheResult<(Enumeration, Vec<PathBuf>)> {
+        let enumeration = self.key_normalizer.file_set(file_set).enumerate()?;
+        Ok(self.walked_keys(file_set, enumeration))
+    }
+
+    //The keys of the files found by walking the file set, and separately every cached file
+    //which was not found in it.
+    fn walked_keys(
+        &self,
+        file_set: &FileSet,
+        Enumeration { files, errors }: Enumeration,
+    ) -> (Enumeration, Vec<PathBuf>) {
+        let file_set = self.key_normalizer.file_set(file_set);
+        let files = self.key_normalizer.keys(files);
+
+        let missing = missing_paths(&file_set, &files, unwalked_keys_removed(self.keys(), &errors));
+        (Enumeration { files, errors }, missing)
+    }
+
+    //Missing files which have been found at a new path. See `renames::find_renames`.
+    fn find_renames(&self, found: &[PathBuf], missing: &[PathBuf]) -> Vec<(PathBuf, PathBuf)> {
+        let find = || {
+            find_renames(
+                &self.base_cache,
+                found,
+                missing,
+                self.invalidation_strategy,
+                &self.time_to_live,
+            )
+        };
+        match &self.thread_pool {
+            Some(pool) => pool.install(find),
+            None => find(),
+        }
+    }
+
+    //Moves the entries of missing files which have been found at a new path, and takes both
+    //paths off the lists of files to visit. Returns the number of entries moved.
+    fn move_renamed(&self, found: &mut Vec<PathBuf>, missing: &mut Vec<PathBuf>) -> FsCacheResult<usize> {
+        let renames = self.find_renames(found, missing);
+        if renames.is_empty() {
+            return Ok(0);
+        }
+
+        let moved_to: Vec<PathBuf> = renames.iter().map(|(_, to)| to.clone()).collect();
+        take_renamed(&renames, found, missing);
+        let moved = self.base_cache.rename_many(renames)?;
+        //A file whose old entry was removed meanwhile was not moved, so must still be visited.
+        found.extend(moved_to.into_iter().filter(|path| !self.base_cache.contains_key(path)));
+        info!(target: "generic_cache_update", "{}: moved {} renamed entries", self.interface.describe(), moved);
+        Ok(moved)
+    }
+
+    /// Wait up to `timeout` for the watcher to see changes to the filesystem, then bring
+    /// everything that changed up to date. Returns an empty report if nothing changed.
+    #[cfg(feature = "watch")]
+    pub fn update_from_events(&self, watcher: &FsWatcher, timeout: Duration) -> FsCacheResult<UpdateReport> {
+        let changed = watcher.changed_paths(timeout)?;
+        Ok(self.update_changed_paths(watcher.file_set(), changed))
+    }
+
+    /// Keep the cache up to date with the filesystem until `stop` is set.
+    ///
+    /// Bursts of changes are coalesced: once something changes, nothing is processed until
+    /// no further changes have been seen for `debounce`, at which point everything changed
+    /// is processed as one batch. The cache is saved according to its save strategy as usual.
+    #[cfg(feature = "watch")]
+    pub fn run_sync_loop(&self, watcher: &FsWatcher, debounce: Duration, stop: &AtomicBool) -> FsCacheResult<()> {
+        //how often to check whether to stop while nothing is happening.
+        const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+        while !stop.load(Relaxed) {
+            let mut changed = watcher.changed_paths(STOP_POLL_INTERVAL)?;
+            if changed.is_empty() {
+                continue;
+            }
+
+            loop {
+                let more = watcher.changed_paths(debounce)?;
+                if more.is_empty() || stop.load(Relaxed) {
+                    break;
+                }
+                changed.extend(more);
+            }
+
+            let report = self.update_changed_paths(watcher.file_set(), changed);
+            info!(target: "generic_cache_watch", "synced changes: {}", report);
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "watch")]
+    fn update_changed_paths(&self, file_set: &FileSet, changed: Vec<PathBuf>) -> UpdateReport {
+        let file_set = self.key_normalizer.file_set(file_set);
+        let mut paths = HashSet::new();
+
+        for changed in self.key_normalizer.keys(changed) {
+            //a changed directory may have been moved in or out of the file set, so
+            //everything beneath it needs looking at.
+            if changed.is_dir() {
+                let subtree = file_set.subtree(&changed).enumerate_from_fs();
+                paths.extend(self.key_normalizer.keys(subtree));
+            }
+            paths.extend(self.keys_under(&changed));
+            paths.insert(changed);
+        }
+
+        let mut paths: Vec<PathBuf> = paths
+            .into_iter()
+            .filter(|path| file_set.includes(path) && !path.is_dir())
+            .filter(|path| path.exists() || self.contains_key(path))
+            .collect();
+        file_set.order(&mut paths);
+        self.update_paths(&paths, &(), false, &WalkedMetadata::new())
+    }
+
+    /// Reprocess a file even if it has not changed, such as after fixing a bug in the
+    /// processing function. Like [`Self::force_update`], but without cloning the value out
+    /// of the cache. If the file no longer exists, its entry is removed.
+    pub fn force_refresh(&self, key: &Path) -> FsCacheResult<()> {
+        let key = self.key(key);
+        match self.update_entry(&key, true, &WalkedMetadata::new())? {
+            (_, Some(cache_entry)) => {
+                self.base_cache.insert(key.into_owned(), cache_entry)?;
+                self.stats.inserted(1);
+                self.evict_excess().map(|_| ())
+            }
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// Reprocess every cached file beneath `dir`, whether or not it has changed. Entries for
+    /// files which no longer exist are removed. Files beneath `dir` which are not cached are
+    /// left alone, as only [`Self::update_from_fs`] knows which of them belong to the file set.
+    pub fn force_refresh_under(&self, dir: &Path) -> FsCacheResult<UpdateReport> {
+        let mut keys = self.keys_under(dir);
+        keys.sort_unstable();
+
+        let report = self.update_paths(&keys, &(), true, &WalkedMetadata::new());
+        info!(target: "generic_cache_update", "{}: refreshed {}: {}", self.interface.describe(), dir.display(), report);
+        Ok(report)
+    }
+
+    /// Reprocess a randomly chosen `sample_rate` (between 0 and 1) of the cached files which
+    /// have not changed, and report any whose value differs from the cached one, such as because
+    /// of corruption or nondeterministic processing. The cache itself is left untouched.
+    pub fn verify(&self, sample_rate: f64) -> VerifyReport
+    where
+        I::T: PartialEq,
+    {
+        let sampler = RandomState::new();
+        let keys: Vec<PathBuf> = self
+            .keys()
+            .into_iter()
+            .filter(|key| sampled(&sampler, key, sample_rate))
+            .collect();
+        let report = Mutex::new(VerifyReport::default());
+
+        let verify_all = || {
+            keys.par_iter().for_each(|key| {
+                let result = self.verify_entry(key);
+                match report.lock() {
+                    Ok(mut report) => report.record(key.clone(), result),
+                    Err(_) => unreachable!(),
+                }
+            })
+        };
+        match &self.thread_pool {
+            Some(pool) => pool.install(verify_all),
+            None => verify_all(),
+        }
+
+        let report = match report.into_inner() {
+            Ok(report) => report,
+            Err(_) => unreachable!(),
+        };
+        info!(target: "generic_cache_update", "{}: verified: {}", self.interface.describe(), report);
+        report
+    }
+
+    //Whether reprocessing an unchanged file gives the cached value, or None if the file has changed.
+    fn verify_entry(&self, key: &Path) -> FsCacheResult<Option<bool>>
+    where
+        I::T: PartialEq,
+    {
+        let entry = match self.base_cache.fetch(key) {
+            Ok(entry) => entry,
+            //removed since the keys were listed.
+            Err(KeyMissing(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let (source, metadata) = match self.fs_state(key) {
+            Ok(state) => state,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(CacheFileIo {
+                    path: key.to_path_buf(),
+                    src: e,
+                })
+            }
+        };
+        if source_changed(self.invalidation_strategy, &source, &entry.source) {
+            return Ok(None);
+        }
+
+        match self.interface.try_load_with_metadata(key, &metadata) {
+            Ok(value) => Ok(Some(value == *entry.value)),
+            Err(src) => Err(Processing {
+                src,
+                path: key.to_path_buf(),
+            }),
+        }
+    }
+
+    //If forced, files are processed whether or not they have changed. `walked` holds any
+    //metadata already read for the files, which is used rather than reading it again.
+    //Files depending on any which changed are then processed again too.
+    fn update_paths(
+        &self,
+        paths: &[PathBuf],
+        progress: &dyn UpdateProgress,
+        force: bool,
+        walked: &WalkedMetadata,
+    ) -> UpdateReport {
+        let report = Mutex::new(UpdateReport::default());
+        let record = |results: Vec<(PathBuf, FsCacheResult<UpdateOutcome>)>| match report.lock() {
+            Ok(mut report) => {
+                for (path, result) in results {
+                    report.record(path, result);
+                }
+            }
+            Err(_) => unreachable!(),
+        };
+
+        match &self.dependencies {
+            //outcomes are kept by path until the dependents have been processed again, so that
+            //a dependent is reported by its last outcome rather than counted twice.
+            Some(dependencies) => {
+                let outcomes = Mutex::new(HashMap::new());
+                let collect = |results: Vec<(PathBuf, FsCacheResult<UpdateOutcome>)>| match outcomes.lock() {
+                    Ok(mut outcomes) => outcomes.extend(results),
+                    Err(_) => unreachable!(),
+                };
+                self.update_each(paths, progress, force, walked, &collect);
+                self.update_dependents(dependencies, walked, &collect);
+                match outcomes.into_inner() {
+                    Ok(outcomes) => record(outcomes.into_iter().collect()),
+                    Err(_) => unreachable!(),
+                }
+            }
+            None => self.update_each(paths, progress, force, walked, &record),
+        }
+
+        let mut report = match report.into_inner() {
+            Ok(report) => report,
+            Err(_) => unreachable!(),
+        };
+        //the entries are evicted from memory even if the cache cannot be saved afterwards, which
+        //is reported again by the next save.
+        match self.evict_excess() {
+            Ok(evicted) => report.evicted = evicted,
+            Err(e) => {
+                warn!(target: "generic_cache_remove", "{}: failed to save after evicting entries: {}", self.interface.describe(), e)
+            }
+        }
+        self.refresh_aggregates();
+        report
+    }
+
+    //Counts an entry as used, so that it is evicted after those used less recently, and records
+    //when it was read. Failing to save the access times does not fail the read.
+    fn accessed(&self, key: &Path) {
+        if let Some(recency) = &self.recency {
+            recency.touch(key);
+        }
+        if let Some(access_log) = &self.access_log {
+            if let Err(e) = access_log.record(key) {
+                warn!(target: "generic_cache_transactions", "{}: failed to record access to {}: {}", self.interface.describe(), key.display(), e);
+            }
+        }
+    }
+
+    //Evicts the least recently used entries while there are more than the LRU capacity,
+    //returning how many were evicted. Any path counted as used after its entry was removed is
+    //forgotten, so that it is not chosen again.
+    fn evict_excess(&self) -> FsCacheResult<usize> {
+        let (max_entries, recency) = match (&self.lru_capacity, &self.recency) {
+            (Some(max_entries), Some(recency)) => (max_entries, recency),
+            _ => return Ok(0),
+        };
+        let mut evicted = 0;
+        loop {
+            let victims: HashSet<PathBuf> = match self.base_cache.len().saturating_sub(*max_entries) {
+                0 => break,
+                excess => recency.least_recent(excess).into_iter().collect(),
+            };
+            if victims.is_empty() {
+                break;
+            }
+            let removed = self.base_cache.retain(|key, _| !victims.contains(key));
+            for victim in &victims {
+                recency.forget(victim);
+            }
+            evicted += removed?;
+        }
+        if evicted > 0 {
+            self.stats.evicted(evicted);
+            info!(target: "generic_cache_remove", "{}: evicted {} least recently used entries", self.interface.describe(), evicted);
+        }
+        Ok(evicted)
+    }
+
+    //Processes every file depending on an entry which has changed since dependents were last
+    //brought up to date, whether or not the file itself has changed, and then the files
+    //depending on those in turn. Each file is processed at most once, even if they depend on
+    //each other in a cycle.
+    fn update_dependents(&self, dependencies: &DependencyGraph<I::T>, walked: &WalkedMetadata, record: RecordFn<'_>) {
+        let mut updated = HashSet::new();
+        loop {
+            let changed = dependencies.take_changed();
+            let dependents: Vec<PathBuf> = dependencies
+                .dependents_of(&changed)
+                .into_iter()
+                .filter(|path| !changed.contains(path) && !updated.contains(path))
+                .collect();
+            updated.extend(changed);
+            if dependents.is_empty() {
+                return;
+            }
+            info!(target: "generic_cache_update", "{}: {} files depend on changed files", self.interface.describe(), dependents.len());
+            self.update_each(&dependents, &(), true, walked, record);
+            updated.extend(dependents);
+        }
+    }
+
+    //Files are processed in parallel, and each worker inserts what it has processed in batches
+    //so that the workers are not all waiting on the cache's write lock for every file. The
+    //outcome for each file is passed to `record`.
+    fn update_each(
+        &self,
+        paths: &[PathBuf],
+        progress: &dyn UpdateProgress,
+        force: bool,
+        walked: &WalkedMetadata,
+        record: RecordFn<'_>,
+    ) {
+        let total = paths.len();
+        progress.discovered(total);
+        let done = AtomicUsize::new(0);
+
+        let update_all = || {
+            let linked;
+            let groups: Vec<&[PathBuf]> = match self.deduplicate_hard_links {
+                true => {
+                    linked = linked_groups(paths);
+                    linked.iter().map(Vec::as_slice).collect()
+                }
+                false => paths.iter().map(std::slice::from_ref).collect(),
+            };
+            groups
+                .into_par_iter()
+                .fold(Vec::new, |mut processed, group| {
+                    for (path, result) in group.iter().zip(self.update_linked(group, force, walked)) {
+                        match result {
+                            Ok((outcome, Some(cache_entry))) => processed.push((path.clone(), cache_entry, outcome)),
+                            result => record(vec![(path.clone(), result.map(|(outcome, _)| outcome))]),
+                        }
+                        progress.file_done(&Progress {
+                            path,
+                            done: done.fetch_add(1, Relaxed) + 1,
+                            total,
+                        });
+                    }
+
+                    if processed.len() >= INSERT_BATCH_SIZE {
+                        record(self.insert_processed(std::mem::take(&mut processed)));
+                    }
+                    processed
+                })
+                .for_each(|processed| record(self.insert_processed(processed)))
+        };
+
+        match &self.thread_pool {
+            Some(pool) => pool.install(update_all),
+            None => update_all(),
+        }
+    }
+
+    fn refresh_aggregates(&self) {
+        if let Some(aggregates) = &self.aggregates {
+            aggregates.refresh(|f| self.base_cache.for_each(|key, entry| f(key, &entry.value)));
+        }
+    }
+
+    //Inserts processed files, returning the outcome for each. If saving afterwards fails, the
+    //files are still cached, so the failure is logged rather than reported against any of them,
+    //and reported by the next save instead. If the files could not be inserted at all, the error
+    //is reported against the first of them, and the rest are reported as `BatchInsertFailed`.
+    fn insert_processed(
+        &self,
+        processed: Vec<(PathBuf, MtimeCacheEntry<I::T>, UpdateOutcome)>,
+    ) -> Vec<(PathBuf, FsCacheResult<UpdateOutcome>)> {
+        let entries = processed
+            .iter()
+            .map(|(path, cache_entry, _)| (path.clone(), cache_entry.clone()))
+            .collect();
+        match self.base_cache.insert_many_reporting_save(entries) {
+            Ok(save) => {
+                if let Err(e) = save {
+                    warn!(target: "generic_cache_transactions", "{}: failed to save after inserting {} entries: {}", self.interface.describe(), processed.len(), e);
+                }
+                self.stats.inserted(processed.len());
+                processed
+                    .into_iter()
+                    .map(|(path, _, outcome)| (path, Ok(outcome)))
+                    .collect()
+            }
+            Err(e) => {
+                //nothing is inserted from an empty batch, so it cannot fail.
+                let mut paths = processed.into_iter().map(|(path, _, _)| path);
+                let first = match paths.next() {
+                    Some(first) => first,
+                    None => unreachable!(),
+                };
+                let rest: Vec<(PathBuf, FsCacheResult<UpdateOutcome>)> = paths
+                    .map(|path| {
+                        let error = BatchInsertFailed {
+                            path: path.clone(),
+                            failed: first.clone(),
+                        };
+                        (path, Err(error))
+                    })
+                    .collect();
+                std::iter::once((first, Err(e))).chain(rest).collect()
+            }
+        }
+    }
+
+    //Like fetch_update, but without cloning the value out of the cache, and returning the entry
+    //to cache for the file (if it was processed) instead of inserting it. If forced, the file
+    //is processed even if it is unchanged or is a known failure.
+    fn update_entry(&self, key: &Path, force: bool, walked: &WalkedMetadata) -> EntryUpdate<I::T> {
+        let was_cached = self.contains_key(key);
+        let action = self.action_for(key, force, walked)?;
+        self.apply_update(key, was_cached, action, force, &mut None)
+    }
+
+    //Like update_entry for each of `keys`, which are hard links to the same file, processing the
+    //file at most once. The value cached for a link which has not changed, or else the value
+    //processed for the first link which needs it, is shared with every link which needs updating.
+    fn update_linked(&self, keys: &[PathBuf], force: bool, walked: &WalkedMetadata) -> Vec<EntryUpdate<I::T>> {
+        if let [key] = keys {
+            return vec![self.update_entry(key, force, walked)];
+        }
+
+        let actions: Vec<(bool, FsCacheResult<UpdateAction>)> = keys
+            .iter()
+            .map(|key| (self.contains_key(key), self.action_for(key, force, walked)))
+            .collect();
+        let mut linked = keys.iter().zip(&actions).find_map(|(key, (_, action))| match action {
+            Ok(UpdateAction::NoChange) => self.base_cache.fetch(key).ok(),
+            _ => None,
+        });
+        keys.iter()
+            .zip(actions)
+            .map(|(key, (was_cached, action))| self.apply_update(key, was_cached, action?, force, &mut linked))
+            .collect()
+    }
+
+    fn action_for(&self, key: &Path, force: bool, walked: &WalkedMetadata) -> FsCacheResult<UpdateAction> {
+        let fs_state = match walked.get(key) {
+            Some(metadata) => SourceMetadata::read_from_metadata(key, metadata.clone(), self.invalidation_strategy),
+            None => self.fs_state(key),
+        };
+        match force {
+            //with no cached state to compare against, an existing file always needs processing.
+            true => update_action(key, self.invalidation_strategy, fs_state, None),
+            false => self.update_action_against(key, fs_state),
+        }
+    }
+
+    //Carries out `action` for `key`. If `linked` holds the entry of another hard link to the
+    //file, its value is used rather than processing the file, and otherwise it is given the entry
+    //if the file is processed.
+    fn apply_update(
+        &self,
+        key: &Path,
+        was_cached: bool,
+        action: UpdateAction,
+        force: bool,
+        linked: &mut Option<MtimeCacheEntry<I::T>>,
+    ) -> EntryUpdate<I::T> {
+        match action {
+            UpdateAction::NoChange => {
+                self.stats.hit();
+                Ok((UpdateOutcome::Unchanged, None))
+            }
+            UpdateAction::Update(source, _) if !force && self.check_known_failure(key, &source).is_err() => {
+                Ok((UpdateOutcome::Skipped, None))
+            }
+            UpdateAction::Update(source, metadata) => {
+                let cache_entry = match linked {
+                    Some(linked) => {
+                        self.stats.hit();
+                        self.failures.forget(key)?;
+                        linked.linked(source)
+                    }
+                    None => {
+                        if !force {
+                            self.stats.miss();
+                        }
+                        let cache_entry = self.process_entry(key, source, &metadata)?;
+                        *linked = Some(cache_entry.clone());
+                        cache_entry
+                    }
+                };
+                let outcome = match was_cached {
+                    true => UpdateOutcome::Reprocessed,
+                    false => UpdateOutcome::Processed,
+                };
+                Ok((outcome, Some(cache_entry)))
+            }
+            //a file can disappear between being found and being looked at.
+            UpdateAction::Remove if !was_cached => self.failures.forget(key).map(|_| (UpdateOutcome::Unchanged, None)),
+            UpdateAction::Remove => self.remove(key).map(|_| (UpdateOutcome::Removed, None)),
+        }
+    }
+
+    //What update_entry would do, without doing it.
+    fn plan_entry(&self, key: &Path) -> FsCacheResult<UpdateOutcome> {
+        let was_cached = self.contains_key(key);
+        Ok(match self.get_update_action(key)? {
+            UpdateAction::NoChange => UpdateOutcome::Unchanged,
+            UpdateAction::Update(source, _) if self.check_known_failure(key, &source).is_err() => {
+                UpdateOutcome::Skipped
+            }
+            UpdateAction::Update(..) if was_cached => UpdateOutcome::Reprocessed,
+            UpdateAction::Update(..) => UpdateOutcome::Processed,
+            UpdateAction::Remove if was_cached => UpdateOutcome::Removed,
+            UpdateAction::Remove => UpdateOutcome::Unchanged,
+        })
+    }
+
+    //Fails if the file failed to process before, has not changed since, and the retry
+    //policy says not to try again yet.
+    fn check_known_failure(&self, key: &Path, source: &SourceMetadata) -> FsCacheResult<()> {
+        match self
+            .failures
+            .known_failure(key, source, self.retry_policy, self.invalidation_strategy)
+        {
+            Some(failure) => Err(KnownFailure {
+                src: failure.error,
+                path: key.to_path_buf(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Write every cached path and value to `writer` as a JSON array of objects with `path`
+    /// and `value` fields, in path order. Paths which are not valid UTF-8 are written as the
+    /// cache file stores them, percent-encoded after a leading NUL character, as they are in CSV.
+    #[cfg(feature = "json")]
+    pub fn export_json(&self, writer: impl std::io::Write) -> FsCacheResult<()> {
+        self.exported_entries()?.write_json(writer)
+    }
+
+    /// Write every cached path and value to `writer` as CSV, in path order. The first column
+    /// is the path, followed by a column for each field of the values, with nested fields named
+    /// like `outer.inner`. Values which are not structs or maps are written to a `value` column.
+    #[cfg(feature = "json")]
+    pub fn export_csv(&self, writer: impl std::io::Write) -> FsCacheResult<()> {
+        self.exported_entries()?.write_csv(writer)
+    }
+
+    #[cfg(feature = "json")]
+    fn exported_entries(&self) -> FsCacheResult<crate::export::ExportedEntries> {
+        let mut entries = crate::export::ExportedEntries::new();
+        let mut result = Ok(());
+        self.base_cache.for_each(|key, entry| {
+            if result.is_ok() {
+                result = entries.push(key, &entry.value);
+            }
+        });
+        result.map(|_| entries)
+    }
+
+    /// Hit, miss and processing counts since the cache was created or [`Self::reset_stats`] was last called.
+    pub fn stats(&self) -> CacheStats {
+        self.stats.snapshot()
+    }
+
+    pub fn reset_stats(&self) {
+        self.stats.reset()
     }
 
     pub fn contains_key(&self, key: &Path) -> bool {
-        self.base_cache.contains_key(key)
+        self.base_cache.contains_key(&self.key(key))
     }
 
     pub fn keys(&self) -> Vec<PathBuf> {
         self.base_cache.keys()
     }
 
+    /// Like [`Self::keys`], but sorted, so that anything built from them is the same from run to run.
+    pub fn sorted_keys(&self) -> Vec<PathBuf> {
+        let mut keys = self.base_cache.keys();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Call `f` with every cached path and value, without cloning either. This holds the
+    /// cache's read lock, so `f` must not modify the cache or it will deadlock.
+    pub fn for_each(&self, mut f: impl FnMut(&Path, &I::T)) {
+        self.base_cache.for_each(|key, entry| f(key, &entry.value))
+    }
+
+    /// The cached paths beneath `dir`.
+    pub fn keys_under(&self, dir: &Path) -> Vec<PathBuf> {
+        let dir = self.key(dir);
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, _| {
+            if key.starts_with(&dir) {
+                ret.push(key.to_path_buf());
+            }
+        });
+        ret
+    }
+
+    /// The cached paths and values beneath `dir`.
+    pub fn entries_under(&self, dir: &Path) -> Vec<(PathBuf, I::T)> {
+        let dir = self.key(dir);
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, entry| {
+            if key.starts_with(&dir) {
+                ret.push((key.to_path_buf(), I::T::clone(&entry.value)));
+            }
+        });
+        ret
+    }
+
+    /// The cached paths whose path and value satisfy `f`.
+    pub fn find(&self, mut f: impl FnMut(&Path, &I::T) -> bool) -> Vec<PathBuf> {
+        let mut ret = vec![];
+        self.base_cache.for_each(|key, entry| {
+            if f(key, &entry.value) {
+                ret.push(key.to_path_buf());
+            }
+        });
+        ret
+    }
+
+    /// Like [`Self::find`], but tests entries in parallel, on the dedicated thread pool if one
+    /// was configured with [`ProcessingFsCacheBuilder::worker_threads`].
+    pub fn par_find(&self, f: impl Fn(&Path, &I::T) -> bool + Sync) -> Vec<PathBuf> {
+        let find_all = || self.base_cache.par_find(|key, entry| f(key, &entry.value));
+        match &self.thread_pool {
+            Some(pool) => pool.install(find_all),
+            None => find_all(),
+        }
+    }
+
+    /// The cached paths whose value equals `value`, in no particular order. This is a lookup if
+    /// values are indexed with [`ProcessingFsCacheBuilder::index_values`], and otherwise every
+    /// entry is looked at, as by [`Self::par_find`].
+    pub fn paths_with_value(&self, value: &I::T) -> Vec<PathBuf>
+    where
+        I::T: PartialEq,
+    {
+        match &self.value_index {
+            Some(index) => index.paths_with_value(value),
+            None => self.par_find(|_, cached| cached == value),
+        }
+    }
+
+    /// The value folded for a directory from the values of the cached files beneath it, or None
+    /// if there are none or directories are not given values, as chosen with
+    /// [`ProcessingFsCacheBuilder::aggregate_directories`]. Entries changed since the last
+    /// update are folded in first.
+    pub fn directory_value(&self, dir: impl AsRef<Path>) -> Option<I::T> {
+        let aggregates = self.aggregates.as_ref()?;
+        if aggregates.is_stale() {
+            self.refresh_aggregates();
+        }
+        aggregates.get(&self.key(dir.as_ref()))
+    }
+
+    /// The paths evicted to keep the cache file within
+    /// [`ProcessingFsCacheBuilder::max_disk_size`] since this was last called, in the order
+    /// they were evicted.
+    pub fn take_evicted(&self) -> Vec<PathBuf> {
+        self.base_cache.take_evicted()
+    }
+
     pub fn len(&self) -> usize {
         self.base_cache.len()
     }
@@ -112,58 +2043,147 @@ where
         self.base_cache.is_empty()
     }
 
-    fn fs_mtime(key: &Path) -> Result<SystemTime, std::io::Error> {
-        fs::metadata(&key)?.modified()
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        self.key_normalizer.key(path)
+    }
+
+    //Shares the value of a new entry with any equal value already cached, if values are interned.
+    fn interned(&self, mut entry: MtimeCacheEntry<I::T>) -> MtimeCacheEntry<I::T> {
+        if let Some(interner) = &self.interner {
+            entry.value = interner.intern(entry.value);
+        }
+        entry
+    }
+
+    fn interned_value(&self, value: I::T) -> Arc<I::T> {
+        match &self.interner {
+            Some(interner) => interner.intern(Arc::new(value)),
+            None => Arc::new(value),
+        }
+    }
+
+    fn fs_metadata(&self, key: &Path) -> Result<SourceMetadata, std::io::Error> {
+        SourceMetadata::read(key, self.invalidation_strategy)
+    }
+
+    fn fs_state(&self, key: &Path) -> Result<(SourceMetadata, Metadata), std::io::Error> {
+        SourceMetadata::read_with_metadata(key, self.invalidation_strategy)
     }
 
-    // helper function to get whether a particular path has been updated in the filesystem.
-    // Contains a hacky workaround for a problem where SSHFS (and presumably FUSE underneath)
-    // reports different mtimes for files compared to a backing BTRFS filesystem (FUSE/sshfs probably
-    // reports less granular mtimes?), where a file will only be considered stale if the mtime
-    // is different by more than DURATION_TOLERANCE.
+    //An expired entry is compared as if it were not cached, so that it is always processed again.
     fn get_update_action(&self, key: &Path) -> FsCacheResult<UpdateAction> {
-        // debug: switch between ignoring nanos and not (current  workaround for nanos-difference might be causing issues?)
-        let include_nanos = false;
-
-        //If the path is not present on the filesystem, then remove it from the cache
-        //(it may have never existed in the cache but this is OK)
-        let fs_mtime = match Self::fs_mtime(key) {
-            Ok(fs_mtime) => fs_mtime,
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::NotFound => return Ok(UpdateAction::Remove),
-                _ => {
-                    return Err(CacheFileIo {
-                        path: key.to_path_buf(),
-                        src: e,
-                    })
-                }
-            },
-        };
+        self.update_action_against(key, self.fs_state(key))
+    }
 
-        //if the file exists on the filesystem but not in the cache, we will insert it.
-        let cache_mtime = match self.base_cache.fetch(key) {
-            Ok(entry) => entry.cache_mtime,
-            Err(_e) => return Ok(UpdateAction::Update(fs_mtime)),
-        };
+    fn update_action_against(
+        &self,
+        key: &Path,
+        fs_state: Result<(SourceMetadata, Metadata), std::io::Error>,
+    ) -> FsCacheResult<UpdateAction> {
+        let cache_source = self
+            .base_cache
+            .fetch(key)
+            .ok()
+            .filter(|entry| !is_expired(&self.time_to_live, key, entry))
+            .map(|entry| entry.source);
+        update_action(key, self.invalidation_strategy, fs_state, cache_source)
+    }
+}
 
-        //otherwise, see if the file is changed...
-        let is_stale = if include_nanos {
-            //original implementation used the following code, which produced errors as SystemTime::duration_since
-            //appears to return an error if only the nanos portion of the fields differ
-            fs_mtime != cache_mtime
-        } else {
-            // To fix the problem the durations are converted seconds since unix epoch.
-            const DURATION_TOLERANCE_SECS: i64 = 2;
-            let cache_mtime_secs = cache_mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
-            let fs_mtime_secs = fs_mtime.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+impl<I, S> SharedWalkCache for ProcessingFsCache<I, S>
+where
+    I: CacheInterface + Send + Sync,
+    I::T: 'static,
+    S: BuildHasher + Default + Send + Sync + 'static,
+{
+    //The walk is of the file set as given, so with a key normalizer the metadata of files whose
+    //keys differ from their walked paths is read again.
+    fn update_from_walk(&self, file_set: &FileSet, walk: &SharedWalk) -> FsCacheResult<UpdateReport> {
+        let walked = self.walked_keys(file_set, walk.enumeration());
+        self.update_walked(file_set, walked, &(), walk.metadata())
+    }
+}
 
-            (cache_mtime_secs - fs_mtime_secs).abs() > DURATION_TOLERANCE_SECS
-        };
+//Passes only the processed value of each entry on to the user's observer.
+struct ValueObserver<T>(Box<dyn CacheObserver<T>>);
 
-        if is_stale {
-            Ok(UpdateAction::Update(fs_mtime))
-        } else {
-            Ok(UpdateAction::NoChange)
+impl<T> CacheObserver<MtimeCacheEntry<T>> for ValueObserver<T> {
+    fn on_insert(&self, path: &Path, entry: &MtimeCacheEntry<T>) {
+        self.0.on_insert(path, &entry.value)
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.0.on_remove(path)
+    }
+
+    fn on_save(&self) {
+        self.0.on_save()
+    }
+
+    fn on_load(&self, entries: usize) {
+        self.0.on_load(entries)
+    }
+}
+
+//Files under a directory which could not be walked were not seen, but have not necessarily been deleted.
+pub(crate) fn unwalked_keys_removed(cached_keys: Vec<PathBuf>, walk_errors: &[WalkError]) -> Vec<PathBuf> {
+    cached_keys
+        .into_iter()
+        .filter(|key| !walk_errors.iter().any(|e| key.starts_with(&e.path)))
+        .collect()
+}
+
+//Cached paths which were not seen during a walk of the filesystem have probably been deleted.
+//They must be revisited too so that they are removed from the cache.
+pub(crate) fn missing_paths(file_set: &FileSet, fs_paths: &[PathBuf], cached_keys: Vec<PathBuf>) -> Vec<PathBuf> {
+    let fs_path_set: HashSet<&PathBuf> = fs_paths.iter().collect();
+    cached_keys
+        .into_iter()
+        .filter(|key| file_set.includes(key) && !fs_path_set.contains(key))
+        .collect()
+}
+
+//Stores entries in `backend`, interning their values if there is an interner, or otherwise
+//storing large values in blobs if there is a threshold for them.
+fn entry_backend<T, S, B>(
+    backend: B,
+    interner: &Option<Arc<dyn Intern<T>>>,
+    blobs: &Option<(u64, FileBackend)>,
+) -> Box<dyn StorageBackend<MtimeCacheEntry<T>, S>>
+where
+    T: DeserializeOwned + Serialize + Send + Sync + 'static,
+    S: BuildHasher + Default + Send + Sync + 'static,
+    B: StorageBackend<MtimeCacheEntry<T>, S>
+        + StorageBackend<InternedEntry<T>, S>
+        + StorageBackend<BlobEntry<T>, S>
+        + 'static,
+{
+    match (interner, blobs) {
+        (Some(interner), _) => Box::new(InterningBackend::new(Box::new(backend), interner.clone())),
+        (None, Some((threshold, file_backend))) => {
+            Box::new(BlobBackend::new(Box::new(backend), file_backend.clone(), *threshold))
         }
+        (None, None) => Box::new(backend),
     }
 }
+
+//Renamed files are visited neither at their old path nor at their new one.
+pub(crate) fn take_renamed(renames: &[(PathBuf, PathBuf)], found: &mut Vec<PathBuf>, missing: &mut Vec<PathBuf>) {
+    if renames.is_empty() {
+        return;
+    }
+    let (moved_from, moved_to): (HashSet<&PathBuf>, HashSet<&PathBuf>) =
+        renames.iter().map(|(from, to)| (from, to)).unzip();
+    missing.retain(|path| !moved_from.contains(path));
+    found.retain(|path| !moved_to.contains(path));
+}
+
+//Chooses roughly `sample_rate` of all paths, differently for each RandomState.
+pub(crate) fn sampled(sampler: &RandomState, key: &Path, sample_rate: f64) -> bool {
+    sample_rate >= 1.0 || (sampler.hash_one(key) as f64 / u64::MAX as f64) < sample_rate
+}
+
+//Only a file which is definitely gone counts as missing, not one which cannot be looked at.
+pub(crate) fn is_missing(key: &Path) -> bool {
+    matches!(std::fs::metadata(key), Err(e) if e.kind() == std::io::ErrorKind::NotFound)
+}
@@ -0,0 +1,41 @@
+use std::path::Path;
+
+/// The state of an `update_from_fs` run, passed to [`UpdateProgress::file_done`].
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    /// The file which has just been brought up to date.
+    pub path: &'a Path,
+    /// The number of files brought up to date so far, including `path`.
+    pub done: usize,
+    /// The number of files found which need checking.
+    pub total: usize,
+}
+
+impl Progress<'_> {
+    pub fn remaining(&self) -> usize {
+        self.total - self.done
+    }
+}
+
+/// Receives progress updates during `update_from_fs`. Files are processed in parallel, so
+/// methods may be called from several threads at once.
+///
+/// Any `Fn(&Progress)` closure can be used, receiving [`UpdateProgress::file_done`] calls.
+pub trait UpdateProgress: Send + Sync {
+    /// Called once the file set has been enumerated, with the number of files to check.
+    fn discovered(&self, _total: usize) {}
+
+    /// Called after each file has been checked, and processed if necessary.
+    fn file_done(&self, _progress: &Progress<'_>) {}
+}
+
+impl UpdateProgress for () {}
+
+impl<F> UpdateProgress for F
+where
+    F: Fn(&Progress<'_>) + Send + Sync,
+{
+    fn file_done(&self, progress: &Progress<'_>) {
+        self(progress)
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    errors::FsCacheResult,
+    format::ProcessorVersion,
+    storage::{CacheView, StorageBackend},
+};
+
+//Stores keys beneath `root` relative to it, and joins whatever root is configured back on when
+//loading, so that a cache can be moved along with the tree of files it describes. Keys which
+//are not beneath the root are stored as they are.
+pub(crate) struct RelativeBackend<T, S> {
+    backend: Box<dyn StorageBackend<T, S>>,
+    root: PathBuf,
+}
+
+impl<T, S> RelativeBackend<T, S>
+where
+    T: Clone,
+    S: BuildHasher + Default,
+{
+    pub(crate) fn new(backend: Box<dyn StorageBackend<T, S>>, root: PathBuf) -> Self {
+        Self { backend, root }
+    }
+
+    //The root itself would be stored as the empty path, which is not a path which can be joined
+    //back on to the root, and holds the values of a cache which interns them.
+    fn stored_key<'a>(&self, key: &'a Path) -> &'a Path {
+        match key.strip_prefix(&self.root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => key,
+        }
+    }
+
+    //Values are cloned, which is cheap for cache entries as they hold their values in an Arc.
+    fn stored_cache(&self, cache: &CacheView<'_, T, S>) -> HashMap<PathBuf, T, S> {
+        cache
+            .iter()
+            .map(|(key, value)| (self.stored_key(key).to_path_buf(), value.clone()))
+            .collect()
+    }
+}
+
+impl<T, S> StorageBackend<T, S> for RelativeBackend<T, S>
+where
+    T: Clone + Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, T, S>> {
+        //Joining an absolute key replaces the root, so keys stored as they were come back unchanged.
+        Ok(self
+            .backend
+            .load()?
+            .into_iter()
+            .map(|(key, value)| (self.root.join(key), value))
+            .collect())
+    }
+
+    fn save(&self, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        self.backend.save(&(&self.stored_cache(cache)).into())
+    }
+
+    fn save_changes(&self, cache: &CacheView<'_, T, S>, changed_keys: &[PathBuf]) -> FsCacheResult<()> {
+        let changed_keys: Vec<PathBuf> = changed_keys
+            .iter()
+            .map(|key| self.stored_key(key).to_path_buf())
+            .collect();
+        self.backend.save_changes(&(&self.stored_cache(cache)).into(), &changed_keys)
+    }
+
+    fn append(&self, changes: &[(&Path, Option<&T>)]) -> FsCacheResult<()> {
+        let changes: Vec<(&Path, Option<&T>)> = changes
+            .iter()
+            .map(|(key, value)| (self.stored_key(key), *value))
+            .collect();
+        self.backend.append(&changes)
+    }
+
+    fn taking_snapshot(&self) {
+        self.backend.taking_snapshot()
+    }
+
+    fn reset(&self) -> FsCacheResult<()> {
+        self.backend.reset()
+    }
+
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        self.backend.stored_version()
+    }
+
+    fn store_version(&self, version: ProcessorVersion, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        self.backend.store_version(version, &(&self.stored_cache(cache)).into())
+    }
+}
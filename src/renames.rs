@@ -0,0 +1,118 @@
+use std::{collections::HashMap, hash::BuildHasher, path::PathBuf, time::SystemTime};
+
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    base_fs_cache::BaseFsCache,
+    cache_entry::{is_expired, MtimeCacheEntry, TimeToLiveFn},
+    invalidation::{source_changed, FileId, InvalidationStrategy, SourceMetadata},
+    processing_fs_cache::is_missing,
+};
+
+//How a renamed file was recognised, from the most to the least certain.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Match {
+    Identity,
+    Content,
+    SizeAndMtime,
+}
+
+//What a file looks like without its path. A file is only recognised by its size and mtime if
+//its content is not hashed.
+#[derive(PartialEq, Eq, Hash)]
+enum Signature {
+    Content([u8; 32]),
+    SizeAndMtime(u64, SystemTime),
+}
+
+impl Signature {
+    fn of(strategy: InvalidationStrategy, source: &SourceMetadata) -> Option<Self> {
+        match strategy.needs_content_hash() {
+            true => source.content_hash.map(Self::Content),
+            false => Some(Self::SizeAndMtime(source.len, source.mtime)),
+        }
+    }
+}
+
+//Finds files which have been renamed or moved since they were cached, so that their entries can
+//be moved to the new path rather than the files being processed again. `missing` are the cached
+//paths which were not found by a walk, and `found` are the paths which were. A file is recognised
+//by its identity, or failing that by its content hash or its size and mtime, and only if it has
+//not otherwise changed. Returns pairs of old and new paths.
+pub(crate) fn find_renames<T, S>(
+    base_cache: &BaseFsCache<MtimeCacheEntry<T>, S>,
+    found: &[PathBuf],
+    missing: &[PathBuf],
+    strategy: InvalidationStrategy,
+    time_to_live: &Option<TimeToLiveFn<T>>,
+) -> Vec<(PathBuf, PathBuf)>
+where
+    T: DeserializeOwned + Serialize + Send + Sync + Clone,
+    S: BuildHasher + Default + Send + Sync,
+{
+    let gone: Vec<(&PathBuf, MtimeCacheEntry<T>)> = missing
+        .iter()
+        .filter_map(|key| {
+            let entry = base_cache.fetch(key).ok()?;
+            match is_expired(time_to_live, key, &entry) {
+                true => None,
+                false => Some((key, entry)),
+            }
+        })
+        .collect();
+    if gone.is_empty() {
+        return vec![];
+    }
+
+    let by_id: HashMap<FileId, (&PathBuf, &SourceMetadata)> = gone
+        .iter()
+        .filter_map(|(key, entry)| Some((entry.file_id?, (*key, &entry.source))))
+        .collect();
+    //Files which look the same as another missing file cannot be told apart, so are not recognised.
+    let mut by_signature: HashMap<Signature, Option<(&PathBuf, &SourceMetadata)>> = HashMap::new();
+    for (key, entry) in &gone {
+        if let Some(signature) = Signature::of(strategy, &entry.source) {
+            by_signature
+                .entry(signature)
+                .and_modify(|ambiguous| *ambiguous = None)
+                .or_insert(Some((*key, &entry.source)));
+        }
+    }
+
+    let mut renames: Vec<(PathBuf, Match, PathBuf)> = found
+        .par_iter()
+        .filter(|path| !base_cache.contains_key(path))
+        .filter_map(|path| {
+            let source = SourceMetadata::read(path, strategy).ok()?;
+            let ((old_path, old_source), how) = match source.file_id.and_then(|id| by_id.get(&id)) {
+                Some(old) => (*old, Match::Identity),
+                None => {
+                    let old = (*by_signature.get(&Signature::of(strategy, &source)?)?)?;
+                    match source.content_hash {
+                        Some(_) => (old, Match::Content),
+                        None => (old, Match::SizeAndMtime),
+                    }
+                }
+            };
+            match source_changed(strategy, &source, old_source) || !is_missing(old_path) {
+                true => None,
+                false => Some((old_path.clone(), how, path.clone())),
+            }
+        })
+        .collect();
+
+    //A file found at several new paths, such as one which was also hard linked or copied, is only
+    //moved to one of them, preferring the most certain match. Files which only share a size and
+    //mtime with several new files may not be the same as any of them, so are not moved.
+    renames.sort_unstable();
+    let mut ret: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for (n, (old_path, how, new_path)) in renames.iter().enumerate() {
+        let is_first = n == 0 || renames[n - 1].0 != *old_path;
+        let is_only = is_first && renames.get(n + 1).is_none_or(|next| next.0 != *old_path);
+        if is_first && (*how != Match::SizeAndMtime || is_only) {
+            ret.push((old_path.clone(), new_path.clone()));
+        }
+    }
+    ret
+}
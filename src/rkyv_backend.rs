@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    io::Write,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+use log::{info, trace};
+use rkyv::{
+    api::high::{HighDeserializer, HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    collections::swiss_table::{map::HashMapResolver, ArchivedHashMap},
+    rancor::{Error, Fallible, Source},
+    ser::{allocator::ArenaHandle, Allocator, Writer},
+    string::ArchivedString,
+    util::AlignedVec,
+    Archive, Deserialize, Place, Serialize,
+};
+
+use crate::{
+    errors::{FsCacheErrorKind::*, FsCacheResult},
+    format::{type_fingerprint, ProcessorVersion},
+    storage::{replace_file, sync_parent_dir, temp_path, CacheView, Durability, StorageBackend},
+    stored_path::{lossless_path, lossless_string},
+};
+
+//A file written by a `RkyvBackend` is `MAGIC`, a fingerprint of the value type, the length and
+//CRC32 of the archive, and the processor version and configuration fingerprint followed by a byte
+//which is 1 if they were stored, padded to `HEADER_LEN` so that the archive which follows is
+//aligned as rkyv requires once the file is mapped into memory.
+const MAGIC: [u8; 8] = *b"GFSRKYV\0";
+const HEADER_LEN: usize = 48;
+
+//The same load factor as rkyv uses for archiving a `HashMap`.
+const LOAD_FACTOR: (usize, usize) = (7, 8);
+
+type ArchivedEntries<T> = ArchivedHashMap<ArchivedString, <T as Archive>::Archived>;
+
+/// A value which can be stored by a [`RkyvBackend`]: one which rkyv can archive, check and
+/// deserialize, such as a type deriving rkyv's `Archive`, `Serialize` and `Deserialize`.
+pub trait RkyvValue:
+    Sized
+    + Archive<Archived: for<'a> CheckBytes<HighValidator<'a, Error>> + Deserialize<Self, HighDeserializer<Error>>>
+    + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>
+{
+}
+
+impl<T> RkyvValue for T where
+    T: Archive<Archived: for<'a> CheckBytes<HighValidator<'a, Error>> + Deserialize<T, HighDeserializer<Error>>>
+        + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>
+{
+}
+
+/// A storage backend which stores the cache as an rkyv archive, whose values can be used where
+/// they lie in the file, without being deserialized, through [`RkyvBackend::archived`]. Loading
+/// the cache into a [`crate::BaseFsCache`] still deserializes every value.
+///
+/// Values are stored with rkyv rather than serde, so must implement [`RkyvValue`] as well as the
+/// serde traits the cache needs. The whole archive is built in memory when saving, and is
+/// checked in full when it is opened, which is much faster than deserializing it.
+///
+/// The entries of a [`crate::ProcessingFsCache`] are [`crate::MtimeCacheEntry`]s, which are
+/// archived along with their values when the `rkyv` feature is enabled, so the cache can be
+/// stored by passing a `RkyvBackend<MtimeCacheEntry<T>>` to
+/// [`crate::ProcessingFsCacheBuilder::backend`]. Each archived entry gives its value through
+/// [`crate::ArchivedMtimeCacheEntry::value`].
+pub struct RkyvBackend<T> {
+    cache_path: PathBuf,
+    durability: Durability,
+    //Read from the cache file when loading, and written to it when saving.
+    processor_version: Mutex<Option<ProcessorVersion>>,
+    _values: PhantomData<fn() -> T>,
+}
+
+impl<T: RkyvValue> RkyvBackend<T> {
+    pub fn new(cache_path: PathBuf) -> Self {
+        Self {
+            cache_path,
+            durability: Durability::default(),
+            processor_version: Mutex::new(None),
+            _values: PhantomData,
+        }
+    }
+
+    pub fn cache_path(&self) -> &Path {
+        &self.cache_path
+    }
+
+    /// How sure saving is that the cache file has reached the disk. See [`Durability`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Map the cache file into memory, to look up its values without deserializing them. The
+    /// values are those last saved, whatever has changed since. A missing cache file is empty.
+    pub fn archived(&self) -> FsCacheResult<ArchivedCache<T>> {
+        ArchivedCache::open(&self.cache_path)
+    }
+
+    fn write(&self, archive: &[u8]) -> std::io::Result<()> {
+        if let Some(parent_dir) = self.cache_path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+
+        let processor_version = *self.lock_processor_version();
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&type_fingerprint::<T>().to_le_bytes());
+        header.extend_from_slice(&(archive.len() as u64).to_le_bytes());
+        header.extend_from_slice(&crc32fast::hash(archive).to_le_bytes());
+        let version = processor_version.unwrap_or_default();
+        header.extend_from_slice(&version.version.to_le_bytes());
+        header.extend_from_slice(&version.config_fingerprint.to_le_bytes());
+        header.push(processor_version.is_some() as u8);
+        header.resize(HEADER_LEN, 0);
+
+        let temp_store_path = temp_path(&self.cache_path);
+        let mut temp_cache_file = std::fs::File::create(&temp_store_path)?;
+        temp_cache_file.write_all(&header)?;
+        temp_cache_file.write_all(archive)?;
+        temp_cache_file.sync_all()?;
+        replace_file(&temp_store_path, &self.cache_path)?;
+
+        match self.durability {
+            Durability::FlushFileAndDirectory => sync_parent_dir(&self.cache_path),
+            Durability::FlushFile => Ok(()),
+        }
+    }
+
+    fn lock_processor_version(&self) -> MutexGuard<'_, Option<ProcessorVersion>> {
+        match self.processor_version.lock() {
+            Ok(processor_version) => processor_version,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<T, S> StorageBackend<T, S> for RkyvBackend<T>
+where
+    T: RkyvValue + Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, T, S>> {
+        let archived = self.archived()?;
+        *self.lock_processor_version() = archived.processor_version;
+        let mut ret = HashMap::with_capacity_and_hasher(archived.len(), S::default());
+        for (key, value) in archived.entries() {
+            let path = match lossless_path(key) {
+                Some(path) => path,
+                None => {
+                    return Err(Deserialization {
+                        src: format!("{:?} is not a valid key", key.as_str()),
+                        path: self.cache_path.clone(),
+                    })
+                }
+            };
+            match rkyv::deserialize::<T, Error>(value) {
+                Ok(value) => ret.insert(path, value),
+                Err(e) => {
+                    return Err(Deserialization {
+                        src: format!("{}", e),
+                        path: self.cache_path.clone(),
+                    })
+                }
+            };
+        }
+
+        trace!(target: "generic_cache_startup",
+            "Loaded rkyv cache. Path: {}, Entries: {}", self.cache_path.display(), ret.len()
+        );
+        Ok(ret)
+    }
+
+    fn save(&self, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        info!(target: "generic_cache_transactions",
+            "saving rkyv cache at {} of size {}", self.cache_path.display(), cache.len()
+        );
+        let entries: Vec<(String, &T)> = cache.iter().map(|(key, value)| (lossless_string(key), value)).collect();
+        let archive = rkyv::to_bytes::<Error>(&StoredEntries(&entries)).map_err(|e| Serialization {
+            src: format!("{}", e),
+            path: self.cache_path.clone(),
+        })?;
+
+        self.write(&archive).map_err(|e| CacheFileIo {
+            src: e,
+            path: self.cache_path.clone(),
+        })
+    }
+
+    fn reset(&self) -> FsCacheResult<()> {
+        info!(target: "generic_cache_transactions", "deleting cache file {}", self.cache_path.display());
+        match std::fs::remove_file(&self.cache_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        Ok(*self.lock_processor_version())
+    }
+
+    //The version is stored in the header, so the cache file is rewritten if it changes.
+    fn store_version(&self, version: ProcessorVersion, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        let previous = self.lock_processor_version().replace(version);
+        match previous.map_or(!cache.is_empty(), |previous| previous != version) {
+            true => StorageBackend::<T, S>::save(self, cache),
+            false => Ok(()),
+        }
+    }
+}
+
+/// The values of a cache saved by a [`RkyvBackend`], as they lie in its file, which is mapped
+/// into memory. Made by [`RkyvBackend::archived`].
+pub struct ArchivedCache<T> {
+    //None if there is no cache file.
+    map: Option<memmap2::Mmap>,
+    processor_version: Option<ProcessorVersion>,
+    _values: PhantomData<fn() -> T>,
+}
+
+impl<T: RkyvValue> ArchivedCache<T> {
+    fn open(cache_path: &Path) -> FsCacheResult<Self> {
+        let io_err = |e| CacheFileIo {
+            src: e,
+            path: cache_path.to_path_buf(),
+        };
+        let corrupt = |src: String| CorruptedCache {
+            src,
+            path: cache_path.to_path_buf(),
+        };
+
+        let cache_file = match std::fs::File::open(cache_path) {
+            Ok(cache_file) => cache_file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    map: None,
+                    processor_version: None,
+                    _values: PhantomData,
+                })
+            }
+            Err(e) => return Err(io_err(e)),
+        };
+        //SAFETY: a mapped file changing under us would change the values read from it. Cache
+        //files are never modified in place: they are always written beside the cache file and
+        //renamed over it, which leaves a file which is already open as it was.
+        let map = unsafe { memmap2::Mmap::map(&cache_file) }.map_err(io_err)?;
+
+        if map.len() < HEADER_LEN || map[..MAGIC.len()] != MAGIC {
+            return Err(IncompatibleCacheFile {
+                src: "the file was not written by a RkyvBackend".to_string(),
+                path: cache_path.to_path_buf(),
+            });
+        }
+        if read_u64(&map[8..16]) != type_fingerprint::<T>() {
+            return Err(IncompatibleCacheFile {
+                src: "the file holds a different value type".to_string(),
+                path: cache_path.to_path_buf(),
+            });
+        }
+
+        let archive = &map[HEADER_LEN..];
+        let expected_len = read_u64(&map[16..24]);
+        if expected_len != archive.len() as u64 {
+            return Err(corrupt(format!(
+                "expected {} bytes of data but found {}",
+                expected_len,
+                archive.len()
+            )));
+        }
+        let expected_crc32 = read_u32(&map[24..28]);
+        let actual_crc32 = crc32fast::hash(archive);
+        if expected_crc32 != actual_crc32 {
+            return Err(corrupt(format!(
+                "checksum mismatch (expected {:08x}, found {:08x})",
+                expected_crc32, actual_crc32
+            )));
+        }
+
+        //once checked, the archive is accessed without checking it again.
+        if let Err(e) = rkyv::access::<ArchivedEntries<T>, Error>(archive) {
+            return Err(corrupt(format!("{}", e)));
+        }
+
+        let processor_version = match map[40] {
+            0 => None,
+            _ => Some(ProcessorVersion {
+                version: read_u32(&map[28..32]),
+                config_fingerprint: read_u64(&map[32..40]),
+            }),
+        };
+
+        trace!(target: "generic_cache_startup", "Mapped rkyv cache. Path: {}", cache_path.display());
+        Ok(Self {
+            map: Some(map),
+            processor_version,
+            _values: PhantomData,
+        })
+    }
+
+    /// The archived value for a path, or None if it is not cached.
+    pub fn get(&self, key: &Path) -> Option<&T::Archived> {
+        let entries = self.entries_map()?;
+        match key.to_str() {
+            Some(key) => entries.get(key),
+            None => entries.get(lossless_string(key).as_str()),
+        }
+    }
+
+    pub fn contains_key(&self, key: &Path) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries_map().map_or(0, |entries| entries.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Every cached path along with its archived value, in no particular order. Paths which
+    /// cannot be decoded are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (PathBuf, &T::Archived)> {
+        self.entries()
+            .filter_map(|(key, value)| lossless_path(key).map(|path| (path, value)))
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&ArchivedString, &T::Archived)> {
+        self.entries_map().into_iter().flat_map(|entries| entries.iter())
+    }
+
+    fn entries_map(&self) -> Option<&ArchivedEntries<T>> {
+        let map = self.map.as_ref()?;
+        //SAFETY: the archive was checked when the file was opened, and the mapped file does
+        //not change (see `open`).
+        Some(unsafe { rkyv::access_unchecked::<ArchivedEntries<T>>(&map[HEADER_LEN..]) })
+    }
+}
+
+//The entries of a cache, archived as a map from the lossless string of each key to its value,
+//without first copying the values into a map of their own.
+struct StoredEntries<'a, T>(&'a [(String, &'a T)]);
+
+impl<T: Archive> Archive for StoredEntries<'_, T> {
+    type Archived = ArchivedEntries<T>;
+    type Resolver = HashMapResolver;
+
+    fn resolve(&self, resolver: Self::Resolver, out: Place<Self::Archived>) {
+        ArchivedHashMap::resolve_from_len(self.0.len(), LOAD_FACTOR, resolver, out);
+    }
+}
+
+impl<T, Se> Serialize<Se> for StoredEntries<'_, T>
+where
+    T: Serialize<Se>,
+    Se: Fallible + Writer + Allocator + ?Sized,
+    Se::Error: Source,
+{
+    fn serialize(&self, serializer: &mut Se) -> Result<Self::Resolver, Se::Error> {
+        ArchivedEntries::<T>::serialize_from_iter::<_, _, _, String, T, _>(
+            self.0.iter().map(|(key, value)| (key, *value)),
+            LOAD_FACTOR,
+            serializer,
+        )
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(bytes);
+    u32::from_le_bytes(buf)
+}
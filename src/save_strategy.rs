@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+/// A snapshot of the unsaved changes in a cache, passed to a [`SaveStrategy`] after every
+/// modification.
+#[derive(Debug, Clone, Copy)]
+pub struct DirtyState {
+    /// Number of inserts and removals since the last triggered save.
+    pub modifications: u32,
+    /// Approximate serialized size of the entries inserted, updated or removed since the last
+    /// triggered save, keys included. Only tracked if the strategy asks for it with
+    /// [`SaveStrategy::needs_dirty_bytes`].
+    pub dirty_bytes: u64,
+    /// Time since the cache was last saved.
+    pub since_last_save: Duration,
+}
+
+/// Decides when a cache automatically saves itself to disk.
+///
+/// A `u32` is a strategy that saves after that many modifications.
+pub trait SaveStrategy: Send + Sync {
+    /// Called after every modification. Returning true saves the cache.
+    fn should_save(&self, state: &DirtyState) -> bool;
+
+    /// Whether [`DirtyState::dirty_bytes`] should be tracked. This costs an extra serialization
+    /// of every inserted value, so is off by default.
+    fn needs_dirty_bytes(&self) -> bool {
+        false
+    }
+}
+
+impl SaveStrategy for u32 {
+    fn should_save(&self, state: &DirtyState) -> bool {
+        EveryNModifications(*self).should_save(state)
+    }
+}
+
+/// Save after every N modifications.
+#[derive(Debug, Clone, Copy)]
+pub struct EveryNModifications(pub u32);
+
+impl SaveStrategy for EveryNModifications {
+    fn should_save(&self, state: &DirtyState) -> bool {
+        state.modifications >= self.0
+    }
+}
+
+/// Save on the first modification made once the interval has passed since the last save.
+///
+/// Nothing is saved while the cache is idle. See `ProcessingFsCacheBuilder::autosave_interval`
+/// for that.
+#[derive(Debug, Clone, Copy)]
+pub struct EveryInterval(pub Duration);
+
+impl SaveStrategy for EveryInterval {
+    fn should_save(&self, state: &DirtyState) -> bool {
+        state.since_last_save >= self.0
+    }
+}
+
+/// Save once roughly N bytes of entries have changed, so that how often the cache is saved
+/// follows how much data would be lost rather than how many entries. Useful when the size of
+/// values varies a lot.
+///
+/// Sizes are estimated with bincode whichever codec the cache is saved with, and an entry
+/// changed twice counts twice.
+#[derive(Debug, Clone, Copy)]
+pub struct EveryNDirtyBytes(pub u64);
+
+impl SaveStrategy for EveryNDirtyBytes {
+    fn should_save(&self, state: &DirtyState) -> bool {
+        state.dirty_bytes >= self.0
+    }
+
+    fn needs_dirty_bytes(&self) -> bool {
+        true
+    }
+}
+
+/// Never save automatically. The cache is only saved by explicitly calling `save()` (or on drop).
+#[derive(Debug, Clone, Copy)]
+pub struct ManualOnly;
+
+impl SaveStrategy for ManualOnly {
+    fn should_save(&self, _state: &DirtyState) -> bool {
+        false
+    }
+}
+
+/// Save as soon as any of the contained strategies would.
+#[derive(Default)]
+pub struct Hybrid(Vec<Box<dyn SaveStrategy>>);
+
+impl Hybrid {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, strategy: impl SaveStrategy + 'static) -> Self {
+        self.0.push(Box::new(strategy));
+        self
+    }
+}
+
+impl SaveStrategy for Hybrid {
+    fn should_save(&self, state: &DirtyState) -> bool {
+        self.0.iter().any(|strategy| strategy.should_save(state))
+    }
+
+    fn needs_dirty_bytes(&self) -> bool {
+        self.0.iter().any(|strategy| strategy.needs_dirty_bytes())
+    }
+}
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering::Relaxed},
+};
+
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::{BincodeCodec, Codec},
+    errors::{
+        FsCacheErrorKind::{CacheFileIo, IncompatibleCacheFile},
+        FsCacheResult,
+    },
+    format::{path_fingerprint, ProcessorVersion},
+    storage::{CacheView, FileBackend, StorageBackend},
+};
+
+/// A storage backend which splits the cache between several files, chosen by a hash of each
+/// path, so that saving only rewrites the files holding changed entries. The files are loaded
+/// in parallel on rayon's global thread pool, and saved in parallel on threads of their own.
+pub struct ShardedFileBackend<C = BincodeCodec> {
+    //Only used for its lock and read-only status.
+    backend: FileBackend<C>,
+    shards: Vec<FileBackend<C>>,
+    //Set when the files were written with a different number of shards, so that the next
+    //save puts every entry in its proper place.
+    needs_rewrite: AtomicBool,
+}
+
+impl<C> ShardedFileBackend<C>
+where
+    C: Codec + Clone,
+{
+    /// Split the cache file of `backend` into `shard_count` files named `<cache_path>.shard<n>`,
+    /// each written with the codec and options of `backend` (such as encryption, load limits
+    /// and backups). The lock of `backend` is taken when loading, but its journal is not used.
+    pub fn new(backend: FileBackend<C>, shard_count: usize) -> Self {
+        let shards = (0..shard_count.max(1))
+            .map(|n| backend.sibling(shard_path(backend.cache_path(), n)))
+            .collect();
+        Self {
+            backend,
+            shards,
+            needs_rewrite: AtomicBool::new(false),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_of(&self, key: &Path) -> usize {
+        (path_fingerprint(key) % self.shards.len() as u64) as usize
+    }
+
+    //For reading the shards one at a time, which only finds entries in the shard they belong to.
+    pub(crate) fn into_shards(self) -> FsCacheResult<Vec<FileBackend<C>>> {
+        match self.stray_shards().is_empty() {
+            true => Ok(self.shards),
+            false => Err(IncompatibleCacheFile {
+                src: "the cache was saved with more shards".to_string(),
+                path: self.backend.cache_path().to_path_buf(),
+            }),
+        }
+    }
+
+    //Shard files beyond the current shard count, left by a cache which used to have more shards.
+    fn stray_shards(&self) -> Vec<FileBackend<C>> {
+        (self.shards.len()..)
+            .map(|n| shard_path(self.backend.cache_path(), n))
+            .take_while(|path| path.exists())
+            .map(|path| self.backend.sibling(path))
+            .collect()
+    }
+
+    //Write only the given shards, each with the entries which belong to it.
+    fn save_shards<T, S>(&self, cache: &CacheView<'_, T, S>, dirty: &[bool]) -> FsCacheResult<()>
+    where
+        T: Serialize + Send + Sync,
+        S: BuildHasher,
+    {
+        let mut entries: Vec<HashMap<&Path, &T>> = vec![HashMap::new(); self.shards.len()];
+        for (key, value) in cache.iter() {
+            let n = self.shard_of(key);
+            if dirty[n] {
+                entries[n].insert(key, value);
+            }
+        }
+        let dirty_shards: Vec<(&FileBackend<C>, HashMap<&Path, &T>)> = self
+            .shards
+            .iter()
+            .zip(entries)
+            .zip(dirty)
+            .filter(|(_, dirty)| **dirty)
+            .map(|(shard, _)| shard)
+            .collect();
+
+        info!(target: "generic_cache_transactions",
+            "saving {} of {} shards of {}",
+            dirty_shards.len(), self.shards.len(), self.backend.cache_path().display()
+        );
+
+        //The shards are written on threads of their own rather than on rayon's, as the cache is
+        //usually saved from one of rayon's workers while it is locked. A worker waiting for the
+        //shards would pick up other work meanwhile, such as processing a file whose value then
+        //waits for the lock, and every worker could be stuck waiting for it.
+        let thread_count = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let per_thread = dirty_shards.len().div_ceil(thread_count).max(1);
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = dirty_shards
+                .chunks(per_thread)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .try_for_each(|(shard, entries)| shard.save_entries::<T>(entries))
+                    })
+                })
+                .collect();
+            threads.into_iter().try_for_each(|thread| match thread.join() {
+                Ok(result) => result,
+                Err(panic) => std::panic::resume_unwind(panic),
+            })
+        })
+    }
+}
+
+impl<T, C, S> StorageBackend<T, S> for ShardedFileBackend<C>
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    C: Codec + Clone,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, T, S>> {
+        self.backend.acquire_lock()?;
+
+        //Each shard is also checked for entries which belong in another on the thread which read
+        //it, leaving only the merging of the shards to a single thread.
+        let stray_shards = self.stray_shards();
+        let loaded: Vec<HashMap<PathBuf, T, S>> = self
+            .shards
+            .par_iter()
+            .chain(stray_shards.par_iter())
+            .enumerate()
+            .map(|(n, shard)| {
+                let loaded = StorageBackend::<T, S>::load(shard)?;
+                if loaded.keys().any(|key| self.shard_of(key) != n) {
+                    self.needs_rewrite.store(true, Relaxed);
+                }
+                Ok(loaded)
+            })
+            .collect::<FsCacheResult<_>>()?;
+
+        let mut cache = HashMap::with_capacity_and_hasher(loaded.iter().map(HashMap::len).sum(), S::default());
+        for shard in loaded {
+            cache.extend(shard);
+        }
+
+        //shards which did not exist yet must be written with the same version as the others.
+        if let Some(version) = StorageBackend::<T, S>::stored_version(self)? {
+            for shard in &self.shards {
+                if StorageBackend::<T, S>::stored_version(shard)?.is_none() {
+                    shard.set_processor_version(version);
+                }
+            }
+        }
+
+        if self.needs_rewrite.load(Relaxed) {
+            warn!(target: "generic_cache_startup",
+                "{} was written with a different number of shards, so will be rewritten when saved",
+                self.backend.cache_path().display()
+            );
+        }
+        Ok(cache)
+    }
+
+    fn save(&self, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        if self.backend.is_read_only() {
+            return Ok(());
+        }
+
+        self.save_shards(cache, &vec![true; self.shards.len()])?;
+        for stray in self.stray_shards() {
+            std::fs::remove_file(stray.cache_path()).map_err(|e| CacheFileIo {
+                src: e,
+                path: stray.cache_path().to_path_buf(),
+            })?;
+        }
+        self.needs_rewrite.store(false, Relaxed);
+        Ok(())
+    }
+
+    fn save_changes(&self, cache: &CacheView<'_, T, S>, changed_keys: &[PathBuf]) -> FsCacheResult<()> {
+        if self.needs_rewrite.load(Relaxed) {
+            return StorageBackend::<T, S>::save(self, cache);
+        }
+        if self.backend.is_read_only() {
+            return Ok(());
+        }
+
+        let mut dirty = vec![false; self.shards.len()];
+        for key in changed_keys {
+            dirty[self.shard_of(key)] = true;
+        }
+        self.save_shards(cache, &dirty)
+    }
+
+    //Shards which do not exist yet have no version, so any shard which does will do.
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        for shard in &self.shards {
+            if let Some(version) = StorageBackend::<T, S>::stored_version(shard)? {
+                return Ok(Some(version));
+            }
+        }
+        Ok(None)
+    }
+
+    fn store_version(&self, version: ProcessorVersion, cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        let mut changed = false;
+        for shard in &self.shards {
+            changed |= shard.set_processor_version(version) != Some(version);
+        }
+        match changed && !cache.is_empty() {
+            true => StorageBackend::<T, S>::save(self, cache),
+            false => Ok(()),
+        }
+    }
+}
+
+fn shard_path(cache_path: &Path, n: usize) -> PathBuf {
+    let mut path = cache_path.to_path_buf().into_os_string();
+    path.push(format!(".shard{}", n));
+    path.into()
+}
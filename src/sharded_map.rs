@@ -0,0 +1,321 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, Hash},
+    sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use rayon::prelude::*;
+
+use crate::{cache_key::CacheKey, storage::CacheView};
+
+//The number of maps the entries of a cache are split between. Enough that threads inserting
+//entries at once rarely want the same one, and few enough that locking every one of them, as
+//saving does, is cheap.
+const SHARD_COUNT: usize = 64;
+
+type Shard<T, S, K> = Arc<HashMap<K, T, S>>;
+
+//The entries of a `BaseFsCache`, split between several maps chosen by a hash of each key, each
+//behind a lock of its own so that threads modifying different entries do not wait for each other.
+//Anything which locks more than one shard locks them in ascending order, so that it can never
+//deadlock with anything else doing so.
+//
+//Each shard is held in an `Arc`, so that a snapshot of every shard can be taken for saving while
+//they are locked only briefly. A shard which is still part of a snapshot is either copied before
+//it is modified, or waited for until the snapshot is dropped.
+pub(crate) struct ShardedMap<T, S, K> {
+    shards: Vec<RwLock<Shard<T, S, K>>>,
+    //Chooses the shard of each key. Kept rather than made when needed, as a default `RandomState`
+    //hashes differently every time.
+    hasher: S,
+    copy_on_write: bool,
+    //Notified whenever a snapshot is dropped, for writers waiting to modify a shard it held.
+    released: Mutex<()>,
+    released_signal: Condvar,
+}
+
+impl<T, S, K> ShardedMap<T, S, K>
+where
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    pub(crate) fn new(map: HashMap<K, T, S>) -> Self {
+        let hasher = S::default();
+        let mut shards: Vec<HashMap<K, T, S>> = (0..SHARD_COUNT).map(|_| HashMap::default()).collect();
+        for (key, value) in map {
+            shards[shard_index(&hasher, SHARD_COUNT, key.borrow())].insert(key, value);
+        }
+        Self {
+            shards: shards.into_iter().map(|shard| RwLock::new(Arc::new(shard))).collect(),
+            hasher,
+            copy_on_write: true,
+            released: Mutex::new(()),
+            released_signal: Condvar::new(),
+        }
+    }
+
+    //Whether a shard which is still part of a snapshot is copied before it is modified, rather
+    //than waited for until the snapshot is dropped.
+    pub(crate) fn set_copy_on_write(&mut self, copy_on_write: bool) {
+        self.copy_on_write = copy_on_write;
+    }
+
+    //The shard holding `key`, locked for reading.
+    pub(crate) fn read(&self, key: &K::Ref) -> RwLockReadGuard<'_, Shard<T, S, K>> {
+        match self.shards[shard_index(&self.hasher, self.shards.len(), key)].read() {
+            Ok(shard) => shard,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    pub(crate) fn read_all(&self) -> ReadShards<'_, T, S, K> {
+        ReadShards {
+            guards: self
+                .shards
+                .iter()
+                .map(|shard| match shard.read() {
+                    Ok(shard) => shard,
+                    Err(_) => unreachable!(),
+                })
+                .collect(),
+            map: self,
+        }
+    }
+
+    //The shards holding `keys`, locked for writing. Only entries under these keys can be modified.
+    pub(crate) fn write<'k>(&self, keys: impl IntoIterator<Item = &'k K::Ref>) -> WriteShards<'_, T, S, K>
+    where
+        K::Ref: 'k,
+    {
+        let mut wanted = vec![false; self.shards.len()];
+        for key in keys {
+            wanted[shard_index(&self.hasher, self.shards.len(), key)] = true;
+        }
+        self.write_shards(&wanted)
+    }
+
+    pub(crate) fn write_all(&self) -> WriteShards<'_, T, S, K> {
+        self.write_shards(&vec![true; self.shards.len()])
+    }
+
+    fn write_shards(&self, wanted: &[bool]) -> WriteShards<'_, T, S, K> {
+        WriteShards {
+            guards: self
+                .shards
+                .iter()
+                .zip(wanted)
+                .map(|(shard, wanted)| match wanted {
+                    true => Some(self.lock_for_writing(shard)),
+                    false => None,
+                })
+                .collect(),
+            hasher: &self.hasher,
+        }
+    }
+
+    //Without copying on write, waits until no snapshot holds the shard. Snapshots are only taken
+    //while every shard is read locked, so once none holds it, none can until it is unlocked.
+    fn lock_for_writing<'a>(
+        &self,
+        shard: &'a RwLock<Shard<T, S, K>>,
+    ) -> RwLockWriteGuard<'a, Shard<T, S, K>> {
+        loop {
+            let shard = match shard.write() {
+                Ok(shard) => shard,
+                Err(_) => unreachable!(),
+            };
+            if self.copy_on_write || Arc::strong_count(&shard) == 1 {
+                return shard;
+            }
+
+            //A snapshot is dropped before `released` is locked to notify its release, so it cannot
+            //be released unnoticed between checking it again here and waiting.
+            let released = match self.released.lock() {
+                Ok(released) => released,
+                Err(_) => unreachable!(),
+            };
+            if Arc::strong_count(&shard) == 1 {
+                return shard;
+            }
+            drop(shard);
+            match self.released_signal.wait(released) {
+                Ok(_) => (),
+                Err(_) => unreachable!(),
+            }
+        }
+    }
+
+    //Entries may be modified while the shards are counted, so this is only exact when nothing is.
+    pub(crate) fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| match shard.read() {
+                Ok(shard) => shard.len(),
+                Err(_) => unreachable!(),
+            })
+            .sum()
+    }
+
+    //Each shard is only locked while it is searched, by the rayon job searching it, so that a rayon
+    //worker never waits for other jobs while holding a lock those jobs may want.
+    pub(crate) fn par_find(&self, f: impl Fn(&K::Ref, &T) -> bool + Sync) -> Vec<K>
+    where
+        T: Send + Sync,
+        S: Send + Sync,
+    {
+        self.shards
+            .par_iter()
+            .flat_map_iter(|shard| {
+                let shard = match shard.read() {
+                    Ok(shard) => shard,
+                    Err(_) => unreachable!(),
+                };
+                shard
+                    .iter()
+                    .filter(|(key, item)| f((*key).borrow(), item))
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<K>>()
+            })
+            .collect()
+    }
+}
+
+impl<T, S, K> Default for ShardedMap<T, S, K>
+where
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    fn default() -> Self {
+        Self::new(HashMap::default())
+    }
+}
+
+//Every shard, locked for reading.
+pub(crate) struct ReadShards<'a, T, S, K> {
+    guards: Vec<RwLockReadGuard<'a, Shard<T, S, K>>>,
+    map: &'a ShardedMap<T, S, K>,
+}
+
+impl<'a, T, S, K> ReadShards<'a, T, S, K>
+where
+    S: BuildHasher,
+    K: CacheKey,
+{
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &T)> {
+        self.guards.iter().flat_map(|shard| shard.iter())
+    }
+
+    //Shares every shard with the snapshot, and unlocks them.
+    pub(crate) fn into_snapshot(self) -> Snapshot<'a, T, S, K> {
+        Snapshot {
+            shards: self.guards.iter().map(|shard| Arc::clone(shard)).collect(),
+            map: self.map,
+        }
+    }
+}
+
+//Every shard as it was when the snapshot was taken, however the cache has been modified since.
+pub(crate) struct Snapshot<'a, T, S, K> {
+    shards: Vec<Shard<T, S, K>>,
+    map: &'a ShardedMap<T, S, K>,
+}
+
+impl<T, S, K> Snapshot<'_, T, S, K>
+where
+    S: BuildHasher,
+    K: CacheKey,
+{
+    pub(crate) fn view(&self) -> CacheView<'_, T, S, K> {
+        CacheView::sharded(self.shards.iter().map(|shard| &**shard).collect(), &self.map.hasher)
+    }
+}
+
+impl<T, S, K> Drop for Snapshot<'_, T, S, K> {
+    fn drop(&mut self) {
+        self.shards.clear();
+        let _released = match self.map.released.lock() {
+            Ok(released) => released,
+            Err(_) => unreachable!(),
+        };
+        self.map.released_signal.notify_all();
+    }
+}
+
+//Some of the shards, locked for writing.
+pub(crate) struct WriteShards<'a, T, S, K> {
+    guards: Vec<Option<RwLockWriteGuard<'a, Shard<T, S, K>>>>,
+    hasher: &'a S,
+}
+
+impl<T, S, K> WriteShards<'_, T, S, K>
+where
+    T: Clone,
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    //Only keys in the shards which were locked may be looked up.
+    fn shard(&self, key: &K::Ref) -> &HashMap<K, T, S> {
+        match &self.guards[shard_index(self.hasher, self.guards.len(), key)] {
+            Some(shard) => shard,
+            None => unreachable!(),
+        }
+    }
+
+    //Copies the shard first if a snapshot still holds it.
+    fn shard_mut(&mut self, key: &K::Ref) -> &mut HashMap<K, T, S> {
+        let index = shard_index(self.hasher, self.guards.len(), key);
+        match &mut self.guards[index] {
+            Some(shard) => unshared(shard),
+            None => unreachable!(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &K::Ref) -> Option<&T> {
+        self.shard(key).get(key)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &K::Ref) -> Option<&mut T> {
+        self.shard_mut(key).get_mut(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, item: T) -> Option<T> {
+        self.shard_mut(key.borrow()).insert(key, item)
+    }
+
+    pub(crate) fn remove(&mut self, key: &K::Ref) -> Option<T> {
+        self.shard_mut(key).remove(key)
+    }
+
+    //The entries of the locked shards.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &T)> {
+        self.guards.iter().flatten().flat_map(|shard| shard.iter())
+    }
+
+    //Shards which are shared with a snapshot are replaced rather than copied.
+    pub(crate) fn clear(&mut self) {
+        for shard in self.guards.iter_mut().flatten() {
+            **shard = Arc::default();
+        }
+    }
+}
+
+//The hasher of a copy need not be the same as the original's, as keys are hashed to choose a
+//shard by the hasher of the `ShardedMap`.
+fn unshared<T, S, K>(shard: &mut Shard<T, S, K>) -> &mut HashMap<K, T, S>
+where
+    T: Clone,
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    if Arc::get_mut(shard).is_none() {
+        *shard = Arc::new(shard.iter().map(|(key, item)| (key.clone(), item.clone())).collect());
+    }
+    match Arc::get_mut(shard) {
+        Some(shard) => shard,
+        None => unreachable!(),
+    }
+}
+
+pub(crate) fn shard_index<S: BuildHasher, Q: Hash + ?Sized>(hasher: &S, shard_count: usize, key: &Q) -> usize {
+    (hasher.hash_one(key) % shard_count as u64) as usize
+}
@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{info, trace};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    errors::{FsCacheErrorKind::*, FsCacheResult},
+    format::ProcessorVersion,
+    storage::{CacheView, StorageBackend},
+    stored_path::{LoadedPath, StoredPath},
+};
+
+const META_TREE: &str = "generic_filesystem_cache_meta";
+const PROCESSOR_VERSION_KEY: &[u8] = b"processor_version";
+
+//Upgrades a stored value which could not be deserialized.
+type ValueMigrationFn = dyn Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync;
+
+/// A storage backend using the sled embedded database. Every insertion and removal is
+/// written to the database as it happens, so at most a fraction of a second of changes
+/// will be lost if the application crashes, no matter the cache's save strategy.
+pub struct SledBackend {
+    db: sled::Db,
+    cache_path: PathBuf,
+    migration: Option<Arc<ValueMigrationFn>>,
+}
+
+impl SledBackend {
+    pub fn open(cache_path: PathBuf) -> FsCacheResult<Self> {
+        match sled::open(&cache_path) {
+            Ok(db) => Ok(Self {
+                db,
+                cache_path,
+                migration: None,
+            }),
+            Err(e) => Err(Backend {
+                src: format!("{}", e),
+                path: cache_path,
+            }),
+        }
+    }
+
+    /// Supply a hook to upgrade stored values which cannot be deserialized, such as those
+    /// written by an older version of this crate. Upgraded values are written back to the database.
+    pub fn with_migration(
+        mut self,
+        migration: impl Fn(&[u8]) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.migration = Some(Arc::new(migration));
+        self
+    }
+
+    //Deserialize a stored value, upgrading it first if it cannot be read as-is.
+    fn decode_value<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<(T, Option<Vec<u8>>), String> {
+        let e = match bincode::deserialize(bytes) {
+            Ok(value) => return Ok((value, None)),
+            Err(e) => format!("{}", e),
+        };
+        let migration = match &self.migration {
+            Some(migration) => migration,
+            None => return Err(e),
+        };
+        let migrated = migration(bytes)?;
+        let value = bincode::deserialize(&migrated).map_err(|e| format!("{}", e))?;
+        Ok((value, Some(migrated)))
+    }
+
+    fn backend_err(&self, e: impl std::fmt::Display) -> crate::errors::FsCacheErrorKind {
+        Backend {
+            src: format!("{}", e),
+            path: self.cache_path.clone(),
+        }
+    }
+}
+
+impl<T, S> StorageBackend<T, S> for SledBackend
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    S: BuildHasher + Default + Send + Sync,
+{
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, T, S>> {
+        let mut ret = HashMap::default();
+        let mut migrated = sled::Batch::default();
+
+        for item in self.db.iter() {
+            let (key, value) = item.map_err(|e| self.backend_err(e))?;
+
+            let decoded = bincode::deserialize::<LoadedPath>(&key)
+                .map_err(|e| format!("{}", e))
+                .and_then(|LoadedPath(path)| Ok((path, self.decode_value(&value)?)));
+            match decoded {
+                Ok((path, (value, upgraded))) => {
+                    if let Some(upgraded) = upgraded {
+                        migrated.insert(key, upgraded);
+                    }
+                    ret.insert(path, value);
+                }
+                Err(src) => {
+                    return Err(Deserialization {
+                        src,
+                        path: self.cache_path.clone(),
+                    })
+                }
+            }
+        }
+        self.db.apply_batch(migrated).map_err(|e| self.backend_err(e))?;
+
+        trace!(target: "generic_cache_startup",
+            "Loaded sled cache. Path: {}, Entries: {}", self.cache_path.display(), ret.len()
+        );
+        Ok(ret)
+    }
+
+    fn save(&self, _cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        //Every change has already been written by append, so there is only a flush to do.
+        info!(target: "generic_cache_transactions", "flushing sled cache at {}", self.cache_path.display());
+        self.db.flush().map(|_| ()).map_err(|e| self.backend_err(e))
+    }
+
+    fn reset(&self) -> FsCacheResult<()> {
+        info!(target: "generic_cache_transactions", "clearing sled cache at {}", self.cache_path.display());
+        self.db.clear().map_err(|e| self.backend_err(e))?;
+        self.db.flush().map(|_| ()).map_err(|e| self.backend_err(e))
+    }
+
+    //The version is kept apart from the entries, so that it is not mistaken for one.
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        let meta = self.db.open_tree(META_TREE).map_err(|e| self.backend_err(e))?;
+        let bytes = match meta.get(PROCESSOR_VERSION_KEY).map_err(|e| self.backend_err(e))? {
+            Some(bytes) => bytes,
+            None => return Ok(None),
+        };
+
+        let (version, config_fingerprint) = bytes.split_at(bytes.len().min(4));
+        let decoded = <[u8; 4]>::try_from(version).and_then(|version| {
+            //versions stored before configuration fingerprints existed have none.
+            let config_fingerprint = match config_fingerprint.is_empty() {
+                true => [0; 8],
+                false => <[u8; 8]>::try_from(config_fingerprint)?,
+            };
+            Ok(ProcessorVersion {
+                version: u32::from_le_bytes(version),
+                config_fingerprint: u64::from_le_bytes(config_fingerprint),
+            })
+        });
+        match decoded {
+            Ok(version) => Ok(Some(version)),
+            Err(e) => Err(Deserialization {
+                src: format!("{}", e),
+                path: self.cache_path.clone(),
+            }),
+        }
+    }
+
+    fn store_version(&self, version: ProcessorVersion, _cache: &CacheView<'_, T, S>) -> FsCacheResult<()> {
+        let mut bytes = version.version.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&version.config_fingerprint.to_le_bytes());
+
+        let meta = self.db.open_tree(META_TREE).map_err(|e| self.backend_err(e))?;
+        meta.insert(PROCESSOR_VERSION_KEY, bytes)
+            .map_err(|e| self.backend_err(e))?;
+        meta.flush().map(|_| ()).map_err(|e| self.backend_err(e))
+    }
+
+    fn append(&self, changes: &[(&Path, Option<&T>)]) -> FsCacheResult<()> {
+        let serialization_err = |e: bincode::Error| Serialization {
+            src: format!("{}", e),
+            path: self.cache_path.clone(),
+        };
+
+        let mut batch = sled::Batch::default();
+        for (key, value) in changes {
+            let key = bincode::serialize(&StoredPath(key)).map_err(serialization_err)?;
+            match value {
+                Some(value) => batch.insert(key, bincode::serialize(value).map_err(serialization_err)?),
+                None => batch.remove(key),
+            }
+        }
+
+        self.db.apply_batch(batch).map_err(|e| self.backend_err(e))
+    }
+}
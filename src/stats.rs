@@ -0,0 +1,122 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+    time::Duration,
+};
+
+/// Counts of what a [`crate::ProcessingFsCache`] has done since it was created (or since
+/// its statistics were last reset).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Lookups answered from the cache without processing the file.
+    pub hits: u64,
+    /// Lookups which had to process the file because it was not cached or had changed.
+    pub misses: u64,
+    pub inserts: u64,
+    pub removes: u64,
+    /// Entries removed to keep the cache within
+    /// [`crate::ProcessingFsCacheBuilder::lru_capacity`]. These are not counted as removes.
+    pub evictions: u64,
+    /// Number of times the processing function was run.
+    pub processed: u64,
+    /// Total time spent in the processing function.
+    pub processing_time: Duration,
+}
+
+impl CacheStats {
+    /// The fraction of lookups answered from the cache, between 0 and 1. Zero if there
+    /// have been no lookups.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 / lookups as f64,
+        }
+    }
+
+    /// An estimate of the processing time avoided by cache hits, assuming each hit would
+    /// have taken as long as the average file that was actually processed.
+    pub fn estimated_time_saved(&self) -> Duration {
+        match self.processed {
+            0 => Duration::ZERO,
+            processed => self.processing_time.mul_f64(self.hits as f64 / processed as f64),
+        }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1}% of {} files served from cache, {} processed in {:.2?} (saving roughly {:.2?})",
+            self.hit_rate() * 100.0,
+            self.hits + self.misses,
+            self.processed,
+            self.processing_time,
+            self.estimated_time_saved()
+        )
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    inserts: AtomicU64,
+    removes: AtomicU64,
+    evictions: AtomicU64,
+    processed: AtomicU64,
+    processing_nanos: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn hit(&self) {
+        self.hits.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn miss(&self) {
+        self.misses.fetch_add(1, Relaxed);
+    }
+
+    pub(crate) fn inserted(&self, count: usize) {
+        self.inserts.fetch_add(count as u64, Relaxed);
+    }
+
+    pub(crate) fn removed(&self, count: usize) {
+        self.removes.fetch_add(count as u64, Relaxed);
+    }
+
+    pub(crate) fn evicted(&self, count: usize) {
+        self.evictions.fetch_add(count as u64, Relaxed);
+    }
+
+    pub(crate) fn processed(&self, time: Duration) {
+        self.processed.fetch_add(1, Relaxed);
+        self.processing_nanos.fetch_add(time.as_nanos() as u64, Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Relaxed),
+            misses: self.misses.load(Relaxed),
+            inserts: self.inserts.load(Relaxed),
+            removes: self.removes.load(Relaxed),
+            evictions: self.evictions.load(Relaxed),
+            processed: self.processed.load(Relaxed),
+            processing_time: Duration::from_nanos(self.processing_nanos.load(Relaxed)),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.hits,
+            &self.misses,
+            &self.inserts,
+            &self.removes,
+            &self.evictions,
+            &self.processed,
+            &self.processing_nanos,
+        ] {
+            counter.store(0, Relaxed);
+        }
+    }
+}
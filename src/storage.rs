@@ -0,0 +1,1375 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{info, trace, warn};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    cache_key::{CacheKey, DisplayKey, LoadedKey, StoredKey},
+    codec::{BincodeCodec, Codec},
+    entry_index,
+    errors::{FsCacheErrorKind::*, FsCacheResult},
+    format::{
+        type_fingerprint, write_checksum, Checksummed, FileHeader, MigrationFn, ProcessorVersion, FORMAT_VERSION,
+    },
+    journal::Journal,
+    lock::{CacheLock, LockPolicy},
+    sharded_map::shard_index,
+    stored_path::{LoadedMap, StoredEntries},
+};
+
+//Types defining the on-disk format of the filesystem cacher.
+pub(crate) type CacheDiskFormat<T, S = RandomState, K = PathBuf> = HashMap<K, T, S>;
+
+/// What [`FileBackend::repair`] recovered from a corrupt cache file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Entries which could be read, and were written back to the cache file.
+    pub recovered: usize,
+    /// The number of bytes after the last readable entry.
+    pub quarantined_bytes: usize,
+    /// Where the bytes after the last readable entry were moved to, if there were any.
+    pub quarantine_path: Option<PathBuf>,
+}
+
+impl std::fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "recovered {} entries, quarantined {} bytes",
+            self.recovered, self.quarantined_bytes
+        )
+    }
+}
+
+/// Limits on what a [`FileBackend`] will load, so that a corrupt or malicious cache file
+/// fails to load instead of using up all available memory. No limit is set by default.
+///
+/// Whatever the limits, the bincode codec never allocates more than the size of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LoadLimits {
+    /// The largest cache file (and journal) to read, in bytes.
+    pub max_file_size: Option<u64>,
+    /// The most entries the cache may hold once loaded.
+    pub max_entries: Option<usize>,
+    /// The longest path allowed as a key, in bytes.
+    pub max_path_len: Option<usize>,
+}
+
+/// How sure a [`FileBackend`] is that a saved cache file has reached the disk before saving
+/// returns. Either way, the cache is written to a temporary file which only replaces the cache
+/// file once it has been written in full, so a crash while saving leaves the previous cache file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// The temporary file is flushed to disk before it replaces the cache file. On some file
+    /// systems a power failure soon after saving can lose the replacement itself, leaving the
+    /// cache file missing or empty.
+    #[default]
+    FlushFile,
+    /// The directory holding the cache file is also flushed to disk once the cache file has been
+    /// replaced, so that the new cache file survives a power failure immediately after saving.
+    /// Only makes a difference on Unix.
+    FlushFileAndDirectory,
+}
+
+/// The entries of a cache, as passed to a [`StorageBackend`] to save them. A cache holds its
+/// entries in several maps, each behind a lock of its own so that threads modifying different
+/// entries do not wait for each other, and this borrows every one of them without copying any
+/// entries. A reference to a single map converts into a view of it with `into`.
+pub struct CacheView<'a, T, S = RandomState, K = PathBuf> {
+    maps: Vec<&'a HashMap<K, T, S>>,
+    //Chooses the map of each key when there is more than one.
+    hasher: Option<&'a S>,
+}
+
+impl<'a, T, S, K> CacheView<'a, T, S, K>
+where
+    S: BuildHasher,
+    K: CacheKey,
+{
+    //`hasher` must be the one which chose the map of each key.
+    pub(crate) fn sharded(maps: Vec<&'a HashMap<K, T, S>>, hasher: &'a S) -> Self {
+        Self {
+            maps,
+            hasher: Some(hasher),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.maps.iter().map(|map| map.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.iter().all(|map| map.is_empty())
+    }
+
+    pub fn get(&self, key: &K::Ref) -> Option<&'a T> {
+        let map = match self.hasher {
+            Some(hasher) => self.maps[shard_index(hasher, self.maps.len(), key)],
+            None => self.maps[0],
+        };
+        map.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K::Ref) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Every entry, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a T)> + '_ {
+        self.maps.iter().flat_map(|map| map.iter())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &'a K> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+}
+
+impl<'a, T, S, K> From<&'a HashMap<K, T, S>> for CacheView<'a, T, S, K> {
+    fn from(map: &'a HashMap<K, T, S>) -> Self {
+        Self {
+            maps: vec![map],
+            hasher: None,
+        }
+    }
+}
+
+/// Persistent storage for the contents of a cache.
+///
+/// The cache holds all entries in memory and calls [`StorageBackend::save`] with a
+/// [`CacheView`] of the complete contents whenever its save strategy says so, so the
+/// simplest backend only needs to implement `load` and `save`. Backends which can durably
+/// store individual changes may also implement [`StorageBackend::append`], which is called
+/// for every insertion and removal.
+///
+/// `S` is the hasher of the map the cache is held in (see
+/// [`crate::ProcessingFsCacheBuilder::with_hasher`]), which backends should be generic over if
+/// they can be. `K` is the type of the keys, which is a path for every cache but a
+/// [`crate::BaseFsCache`] made with another [`CacheKey`].
+pub trait StorageBackend<T, S = RandomState, K = PathBuf>: Send + Sync
+where
+    K: CacheKey,
+{
+    /// Load all stored entries. If nothing has been stored yet, this is not an error
+    /// and an empty map should be returned.
+    fn load(&self) -> FsCacheResult<HashMap<K, T, S>>;
+
+    /// Store the complete contents of the cache, replacing anything stored previously.
+    /// Backends which have already durably stored every change passed to `append` may
+    /// treat this as a flush.
+    fn save(&self, cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()>;
+
+    /// Store the changes made to the cache since it was last saved. `changed_keys` lists
+    /// every key inserted or removed since then, and their current values (if any) can be
+    /// found in `cache`. The default implementation stores the complete cache with `save`.
+    fn save_changes(&self, cache: &CacheView<'_, T, S, K>, _changed_keys: &[K]) -> FsCacheResult<()> {
+        self.save(cache)
+    }
+
+    /// Store changes to individual entries as they happen. A value of `None` means the
+    /// entry was removed. The default implementation does nothing, relying on `save`.
+    fn append(&self, _changes: &[(&K::Ref, Option<&T>)]) -> FsCacheResult<()> {
+        Ok(())
+    }
+
+    /// Called just before a snapshot of the cache is taken to pass to `save`, `save_changes` or
+    /// `store_version`, while nothing can be passed to `append`. Changes appended after this call
+    /// may be missing from the snapshot, so must not be discarded when it is stored. The default
+    /// implementation does nothing.
+    fn taking_snapshot(&self) {}
+
+    /// Delete everything that has been stored. The default implementation saves an empty cache.
+    fn reset(&self) -> FsCacheResult<()>
+    where
+        S: Default,
+    {
+        self.save(&(&HashMap::default()).into())
+    }
+
+    /// The version of the processing which produced the stored values, as last passed to
+    /// [`StorageBackend::store_version`], or None if it is not known. Called after `load`.
+    /// The default implementation returns None, so that a change of version is never noticed.
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        Ok(None)
+    }
+
+    /// Record the version of the processing which produced the values. `cache` is the current
+    /// contents of the cache, for backends which must rewrite everything to store the version.
+    /// The default implementation does nothing.
+    fn store_version(&self, _version: ProcessorVersion, _cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()> {
+        Ok(())
+    }
+}
+
+/// The default storage backend, which stores the whole cache as a single file, encoded
+/// with bincode unless another [`Codec`] is chosen.
+#[derive(Clone)]
+pub struct FileBackend<C = BincodeCodec> {
+    cache_path: PathBuf,
+    codec: C,
+    migration: Option<Arc<MigrationFn>>,
+    //Files with an older format version than this need migrating even if they hold the same value
+    //type, as the way that type is stored has changed.
+    value_format_version: u32,
+    limits: LoadLimits,
+    backup_count: usize,
+    durability: Durability,
+    journal: Option<Arc<Journal>>,
+    journal_mode: JournalMode,
+    lock: Option<Arc<CacheLock>>,
+    //The processor version in the header of the cache file, or None if there is no cache file yet.
+    processor_version: Arc<Mutex<Option<ProcessorVersion>>>,
+    entry_index: bool,
+    #[cfg(feature = "encryption")]
+    encryption_key: Option<[u8; 32]>,
+}
+
+//When changes are written to the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum JournalMode {
+    EveryChange,
+    OnSave,
+}
+
+impl FileBackend {
+    pub fn new(cache_path: PathBuf) -> Self {
+        Self::with_codec(cache_path, BincodeCodec)
+    }
+}
+
+impl<C> FileBackend<C>
+where
+    C: Codec,
+{
+    pub fn with_codec(cache_path: PathBuf, codec: C) -> Self {
+        Self {
+            cache_path,
+            codec,
+            migration: None,
+            value_format_version: 0,
+            limits: LoadLimits::default(),
+            backup_count: 0,
+            durability: Durability::default(),
+            journal: None,
+            journal_mode: JournalMode::EveryChange,
+            lock: None,
+            processor_version: Default::default(),
+            entry_index: false,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+        }
+    }
+
+    pub fn cache_path(&self) -> &Path {
+        &self.cache_path
+    }
+
+    /// Encrypt the cache file with XChaCha20-Poly1305, using the given 256-bit key.
+    /// A cache file written with one key cannot be loaded with another (or without a key).
+    /// Unlike a plain cache file, which is written as its entries are encoded, an encrypted one
+    /// is encoded in memory in full before it is written.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Write an index of the entries of the cache file beside it, at `<cache_path>.index`, so
+    /// that a [`crate::LazyCache`] can map the file into memory and decode only the entries it
+    /// looks up, rather than reading the whole file. Only codecs which encode each entry on its
+    /// own, such as the default, bincode, can be indexed, and encrypted files cannot. An index
+    /// is ignored once the cache file has been saved without it.
+    ///
+    /// On Windows a cache file cannot be replaced while it is mapped, so saving fails while a
+    /// [`crate::LazyCache`] is reading the file it saves.
+    #[cfg(feature = "mmap")]
+    pub fn with_entry_index(mut self, entry_index: bool) -> Self {
+        self.entry_index = entry_index;
+        self
+    }
+
+    /// Keep up to `backup_count` previous versions of the cache file when saving, named
+    /// `<cache_path>.1` (the newest) to `<cache_path>.<backup_count>` (the oldest).
+    pub fn with_backups(mut self, backup_count: usize) -> Self {
+        self.backup_count = backup_count;
+        self
+    }
+
+    /// How sure saving is that the cache file has reached the disk. See [`Durability`].
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Refuse to load cache files which break any of `limits`, failing with
+    /// [`crate::FsCacheErrorKind::LoadLimitExceeded`].
+    pub fn with_load_limits(mut self, limits: LoadLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Record every insertion and removal in an append-only journal at `<cache_path>.journal`,
+    /// so that saving only needs to flush the journal. Once `compact_after` changes have been
+    /// journaled, the next save rewrites the main cache file and empties the journal.
+    ///
+    /// The journal is replayed when the cache is loaded.
+    pub fn with_journal(mut self, compact_after: usize) -> Self {
+        let mut journal_path = self.cache_path.clone().into_os_string();
+        journal_path.push(".journal");
+        self.journal = Some(Arc::new(Journal::new(journal_path.into(), compact_after)));
+        self.journal_mode = JournalMode::EveryChange;
+        self
+    }
+
+    /// Save only the entries which have changed since the last save, appending them to a
+    /// delta file at `<cache_path>.journal`. Once `compact_after` changed entries have been
+    /// written, the next save merges them into the main cache file.
+    ///
+    /// Unlike [`Self::with_journal`], nothing is written between saves.
+    pub fn with_delta_saves(mut self, compact_after: usize) -> Self {
+        self = self.with_journal(compact_after);
+        self.journal_mode = JournalMode::OnSave;
+        self
+    }
+
+    /// Take an advisory lock on `<cache_path>.lock` when the cache is loaded, to stop
+    /// several processes overwriting each other's changes. The policy decides what happens
+    /// if another process holds the lock.
+    pub fn with_lock_policy(mut self, policy: LockPolicy) -> Self {
+        let mut lock_path = self.cache_path.clone().into_os_string();
+        lock_path.push(".lock");
+        self.lock = match policy {
+            LockPolicy::None => None,
+            policy => Some(Arc::new(CacheLock::new(lock_path.into(), policy))),
+        };
+        self
+    }
+
+    /// Whether another process held the lock when the cache was loaded, so that the cache
+    /// will not be written to. See [`LockPolicy::ReadOnly`].
+    pub fn is_read_only(&self) -> bool {
+        match &self.lock {
+            Some(lock) if lock.is_read_only() => {
+                trace!(target: "generic_cache_transactions", "not writing read-only cache ({})", lock.path().display());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The path of the nth most recent backup, starting from 1.
+    pub fn backup_path(&self, n: usize) -> PathBuf {
+        let mut path = self.cache_path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Replace the cache file with the newest backup which can be loaded successfully,
+    /// returning the path of the backup used, or None if no backup could be read.
+    pub fn restore_newest_backup<T>(&self) -> FsCacheResult<Option<PathBuf>>
+    where
+        T: DeserializeOwned + Serialize + Send + Sync,
+        C: Clone,
+    {
+        for n in 1..=self.backup_count {
+            let backup = self.sibling(self.backup_path(n));
+
+            if !backup.cache_path.exists() {
+                continue;
+            }
+
+            if let Err(e) = backup.load_snapshot::<T, RandomState, PathBuf>(false) {
+                warn!(target: "generic_cache_startup", "Backup is unusable: {}", e);
+                continue;
+            }
+
+            //copy rather than rename, so that the backup is still there if restoring fails part way.
+            let temp_store_path = temp_path(&self.cache_path);
+            let restored = std::fs::copy(&backup.cache_path, &temp_store_path)
+                .and_then(|_| replace_file(&temp_store_path, &self.cache_path));
+            if let Err(e) = restored {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                });
+            }
+
+            info!(target: "generic_cache_startup",
+                "Restored cache file {} from {}", self.cache_path.display(), backup.cache_path.display()
+            );
+            return Ok(Some(backup.cache_path));
+        }
+
+        Ok(None)
+    }
+
+    /// A backend for another file, with the same codec and options as this one but without
+    /// its journal or lock.
+    pub(crate) fn sibling(&self, cache_path: PathBuf) -> Self
+    where
+        C: Clone,
+    {
+        Self {
+            cache_path,
+            codec: self.codec.clone(),
+            migration: self.migration.clone(),
+            value_format_version: self.value_format_version,
+            limits: self.limits,
+            backup_count: self.backup_count,
+            durability: self.durability,
+            journal: None,
+            journal_mode: self.journal_mode,
+            lock: None,
+            processor_version: Default::default(),
+            entry_index: self.entry_index,
+            #[cfg(feature = "encryption")]
+            encryption_key: self.encryption_key,
+        }
+    }
+
+    pub(crate) fn acquire_lock(&self) -> FsCacheResult<()> {
+        match &self.lock {
+            Some(lock) => lock.acquire(),
+            None => Ok(()),
+        }
+    }
+
+    //Shift each backup along by one, dropping the oldest, and make the current cache file the newest backup.
+    fn rotate_backups(&self) -> std::io::Result<()> {
+        if self.backup_count == 0 || !self.cache_path.exists() {
+            return Ok(());
+        }
+
+        let oldest = self.backup_path(self.backup_count);
+        if oldest.exists() {
+            std::fs::remove_file(oldest)?;
+        }
+        for n in (1..self.backup_count).rev() {
+            let backup = self.backup_path(n);
+            if backup.exists() {
+                replace_file(&backup, &self.backup_path(n + 1))?;
+            }
+        }
+
+        //A hard link means that the cache file never goes missing, as it is then replaced by
+        //an atomic rename. Not all filesystems support them, so fall back to copying.
+        let newest = self.backup_path(1);
+        std::fs::hard_link(&self.cache_path, &newest).or_else(|_| std::fs::copy(&self.cache_path, &newest).map(|_| ()))
+    }
+
+    /// Supply a hook to upgrade cache files written with an older format version or a
+    /// different value type. Without a hook, files written before the format was versioned
+    /// are read as-is, and files holding a different value type fail to load. With a hook,
+    /// journal records which cannot be read are skipped, as they may predate the upgrade.
+    pub fn with_migration(
+        mut self,
+        migration: impl Fn(&FileHeader, Vec<u8>) -> Result<Vec<u8>, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.migration = Some(Arc::new(migration));
+        self
+    }
+
+    //Files older than `version` are passed to the migration hook, if there is one, even if they
+    //hold the current value type.
+    pub(crate) fn with_value_format_version(mut self, version: u32) -> Self {
+        self.value_format_version = version;
+        self
+    }
+
+    fn read_versioned_payload<T: DeserializeOwned>(
+        &self,
+        header: FileHeader,
+        expected_fingerprint: u64,
+        reader: impl Read,
+        payload_len: u64,
+    ) -> FsCacheResult<(T, bool)> {
+        let incompatible = |src: String| {
+            Err(IncompatibleCacheFile {
+                src,
+                path: self.cache_path.clone(),
+            })
+        };
+
+        if header.format_version > FORMAT_VERSION {
+            return incompatible(format!(
+                "the file has format version {}, but only versions up to {} are supported",
+                header.format_version, FORMAT_VERSION
+            ));
+        }
+
+        //Older format versions differ only in their header, so only a change of value type, or
+        //of how it is stored, needs migrating.
+        let value_format_changed = header.format_version < self.value_format_version && self.migration.is_some();
+        if header.fingerprint == Some(expected_fingerprint) && !value_format_changed {
+            return self.read_payload(reader, payload_len).map(|payload| (payload, false));
+        }
+
+        if let Some(migration) = &self.migration {
+            info!(target: "generic_cache_startup",
+                "Migrating cache file {} from {:?}", self.cache_path.display(), header
+            );
+            let payload = self.read_plaintext(reader)?;
+            let migrated = migration(&header, payload).map_err(|src| IncompatibleCacheFile {
+                src,
+                path: self.cache_path.clone(),
+            })?;
+            return self
+                .deserialize(&migrated[..], migrated.len() as u64)
+                .map(|payload| (payload, true));
+        }
+
+        match header.fingerprint {
+            //Files written before headers existed have the same payload format.
+            None => self.read_payload(reader, payload_len).map(|payload| (payload, false)),
+            Some(_) => incompatible("the file holds a different value type".to_string()),
+        }
+    }
+
+    //Checks the payload against the checksum in the header. This is done after (attempting) to
+    //deserialize the payload to avoid reading the file twice, so any unread part of the payload
+    //is consumed first.
+    fn verify_checksum<R: Read>(&self, header: &FileHeader, payload: Checksummed<R>) -> FsCacheResult<()> {
+        let expected = match header.checksum {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        let mut payload = payload;
+        let drained = std::io::copy(&mut payload, &mut std::io::sink());
+        let (_, actual) = payload.finish();
+
+        match drained {
+            Ok(_) if actual == expected => Ok(()),
+            Ok(_) if actual.len != expected.len => Err(CorruptedCache {
+                src: format!("expected {} bytes of data but found {}", expected.len, actual.len),
+                path: self.cache_path.clone(),
+            }),
+            Ok(_) => Err(CorruptedCache {
+                src: format!(
+                    "checksum mismatch (expected {:08x}, found {:08x})",
+                    expected.crc32, actual.crc32
+                ),
+                path: self.cache_path.clone(),
+            }),
+            Err(e) => Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.clone(),
+            }),
+        }
+    }
+
+    //`payload_len` is an upper bound on the length of the payload.
+    fn read_payload<T: DeserializeOwned>(&self, mut reader: impl Read, payload_len: u64) -> FsCacheResult<T> {
+        #[cfg(feature = "encryption")]
+        if self.encryption_key.is_some() {
+            let plaintext = self.read_plaintext(reader)?;
+            return self.deserialize(&plaintext[..], plaintext.len() as u64);
+        }
+
+        self.deserialize(&mut reader, payload_len)
+    }
+
+    fn append_to_journal<T: Serialize, K: CacheKey>(
+        &self,
+        journal: &Journal,
+        changes: &[(&K::Ref, Option<&T>)],
+    ) -> FsCacheResult<()> {
+        let mut records = Vec::with_capacity(changes.len());
+        for (key, value) in changes {
+            let mut record = vec![];
+            self.serialize(&mut record, &(StoredKey::<K>(key), value))?;
+            records.push(self.encode_record(record)?);
+        }
+
+        journal.append(&records).map_err(|e| CacheFileIo {
+            src: e,
+            path: journal.path().to_path_buf(),
+        })
+    }
+
+    //Journal records are encrypted individually.
+    fn encode_record(&self, record: Vec<u8>) -> FsCacheResult<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption_key {
+            return crate::encryption::encrypt(key, &record, &self.cache_path);
+        }
+
+        Ok(record)
+    }
+
+    fn decode_record(&self, record: &[u8]) -> FsCacheResult<Vec<u8>> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption_key {
+            return crate::encryption::decrypt(key, record, &self.cache_path);
+        }
+
+        Ok(record.to_vec())
+    }
+
+    //Read the whole payload into memory, decrypting it if necessary.
+    fn read_plaintext(&self, mut reader: impl Read) -> FsCacheResult<Vec<u8>> {
+        let mut payload = vec![];
+        if let Err(e) = reader.read_to_end(&mut payload) {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.clone(),
+            });
+        }
+
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption_key {
+            return crate::encryption::decrypt(key, &payload, &self.cache_path);
+        }
+
+        Ok(payload)
+    }
+
+    fn write_payload<T: Serialize>(&self, mut writer: impl Write, value: &T) -> FsCacheResult<()> {
+        #[cfg(feature = "encryption")]
+        if let Some(key) = &self.encryption_key {
+            let mut plaintext = vec![];
+            self.serialize(&mut plaintext, value)?;
+            let ciphertext = crate::encryption::encrypt(key, &plaintext, &self.cache_path)?;
+            return writer.write_all(&ciphertext).map_err(|e| CacheFileIo {
+                src: e,
+                path: self.cache_path.clone(),
+            });
+        }
+
+        self.serialize(&mut writer, value)
+    }
+
+    //Reading more than `len` bytes means that the data is corrupt, so the codec is not allowed to.
+    fn deserialize<T: DeserializeOwned>(&self, mut reader: impl Read, len: u64) -> FsCacheResult<T> {
+        //we may fail to read the hash file. This most likely to occur in development if <T> is changed.
+        self.codec
+            .deserialize_limited(&mut reader, len)
+            .map_err(|src| Deserialization {
+                src,
+                path: self.cache_path.to_path_buf(),
+            })
+    }
+
+    fn serialize<T: Serialize>(&self, mut writer: impl Write, value: &T) -> FsCacheResult<()> {
+        self.codec.serialize(&mut writer, value).map_err(|src| Serialization {
+            src,
+            path: self.cache_path.to_path_buf(),
+        })
+    }
+
+    //Whether each entry of the cache file can be found and decoded on its own.
+    fn entries_separable(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        if self.encryption_key.is_some() {
+            return false;
+        }
+
+        self.codec.encodes_entries_separately()
+    }
+
+    //Writes the entries just as the codec would write them as a map, noting the hash of each
+    //encoded key and the offset of its entry within the payload, for the entry index.
+    fn write_indexed_entries<'e, T, Ks>(
+        &self,
+        writer: &mut Checksummed<impl Write>,
+        entries: impl Iterator<Item = (Ks, &'e T)>,
+        len: usize,
+    ) -> FsCacheResult<Vec<(u64, u64)>>
+    where
+        T: Serialize + 'e,
+        Ks: Serialize,
+    {
+        self.serialize(&mut *writer, &(len as u64))?;
+        let mut records = Vec::with_capacity(len);
+        let mut key = vec![];
+        for (stored_key, value) in entries {
+            key.clear();
+            self.serialize(&mut key, &stored_key)?;
+            records.push((entry_index::key_hash(&key), writer.len()));
+            if let Err(e) = writer.write_all(&key) {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                });
+            }
+            self.serialize(&mut *writer, value)?;
+        }
+        Ok(records)
+    }
+
+    fn check_file_size(&self, path: &Path, len: u64) -> FsCacheResult<()> {
+        match self.limits.max_file_size {
+            Some(max_file_size) if len > max_file_size => Err(LoadLimitExceeded {
+                src: format!("the file is {} bytes, but at most {} are allowed", len, max_file_size),
+                path: path.to_path_buf(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_entries<T, S, K: CacheKey>(&self, cache: &CacheDiskFormat<T, S, K>) -> FsCacheResult<()> {
+        let exceeded = |src| {
+            Err(LoadLimitExceeded {
+                src,
+                path: self.cache_path.clone(),
+            })
+        };
+
+        if let Some(max_entries) = self.limits.max_entries {
+            if cache.len() > max_entries {
+                return exceeded(format!(
+                    "the cache holds {} entries, but at most {} are allowed",
+                    cache.len(),
+                    max_entries
+                ));
+            }
+        }
+
+        if let Some(max_path_len) = self.limits.max_path_len {
+            if let Some(key) = cache.keys().find(|key| K::key_len((*key).borrow()) > max_path_len) {
+                return exceeded(format!(
+                    "the key {} is longer than the limit of {} bytes",
+                    DisplayKey::<K>(key.borrow()),
+                    max_path_len
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<C> std::fmt::Debug for FileBackend<C> {
+    //Hand-written so that the encryption key never ends up in logs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileBackend")
+            .field("cache_path", &self.cache_path)
+            .finish()
+    }
+}
+
+impl<C> FileBackend<C>
+where
+    C: Codec,
+{
+    /// Read the cache file (and journal, if any) without taking the lock, and without writing
+    /// anything back even if it had to be migrated. Used for reading another cache's file.
+    pub fn read<T: DeserializeOwned>(&self) -> FsCacheResult<CacheDiskFormat<T>> {
+        let (mut cache, _) = self.load_snapshot(false)?;
+        self.replay_journal(&mut cache)?;
+        Ok(cache)
+    }
+
+    /// Read the cache file (and journal, if any) as holding values of type `T`, whatever value
+    /// type it was written with, without migrating or otherwise modifying it. This is only useful
+    /// with a self-describing codec, such as for reading any JSON cache file as `serde_json::Value`.
+    pub fn read_as<T: DeserializeOwned>(&self) -> FsCacheResult<CacheDiskFormat<T>> {
+        let (mut cache, _) = self.load_snapshot(true)?;
+        self.replay_journal(&mut cache)?;
+        Ok(cache)
+    }
+
+    //Also returns whether the cache file had to be migrated. If `any_type` is set, the payload is
+    //read as `T` whichever type the header says it holds.
+    fn load_snapshot<T, S, K>(&self, any_type: bool) -> FsCacheResult<(CacheDiskFormat<T, S, K>, bool)>
+    where
+        T: DeserializeOwned,
+        S: BuildHasher + Default,
+        K: CacheKey,
+    {
+        //Try and read from disk. If there is nothing  available, this is not an error.
+        //It just means that no cached values can be used. If so then go ahead and return early
+        //as there is no deserialization to do.
+        if !&self.cache_path.exists() {
+            info!(target: "generic_cache_startup",
+                "Creating new cache file: {}.", self.cache_path.display()
+            );
+            return Ok(Default::default());
+        }
+
+        let cache_file = match std::fs::File::open(&self.cache_path) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                })
+            }
+        };
+
+        let file_len = match cache_file.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                })
+            }
+        };
+        self.check_file_size(&self.cache_path, file_len)?;
+
+        let reader = std::io::BufReader::new(cache_file);
+        let (header, payload) = match FileHeader::read(reader) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                })
+            }
+        };
+        *self.lock_processor_version() = Some(header.processor_version.unwrap_or_default());
+        let expected_fingerprint = match (any_type, header.fingerprint) {
+            (true, Some(fingerprint)) => fingerprint,
+            _ => type_fingerprint::<T>(),
+        };
+        let mut payload = Checksummed::new(payload);
+        let decode_result: FsCacheResult<(LoadedMap<T, S, K>, bool)> =
+            self.read_versioned_payload(header, expected_fingerprint, &mut payload, file_len);
+
+        //If the file is corrupt then that is the more useful error to report, as it will
+        //be why deserialization failed.
+        self.verify_checksum(&header, payload)?;
+        let (LoadedMap(cache_file_data), migrated) = decode_result?;
+        self.check_entries(&cache_file_data)?;
+
+        trace!(target: "generic_cache_startup",
+            "Loaded cache. Path: {}, Entries: {}", self.cache_path.display(), cache_file_data.len()
+        );
+        Ok((cache_file_data, migrated))
+    }
+
+    /// Recover as many entries as possible from a partly corrupt cache file, such as one cut
+    /// short by a full disk. The entries which can be read are written back to the cache file,
+    /// and everything after them is moved to a `.corrupt` file beside it. Only codecs which can
+    /// read a map entry by entry (such as the default, bincode) can recover anything from a file
+    /// which does not decode in full, and nothing can be recovered from a corrupt encrypted file.
+    pub fn repair<T>(&self) -> FsCacheResult<RepairReport>
+    where
+        T: DeserializeOwned + Serialize,
+    {
+        let io_err = |e| CacheFileIo {
+            src: e,
+            path: self.cache_path.clone(),
+        };
+        let cache_file = std::fs::File::open(&self.cache_path).map_err(io_err)?;
+        let (header, payload) = FileHeader::read(std::io::BufReader::new(cache_file)).map_err(io_err)?;
+        if header.fingerprint.is_some() && header.fingerprint != Some(type_fingerprint::<T>()) {
+            return Err(IncompatibleCacheFile {
+                src: "the file holds a different value type".to_string(),
+                path: self.cache_path.clone(),
+            });
+        }
+
+        let plaintext = self.read_plaintext(payload)?;
+        let (cache, used) = self.codec.deserialize_map_prefix::<T>(&plaintext);
+        let tail = &plaintext[used..];
+
+        let quarantine_path = match tail.is_empty() {
+            true => None,
+            false => {
+                let mut path = self.cache_path.clone().into_os_string();
+                path.push(".corrupt");
+                let path = PathBuf::from(path);
+                std::fs::write(&path, tail).map_err(io_err)?;
+                Some(path)
+            }
+        };
+
+        *self.lock_processor_version() = Some(header.processor_version.unwrap_or_default());
+        self.save_snapshot::<T, RandomState, PathBuf>(&(&cache).into())?;
+
+        let report = RepairReport {
+            recovered: cache.len(),
+            quarantined_bytes: tail.len(),
+            quarantine_path,
+        };
+        warn!(target: "generic_cache_startup", "Repaired cache file {}: {}", self.cache_path.display(), report);
+        Ok(report)
+    }
+
+    fn save_snapshot<T, S, K>(&self, cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()>
+    where
+        T: Serialize,
+        S: BuildHasher,
+        K: CacheKey,
+    {
+        let entries = || cache.iter().map(|(key, value)| (StoredKey::<K>(key.borrow()), value));
+        self.write_snapshot(entries, cache.len())
+    }
+
+    /// Write `entries` to the cache file, as if they were the whole cache.
+    pub(crate) fn save_entries<T: Serialize>(&self, entries: &HashMap<&Path, &T>) -> FsCacheResult<()> {
+        let stored_entries = || entries.iter().map(|(key, value)| (StoredKey::<PathBuf>(key), *value));
+        self.write_snapshot(stored_entries, entries.len())
+    }
+
+    //`entries` returns an iterator over the `len` entries of the cache, with their keys as stored.
+    fn write_snapshot<'e, T, Ks, I>(&self, entries: impl Fn() -> I, len: usize) -> FsCacheResult<()>
+    where
+        T: Serialize + 'e,
+        Ks: Serialize,
+        I: Iterator<Item = (Ks, &'e T)>,
+    {
+        use std::io::BufWriter;
+
+        //The cache file and its directory may not exist yet. So first create the directory
+        //first if necessary.
+        if !&self.cache_path.exists() {
+            if let Some(ref parent_dir) = self.cache_path.parent() {
+                if let Err(e) = std::fs::create_dir_all(parent_dir) {
+                    return Err(CacheFileIo {
+                        src: e,
+                        path: self.cache_path.clone(),
+                    });
+                }
+            }
+        }
+
+        //If the application dies or gets killed while saving, we risk losing the cache.
+        //So we will first save the cache to a temporary file and rename it into the real
+        //cache file. It is named after the whole file name, so that the shards and logs saved
+        //beside a cache file each have their own.
+        let temp_store_path = temp_path(&self.cache_path);
+
+        info!(
+            target: "generic_cache_transactions",
+            "saving updated cache at {} of size {}",
+
+            self.cache_path.display(),
+            len
+        );
+
+        let temp_cache_file = match std::fs::File::create(&temp_store_path) {
+            Ok(temp_cache_file) => Ok(temp_cache_file),
+            Err(e) => Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            }),
+        }?;
+
+        let mut cache_buf = BufWriter::new(temp_cache_file);
+
+        let processor_version = self.lock_processor_version().unwrap_or_default();
+        if let Err(e) = FileHeader::current::<T>(processor_version).write(&mut cache_buf) {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            });
+        }
+        let mut payload_buf = Checksummed::new(cache_buf);
+        let index = match self.entry_index && self.entries_separable() {
+            true => Some(self.write_indexed_entries(&mut payload_buf, entries(), len)?),
+            false => {
+                self.write_payload(&mut payload_buf, &StoredEntries(&entries, len))?;
+                None
+            }
+        };
+        let (cache_buf, checksum) = payload_buf.finish();
+
+        let mut temp_cache_file = match cache_buf.into_inner() {
+            Err(e) => {
+                return Err(CacheFileIo {
+                    src: e.into_error(),
+                    path: self.cache_path.to_path_buf(),
+                })
+            }
+            Ok(x) => x,
+        };
+
+        if let Err(e) = write_checksum(&mut temp_cache_file, checksum) {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            });
+        }
+
+        if let Err(e) = temp_cache_file.sync_all() {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            });
+        }
+
+        if let Err(e) = self.rotate_backups() {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            });
+        }
+
+        //now move the store to replace the old one.
+        if let Err(e) = replace_file(&temp_store_path, &self.cache_path) {
+            return Err(CacheFileIo {
+                src: e,
+                path: self.cache_path.to_path_buf(),
+            });
+        }
+
+        if self.durability == Durability::FlushFileAndDirectory {
+            if let Err(e) = sync_parent_dir(&self.cache_path) {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.to_path_buf(),
+                });
+            }
+        }
+
+        //The cache file is already saved, and is read in full without an index, so failing to
+        //write one is not worth failing the save over.
+        if let Some(records) = index {
+            if let Err(e) = entry_index::write(&self.cache_path, checksum, records) {
+                warn!(target: "generic_cache_transactions",
+                    "Failed to write the entry index of {}: {}", self.cache_path.display(), e
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    //Writes the whole cache to the cache file, emptying the journal (if any) of the changes
+    //`cache` holds. Changes journaled since the snapshot `cache` was taken are kept.
+    fn rewrite<T: Serialize, S: BuildHasher, K: CacheKey>(&self, cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()> {
+        self.save_snapshot(cache)?;
+        match &self.journal {
+            Some(journal) => journal.clear_saved().map_err(|e| CacheFileIo {
+                src: e,
+                path: journal.path().to_path_buf(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    //Applies the changes in the journal (if any) to `cache`. Returns whether any records were
+    //skipped because they could not be read.
+    fn replay_journal<T, S, K>(&self, cache: &mut CacheDiskFormat<T, S, K>) -> FsCacheResult<bool>
+    where
+        T: DeserializeOwned,
+        S: BuildHasher,
+        K: CacheKey,
+    {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return Ok(false),
+        };
+
+        if let Ok(metadata) = std::fs::metadata(journal.path()) {
+            self.check_file_size(journal.path(), metadata.len())?;
+        }
+
+        let records = journal.replay().map_err(|e| CacheFileIo {
+            src: e,
+            path: journal.path().to_path_buf(),
+        })?;
+
+        trace!(target: "generic_cache_startup",
+            "Replaying {} journal records from {}", records.len(), journal.path().display()
+        );
+        let mut skipped = false;
+        for record in records {
+            let plaintext = self.decode_record(&record)?;
+            let (LoadedKey(key), value): (LoadedKey<K>, Option<T>) =
+                match self.deserialize(&plaintext[..], plaintext.len() as u64) {
+                    Ok(record) => record,
+                    //records written before a migration are still in the old format. Dropping
+                    //them only loses cached values, which will be processed again.
+                    Err(e) if self.migration.is_some() => {
+                        warn!(target: "generic_cache_startup", "Skipping journal record: {}", e);
+                        skipped = true;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+            match value {
+                Some(value) => cache.insert(key, value),
+                None => cache.remove(key.borrow()),
+            };
+        }
+        self.check_entries(cache)?;
+        Ok(skipped)
+    }
+
+    //Returns the previous version.
+    pub(crate) fn set_processor_version(&self, version: ProcessorVersion) -> Option<ProcessorVersion> {
+        self.lock_processor_version().replace(version)
+    }
+
+    fn lock_processor_version(&self) -> std::sync::MutexGuard<'_, Option<ProcessorVersion>> {
+        match self.processor_version.lock() {
+            Ok(processor_version) => processor_version,
+            Err(_) => unreachable!(),
+        }
+    }
+
+    //Maps the cache file into memory to read its entries one at a time, if it can be read that
+    //way: it has an up to date entry index, holds `T` without needing migrating, and has no
+    //journal to replay. Also returns the processor version in its header. Returns None if it
+    //cannot, or if there is no cache file. As entries are only decoded when they are looked up,
+    //the payload checksum is not verified, and corruption only shows in the entries read.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn map_entries<T>(&self) -> FsCacheResult<Option<(entry_index::MappedEntries, ProcessorVersion)>> {
+        if self.journal.is_some() || !self.entries_separable() {
+            return Ok(None);
+        }
+
+        let io_err = |e| CacheFileIo {
+            src: e,
+            path: self.cache_path.clone(),
+        };
+        let cache_file = match std::fs::File::open(&self.cache_path) {
+            Ok(cache_file) => cache_file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(io_err(e)),
+        };
+        self.check_file_size(&self.cache_path, cache_file.metadata().map_err(io_err)?.len())?;
+
+        let (header, _) = FileHeader::read(&cache_file).map_err(io_err)?;
+        let value_format_changed = header.format_version < self.value_format_version && self.migration.is_some();
+        let checksum = match header.checksum {
+            Some(checksum)
+                if header.format_version <= FORMAT_VERSION
+                    && header.fingerprint == Some(type_fingerprint::<T>())
+                    && !value_format_changed =>
+            {
+                checksum
+            }
+            _ => return Ok(None),
+        };
+        let entries = match entry_index::MappedEntries::open(&cache_file, &self.cache_path, checksum) {
+            Ok(Some(entries)) => entries,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(io_err(e)),
+        };
+
+        if let Some(max_entries) = self.limits.max_entries {
+            if entries.len() > max_entries {
+                return Err(LoadLimitExceeded {
+                    src: format!(
+                        "the cache holds {} entries, but at most {} are allowed",
+                        entries.len(),
+                        max_entries
+                    ),
+                    path: self.cache_path.clone(),
+                });
+            }
+        }
+
+        trace!(target: "generic_cache_startup",
+            "Mapped cache. Path: {}, Entries: {}", self.cache_path.display(), entries.len()
+        );
+        Ok(Some((entries, header.processor_version.unwrap_or_default())))
+    }
+
+    //Returns the position of the entry under `key` within the index of `entries`, along with the
+    //encoding of its value.
+    #[cfg(feature = "mmap")]
+    pub(crate) fn find_mapped<'a, K: CacheKey>(
+        &self,
+        entries: &'a entry_index::MappedEntries,
+        key: &K::Ref,
+    ) -> FsCacheResult<Option<(usize, &'a [u8])>> {
+        let mut encoded_key = vec![];
+        self.serialize(&mut encoded_key, &StoredKey::<K>(key))?;
+        Ok(entries.find(&encoded_key))
+    }
+
+    #[cfg(feature = "mmap")]
+    pub(crate) fn decode_mapped<T: DeserializeOwned>(&self, value: &[u8]) -> FsCacheResult<T> {
+        self.deserialize(value, value.len() as u64)
+    }
+}
+
+impl<T, C, S, K> StorageBackend<T, S, K> for FileBackend<C>
+where
+    T: DeserializeOwned + Serialize + Send + Sync,
+    C: Codec,
+    S: BuildHasher + Default + Send + Sync,
+    K: CacheKey,
+{
+    fn load(&self) -> FsCacheResult<CacheDiskFormat<T, S, K>> {
+        self.acquire_lock()?;
+
+        let (mut cache, migrated) = self.load_snapshot(false)?;
+        let skipped_records = self.replay_journal(&mut cache)?;
+
+        //so that the old format does not need migrating again next time.
+        if (migrated || skipped_records) && !self.is_read_only() {
+            self.rewrite(&(&cache).into())?;
+        }
+
+        Ok(cache)
+    }
+
+    fn save(&self, cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        let journal = match &self.journal {
+            None => return self.save_snapshot(cache),
+            Some(journal) => journal,
+        };
+
+        let journal_io_err = |e| CacheFileIo {
+            src: e,
+            path: journal.path().to_path_buf(),
+        };
+
+        //while the journal is small, making sure it is on disk is enough. In delta mode the
+        //changes aren't known here so a full save is needed.
+        if self.journal_mode == JournalMode::EveryChange && !journal.wants_compaction() {
+            return journal.sync().map_err(journal_io_err);
+        }
+
+        info!(target: "generic_cache_transactions", "compacting journal {}", journal.path().display());
+        self.rewrite(cache)
+    }
+
+    fn save_changes(&self, cache: &CacheView<'_, T, S, K>, changed_keys: &[K]) -> FsCacheResult<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        let journal = match &self.journal {
+            Some(journal) if self.journal_mode == JournalMode::OnSave => journal,
+            _ => return StorageBackend::<T, S, K>::save(self, cache),
+        };
+
+        if journal.wants_compaction() {
+            return StorageBackend::<T, S, K>::save(self, cache);
+        }
+
+        let changes: Vec<(&K::Ref, Option<&T>)> = changed_keys
+            .iter()
+            .map(|key| (key.borrow(), cache.get(key.borrow())))
+            .collect();
+        self.append_to_journal::<T, K>(journal, &changes)?;
+        journal.sync().map_err(|e| CacheFileIo {
+            src: e,
+            path: journal.path().to_path_buf(),
+        })
+    }
+
+    fn append(&self, changes: &[(&K::Ref, Option<&T>)]) -> FsCacheResult<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        match &self.journal {
+            Some(journal) if self.journal_mode == JournalMode::EveryChange => {
+                self.append_to_journal::<T, K>(journal, changes)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn taking_snapshot(&self) {
+        match &self.journal {
+            Some(journal) if self.journal_mode == JournalMode::EveryChange => journal.mark(),
+            _ => (),
+        }
+    }
+
+    fn stored_version(&self) -> FsCacheResult<Option<ProcessorVersion>> {
+        Ok(*self.lock_processor_version())
+    }
+
+    //While a journal is in use, the cache file may not have been written yet even if there are
+    //entries, in which case it must be written now so that the version is not lost.
+    fn store_version(&self, version: ProcessorVersion, cache: &CacheView<'_, T, S, K>) -> FsCacheResult<()> {
+        let previous = self.lock_processor_version().replace(version);
+        let changed = previous.map_or(!cache.is_empty(), |previous| previous != version);
+        match changed && !self.is_read_only() {
+            true => self.rewrite(cache),
+            false => Ok(()),
+        }
+    }
+
+    //Backups are deliberately left alone, so that a reset can still be undone.
+    fn reset(&self) -> FsCacheResult<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+
+        info!(target: "generic_cache_transactions", "deleting cache file {}", self.cache_path.display());
+        match std::fs::remove_file(&self.cache_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: self.cache_path.clone(),
+                })
+            }
+            _ => (),
+        }
+
+        let index_path = entry_index::index_path(&self.cache_path);
+        match std::fs::remove_file(&index_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(CacheFileIo {
+                    src: e,
+                    path: index_path,
+                })
+            }
+            _ => (),
+        }
+
+        match &self.journal {
+            Some(journal) => journal.clear().map_err(|e| CacheFileIo {
+                src: e,
+                path: journal.path().to_path_buf(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+//A rename is only durable once the directory holding the file has been flushed. Directories
+//cannot be opened as files on Windows, where renames are journaled anyway.
+pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(dir)?.sync_all()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+//Renames `from` over `to`. On Windows this fails while another process has `to` open without
+//allowing it to be deleted, as virus scanners and search indexers briefly do, so it is retried
+//for a while before giving up.
+pub(crate) fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mut delay = std::time::Duration::from_millis(1);
+    loop {
+        match std::fs::rename(from, to) {
+            Err(e) if cfg!(windows) && e.kind() == std::io::ErrorKind::PermissionDenied && delay.as_millis() < 1000 => {
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+//`<path>.tmp`, where a file is written before it replaces `path`.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(".tmp");
+    temp_path.into()
+}
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt,
+    hash::BuildHasher,
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
+
+use serde::{
+    de::{self, DeserializeOwned, MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::cache_key::{CacheKey, LoadedKey};
+
+//serde can only store paths which are valid unicode, so paths are stored through these wrappers
+//instead. A unicode path is stored as a string, exactly as serde would store it, so files which
+//serde could already write are unchanged. Any other path is stored as a string starting with a
+//NUL character, which no path can contain, followed by its bytes (or on Windows, its UTF-16
+//code units) with everything but printable ASCII percent-encoded.
+const ENCODED_MARKER: char = '\0';
+
+pub(crate) struct StoredPath<'a>(pub(crate) &'a Path);
+
+impl Serialize for StoredPath<'_> {
+    fn serialize<Se: Serializer>(&self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        match self.0.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.serialize_str(&encoded(self.0)),
+        }
+    }
+}
+
+pub(crate) struct LoadedPath(pub(crate) PathBuf);
+
+impl<'de> Deserialize<'de> for LoadedPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_string(LoadedPathVisitor)
+    }
+}
+
+struct LoadedPathVisitor;
+
+impl Visitor<'_> for LoadedPathVisitor {
+    type Value = LoadedPath;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a path string")
+    }
+
+    fn visit_str<E: de::Error>(self, path: &str) -> Result<LoadedPath, E> {
+        lossless_path(path)
+            .map(LoadedPath)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Str(path), &"a percent-encoded path"))
+    }
+
+    //Some formats hand over strings as bytes, which serde's own path visitor also accepts.
+    fn visit_bytes<E: de::Error>(self, path: &[u8]) -> Result<LoadedPath, E> {
+        match std::str::from_utf8(path) {
+            Ok(path) => self.visit_str(path),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(path), &self)),
+        }
+    }
+}
+
+//The entries of a map from keys, as stored in cache files, given by a function returning an
+//iterator over them, as serializing them only borrows this. `1` is the number of entries, which
+//those of several maps together cannot give exactly.
+pub(crate) struct StoredEntries<F>(pub(crate) F, pub(crate) usize);
+
+impl<'a, F, I, Ks, T> Serialize for StoredEntries<F>
+where
+    F: Fn() -> I,
+    I: Iterator<Item = (Ks, &'a T)>,
+    Ks: Serialize,
+    T: Serialize + 'a,
+{
+    fn serialize<Se: Serializer>(&self, serializer: Se) -> Result<Se::Ok, Se::Error> {
+        let mut map = serializer.serialize_map(Some(self.1))?;
+        for (key, value) in (self.0)() {
+            map.serialize_entry(&key, value)?;
+        }
+        map.end()
+    }
+}
+
+pub(crate) struct LoadedMap<T, S, K = PathBuf>(pub(crate) HashMap<K, T, S>);
+
+impl<'de, T, S, K> Deserialize<'de> for LoadedMap<T, S, K>
+where
+    T: DeserializeOwned,
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(LoadedMapVisitor(PhantomData))
+    }
+}
+
+struct LoadedMapVisitor<T, S, K>(PhantomData<(T, S, K)>);
+
+impl<'de, T, S, K> Visitor<'de> for LoadedMapVisitor<T, S, K>
+where
+    T: DeserializeOwned,
+    S: BuildHasher + Default,
+    K: CacheKey,
+{
+    type Value = LoadedMap<T, S, K>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map from keys")
+    }
+
+    //The length comes from the file, so is not trusted with more than a modest allocation.
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let capacity = access.size_hint().unwrap_or(0).min(4096);
+        let mut map = HashMap::with_capacity_and_hasher(capacity, S::default());
+        while let Some((LoadedKey(key), value)) = access.next_entry()? {
+            map.insert(key, value);
+        }
+        Ok(LoadedMap(map))
+    }
+}
+
+/// A string naming `path` which can be turned back into it exactly, as used for paths which
+/// are not valid unicode in cache files. Unicode paths are returned as they are.
+#[cfg(any(feature = "json", feature = "rkyv"))]
+pub(crate) fn lossless_string(path: &Path) -> String {
+    match path.to_str() {
+        Some(path) => path.to_string(),
+        None => encoded(path),
+    }
+}
+
+/// The path named by a string from [`lossless_string`], or None if it is not a valid encoding.
+pub(crate) fn lossless_path(string: &str) -> Option<PathBuf> {
+    match string.strip_prefix(ENCODED_MARKER) {
+        Some(encoded) => decoded(encoded),
+        None => Some(PathBuf::from(string)),
+    }
+}
+
+fn encoded(path: &Path) -> String {
+    let mut ret = String::from(ENCODED_MARKER);
+    for unit in units(path) {
+        match u8::try_from(unit) {
+            Ok(byte) if byte.is_ascii_graphic() && byte != b'%' => ret.push(byte as char),
+            _ => ret.push_str(&format!("%{:0width$X}", unit, width = UNIT_DIGITS)),
+        }
+    }
+    ret
+}
+
+fn decoded(encoded: &str) -> Option<PathBuf> {
+    let mut units = vec![];
+    let mut rest = encoded;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '%' => {
+                let digits = rest.get(1..1 + UNIT_DIGITS)?;
+                units.push(u16::from_str_radix(digits, 16).ok()?);
+                rest = &rest[1 + UNIT_DIGITS..];
+            }
+            c if c.is_ascii_graphic() => {
+                units.push(c as u16);
+                rest = &rest[1..];
+            }
+            _ => return None,
+        }
+    }
+    from_units(units)
+}
+
+#[cfg(unix)]
+const UNIT_DIGITS: usize = 2;
+
+#[cfg(unix)]
+fn units(path: &Path) -> Vec<u16> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().iter().map(|&byte| byte.into()).collect()
+}
+
+#[cfg(unix)]
+fn from_units(units: Vec<u16>) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    let bytes = units
+        .into_iter()
+        .map(u8::try_from)
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()?;
+    Some(std::ffi::OsString::from_vec(bytes).into())
+}
+
+#[cfg(windows)]
+const UNIT_DIGITS: usize = 4;
+
+#[cfg(windows)]
+fn units(path: &Path) -> Vec<u16> {
+    use std::os::windows::ffi::OsStrExt;
+    path.as_os_str().encode_wide().collect()
+}
+
+#[cfg(windows)]
+fn from_units(units: Vec<u16>) -> Option<PathBuf> {
+    use std::os::windows::ffi::OsStringExt;
+    Some(std::ffi::OsString::from_wide(&units).into())
+}
+
+//Elsewhere paths are always unicode.
+#[cfg(not(any(unix, windows)))]
+const UNIT_DIGITS: usize = 2;
+
+#[cfg(not(any(unix, windows)))]
+fn units(path: &Path) -> Vec<u16> {
+    path.to_string_lossy().bytes().map(|byte| byte.into()).collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn from_units(units: Vec<u16>) -> Option<PathBuf> {
+    let bytes = units
+        .into_iter()
+        .map(u8::try_from)
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()?;
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
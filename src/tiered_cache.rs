@@ -0,0 +1,498 @@
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    fs::Metadata,
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::{error, info, trace};
+use rayon::prelude::*;
+
+use crate::{
+    cache_entry::MtimeCacheEntry,
+    cache_interface::CacheInterface,
+    codec::{BincodeCodec, Codec},
+    errors::{
+        FsCacheErrorKind::{KeyMissing, Processing},
+        FsCacheResult,
+    },
+    file_set::{Enumeration, FileSet},
+    format::{path_fingerprint, ProcessorVersion},
+    invalidation::{update_action, InvalidationStrategy, SourceMetadata, UpdateAction},
+    key_normalization::KeyNormalizer,
+    processing_fs_cache::{missing_paths, unwalked_keys_removed},
+    storage::{FileBackend, StorageBackend},
+    update_report::{UpdateOutcome, UpdateReport},
+};
+
+//What bringing a file up to date did, and the entry to cache for it if it was processed.
+type EntryUpdate<T> = FsCacheResult<(UpdateOutcome, Option<MtimeCacheEntry<T>>)>;
+
+//The entries of a shard, keyed as they are stored, which is relative to the relative root if
+//there is one.
+type ShardEntries<T> = HashMap<PathBuf, MtimeCacheEntry<T>>;
+
+//Files are processed this many at a time when updating from the filesystem, so that no more
+//processed values are held outside the cache at once, where the memory budget cannot see them.
+const UPDATE_CHUNK_SIZE: usize = 1024;
+
+/// A cache of more files than fit in memory, which holds only a bounded number of its shards
+/// (see [`crate::ProcessingFsCacheBuilder::shards`]) in memory at once, and reads the others from
+/// disk when an entry in them is needed. Made by [`crate::ProcessingFsCacheBuilder::build_tiered`].
+///
+/// When another shard must be read, the least recently used shard in memory is dropped to make
+/// room for it, being written first if it has changed. The more shards, the less is held in
+/// memory, but the more often shards are read and written. An unsharded cache is held in memory
+/// in full. Shards are read and written while the cache is locked, so other threads using the
+/// cache wait meanwhile, but files are processed without holding the lock.
+///
+/// With a memory budget (see [`crate::ProcessingFsCacheBuilder::memory_budget`]), shards are
+/// also dropped from memory whenever the estimated size of those in memory grows beyond it, so
+/// that a long update writes out what it has processed rather than running out of memory. The
+/// size of each value is estimated by [`CacheInterface::estimated_size`].
+pub struct TieredCache<I, C = BincodeCodec>
+where
+    I: CacheInterface + Send + Sync,
+    C: Codec,
+{
+    interface: I,
+    //Only used for its lock and read-only status.
+    backend: FileBackend<C>,
+    shards: Vec<FileBackend<C>>,
+    resident: Mutex<Resident<I::T>>,
+    max_resident: usize,
+    memory_budget: Option<usize>,
+    version: ProcessorVersion,
+    invalidation_strategy: InvalidationStrategy,
+    key_normalizer: KeyNormalizer,
+    relative_root: Option<PathBuf>,
+    save_on_drop: bool,
+}
+
+//The shards held in memory, and when each was last used.
+struct Resident<T> {
+    shards: HashMap<usize, Shard<T>>,
+    next_tick: u64,
+}
+
+impl<T> Resident<T> {
+    fn bytes(&self) -> usize {
+        self.shards.values().map(|shard| shard.bytes).sum()
+    }
+}
+
+struct Shard<T> {
+    entries: ShardEntries<T>,
+    //The estimated memory used by the entries.
+    bytes: usize,
+    changed: bool,
+    last_used: u64,
+}
+
+//Estimates the memory used by an entry.
+type SizeFn<'a, T> = &'a dyn Fn(&Path, &MtimeCacheEntry<T>) -> usize;
+
+impl<T> Shard<T> {
+    fn insert(&mut self, key: PathBuf, entry: MtimeCacheEntry<T>, size: SizeFn<'_, T>) {
+        let added = size(&key, &entry);
+        let replaced = self.entries.get(&key).map_or(0, |old| size(&key, old));
+        self.bytes = (self.bytes + added).saturating_sub(replaced);
+        self.entries.insert(key, entry);
+        self.changed = true;
+    }
+
+    fn remove(&mut self, key: &Path, size: SizeFn<'_, T>) {
+        if let Some(old) = self.entries.remove(key) {
+            self.bytes = self.bytes.saturating_sub(size(key, &old));
+            self.changed = true;
+        }
+    }
+}
+
+impl<I, C> TieredCache<I, C>
+where
+    I: CacheInterface + Send + Sync,
+    C: Codec,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        interface: I,
+        backend: FileBackend<C>,
+        shards: Vec<FileBackend<C>>,
+        max_resident: usize,
+        memory_budget: Option<usize>,
+        version: ProcessorVersion,
+        invalidation_strategy: InvalidationStrategy,
+        key_normalizer: KeyNormalizer,
+        relative_root: Option<PathBuf>,
+        save_on_drop: bool,
+    ) -> Self {
+        Self {
+            interface,
+            backend,
+            shards,
+            resident: Mutex::new(Resident {
+                shards: HashMap::new(),
+                next_tick: 0,
+            }),
+            max_resident: max_resident.max(1),
+            memory_budget,
+            version,
+            invalidation_strategy,
+            key_normalizer,
+            relative_root,
+            save_on_drop,
+        }
+    }
+
+    /// Returns the cached value for a path without checking whether the file has changed on
+    /// disk. Fails with [`crate::FsCacheErrorKind::KeyMissing`] if the path is not cached, or if
+    /// the shard holding it cannot be read.
+    pub fn fetch(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<I::T> {
+        let key = self.key_normalizer.key(key.borrow());
+        let stored = self.stored_key(&key);
+        let value = self.with_shard(stored, |shard| {
+            shard.entries.get(stored).map(|entry| entry.value.clone())
+        })?;
+        match value {
+            Some(value) => Ok(Arc::unwrap_or_clone(value)),
+            None => Err(KeyMissing(key.into_owned())),
+        }
+    }
+
+    /// Returns the cached value for a path, first processing the file if it is not cached or if
+    /// the configured [`InvalidationStrategy`] considers it to have changed since it was cached.
+    /// Returns None (and removes any cached entry) if the file no longer exists.
+    pub fn fetch_update(&self, key: impl Borrow<PathBuf>) -> FsCacheResult<Option<I::T>> {
+        let key = self.key_normalizer.key(key.borrow());
+        let stored = self.stored_key(&key);
+        let cached = self.with_shard(stored, |shard| {
+            shard
+                .entries
+                .get(stored)
+                .map(|entry| (entry.source, entry.value.clone()))
+        })?;
+
+        let fs_state = SourceMetadata::read_with_metadata(&key, self.invalidation_strategy);
+        let cached_source = cached.as_ref().map(|(source, _)| *source);
+        match update_action(&key, self.invalidation_strategy, fs_state, cached_source)? {
+            UpdateAction::NoChange => Ok(cached.map(|(_, value)| Arc::unwrap_or_clone(value))),
+            UpdateAction::Update(source, metadata) => {
+                let entry = self.process(&key, source, &metadata)?;
+                let value = entry.value.clone();
+                self.with_shard(stored, |shard| {
+                    shard.insert(stored.to_path_buf(), entry, &|key, entry| self.entry_size(key, entry))
+                })?;
+                Ok(Some(Arc::unwrap_or_clone(value)))
+            }
+            UpdateAction::Remove => self
+                .with_shard(stored, |shard| {
+                    shard.remove(stored, &|key, entry| self.entry_size(key, entry))
+                })
+                .map(|_| None),
+        }
+    }
+
+    /// Bring every file in the file set up to date, like
+    /// [`crate::ProcessingFsCache::update_from_fs`], a shard at a time. Every shard is read in
+    /// turn to find the entries of files which no longer exist, but no more are held in memory
+    /// at once than the cache allows. Renames are not detected, and failures are not recorded.
+    pub fn update_from_fs(&self, file_set: &FileSet) -> FsCacheResult<UpdateReport> {
+        let file_set = self.key_normalizer.file_set(file_set);
+        let Enumeration { files, errors } = file_set.enumerate()?;
+
+        let mut found: Vec<Vec<PathBuf>> = vec![Vec::new(); self.shards.len()];
+        for file in self.key_normalizer.keys(files) {
+            found[self.shard_of(self.stored_key(&file))].push(file);
+        }
+
+        let size: SizeFn<'_, I::T> = &|key, entry| self.entry_size(key, entry);
+        let mut report = UpdateReport::default();
+        for (n, found) in found.into_iter().enumerate() {
+            let (found, cached_keys) = self.with_shard_at(n, |shard| {
+                let found: Vec<(PathBuf, Option<SourceMetadata>)> = found
+                    .into_iter()
+                    .map(|key| {
+                        let source = shard.entries.get(self.stored_key(&key)).map(|entry| entry.source);
+                        (key, source)
+                    })
+                    .collect();
+                let cached_keys: Vec<PathBuf> = shard.entries.keys().map(|key| self.cached_key(key)).collect();
+                (found, cached_keys)
+            })?;
+            let found_keys: Vec<PathBuf> = found.iter().map(|(key, _)| key.clone()).collect();
+            let missing = missing_paths(&file_set, &found_keys, unwalked_keys_removed(cached_keys, &errors));
+
+            self.with_shard_at(n, |shard| {
+                for key in missing {
+                    shard.remove(self.stored_key(&key), size);
+                    report.record(key, Ok(UpdateOutcome::Removed));
+                }
+            })?;
+
+            //Files are processed without holding the lock, so the shard may have been dropped
+            //from memory by the time their entries are inserted.
+            for chunk in found.chunks(UPDATE_CHUNK_SIZE) {
+                let updates: Vec<(PathBuf, EntryUpdate<I::T>)> = chunk
+                    .par_iter()
+                    .map(|(key, cached_source)| (key.clone(), self.update_entry(key, *cached_source)))
+                    .collect();
+                self.insert_updates(n, updates, &mut report)?;
+            }
+        }
+        report.record_walk_errors(errors);
+        Ok(report)
+    }
+
+    fn insert_updates(
+        &self,
+        n: usize,
+        updates: Vec<(PathBuf, EntryUpdate<I::T>)>,
+        report: &mut UpdateReport,
+    ) -> FsCacheResult<()> {
+        let size: SizeFn<'_, I::T> = &|key, entry| self.entry_size(key, entry);
+        self.with_shard_at(n, |shard| {
+            for (key, update) in updates {
+                let stored = self.stored_key(&key).to_path_buf();
+                let outcome = update.map(|(outcome, entry)| {
+                    match entry {
+                        Some(entry) => shard.insert(stored, entry, size),
+                        None if matches!(outcome, UpdateOutcome::Removed) => shard.remove(&stored, size),
+                        None => {}
+                    }
+                    outcome
+                });
+                report.record(key, outcome);
+            }
+        })
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// How many shards are held in memory.
+    pub fn resident_shards(&self) -> usize {
+        self.lock().shards.len()
+    }
+
+    /// The estimated memory used by the shards held in memory.
+    pub fn estimated_memory(&self) -> usize {
+        self.lock().bytes()
+    }
+
+    //The key and entry themselves, and whatever the value holds beyond them.
+    fn entry_size(&self, key: &Path, entry: &MtimeCacheEntry<I::T>) -> usize {
+        mem::size_of::<(PathBuf, MtimeCacheEntry<I::T>)>()
+            + key.as_os_str().len()
+            + self.interface.estimated_size(&entry.value)
+    }
+
+    fn update_entry(&self, key: &Path, cached_source: Option<SourceMetadata>) -> EntryUpdate<I::T> {
+        let fs_state = SourceMetadata::read_with_metadata(key, self.invalidation_strategy);
+        match update_action(key, self.invalidation_strategy, fs_state, cached_source)? {
+            UpdateAction::NoChange => Ok((UpdateOutcome::Unchanged, None)),
+            UpdateAction::Update(source, metadata) => {
+                let entry = self.process(key, source, &metadata)?;
+                let outcome = match cached_source {
+                    Some(_) => UpdateOutcome::Reprocessed,
+                    None => UpdateOutcome::Processed,
+                };
+                Ok((outcome, Some(entry)))
+            }
+            //the file was deleted after the file set was walked.
+            UpdateAction::Remove => Ok((UpdateOutcome::Removed, None)),
+        }
+    }
+
+    fn process(&self, key: &Path, source: SourceMetadata, metadata: &Metadata) -> FsCacheResult<MtimeCacheEntry<I::T>> {
+        let start = Instant::now();
+        match self.interface.try_load_with_metadata(key, metadata) {
+            Ok(value) => Ok(MtimeCacheEntry::processed(source, value, start.elapsed())),
+            Err(src) => Err(Processing {
+                src,
+                path: key.to_path_buf(),
+            }),
+        }
+    }
+
+    //Like the relative backend, keys beneath the relative root are stored relative to it.
+    fn stored_key<'a>(&self, key: &'a Path) -> &'a Path {
+        match &self.relative_root {
+            Some(root) => match key.strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => key,
+            },
+            None => key,
+        }
+    }
+
+    //Joining an absolute key replaces the root, so keys stored as they were come back unchanged.
+    fn cached_key(&self, stored: &Path) -> PathBuf {
+        match &self.relative_root {
+            Some(root) => root.join(stored),
+            None => stored.to_path_buf(),
+        }
+    }
+
+    fn shard_of(&self, stored: &Path) -> usize {
+        (path_fingerprint(stored) % self.shards.len() as u64) as usize
+    }
+
+    fn with_shard<R>(&self, stored: &Path, f: impl FnOnce(&mut Shard<I::T>) -> R) -> FsCacheResult<R> {
+        self.with_shard_at(self.shard_of(stored), f)
+    }
+
+    //Calls `f` with the nth shard, first reading it if it is not in memory, which drops the
+    //least recently used shards from memory until there is room for it.
+    fn with_shard_at<R>(&self, n: usize, f: impl FnOnce(&mut Shard<I::T>) -> R) -> FsCacheResult<R> {
+        let mut guard = self.lock();
+        let resident = &mut *guard;
+        let tick = resident.next_tick;
+        resident.next_tick += 1;
+
+        if !resident.shards.contains_key(&n) {
+            while resident.shards.len() >= self.max_resident {
+                self.unload_least_recent(resident, n)?;
+            }
+            let (entries, changed) = self.read_shard(n)?;
+            let bytes = entries.iter().map(|(key, entry)| self.entry_size(key, entry)).sum();
+            resident.shards.insert(
+                n,
+                Shard {
+                    entries,
+                    bytes,
+                    changed,
+                    last_used: tick,
+                },
+            );
+        }
+        let ret = match resident.shards.get_mut(&n) {
+            Some(shard) => {
+                shard.last_used = tick;
+                f(shard)
+            }
+            None => unreachable!(),
+        };
+        self.keep_within_budget(resident, n)?;
+        Ok(ret)
+    }
+
+    //Drops the least recently used shards other than the nth from memory while those in memory
+    //are estimated to use more than the budget.
+    fn keep_within_budget(&self, resident: &mut Resident<I::T>, n: usize) -> FsCacheResult<()> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        while resident.shards.len() > 1 && resident.bytes() > budget {
+            info!(target: "generic_cache_transactions",
+                "{} shards in memory use about {} bytes, over the budget of {}, so dropping one",
+                resident.shards.len(), resident.bytes(), budget
+            );
+            self.unload_least_recent(resident, n)?;
+        }
+        Ok(())
+    }
+
+    //A shard which cannot be written is kept in memory, so that its changes are not lost.
+    fn unload_least_recent(&self, resident: &mut Resident<I::T>, except: usize) -> FsCacheResult<()> {
+        let least_recent = resident
+            .shards
+            .iter()
+            .filter(|(n, _)| **n != except)
+            .min_by_key(|(_, shard)| shard.last_used);
+        let n = match least_recent {
+            Some((n, _)) => *n,
+            None => return Ok(()),
+        };
+        if let Some(shard) = resident.shards.get(&n) {
+            if shard.changed {
+                self.write_shard(n, &shard.entries)?;
+            }
+        }
+        resident.shards.remove(&n);
+        Ok(())
+    }
+
+    //Also returns whether the shard must be written again, as it held entries made by a
+    //different version of the processing, which are all stale.
+    fn read_shard(&self, n: usize) -> FsCacheResult<(ShardEntries<I::T>, bool)> {
+        let backend = &self.shards[n];
+        let mut entries = backend.read::<MtimeCacheEntry<I::T>>()?;
+        let mut changed = false;
+        if let Some(stored_version) = StorageBackend::<MtimeCacheEntry<I::T>>::stored_version(backend)? {
+            if stored_version != self.version && !entries.is_empty() {
+                info!(target: "generic_cache_startup",
+                    "{} was made by a different version of the processing, so none of its entries are used",
+                    backend.cache_path().display()
+                );
+                entries.clear();
+                changed = true;
+            }
+        }
+        trace!(target: "generic_cache_startup",
+            "Loaded shard. Path: {}, Entries: {}", backend.cache_path().display(), entries.len()
+        );
+        Ok((entries, changed))
+    }
+
+    /// Write every shard in memory which has changed since it was read, in parallel. Shards
+    /// which are not in memory have already been written.
+    pub fn save(&self) -> FsCacheResult<()> {
+        let mut resident = self.lock();
+        let changed: Vec<(&usize, &mut Shard<I::T>)> =
+            resident.shards.iter_mut().filter(|(_, shard)| shard.changed).collect();
+        //Shards which were written are not written again, even if others could not be.
+        let results: Vec<FsCacheResult<()>> = changed
+            .into_par_iter()
+            .map(|(n, shard)| {
+                self.write_shard(*n, &shard.entries)?;
+                shard.changed = false;
+                Ok(())
+            })
+            .collect();
+        results.into_iter().collect()
+    }
+
+    fn write_shard(&self, n: usize, entries: &ShardEntries<I::T>) -> FsCacheResult<()> {
+        if self.backend.is_read_only() {
+            return Ok(());
+        }
+        let shard = &self.shards[n];
+        info!(target: "generic_cache_transactions",
+            "saving shard {} of {} ({} entries)", n, self.shards.len(), entries.len()
+        );
+        shard.set_processor_version(self.version);
+        let entries: HashMap<&Path, &MtimeCacheEntry<I::T>> =
+            entries.iter().map(|(key, entry)| (key.as_path(), entry)).collect();
+        shard.save_entries(&entries)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Resident<I::T>> {
+        match self.resident.lock() {
+            Ok(resident) => resident,
+            Err(_) => unreachable!(),
+        }
+    }
+}
+
+impl<I, C> Drop for TieredCache<I, C>
+where
+    I: CacheInterface + Send + Sync,
+    C: Codec,
+{
+    fn drop(&mut self) {
+        if !self.save_on_drop {
+            return;
+        }
+
+        //There is no way to report an error from here, so the best that can be done is to log it.
+        if let Err(e) = self.save() {
+            error!(target: "generic_cache_transactions", "Failed to save cache on drop: {}", e);
+        }
+    }
+}
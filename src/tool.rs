@@ -0,0 +1,247 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{
+    cache_entry::MtimeCacheEntry, cache_interface::CacheInterface, codec::Codec, errors::FsCacheResult,
+    export::ExportedEntries, processing_fs_cache::ProcessingFsCache, storage::FileBackend,
+};
+
+pub const USAGE: &str = "\
+usage: fs-cache-tool [--codec json|cbor|msgpack] <cache file> <command>
+
+commands:
+    stats                    the number of entries and the size of the cache file
+    keys [dir]               the cached paths, optionally only those beneath dir
+    get <path>               the value cached for path, as JSON
+    prune [--missing | --expired | --older-than <seconds>]
+                             remove entries for deleted files (the default), expired
+                             entries, or entries cached longer ago than the given age
+    verify [sample rate]     reprocess some or all (the default) unchanged files, and
+                             report any whose value differs from the cached one
+    export [--csv]           every cached path and value, as a JSON array or as CSV";
+
+/// Opens cache files for [`run`], which needs to know the processing behind a cache to do
+/// anything more than read it.
+///
+/// A binary which calls `run` with an inspector for a particular cache can do everything the
+/// `fs-cache-tool` binary can, and also `prune` and `verify` it, whatever its codec.
+pub trait CacheInspector {
+    type Interface: CacheInterface + Send + Sync;
+
+    /// Open the cache at `cache_path` the same way as the application which writes it, so
+    /// that it is not mistaken for a cache made by a different version of the processing.
+    fn open(&self, cache_path: PathBuf) -> FsCacheResult<ProcessingFsCache<Self::Interface>>;
+}
+
+/// Run the `fs-cache-tool` command line (see [`USAGE`]) with `args`, not including the
+/// name of the program, using `inspector` to open the cache.
+pub fn run<C>(inspector: &C, args: &[String]) -> Result<(), String>
+where
+    C: CacheInspector,
+    <C::Interface as CacheInterface>::T: PartialEq + 'static,
+{
+    let (cache_path, command) = match args {
+        [cache_path, command @ ..] => (PathBuf::from(cache_path), Command::parse(command)?),
+        _ => return Err(USAGE.to_string()),
+    };
+    let cache = inspector.open(cache_path.clone()).map_err(|e| format!("{}", e))?;
+
+    match command {
+        Command::Stats => {
+            println!("entries: {}", cache.len());
+            println!("failures: {}", cache.failures().len());
+            print_file_size(&cache_path);
+            Ok(())
+        }
+        Command::Keys(dir) => {
+            print_keys(cache.sorted_keys(), dir.as_deref());
+            Ok(())
+        }
+        Command::Get(key) => match cache.fetch(&key) {
+            Ok(value) => print_json(&value),
+            Err(e) => Err(format!("{}", e)),
+        },
+        Command::Prune(prune) => {
+            let removed = match prune {
+                Prune::Missing => cache.prune_missing_files(),
+                Prune::Expired => cache.prune_expired(),
+                Prune::OlderThan(age) => cache.prune_older_than(age),
+            };
+            let removed = removed.and_then(|removed| cache.save().map(|_| removed));
+            match removed {
+                Ok(removed) => {
+                    println!("removed {} entries", removed);
+                    Ok(())
+                }
+                Err(e) => Err(format!("{}", e)),
+            }
+        }
+        Command::Verify(sample_rate) => {
+            let report = cache.verify(sample_rate);
+            for path in &report.mismatched {
+                println!("{}", path.display());
+            }
+            println!("{}", report);
+            match report.mismatched.is_empty() && report.errors.is_empty() {
+                true => Ok(()),
+                false => Err("verification failed".to_string()),
+            }
+        }
+        Command::Export(format) => {
+            let stdout = std::io::stdout().lock();
+            let exported = match format {
+                ExportFormat::Json => cache.export_json(stdout),
+                ExportFormat::Csv => cache.export_csv(stdout),
+            };
+            exported.map_err(|e| format!("{}", e))
+        }
+    }
+}
+
+/// Run the `fs-cache-tool` command line without knowing anything about the cache, as the
+/// `fs-cache-tool` binary does. Values are read as JSON values whatever their type, so this
+/// only works for cache files written with a self-describing codec (chosen with a leading
+/// `--codec` argument, and defaulting to JSON), and cannot `prune` or `verify` them.
+pub fn run_untyped(args: &[String]) -> Result<(), String> {
+    let (codec, args) = match args {
+        [flag, codec, args @ ..] if flag == "--codec" => (codec.as_str(), args),
+        args => ("json", args),
+    };
+    let (cache_path, command) = match args {
+        [cache_path, command @ ..] => (PathBuf::from(cache_path), Command::parse(command)?),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let entries = match codec {
+        "json" => read_untyped(&cache_path, crate::codec::JsonCodec),
+        #[cfg(feature = "cbor")]
+        "cbor" => read_untyped(&cache_path, crate::codec::CborCodec),
+        #[cfg(feature = "msgpack")]
+        "msgpack" => read_untyped(&cache_path, crate::codec::MessagePackCodec),
+        codec => return Err(format!("unsupported codec: {}", codec)),
+    };
+    let mut entries: Vec<(PathBuf, serde_json::Value)> = entries
+        .map_err(|e| format!("{}", e))?
+        .into_iter()
+        .map(|(key, entry)| (key, std::sync::Arc::unwrap_or_clone(entry.value)))
+        .collect();
+    entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    match command {
+        Command::Stats => {
+            println!("entries: {}", entries.len());
+            print_file_size(&cache_path);
+            Ok(())
+        }
+        Command::Keys(dir) => {
+            print_keys(entries.into_iter().map(|(key, _)| key).collect(), dir.as_deref());
+            Ok(())
+        }
+        Command::Get(key) => match entries.iter().find(|(path, _)| *path == key) {
+            Some((_, value)) => print_json(value),
+            None => Err(format!("not cached: {}", key.display())),
+        },
+        Command::Prune(_) | Command::Verify(_) => Err(
+            "prune and verify need to know the processing behind the cache, so are only available \
+             from a binary which calls generic_filesystem_cache::tool::run"
+                .to_string(),
+        ),
+        Command::Export(format) => {
+            let mut exported = ExportedEntries::new();
+            for (path, value) in &entries {
+                exported.push(path, value).map_err(|e| format!("{}", e))?;
+            }
+            let stdout = std::io::stdout().lock();
+            let exported = match format {
+                ExportFormat::Json => exported.write_json(stdout),
+                ExportFormat::Csv => exported.write_csv(stdout),
+            };
+            exported.map_err(|e| format!("{}", e))
+        }
+    }
+}
+
+enum Command {
+    Stats,
+    Keys(Option<PathBuf>),
+    Get(PathBuf),
+    Prune(Prune),
+    Verify(f64),
+    Export(ExportFormat),
+}
+
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+enum Prune {
+    Missing,
+    Expired,
+    OlderThan(Duration),
+}
+
+impl Command {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(match args[..] {
+            ["stats"] => Self::Stats,
+            ["keys"] => Self::Keys(None),
+            ["keys", dir] => Self::Keys(Some(dir.into())),
+            ["get", key] => Self::Get(key.into()),
+            ["prune"] | ["prune", "--missing"] => Self::Prune(Prune::Missing),
+            ["prune", "--expired"] => Self::Prune(Prune::Expired),
+            ["prune", "--older-than", seconds] => match seconds.parse() {
+                Ok(seconds) => Self::Prune(Prune::OlderThan(Duration::from_secs(seconds))),
+                Err(_) => return Err(format!("not a number of seconds: {}", seconds)),
+            },
+            ["verify"] => Self::Verify(1.0),
+            ["verify", sample_rate] => match sample_rate.parse() {
+                Ok(sample_rate) => Self::Verify(sample_rate),
+                Err(_) => return Err(format!("not a sample rate: {}", sample_rate)),
+            },
+            ["export"] => Self::Export(ExportFormat::Json),
+            ["export", "--csv"] => Self::Export(ExportFormat::Csv),
+            _ => return Err(USAGE.to_string()),
+        })
+    }
+}
+
+fn read_untyped<C: Codec>(
+    cache_path: &Path,
+    codec: C,
+) -> FsCacheResult<std::collections::HashMap<PathBuf, MtimeCacheEntry<serde_json::Value>>> {
+    let backend = FileBackend::with_codec(cache_path.to_path_buf(), codec);
+    let mut journal_path = cache_path.to_path_buf().into_os_string();
+    journal_path.push(".journal");
+    match Path::new(&journal_path).exists() {
+        true => backend.with_journal(usize::MAX).read_as(),
+        false => backend.read_as(),
+    }
+}
+
+fn print_keys(keys: Vec<PathBuf>, dir: Option<&Path>) {
+    for key in keys {
+        if dir.is_none_or(|dir| key.starts_with(dir)) {
+            println!("{}", key.display());
+        }
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<(), String> {
+    let mut stdout = std::io::stdout().lock();
+    serde_json::to_writer_pretty(&mut stdout, value)
+        .map_err(|e| format!("{}", e))
+        .and_then(|_| writeln!(stdout).map_err(|e| format!("{}", e)))
+}
+
+fn print_file_size(cache_path: &Path) {
+    if let Ok(metadata) = std::fs::metadata(cache_path) {
+        println!("cache file: {} bytes", metadata.len());
+    }
+}
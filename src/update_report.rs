@@ -0,0 +1,196 @@
+use std::{fmt, path::PathBuf};
+
+use log::warn;
+
+use crate::{
+    errors::{FsCacheErrorKind, FsCacheErrorKind::Traversal, FsCacheResult},
+    file_set::WalkError,
+};
+
+/// A summary of what `update_from_fs` changed.
+#[derive(Debug, Default)]
+pub struct UpdateReport {
+    /// Files which were not cached before, and have now been processed.
+    pub processed: usize,
+    /// Cached files which had changed, and have been processed again.
+    pub reprocessed: usize,
+    /// Cached files which no longer exist, and have been removed from the cache.
+    pub removed: usize,
+    /// Cached files which had been renamed or moved, and whose entries have been moved to
+    /// their new path rather than processed again. See
+    /// [`crate::ProcessingFsCacheBuilder::detect_renames`].
+    pub migrated: usize,
+    /// Entries evicted afterwards to keep within
+    /// [`crate::ProcessingFsCacheBuilder::lru_capacity`].
+    pub evicted: usize,
+    /// Cached files which had not changed.
+    pub unchanged: usize,
+    /// Files which failed to process on an earlier update, and were not retried because
+    /// of the cache's [`crate::RetryPolicy`].
+    pub skipped: usize,
+    /// Files which could not be brought up to date. Unlike errors from outside an
+    /// individual file, these do not stop the rest of the update.
+    pub errors: Vec<(PathBuf, FsCacheErrorKind)>,
+}
+
+impl UpdateReport {
+    /// The number of entries which were processed, reprocessed, removed or migrated.
+    pub fn changed(&self) -> usize {
+        self.processed + self.reprocessed + self.removed + self.migrated
+    }
+
+    pub(crate) fn record(&mut self, path: PathBuf, result: FsCacheResult<UpdateOutcome>) {
+        match result {
+            Ok(UpdateOutcome::Processed) => self.processed += 1,
+            Ok(UpdateOutcome::Reprocessed) => self.reprocessed += 1,
+            Ok(UpdateOutcome::Removed) => self.removed += 1,
+            Ok(UpdateOutcome::Unchanged) => self.unchanged += 1,
+            Ok(UpdateOutcome::Skipped) => self.skipped += 1,
+            Err(e) => {
+                warn!(target: "generic_cache_update", "failed to update {}: {}", path.display(), e);
+                self.errors.push((path, e));
+            }
+        }
+    }
+
+    pub(crate) fn record_walk_errors(&mut self, walk_errors: Vec<WalkError>) {
+        self.errors.extend(traversal_errors(walk_errors));
+    }
+}
+
+impl fmt::Display for UpdateReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new, {} changed, {} removed, {} migrated, {} unchanged, {} skipped, {} errors",
+            self.processed,
+            self.reprocessed,
+            self.removed,
+            self.migrated,
+            self.unchanged,
+            self.skipped,
+            self.errors.len()
+        )
+    }
+}
+
+/// What `update_from_fs` would do, as returned by `plan_update`. Each list is in the order
+/// the files would be visited.
+#[derive(Debug, Default)]
+pub struct UpdatePlan {
+    /// Files which are not cached, and would be processed.
+    pub process: Vec<PathBuf>,
+    /// Cached files which have changed or expired, and would be processed again.
+    pub reprocess: Vec<PathBuf>,
+    /// Cached files which no longer exist, and would be removed from the cache.
+    pub remove: Vec<PathBuf>,
+    /// Cached files which have been renamed or moved, and whose entries would be moved from
+    /// the first path to the second.
+    pub migrate: Vec<(PathBuf, PathBuf)>,
+    /// Files which would not be retried because of the cache's [`crate::RetryPolicy`].
+    pub skip: Vec<PathBuf>,
+    /// Cached files which have not changed.
+    pub unchanged: usize,
+    /// Files which could not be looked at, and parts of the file set which could not be walked.
+    pub errors: Vec<(PathBuf, FsCacheErrorKind)>,
+}
+
+impl UpdatePlan {
+    /// The number of files which would be processed or reprocessed.
+    pub fn to_process(&self) -> usize {
+        self.process.len() + self.reprocess.len()
+    }
+
+    pub(crate) fn record(&mut self, path: PathBuf, result: FsCacheResult<UpdateOutcome>) {
+        match result {
+            Ok(UpdateOutcome::Processed) => self.process.push(path),
+            Ok(UpdateOutcome::Reprocessed) => self.reprocess.push(path),
+            Ok(UpdateOutcome::Removed) => self.remove.push(path),
+            Ok(UpdateOutcome::Unchanged) => self.unchanged += 1,
+            Ok(UpdateOutcome::Skipped) => self.skip.push(path),
+            Err(e) => self.errors.push((path, e)),
+        }
+    }
+
+    pub(crate) fn record_walk_errors(&mut self, walk_errors: Vec<WalkError>) {
+        self.errors.extend(traversal_errors(walk_errors));
+    }
+}
+
+impl fmt::Display for UpdatePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new, {} changed, {} removed, {} migrated, {} unchanged, {} skipped, {} errors",
+            self.process.len(),
+            self.reprocess.len(),
+            self.remove.len(),
+            self.migrate.len(),
+            self.unchanged,
+            self.skip.len(),
+            self.errors.len()
+        )
+    }
+}
+
+//Anything which could not be walked has already been logged by the walk.
+fn traversal_errors(walk_errors: Vec<WalkError>) -> impl Iterator<Item = (PathBuf, FsCacheErrorKind)> {
+    walk_errors
+        .into_iter()
+        .map(|WalkError { path, error }| (path.clone(), Traversal { src: error, path }))
+}
+
+/// A summary of what `verify` found.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Entries which were reprocessed and compared with the cached value.
+    pub checked: usize,
+    /// Entries whose reprocessed value differed from the cached value.
+    pub mismatched: Vec<PathBuf>,
+    /// Sampled entries which were not compared, because the file had changed since it was
+    /// cached or no longer exists.
+    pub skipped: usize,
+    /// Files which could not be reprocessed.
+    pub errors: Vec<(PathBuf, FsCacheErrorKind)>,
+}
+
+impl VerifyReport {
+    //Some(same) if the entry was compared, or None if it was skipped.
+    pub(crate) fn record(&mut self, path: PathBuf, result: FsCacheResult<Option<bool>>) {
+        match result {
+            Ok(Some(true)) => self.checked += 1,
+            Ok(Some(false)) => {
+                warn!(target: "generic_cache_update", "cached value differs from reprocessed value for {}", path.display());
+                self.checked += 1;
+                self.mismatched.push(path);
+            }
+            Ok(None) => self.skipped += 1,
+            Err(e) => {
+                warn!(target: "generic_cache_update", "failed to verify {}: {}", path.display(), e);
+                self.errors.push((path, e));
+            }
+        }
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} checked, {} mismatched, {} skipped, {} errors",
+            self.checked,
+            self.mismatched.len(),
+            self.skipped,
+            self.errors.len()
+        )
+    }
+}
+
+// What bringing a single path up to date did.
+pub(crate) enum UpdateOutcome {
+    Processed,
+    Reprocessed,
+    Removed,
+    Unchanged,
+    Skipped,
+}
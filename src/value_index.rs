@@ -0,0 +1,136 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use crate::{cache_entry::MtimeCacheEntry, observer::CacheObserver};
+
+//Finds the paths cached with a value without looking at every entry.
+pub(crate) trait ValueIndex<T>: Send + Sync {
+    fn insert(&self, path: &Path, value: &Arc<T>);
+    fn remove(&self, path: &Path);
+    fn paths_with_value(&self, value: &T) -> Vec<PathBuf>;
+}
+
+pub(crate) struct ReverseIndex<T> {
+    paths: RwLock<IndexedPaths<T>>,
+}
+
+struct IndexedPaths<T> {
+    by_value: HashMap<Arc<T>, HashSet<PathBuf>>,
+    //The value each path is indexed under, so that it can be found again when the path's entry
+    //is replaced or removed.
+    by_path: HashMap<PathBuf, Arc<T>>,
+}
+
+impl<T> Default for ReverseIndex<T> {
+    fn default() -> Self {
+        Self {
+            paths: RwLock::new(IndexedPaths {
+                by_value: HashMap::new(),
+                by_path: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl<T> IndexedPaths<T>
+where
+    T: Eq + Hash,
+{
+    fn forget(&mut self, path: &Path, value: &T) {
+        if let Some(paths) = self.by_value.get_mut(value) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.by_value.remove(value);
+            }
+        }
+    }
+}
+
+impl<T> ValueIndex<T> for ReverseIndex<T>
+where
+    T: Eq + Hash + Send + Sync,
+{
+    fn insert(&self, path: &Path, value: &Arc<T>) {
+        let mut indexed = match self.paths.write() {
+            Ok(indexed) => indexed,
+            Err(_) => unreachable!(),
+        };
+        match indexed.by_path.insert(path.to_path_buf(), value.clone()) {
+            Some(old) if old == *value => return,
+            Some(old) => indexed.forget(path, &old),
+            None => {}
+        }
+        indexed
+            .by_value
+            .entry(value.clone())
+            .or_default()
+            .insert(path.to_path_buf());
+    }
+
+    fn remove(&self, path: &Path) {
+        let mut indexed = match self.paths.write() {
+            Ok(indexed) => indexed,
+            Err(_) => unreachable!(),
+        };
+        if let Some(old) = indexed.by_path.remove(path) {
+            indexed.forget(path, &old);
+        }
+    }
+
+    fn paths_with_value(&self, value: &T) -> Vec<PathBuf> {
+        let indexed = match self.paths.read() {
+            Ok(indexed) => indexed,
+            Err(_) => unreachable!(),
+        };
+        match indexed.by_value.get(value) {
+            Some(paths) => paths.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+//Keeps an index up to date with every change to the cache, then passes the change on to the
+//user's observer if there is one. Changes are observed while the cache is locked, so the index
+//always agrees with the cache.
+pub(crate) struct IndexingObserver<T> {
+    index: Arc<dyn ValueIndex<T>>,
+    next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>,
+}
+
+impl<T> IndexingObserver<T> {
+    pub(crate) fn new(index: Arc<dyn ValueIndex<T>>, next: Option<Box<dyn CacheObserver<MtimeCacheEntry<T>>>>) -> Self {
+        Self { index, next }
+    }
+}
+
+impl<T> CacheObserver<MtimeCacheEntry<T>> for IndexingObserver<T> {
+    fn on_insert(&self, path: &Path, entry: &MtimeCacheEntry<T>) {
+        self.index.insert(path, &entry.value);
+        if let Some(next) = &self.next {
+            next.on_insert(path, entry)
+        }
+    }
+
+    fn on_remove(&self, path: &Path) {
+        self.index.remove(path);
+        if let Some(next) = &self.next {
+            next.on_remove(path)
+        }
+    }
+
+    fn on_save(&self) {
+        if let Some(next) = &self.next {
+            next.on_save()
+        }
+    }
+
+    fn on_load(&self, entries: usize) {
+        if let Some(next) = &self.next {
+            next.on_load(entries)
+        }
+    }
+}
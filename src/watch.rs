@@ -0,0 +1,83 @@
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+use log::warn;
+use notify::{event::EventKind, recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    errors::{FsCacheErrorKind::Watch, FsCacheResult},
+    file_set::FileSet,
+};
+
+/// Watches the roots of a [`FileSet`] for changes, so that a cache can be kept up to date
+/// with `ProcessingFsCache::update_from_events` instead of repeatedly rescanning everything.
+pub struct FsWatcher {
+    file_set: FileSet,
+    //never read, but watching stops when it is dropped.
+    _watcher: RecommendedWatcher,
+    events: mpsc::Receiver<notify::Result<Event>>,
+}
+
+impl FsWatcher {
+    pub fn new(file_set: FileSet) -> FsCacheResult<Self> {
+        let (sender, events) = mpsc::channel();
+        let mut watcher = recommended_watcher(sender).map_err(|e| Watch {
+            src: format!("{}", e),
+            path: PathBuf::new(),
+        })?;
+
+        for root in file_set.roots() {
+            watcher.watch(root, RecursiveMode::Recursive).map_err(|e| Watch {
+                src: format!("{}", e),
+                path: root.clone(),
+            })?;
+        }
+
+        Ok(Self {
+            file_set,
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    pub fn file_set(&self) -> &FileSet {
+        &self.file_set
+    }
+
+    // Wait up to `timeout` for something to change, then return every path which has been
+    // created, modified or removed since the last call. Returns nothing if the timeout expired.
+    // Paths may be directories.
+    pub(crate) fn changed_paths(&self, timeout: Duration) -> FsCacheResult<Vec<PathBuf>> {
+        let mut changed = HashSet::new();
+
+        let first = match self.events.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return Ok(vec![]),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Watch {
+                    src: "the watcher stopped unexpectedly".to_string(),
+                    path: PathBuf::new(),
+                })
+            }
+        };
+
+        for event in std::iter::once(first).chain(self.events.try_iter()) {
+            match event {
+                Ok(event) => match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Any => {
+                        changed.extend(event.paths)
+                    }
+                    EventKind::Access(_) | EventKind::Other => (),
+                },
+                //the next full update_from_fs will catch anything missed.
+                Err(e) => warn!(target: "generic_cache_watch", "error watching for changes: {}", e),
+            }
+        }
+
+        Ok(changed.into_iter().collect())
+    }
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use common::TempDir;
+use generic_filesystem_cache::{CacheView, FileBackend, FsCacheErrorKind, StorageBackend};
+
+fn entries(count: u64) -> HashMap<PathBuf, u64> {
+    (0..count).map(|n| (PathBuf::from(format!("/data/file{}", n)), n)).collect()
+}
+
+fn load(backend: &FileBackend) -> Result<HashMap<PathBuf, u64>, FsCacheErrorKind> {
+    StorageBackend::<u64>::load(backend)
+}
+
+#[test]
+fn changed_byte_fails_checksum() {
+    let dir = TempDir::new("changed_byte_fails_checksum");
+    let backend = FileBackend::new(dir.join("cache"));
+    backend.save(&CacheView::from(&entries(100))).unwrap();
+
+    //the last byte is part of the last value, so the file still decodes.
+    let mut bytes = std::fs::read(dir.join("cache")).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(dir.join("cache"), bytes).unwrap();
+
+    match load(&FileBackend::new(dir.join("cache"))) {
+        Err(FsCacheErrorKind::CorruptedCache { src, .. }) => assert!(src.contains("checksum mismatch"), "{}", src),
+        other => panic!("expected a corrupted cache, got {:?}", other),
+    }
+}
+
+#[test]
+fn truncated_file_fails_checksum() {
+    let dir = TempDir::new("truncated_file_fails_checksum");
+    let backend = FileBackend::new(dir.join("cache"));
+    backend.save(&CacheView::from(&entries(100))).unwrap();
+
+    let bytes = std::fs::read(dir.join("cache")).unwrap();
+    std::fs::write(dir.join("cache"), &bytes[..bytes.len() - 3]).unwrap();
+
+    match load(&FileBackend::new(dir.join("cache"))) {
+        Err(FsCacheErrorKind::CorruptedCache { src, .. }) => assert!(src.contains("bytes of data"), "{}", src),
+        other => panic!("expected a corrupted cache, got {:?}", other),
+    }
+}
+
+#[test]
+fn repair_recovers_entries_before_truncation() {
+    let dir = TempDir::new("repair_recovers_entries_before_truncation");
+    let backend = FileBackend::new(dir.join("cache"));
+    let cache = entries(100);
+    backend.save(&CacheView::from(&cache)).unwrap();
+
+    let bytes = std::fs::read(dir.join("cache")).unwrap();
+    std::fs::write(dir.join("cache"), &bytes[..bytes.len() - 3]).unwrap();
+
+    let report = FileBackend::new(dir.join("cache")).repair::<u64>().unwrap();
+    assert_eq!(report.recovered, 99);
+    assert!(report.quarantined_bytes > 0);
+    let repaired = load(&FileBackend::new(dir.join("cache"))).unwrap();
+    assert_eq!(repaired.len(), 99);
+    assert!(repaired.iter().all(|(key, value)| cache[key] == *value));
+}
@@ -0,0 +1,75 @@
+//Helpers shared by the integration tests. Not every test uses all of them.
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+use generic_filesystem_cache::{FileSet, ProcessingFsCacheBuilder};
+
+/// A directory which is deleted when dropped, unique to each test (and each process running tests).
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "generic_filesystem_cache-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+
+    /// Write `count` files named `file<n>` into the subdirectory `dir`, each holding its own number.
+    pub fn write_files(&self, dir: &str, count: usize) -> PathBuf {
+        let dir = self.join(dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for n in 0..count {
+            std::fs::write(dir.join(format!("file{}", n)), n.to_string()).unwrap();
+        }
+        dir
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A rayon thread pool with several threads, so that parallel code runs in parallel even on a
+/// machine with a single core.
+pub fn thread_pool() -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap()
+}
+
+/// Processes the files written by [`TempDir::write_files`] into the number each holds.
+pub fn read_number(path: &Path) -> u64 {
+    std::fs::read_to_string(path).unwrap().parse().unwrap()
+}
+
+/// A builder for a cache at `cache` in `dir` processing files with [`read_number`], which saves
+/// after every `threshold` modifications.
+pub fn builder(dir: &TempDir, threshold: u32) -> ProcessingFsCacheBuilder<fn(&Path) -> u64> {
+    ProcessingFsCacheBuilder::new(threshold, dir.join("cache"), read_number as fn(&Path) -> u64)
+}
+
+/// Every file beneath `files`.
+pub fn file_set(files: &Path) -> FileSet {
+    FileSet::new([files], Vec::<PathBuf>::new())
+}
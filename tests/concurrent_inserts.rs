@@ -0,0 +1,67 @@
+mod common;
+
+use std::{path::PathBuf, sync::Arc};
+
+use common::TempDir;
+use generic_filesystem_cache::{save_strategy::ManualOnly, BaseFsCache, FileBackend};
+
+fn key(thread: u64, n: u64) -> PathBuf {
+    PathBuf::from(format!("/data/{}/file{}", thread, n))
+}
+
+//Delta saves only write the changed entries, which are looked up by key from the saved cache.
+fn open(dir: &TempDir) -> BaseFsCache<u64> {
+    let backend = FileBackend::new(dir.join("cache")).with_delta_saves(10_000);
+    BaseFsCache::with_backend(Arc::new(ManualOnly), Box::new(backend)).unwrap()
+}
+
+#[test]
+fn inserts_from_many_threads_are_all_kept() {
+    let dir = TempDir::new("inserts_from_many_threads_are_all_kept");
+    let cache = open(&dir);
+
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let cache = &cache;
+            scope.spawn(move || {
+                for n in 0..500 {
+                    cache.insert(key(thread, n), thread * 1000 + n).unwrap();
+                }
+                let batch = (500..1000).map(|n| (key(thread, n), thread * 1000 + n)).collect();
+                cache.insert_batch(batch).unwrap();
+            });
+        }
+    });
+    assert_eq!(cache.len(), 8000);
+    assert_eq!(cache.get(&key(3, 750)), Some(3750));
+    assert_eq!(cache.par_find(|_, value| value % 1000 == 0).len(), 8);
+    cache.save().unwrap();
+    drop(cache);
+
+    let cache = open(&dir);
+    assert_eq!(cache.len(), 8000);
+    assert_eq!(cache.get(&key(7, 999)), Some(7999));
+}
+
+#[test]
+fn changes_spanning_many_keys_are_saved() {
+    let dir = TempDir::new("changes_spanning_many_keys_are_saved");
+    let cache = open(&dir);
+    cache
+        .insert_batch((0..1000).map(|n| (key(0, n), n)).collect())
+        .unwrap();
+    cache.save().unwrap();
+
+    let moves = (0..100).map(|n| (key(0, n), key(1, n))).collect();
+    assert_eq!(cache.rename_many(moves).unwrap(), 100);
+    assert_eq!(cache.retain(|_, value| value % 2 == 0).unwrap(), 500);
+    cache.save().unwrap();
+    drop(cache);
+
+    let cache = open(&dir);
+    assert_eq!(cache.len(), 500);
+    assert_eq!(cache.get(&key(1, 50)), Some(50));
+    assert_eq!(cache.get(&key(0, 50)), None);
+    assert_eq!(cache.get(&key(0, 500)), Some(500));
+    assert_eq!(cache.get(&key(0, 501)), None);
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use std::path::Path;
+
+use common::{builder, file_set, TempDir};
+use generic_filesystem_cache::{ProcessingFsCache, ProcessingFsCacheBuilder};
+
+type Cache = ProcessingFsCache<fn(&Path) -> u64>;
+
+//Saves after every modification, so that the workers keep saving at the same time.
+fn saving_builder(dir: &TempDir) -> ProcessingFsCacheBuilder<fn(&Path) -> u64> {
+    builder(dir, 1).worker_threads(8)
+}
+
+fn assert_all_cached(cache: &Cache, files: &Path, count: usize) {
+    assert_eq!(cache.len(), count);
+    for n in 0..count {
+        assert_eq!(cache.fetch(files.join(format!("file{}", n))).unwrap(), n as u64);
+    }
+}
+
+fn update_and_reload(dir: &TempDir, builder: impl Fn() -> ProcessingFsCacheBuilder<fn(&Path) -> u64>) {
+    let files = dir.write_files("files", 2000);
+    let cache = builder().build().unwrap();
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert!(
+        report.errors.is_empty(),
+        "{:?}",
+        &report.errors[..report.errors.len().min(5)]
+    );
+    assert_eq!(report.processed, 2000);
+    drop(cache);
+
+    assert_all_cached(&builder().build().unwrap(), &files, 2000);
+}
+
+#[test]
+fn threshold_saves_from_workers() {
+    let dir = TempDir::new("threshold_saves_from_workers");
+    update_and_reload(&dir, || saving_builder(&dir));
+}
+
+#[test]
+fn threshold_saves_from_workers_with_shards() {
+    let dir = TempDir::new("threshold_saves_from_workers_with_shards");
+    update_and_reload(&dir, || saving_builder(&dir).shards(8));
+}
+
+#[test]
+fn threshold_saves_from_workers_without_snapshots() {
+    let dir = TempDir::new("threshold_saves_from_workers_without_snapshots");
+    update_and_reload(&dir, || saving_builder(&dir).save_from_snapshot(false));
+}
+
+#[test]
+fn explicit_saves_during_update() {
+    let dir = TempDir::new("explicit_saves_during_update");
+    let files = dir.write_files("files", 1000);
+    let cache = builder(&dir, u32::MAX).worker_threads(4)
+        .build()
+        .unwrap();
+
+    std::thread::scope(|scope| {
+        let cache = &cache;
+        let savers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        cache.flush().unwrap();
+                    }
+                })
+            })
+            .collect();
+        let report = cache.update_from_fs(&file_set(&files)).unwrap();
+        assert!(report.errors.is_empty());
+        for saver in savers {
+            saver.join().unwrap();
+        }
+    });
+    cache.save().unwrap();
+    drop(cache);
+
+    let reopened = builder(&dir, u32::MAX).build().unwrap();
+    assert_all_cached(&reopened, &files, 1000);
+}
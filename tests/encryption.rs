@@ -0,0 +1,78 @@
+#![cfg(feature = "encryption")]
+
+mod common;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use common::TempDir;
+use generic_filesystem_cache::{CacheView, FileBackend, FsCacheErrorKind, StorageBackend};
+
+const KEY: [u8; 32] = [7; 32];
+
+//Where the payload starts, and where its CRC32 is kept, in a cache file of the current format.
+const HEADER_LEN: usize = 44;
+const CRC32_OFFSET: usize = 28;
+
+fn entries(count: u64) -> HashMap<PathBuf, String> {
+    (0..count)
+        .map(|n| (PathBuf::from(format!("/data/file{}", n)), format!("secret value {}", n)))
+        .collect()
+}
+
+fn load(backend: &FileBackend) -> Result<HashMap<PathBuf, String>, FsCacheErrorKind> {
+    StorageBackend::<String>::load(backend)
+}
+
+#[test]
+fn encrypted_cache_reloads_with_its_key() {
+    let dir = TempDir::new("encrypted_cache_reloads_with_its_key");
+    let cache = entries(50);
+    FileBackend::new(dir.join("cache")).with_encryption_key(KEY).save(&CacheView::from(&cache)).unwrap();
+
+    let bytes = std::fs::read(dir.join("cache")).unwrap();
+    assert!(!bytes.windows(b"secret value".len()).any(|window| window == b"secret value"));
+    assert!(!bytes.windows(b"/data/file".len()).any(|window| window == b"/data/file"));
+    assert_eq!(load(&FileBackend::new(dir.join("cache")).with_encryption_key(KEY)).unwrap(), cache);
+}
+
+#[test]
+fn encrypted_cache_fails_with_another_key() {
+    let dir = TempDir::new("encrypted_cache_fails_with_another_key");
+    FileBackend::new(dir.join("cache")).with_encryption_key(KEY).save(&CacheView::from(&entries(50))).unwrap();
+
+    let result = load(&FileBackend::new(dir.join("cache")).with_encryption_key([8; 32]));
+    assert!(matches!(result, Err(FsCacheErrorKind::Decryption(_))), "{:?}", result);
+    assert!(load(&FileBackend::new(dir.join("cache"))).is_err());
+}
+
+#[test]
+fn tampered_encrypted_cache_fails_to_decrypt() {
+    let dir = TempDir::new("tampered_encrypted_cache_fails_to_decrypt");
+    FileBackend::new(dir.join("cache")).with_encryption_key(KEY).save(&CacheView::from(&entries(50))).unwrap();
+
+    //the checksum in the header covers the ciphertext, so it is updated to match, leaving only
+    //the authentication tag to notice the change.
+    let mut bytes = std::fs::read(dir.join("cache")).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    let crc32 = crc32fast::hash(&bytes[HEADER_LEN..]);
+    bytes[CRC32_OFFSET..CRC32_OFFSET + 4].copy_from_slice(&crc32.to_le_bytes());
+    std::fs::write(dir.join("cache"), &bytes).unwrap();
+
+    let result = load(&FileBackend::new(dir.join("cache")).with_encryption_key(KEY));
+    assert!(matches!(result, Err(FsCacheErrorKind::Decryption(_))), "{:?}", result);
+}
+
+#[test]
+fn encrypted_journal_replays() {
+    let dir = TempDir::new("encrypted_journal_replays");
+    let backend = FileBackend::new(dir.join("cache")).with_encryption_key(KEY).with_journal(1000);
+    let key = PathBuf::from("/data/journaled");
+    let value = "secret journaled value".to_string();
+    StorageBackend::<String>::append(&backend, &[(key.as_path(), Some(&value))]).unwrap();
+
+    let journal = std::fs::read(dir.join("cache.journal")).unwrap();
+    assert!(!journal.windows(b"secret".len()).any(|window| window == b"secret"));
+    let reopened = FileBackend::new(dir.join("cache")).with_encryption_key(KEY).with_journal(1000);
+    assert_eq!(load(&reopened).unwrap(), HashMap::from([(key, value)]));
+}
@@ -0,0 +1,128 @@
+#![cfg(feature = "mmap")]
+
+mod common;
+
+use common::{builder, file_set, TempDir};
+
+#[test]
+fn lazy_cache_decodes_only_the_entries_looked_up() {
+    let dir = TempDir::new("lazy_cache_decodes_only_the_entries_looked_up");
+    let files = dir.write_files("files", 500);
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    assert!(dir.join("cache.index").exists());
+
+    let lazy = builder(&dir, u32::MAX).build_lazy().unwrap();
+    assert_eq!(lazy.get(&files.join("file7")).unwrap(), Some(&7));
+    assert_eq!(lazy.loaded_shards(), 1);
+    assert_eq!(lazy.decoded_entries(), 1);
+
+    assert_eq!(lazy.get_fresh(&files.join("file8")).unwrap(), Some(&8));
+    assert_eq!(lazy.get(&files.join("file7")).unwrap(), Some(&7));
+    assert_eq!(lazy.get(&files.join("missing")).unwrap(), None);
+    assert_eq!(lazy.decoded_entries(), 2);
+    for n in 0..500 {
+        assert_eq!(lazy.get(&files.join(format!("file{}", n))).unwrap(), Some(&n));
+    }
+    assert_eq!(lazy.decoded_entries(), 500);
+}
+
+#[test]
+fn each_shard_has_an_index() {
+    let dir = TempDir::new("each_shard_has_an_index");
+    let files = dir.write_files("files", 200);
+    let cache = builder(&dir, u32::MAX).shards(4).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    for n in 0..4 {
+        assert!(dir.join(format!("cache.shard{}.index", n)).exists());
+    }
+
+    let lazy = builder(&dir, u32::MAX).shards(4).build_lazy().unwrap();
+    for n in 0..200 {
+        assert_eq!(lazy.get(&files.join(format!("file{}", n))).unwrap(), Some(&n));
+    }
+    assert_eq!(lazy.loaded_shards(), 4);
+    assert_eq!(lazy.decoded_entries(), 200);
+}
+
+#[test]
+fn stale_indexes_are_ignored() {
+    let dir = TempDir::new("stale_indexes_are_ignored");
+    let files = dir.write_files("files", 100);
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+
+    //saved without an index, so the old one no longer matches the cache file.
+    std::fs::write(files.join("file7"), "7000").unwrap();
+    let cache = builder(&dir, u32::MAX).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    assert!(dir.join("cache.index").exists());
+
+    let lazy = builder(&dir, u32::MAX).build_lazy().unwrap();
+    assert_eq!(lazy.get(&files.join("file7")).unwrap(), Some(&7000));
+    assert_eq!(lazy.decoded_entries(), 100);
+}
+
+#[test]
+fn missing_indexes_fall_back_to_reading_the_shard() {
+    let dir = TempDir::new("missing_indexes_fall_back_to_reading_the_shard");
+    let files = dir.write_files("files", 100);
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    std::fs::remove_file(dir.join("cache.index")).unwrap();
+
+    let lazy = builder(&dir, u32::MAX).build_lazy().unwrap();
+    assert_eq!(lazy.get(&files.join("file7")).unwrap(), Some(&7));
+    assert_eq!(lazy.decoded_entries(), 100);
+}
+
+#[test]
+fn truncated_or_corrupt_indexes_are_ignored() {
+    let dir = TempDir::new("truncated_or_corrupt_indexes_are_ignored");
+    let files = dir.write_files("files", 100);
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    let index = std::fs::read(dir.join("cache.index")).unwrap();
+
+    //cut short, as if by a crash, and with a changed byte in one of its records.
+    let mut corrupt = index.clone();
+    corrupt[index.len() - 20] ^= 0xff;
+    for damaged in [&index[..index.len() - 5], &index[..20], &corrupt[..]] {
+        std::fs::write(dir.join("cache.index"), damaged).unwrap();
+        let lazy = builder(&dir, u32::MAX).build_lazy().unwrap();
+        for n in 0..100 {
+            assert_eq!(lazy.get(&files.join(format!("file{}", n))).unwrap(), Some(&n));
+        }
+        assert_eq!(lazy.decoded_entries(), 100);
+    }
+}
+
+#[test]
+fn indexes_of_replaced_cache_files_are_ignored() {
+    let dir = TempDir::new("indexes_of_replaced_cache_files_are_ignored");
+    let files = dir.write_files("files", 100);
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+    let older = std::fs::read(dir.join("cache")).unwrap();
+
+    //the same length as before, but with another value for one entry.
+    std::fs::write(files.join("file7"), "8").unwrap();
+    let cache = builder(&dir, u32::MAX).entry_index(true).build().unwrap();
+    assert_eq!(cache.force_update(files.join("file7")).unwrap(), 8);
+    drop(cache);
+    let newer = std::fs::read(dir.join("cache")).unwrap();
+    assert!(newer != older && newer.len() == older.len());
+
+    //such as by restoring a copy, leaving the index of the newer file beside it.
+    std::fs::write(dir.join("cache"), older).unwrap();
+    let lazy = builder(&dir, u32::MAX).build_lazy().unwrap();
+    assert_eq!(lazy.get(&files.join("file7")).unwrap(), Some(&7));
+    assert_eq!(lazy.decoded_entries(), 100);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use common::{builder, file_set, TempDir};
+
+#[test]
+fn invalidating_removes_only_cached_files() {
+    let dir = TempDir::new("invalidating_removes_only_cached_files");
+    let files = dir.write_files("files", 10);
+    let cache = builder(&dir, u32::MAX).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    cache.save().unwrap();
+
+    assert!(cache.invalidate(&files.join("file3")).unwrap());
+    assert!(!cache.contains_key(&files.join("file3")));
+    assert_eq!(cache.pending_modifications(), 1);
+
+    //nothing is left to remove, so nothing is modified.
+    cache.save().unwrap();
+    assert!(!cache.invalidate(&files.join("file3")).unwrap());
+    assert!(!cache.invalidate(&files.join("missing")).unwrap());
+    assert!(!cache.is_dirty());
+    assert_eq!(cache.len(), 9);
+}
+
+#[test]
+fn invalidating_normalizes_keys() {
+    let dir = TempDir::new("invalidating_normalizes_keys");
+    let files = dir.write_files("files", 10);
+    let other = dir.write_files("other", 5);
+    let cache = builder(&dir, u32::MAX).case_insensitive_keys(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    cache.update_from_fs(&file_set(&other)).unwrap();
+    assert_eq!(cache.len(), 15);
+
+    assert!(cache.invalidate(&files.join("FILE3")).unwrap());
+    assert!(!cache.contains_key(&files.join("file3")));
+    assert_eq!(cache.invalidate_dir(&dir.join("OTHER")).unwrap(), 5);
+    assert_eq!(cache.len(), 9);
+
+    cache.update_from_fs(&file_set(&other)).unwrap();
+    let upper = file_set(&dir.join("FILES"));
+    assert_eq!(cache.remove_outside(&upper).unwrap(), 5);
+    assert_eq!(cache.len(), 9);
+}
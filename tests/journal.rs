@@ -0,0 +1,171 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+};
+
+use common::TempDir;
+use generic_filesystem_cache::{save_strategy::ManualOnly, BaseFsCache, CacheView, FileBackend, StorageBackend};
+
+fn key(n: u64) -> PathBuf {
+    PathBuf::from(format!("/data/file{}", n))
+}
+
+fn entries(count: u64) -> HashMap<PathBuf, u64> {
+    (0..count).map(|n| (key(n), n)).collect()
+}
+
+fn load(backend: &FileBackend) -> HashMap<PathBuf, u64> {
+    StorageBackend::<u64>::load(backend).unwrap()
+}
+
+//Writes `cache` as the cache file, as if the journal had last been compacted when it held `cache`.
+fn save_compacted(dir: &TempDir, cache: &HashMap<PathBuf, u64>) {
+    FileBackend::new(dir.join("cache")).save(&CacheView::from(cache)).unwrap();
+}
+
+//Journals the change of `key(n)` to `value`, or its removal, as the cache would.
+fn append(backend: &FileBackend, n: u64, value: Option<u64>) {
+    StorageBackend::<u64>::append(backend, &[(key(n).as_path(), value.as_ref())]).unwrap();
+}
+
+#[test]
+fn journaled_changes_replayed_without_saving() {
+    let dir = TempDir::new("journaled_changes_replayed_without_saving");
+    let backend = FileBackend::new(dir.join("cache")).with_journal(1000);
+    let mut cache = entries(10);
+    save_compacted(&dir, &cache);
+
+    append(&backend, 3, Some(300));
+    append(&backend, 4, None);
+    append(&backend, 20, Some(20));
+    cache.insert(key(3), 300);
+    cache.remove(&key(4));
+    cache.insert(key(20), 20);
+
+    assert_eq!(load(&FileBackend::new(dir.join("cache")).with_journal(1000)), cache);
+    //without the journal, only the saved cache file is read.
+    assert_eq!(load(&FileBackend::new(dir.join("cache"))), entries(10));
+}
+
+#[test]
+fn torn_journal_record_is_dropped() {
+    let dir = TempDir::new("torn_journal_record_is_dropped");
+    let backend = FileBackend::new(dir.join("cache")).with_journal(1000);
+    save_compacted(&dir, &entries(10));
+    append(&backend, 3, Some(300));
+    append(&backend, 5, Some(500));
+
+    //as if the application died part way through appending the second record.
+    let journal = std::fs::read(dir.join("cache.journal")).unwrap();
+    std::fs::write(dir.join("cache.journal"), &journal[..journal.len() - 2]).unwrap();
+
+    let mut expected = entries(10);
+    expected.insert(key(3), 300);
+    assert_eq!(load(&FileBackend::new(dir.join("cache")).with_journal(1000)), expected);
+}
+
+#[test]
+fn corrupt_journal_record_stops_replay() {
+    let dir = TempDir::new("corrupt_journal_record_stops_replay");
+    let backend = FileBackend::new(dir.join("cache")).with_journal(1000);
+    save_compacted(&dir, &entries(10));
+    append(&backend, 3, Some(300));
+    let first_record_len = std::fs::metadata(dir.join("cache.journal")).unwrap().len() as usize;
+    append(&backend, 5, Some(500));
+    append(&backend, 6, Some(600));
+
+    //a changed byte in the second record fails its CRC, so neither it nor anything after it is replayed.
+    let mut journal = std::fs::read(dir.join("cache.journal")).unwrap();
+    *journal.get_mut(first_record_len + 9).unwrap() ^= 0xff;
+    std::fs::File::create(dir.join("cache.journal"))
+        .unwrap()
+        .write_all(&journal)
+        .unwrap();
+
+    let mut expected = entries(10);
+    expected.insert(key(3), 300);
+    assert_eq!(load(&FileBackend::new(dir.join("cache")).with_journal(1000)), expected);
+}
+
+#[test]
+fn saving_compacts_full_journal() {
+    let dir = TempDir::new("saving_compacts_full_journal");
+    let backend = FileBackend::new(dir.join("cache")).with_journal(3);
+    let mut cache = entries(10);
+    save_compacted(&dir, &cache);
+
+    append(&backend, 1, Some(100));
+    cache.insert(key(1), 100);
+    backend.save(&CacheView::from(&cache)).unwrap();
+    assert!(std::fs::metadata(dir.join("cache.journal")).unwrap().len() > 0);
+    assert_eq!(load(&FileBackend::new(dir.join("cache"))), entries(10));
+
+    for n in 2..4 {
+        append(&backend, n, Some(n * 100));
+        cache.insert(key(n), n * 100);
+    }
+    backend.save(&CacheView::from(&cache)).unwrap();
+    assert_eq!(std::fs::metadata(dir.join("cache.journal")).unwrap().len(), 0);
+    assert_eq!(load(&FileBackend::new(dir.join("cache"))), cache);
+}
+
+#[test]
+fn compaction_keeps_changes_journaled_after_the_snapshot() {
+    let dir = TempDir::new("compaction_keeps_changes_journaled_after_the_snapshot");
+    let backend = FileBackend::new(dir.join("cache")).with_journal(3);
+    let mut cache = entries(10);
+    save_compacted(&dir, &cache);
+    for n in 1..4 {
+        append(&backend, n, Some(n * 100));
+        cache.insert(key(n), n * 100);
+    }
+
+    //changed after the snapshot was taken, so not in the cache file written from it.
+    StorageBackend::<u64>::taking_snapshot(&backend);
+    append(&backend, 20, Some(20));
+    backend.save(&CacheView::from(&cache)).unwrap();
+    assert_eq!(load(&FileBackend::new(dir.join("cache"))), cache);
+
+    cache.insert(key(20), 20);
+    assert_eq!(load(&FileBackend::new(dir.join("cache")).with_journal(3)), cache);
+}
+
+#[test]
+fn inserts_during_compacting_saves_are_kept() {
+    let dir = TempDir::new("inserts_during_compacting_saves_are_kept");
+    let open = || {
+        let backend = FileBackend::new(dir.join("cache")).with_journal(50);
+        let mut cache = BaseFsCache::<u64>::with_backend(Arc::new(ManualOnly), Box::new(backend)).unwrap();
+        cache.set_save_on_drop(false);
+        cache
+    };
+
+    let cache = open();
+    let inserted = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while !inserted.load(SeqCst) {
+                cache.save().unwrap();
+            }
+        });
+        for n in 0..5000 {
+            cache.insert(key(n), n).unwrap();
+        }
+        inserted.store(true, SeqCst);
+    });
+
+    //dropped without saving, so the last inserts are only in the journal.
+    drop(cache);
+    let cache = open();
+    assert_eq!(cache.len(), 5000);
+    for n in 0..5000 {
+        assert_eq!(cache.get(&key(n)), Some(n));
+    }
+}
@@ -0,0 +1,32 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{builder, file_set, TempDir};
+
+#[test]
+fn prune_older_than_removes_unprocessed_entries() {
+    let dir = TempDir::new("prune_older_than_removes_unprocessed_entries");
+    let files = dir.write_files("files", 10);
+    let cache = builder(&dir, u32::MAX).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+
+    assert_eq!(cache.prune_older_than(Duration::from_secs(3600)).unwrap(), 0);
+    std::thread::sleep(Duration::from_millis(300));
+    cache.fetch(files.join("file0")).unwrap();
+    assert_eq!(cache.prune_older_than(Duration::from_millis(200)).unwrap(), 10);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn prune_older_than_keeps_recently_read_entries() {
+    let dir = TempDir::new("prune_older_than_keeps_recently_read_entries");
+    let files = dir.write_files("files", 10);
+    let cache = builder(&dir, u32::MAX).track_access_times(true).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+
+    std::thread::sleep(Duration::from_millis(300));
+    cache.fetch(files.join("file3")).unwrap();
+    assert_eq!(cache.prune_older_than(Duration::from_millis(200)).unwrap(), 9);
+    assert_eq!(cache.keys(), vec![files.join("file3")]);
+}
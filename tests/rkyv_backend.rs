@@ -0,0 +1,150 @@
+#![cfg(feature = "rkyv")]
+
+mod common;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use common::{builder, file_set, TempDir};
+use generic_filesystem_cache::{
+    format::ProcessorVersion, CacheView, FsCacheErrorKind, MtimeCacheEntry, RkyvBackend, StorageBackend,
+};
+
+#[derive(
+    Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, rkyv::Archive, rkyv::Serialize, rkyv::Deserialize,
+)]
+struct Summary {
+    name: String,
+    sizes: Vec<u32>,
+}
+
+fn key(n: u32) -> PathBuf {
+    PathBuf::from(format!("/data/file{}", n))
+}
+
+fn summary(n: u32) -> Summary {
+    Summary {
+        name: format!("file{}", n),
+        sizes: (0..n % 10).collect(),
+    }
+}
+
+fn entries(count: u32) -> HashMap<PathBuf, Summary> {
+    (0..count).map(|n| (key(n), summary(n))).collect()
+}
+
+fn backend(dir: &TempDir) -> RkyvBackend<Summary> {
+    RkyvBackend::new(dir.join("cache"))
+}
+
+#[test]
+fn archived_values_are_read_in_place() {
+    let dir = TempDir::new("archived_values_are_read_in_place");
+    let mut cache = entries(1000);
+    backend(&dir).save(&CacheView::from(&cache)).unwrap();
+
+    let archived = backend(&dir).archived().unwrap();
+    assert_eq!(archived.len(), 1000);
+    let value = archived.get(&key(123)).unwrap();
+    assert_eq!(value.name, "file123");
+    assert_eq!(value.sizes.iter().map(|size| size.to_native()).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(archived.get(&key(1000)).is_none());
+    assert_eq!(archived.iter().count(), 1000);
+
+    //the archive is as it was when mapped, whatever has been saved since.
+    cache.remove(&key(123));
+    backend(&dir).save(&CacheView::from(&cache)).unwrap();
+    assert!(archived.contains_key(&key(123)));
+}
+
+#[test]
+fn cache_reloads_from_archive() {
+    let dir = TempDir::new("rkyv_cache_reloads_from_archive");
+    backend(&dir).save(&CacheView::from(&entries(100))).unwrap();
+    assert_eq!(backend(&dir).load().unwrap(), entries(100));
+}
+
+#[test]
+fn missing_archive_is_empty() {
+    let dir = TempDir::new("rkyv_missing_archive_is_empty");
+    let archived = backend(&dir).archived().unwrap();
+    assert!(archived.is_empty());
+    assert!(archived.get(&key(0)).is_none());
+    let loaded: HashMap<PathBuf, Summary> = backend(&dir).load().unwrap();
+    assert!(loaded.is_empty());
+}
+
+#[test]
+fn changed_byte_fails_checksum() {
+    let dir = TempDir::new("rkyv_changed_byte_fails_checksum");
+    backend(&dir).save(&CacheView::from(&entries(1))).unwrap();
+    let mut bytes = std::fs::read(dir.join("cache")).unwrap();
+    *bytes.last_mut().unwrap() ^= 0xff;
+    std::fs::write(dir.join("cache"), bytes).unwrap();
+
+    match backend(&dir).archived().err() {
+        Some(FsCacheErrorKind::CorruptedCache { src, .. }) => assert!(src.contains("checksum mismatch"), "{}", src),
+        other => panic!("expected a corrupted cache, got {:?}", other),
+    }
+}
+
+#[test]
+fn other_value_types_are_rejected() {
+    let dir = TempDir::new("rkyv_other_value_types_are_rejected");
+    backend(&dir).save(&CacheView::from(&entries(1))).unwrap();
+    match RkyvBackend::<u64>::new(dir.join("cache")).archived().err() {
+        Some(FsCacheErrorKind::IncompatibleCacheFile { .. }) => (),
+        other => panic!("expected an incompatible cache file, got {:?}", other),
+    }
+}
+
+#[test]
+fn processor_version_is_kept() {
+    let dir = TempDir::new("rkyv_processor_version_is_kept");
+    let version = ProcessorVersion {
+        version: 3,
+        config_fingerprint: 100,
+    };
+    let cache = entries(10);
+    backend(&dir).store_version(version, &CacheView::from(&cache)).unwrap();
+
+    let reopened = backend(&dir);
+    assert_eq!(reopened.load().unwrap(), cache);
+    assert_eq!(StorageBackend::<Summary>::stored_version(&reopened).unwrap(), Some(version));
+}
+
+#[cfg(unix)]
+#[test]
+fn paths_which_are_not_unicode_are_kept() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let dir = TempDir::new("rkyv_paths_which_are_not_unicode_are_kept");
+    let path = PathBuf::from(OsStr::from_bytes(b"/data/caf\xe9"));
+    let cache = HashMap::from([(path.clone(), summary(1))]);
+    backend(&dir).save(&CacheView::from(&cache)).unwrap();
+
+    let archived = backend(&dir).archived().unwrap();
+    assert_eq!(archived.get(&path).unwrap().name, "file1");
+    assert_eq!(archived.iter().next().unwrap().0, path);
+    let loaded: HashMap<PathBuf, Summary> = backend(&dir).load().unwrap();
+    assert_eq!(loaded, cache);
+}
+
+#[test]
+fn processing_cache_entries_are_archived() {
+    let dir = TempDir::new("rkyv_processing_cache_entries_are_archived");
+    let files = dir.write_files("files", 20);
+    let backend = || RkyvBackend::<MtimeCacheEntry<u64>>::new(dir.join("cache"));
+    let cache = builder(&dir, u32::MAX).backend(backend()).build().unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+    drop(cache);
+
+    let archived = backend().archived().unwrap();
+    assert_eq!(archived.len(), 20);
+    assert_eq!(archived.get(&files.join("file7")).unwrap().value().to_native(), 7);
+
+    //the files have not changed since, so none is processed again.
+    let cache = builder(&dir, u32::MAX).backend(backend()).build().unwrap();
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert_eq!((report.processed, report.reprocessed, report.unchanged), (0, 0, 20));
+    assert_eq!(cache.fetch(files.join("file7")).unwrap(), 7);
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc, Barrier, Mutex,
+    },
+    time::Duration,
+};
+
+use generic_filesystem_cache::{
+    errors::FsCacheResult, save_strategy::ManualOnly, BaseFsCache, CacheView, StorageBackend,
+};
+
+//Keeps the last saved cache in memory. While a gate is set, the next save waits part way through
+//until the test lets it carry on, with the entries it is saving already in hand.
+#[derive(Clone, Default)]
+struct GatedBackend {
+    saved: Arc<Mutex<HashMap<PathBuf, u64>>>,
+    gate: Arc<Mutex<Option<Gate>>>,
+}
+
+#[derive(Clone)]
+struct Gate {
+    started: Arc<Barrier>,
+    resume: Arc<Barrier>,
+}
+
+impl GatedBackend {
+    fn close_gate(&self) -> Gate {
+        let gate = Gate {
+            started: Arc::new(Barrier::new(2)),
+            resume: Arc::new(Barrier::new(2)),
+        };
+        *self.gate.lock().unwrap() = Some(gate.clone());
+        gate
+    }
+}
+
+impl StorageBackend<u64> for GatedBackend {
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, u64>> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    fn save(&self, cache: &CacheView<'_, u64>) -> FsCacheResult<()> {
+        let gate = self.gate.lock().unwrap().take();
+        if let Some(gate) = &gate {
+            gate.started.wait();
+            gate.resume.wait();
+        }
+        *self.saved.lock().unwrap() = cache.iter().map(|(key, item)| (key.clone(), *item)).collect();
+        Ok(())
+    }
+}
+
+fn cache_with(backend: &GatedBackend) -> BaseFsCache<u64> {
+    let cache = BaseFsCache::with_backend(Arc::new(ManualOnly), Box::new(backend.clone())).unwrap();
+    for n in 0..100 {
+        cache.insert(PathBuf::from(format!("/file{}", n)), n).unwrap();
+    }
+    cache
+}
+
+#[test]
+fn modifications_while_saving_do_not_wait() {
+    let backend = GatedBackend::default();
+    let cache = cache_with(&backend);
+    let gate = backend.close_gate();
+
+    std::thread::scope(|scope| {
+        let saver = scope.spawn(|| cache.save().unwrap());
+        gate.started.wait();
+
+        //the save is paused, so these would never finish if they waited for it.
+        cache.insert(PathBuf::from("/new"), 1000).unwrap();
+        cache.update_with(PathBuf::from("/file0"), |_| Some(500)).unwrap();
+        cache.remove(Path::new("/file1")).unwrap();
+        gate.resume.wait();
+        saver.join().unwrap();
+    });
+
+    //the save holds the cache as it was when saving started.
+    {
+        let saved = backend.saved.lock().unwrap();
+        assert_eq!(saved.len(), 100);
+        assert_eq!(saved[Path::new("/file0")], 0);
+        assert_eq!(saved[Path::new("/file1")], 1);
+        assert!(!saved.contains_key(Path::new("/new")));
+    }
+    assert!(cache.is_dirty());
+    assert_eq!(cache.get(Path::new("/file0")), Some(500));
+
+    cache.save().unwrap();
+    let saved = backend.saved.lock().unwrap();
+    assert_eq!(saved.len(), 100);
+    assert_eq!(saved[Path::new("/file0")], 500);
+    assert_eq!(saved[Path::new("/new")], 1000);
+    assert!(!saved.contains_key(Path::new("/file1")));
+}
+
+#[test]
+fn modifications_wait_for_saves_without_snapshots() {
+    let backend = GatedBackend::default();
+    let mut cache = cache_with(&backend);
+    cache.set_save_from_snapshot(false);
+    let gate = backend.close_gate();
+    let inserted = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        let saver = scope.spawn(|| cache.save().unwrap());
+        gate.started.wait();
+        let inserter = scope.spawn(|| {
+            cache.insert(PathBuf::from("/new"), 1000).unwrap();
+            inserted.store(true, SeqCst);
+        });
+
+        //reading is never blocked by saving.
+        assert_eq!(cache.get(Path::new("/file5")), Some(5));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!inserted.load(SeqCst));
+        gate.resume.wait();
+        saver.join().unwrap();
+        inserter.join().unwrap();
+    });
+
+    assert!(inserted.load(SeqCst));
+    assert_eq!(backend.saved.lock().unwrap().len(), 100);
+    assert_eq!(cache.get(Path::new("/new")), Some(1000));
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use std::{collections::HashMap, path::PathBuf};
+
+use common::{thread_pool, TempDir};
+use generic_filesystem_cache::{CacheView, FileBackend, ShardedFileBackend, StorageBackend};
+
+fn entries(count: u64, generation: u64) -> HashMap<PathBuf, u64> {
+    (0..count)
+        .map(|n| (PathBuf::from(format!("/data/file{}", n)), n * 1000 + generation))
+        .collect()
+}
+
+fn load(backend: &ShardedFileBackend) -> HashMap<PathBuf, u64> {
+    StorageBackend::<u64>::load(backend).unwrap()
+}
+
+#[test]
+fn shards_saved_in_parallel_reload() {
+    let dir = TempDir::new("shards_saved_in_parallel_reload");
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+
+    //every shard is rewritten each time, so that all of them save at once.
+    thread_pool().install(|| {
+        for generation in 0..20 {
+            let cache = entries(500, generation);
+            backend.save(&CacheView::from(&cache)).unwrap();
+            assert_eq!(load(&backend), cache);
+        }
+    });
+
+    let reopened = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    assert_eq!(load(&reopened), entries(500, 19));
+    assert!(!dir.join("cache.tmp").exists());
+    assert!((0..8).all(|n| !dir.join(format!("cache.shard{}.tmp", n)).exists()));
+}
+
+#[test]
+fn changed_shards_saved_in_parallel_reload() {
+    let dir = TempDir::new("changed_shards_saved_in_parallel_reload");
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    let mut cache = entries(500, 0);
+    backend.save(&CacheView::from(&cache)).unwrap();
+
+    thread_pool().install(|| {
+        for generation in 1..20 {
+            let changed: Vec<PathBuf> = (0..50)
+                .map(|n| PathBuf::from(format!("/data/file{}", n * 7 + generation)))
+                .collect();
+            for key in &changed {
+                *cache.get_mut(key).unwrap() += 1;
+            }
+            backend.save_changes(&CacheView::from(&cache), &changed).unwrap();
+        }
+    });
+
+    let reopened = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    assert_eq!(load(&reopened), cache);
+}
+
+#[test]
+fn reload_with_another_shard_count() {
+    let dir = TempDir::new("reload_with_another_shard_count");
+    let cache = entries(300, 0);
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 4);
+    StorageBackend::<u64>::save(&backend, &CacheView::from(&cache)).unwrap();
+
+    let fewer = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 2);
+    assert_eq!(load(&fewer), cache);
+    fewer.save(&CacheView::from(&cache)).unwrap();
+    assert!(!dir.join("cache.shard2").exists());
+    assert_eq!(
+        load(&ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 2)),
+        cache
+    );
+}
+
+fn shard_contents(dir: &TempDir, count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|n| std::fs::read(dir.join(format!("cache.shard{}", n))).unwrap())
+        .collect()
+}
+
+#[test]
+fn saving_changes_rewrites_only_their_shards() {
+    let dir = TempDir::new("saving_changes_rewrites_only_their_shards");
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    let mut cache = entries(500, 0);
+    backend.save(&CacheView::from(&cache)).unwrap();
+    let before = shard_contents(&dir, 8);
+
+    //the shard a key belongs to is found as the one whose file changes.
+    let changed = PathBuf::from("/data/file42");
+    *cache.get_mut(&changed).unwrap() += 1;
+    backend.save_changes(&CacheView::from(&cache), std::slice::from_ref(&changed)).unwrap();
+    let after = shard_contents(&dir, 8);
+    let rewritten: Vec<usize> = (0..8).filter(|n| before[*n] != after[*n]).collect();
+    assert_eq!(rewritten.len(), 1);
+
+    //removing a key rewrites its shard without it.
+    cache.remove(&changed);
+    backend.save_changes(&CacheView::from(&cache), &[changed]).unwrap();
+    let removed = shard_contents(&dir, 8);
+    assert!((0..8).all(|n| (removed[n] == after[n]) != (n == rewritten[0])));
+    assert_eq!(load(&ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8)), cache);
+}
+
+#[test]
+fn reload_with_more_shards_rewrites_every_shard() {
+    let dir = TempDir::new("reload_with_more_shards_rewrites_every_shard");
+    let mut cache = entries(300, 0);
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 2);
+    StorageBackend::<u64>::save(&backend, &CacheView::from(&cache)).unwrap();
+
+    //entries are in the wrong shards until the first save, which rewrites them all however few changed.
+    let more = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    assert_eq!(load(&more), cache);
+    let changed = PathBuf::from("/data/file7");
+    *cache.get_mut(&changed).unwrap() += 1;
+    more.save_changes(&CacheView::from(&cache), &[changed]).unwrap();
+    assert_eq!(load(&ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8)), cache);
+
+    //once every entry is in its proper shard, saving a change only rewrites one shard again.
+    let reopened = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    assert_eq!(load(&reopened), cache);
+    let before = shard_contents(&dir, 8);
+    let changed = PathBuf::from("/data/file8");
+    *cache.get_mut(&changed).unwrap() += 1;
+    reopened.save_changes(&CacheView::from(&cache), &[changed]).unwrap();
+    let after = shard_contents(&dir, 8);
+    assert_eq!((0..8).filter(|n| before[*n] != after[*n]).count(), 1);
+    assert!(!dir.join("cache.shard8").exists());
+}
+
+#[test]
+fn failed_shard_leaves_the_others_valid() {
+    let dir = TempDir::new("failed_shard_leaves_the_others_valid");
+    let backend = ShardedFileBackend::new(FileBackend::new(dir.join("cache")), 8);
+    let mut cache = entries(500, 0);
+    backend.save(&CacheView::from(&cache)).unwrap();
+
+    //the shards of the changed keys are found as those whose files change.
+    let changed: Vec<PathBuf> = (0..4).map(|n| PathBuf::from(format!("/data/file{}", n))).collect();
+    let change = |cache: &mut HashMap<PathBuf, u64>| {
+        for key in &changed {
+            *cache.get_mut(key).unwrap() += 1;
+        }
+    };
+    let before = shard_contents(&dir, 8);
+    change(&mut cache);
+    backend.save_changes(&CacheView::from(&cache), &changed).unwrap();
+    let saved = shard_contents(&dir, 8);
+    let dirty: Vec<usize> = (0..8).filter(|n| before[*n] != saved[*n]).collect();
+    assert!(dirty.len() > 1);
+
+    //a directory in the way of its temporary file stops one of them from being written.
+    let failing = dirty[0];
+    std::fs::create_dir(dir.join(format!("cache.shard{}.tmp", failing))).unwrap();
+    let last_saved = cache.clone();
+    change(&mut cache);
+    assert!(backend.save_changes(&CacheView::from(&cache), &changed).is_err());
+
+    //the other changed shards may or may not have been written by then, but each is whole.
+    let after = shard_contents(&dir, 8);
+    for n in 0..8 {
+        let shard = FileBackend::new(dir.join(format!("cache.shard{}", n)));
+        let loaded = StorageBackend::<u64>::load(&shard).unwrap();
+        let matches = |expected: &HashMap<PathBuf, u64>| loaded.iter().all(|(key, value)| expected[key] == *value);
+        match n != failing && dirty.contains(&n) {
+            true => assert!(matches(&cache) || matches(&last_saved)),
+            false => assert_eq!(after[n], saved[n]),
+        }
+    }
+}
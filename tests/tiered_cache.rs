@@ -0,0 +1,110 @@
+mod common;
+
+use common::{builder, file_set, TempDir};
+use generic_filesystem_cache::{FileBackend, FsCacheErrorKind};
+
+#[test]
+fn tiered_cache_holds_bounded_shards_and_writes_dropped_ones() {
+    let dir = TempDir::new("tiered_cache_holds_bounded_shards_and_writes_dropped_ones");
+    let files = dir.write_files("files", 500);
+    let cache = builder(&dir, u32::MAX).shards(8).build_tiered(2).unwrap();
+
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.processed, 500);
+    assert!(cache.resident_shards() <= 2);
+
+    //reading every entry reads every shard back in, without holding more than two at once.
+    for n in 0..500 {
+        assert_eq!(cache.fetch(files.join(format!("file{}", n))).unwrap(), n as u64);
+        assert!(cache.resident_shards() <= 2);
+    }
+
+    //only shards which have changed since they were read are written.
+    let before: Vec<Vec<u8>> = (0..8)
+        .map(|n| std::fs::read(dir.join(format!("cache.shard{}", n))).unwrap())
+        .collect();
+    std::fs::write(files.join("file7"), "7000").unwrap();
+    assert_eq!(cache.fetch_update(files.join("file7")).unwrap(), Some(7000));
+    for n in (0..500).step_by(10) {
+        cache.fetch(files.join(format!("file{}", n))).unwrap();
+    }
+    cache.save().unwrap();
+    drop(cache);
+    let after: Vec<Vec<u8>> = (0..8)
+        .map(|n| std::fs::read(dir.join(format!("cache.shard{}", n))).unwrap())
+        .collect();
+    assert_eq!((0..8).filter(|n| before[*n] != after[*n]).count(), 1);
+
+    let reopened = builder(&dir, u32::MAX).shards(8).build().unwrap();
+    assert_eq!(reopened.len(), 500);
+    assert_eq!(reopened.fetch(files.join("file7")).unwrap(), 7000);
+    assert_eq!(reopened.fetch(files.join("file8")).unwrap(), 8);
+}
+
+#[test]
+fn tiered_cache_removes_deleted_files_from_dropped_shards() {
+    let dir = TempDir::new("tiered_cache_removes_deleted_files_from_dropped_shards");
+    let files = dir.write_files("files", 200);
+    let cache = builder(&dir, u32::MAX).shards(4).build_tiered(1).unwrap();
+    cache.update_from_fs(&file_set(&files)).unwrap();
+
+    for n in 0..50 {
+        std::fs::remove_file(files.join(format!("file{}", n))).unwrap();
+    }
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert_eq!(report.removed, 50);
+    assert!(cache.fetch(files.join("file0")).is_err());
+    drop(cache);
+
+    let reopened = builder(&dir, u32::MAX).shards(4).build().unwrap();
+    assert_eq!(reopened.len(), 150);
+    assert!(!reopened.contains_key(&files.join("file0")));
+}
+
+#[test]
+fn tiered_cache_keeps_within_memory_budget() {
+    let dir = TempDir::new("tiered_cache_keeps_within_memory_budget");
+    let files = dir.write_files("files", 800);
+    let unlimited = builder(&dir, u32::MAX).shards(8).save_on_drop(false).build_tiered(8).unwrap();
+    unlimited.update_from_fs(&file_set(&files)).unwrap();
+    assert_eq!(unlimited.resident_shards(), 8);
+    let per_shard = unlimited.estimated_memory() / 8;
+    drop(unlimited);
+
+    //room for about two and a half shards, however many are allowed.
+    let budget = per_shard * 5 / 2;
+    let cache = builder(&dir, u32::MAX)
+        .shards(8)
+        .memory_budget(budget)
+        .build_tiered(8)
+        .unwrap();
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert_eq!(report.processed, 800);
+    assert!(cache.estimated_memory() <= budget);
+    assert!(cache.resident_shards() < 8);
+    for n in 0..800 {
+        assert_eq!(cache.fetch(files.join(format!("file{}", n))).unwrap(), n as u64);
+        assert!(cache.estimated_memory() <= budget);
+    }
+    drop(cache);
+
+    assert_eq!(builder(&dir, u32::MAX).shards(8).build().unwrap().len(), 800);
+}
+
+#[test]
+fn lazy_and_tiered_caches_reject_other_entry_formats() {
+    let dir = TempDir::new("lazy_and_tiered_caches_reject_other_entry_formats");
+    let unsupported = |result: Result<(), FsCacheErrorKind>| {
+        assert!(matches!(result, Err(FsCacheErrorKind::UnsupportedConfiguration(_))), "{:?}", result)
+    };
+
+    unsupported(builder(&dir, 1).intern_values(true).build_lazy().map(drop));
+    unsupported(builder(&dir, 1).intern_values(true).build_tiered(2).map(drop));
+    unsupported(builder(&dir, 1).blob_threshold(64).build_lazy().map(drop));
+    unsupported(builder(&dir, 1).blob_threshold(64).build_tiered(2).map(drop));
+    let backend = || FileBackend::new(dir.join("other"));
+    unsupported(builder(&dir, 1).backend(backend()).build_lazy().map(drop));
+    unsupported(builder(&dir, 1).backend(backend()).build_tiered(2).map(drop));
+    assert!(!dir.join("cache").exists());
+}
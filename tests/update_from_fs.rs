@@ -0,0 +1,85 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use common::{builder, file_set, TempDir};
+use generic_filesystem_cache::{errors::FsCacheResult, CacheView, FsCacheErrorKind, MtimeCacheEntry, StorageBackend};
+
+#[test]
+fn failed_saves_keep_processed_files() {
+    let dir = TempDir::new("failed_saves_keep_processed_files");
+    let files = dir.write_files("files", 200);
+    let cache = builder(&dir, 1).worker_threads(4).build().unwrap();
+
+    //a directory where the temporary file would be written makes every save fail.
+    std::fs::create_dir(dir.join("cache.tmp")).unwrap();
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert!(report.errors.is_empty(), "{:?}", report.errors);
+    assert_eq!(report.processed, 200);
+    assert_eq!(cache.len(), 200);
+    assert!(cache.save().is_err());
+
+    std::fs::remove_dir(dir.join("cache.tmp")).unwrap();
+    cache.save().unwrap();
+    drop(cache);
+    assert_eq!(builder(&dir, 1).build().unwrap().len(), 200);
+}
+
+//Stores nothing, and fails to store every change as it is made.
+struct FailingAppends;
+
+impl StorageBackend<MtimeCacheEntry<u64>> for FailingAppends {
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, MtimeCacheEntry<u64>>> {
+        Ok(HashMap::new())
+    }
+
+    fn save(&self, _cache: &CacheView<'_, MtimeCacheEntry<u64>>) -> FsCacheResult<()> {
+        Ok(())
+    }
+
+    fn append(&self, changes: &[(&Path, Option<&MtimeCacheEntry<u64>>)]) -> FsCacheResult<()> {
+        Err(FsCacheErrorKind::Backend {
+            src: format!("cannot store {} changes", changes.len()),
+            path: PathBuf::new(),
+        })
+    }
+}
+
+#[test]
+fn failed_inserts_report_their_batch() {
+    let dir = TempDir::new("failed_inserts_report_their_batch");
+    let files = dir.write_files("files", 200);
+    let cache = builder(&dir, u32::MAX)
+        .worker_threads(4)
+        .backend(FailingAppends)
+        .build()
+        .unwrap();
+
+    let report = cache.update_from_fs(&file_set(&files)).unwrap();
+    assert_eq!(report.processed, 0);
+    assert_eq!(report.errors.len(), 200);
+    assert!(cache.is_empty());
+
+    //every file not inserted because of another is reported along with that file, whose own
+    //error is the backend's.
+    let backend_errors: Vec<&PathBuf> = report
+        .errors
+        .iter()
+        .filter(|(_, e)| matches!(e, FsCacheErrorKind::Backend { .. }))
+        .map(|(path, _)| path)
+        .collect();
+    assert!(!backend_errors.is_empty());
+    for (path, e) in &report.errors {
+        match e {
+            FsCacheErrorKind::Backend { .. } => {}
+            FsCacheErrorKind::BatchInsertFailed { path: failed_path, failed } => {
+                assert_eq!(failed_path, path);
+                assert!(backend_errors.contains(&failed), "{}", failed.display());
+            }
+            e => panic!("unexpected error for {}: {}", path.display(), e),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use generic_filesystem_cache::{
+    errors::FsCacheResult, save_strategy::ManualOnly, BaseFsCache, CacheView, FsCacheErrorKind, StorageBackend,
+};
+
+//Keeps the last saved cache in memory, and fails to append changes while `fail_appends` is set.
+#[derive(Clone, Default)]
+struct FlakyBackend {
+    saved: Arc<Mutex<HashMap<PathBuf, Vec<u32>>>>,
+    fail_appends: Arc<AtomicBool>,
+}
+
+impl StorageBackend<Vec<u32>> for FlakyBackend {
+    fn load(&self) -> FsCacheResult<HashMap<PathBuf, Vec<u32>>> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    fn save(&self, cache: &CacheView<'_, Vec<u32>>) -> FsCacheResult<()> {
+        *self.saved.lock().unwrap() = cache.iter().map(|(key, item)| (key.clone(), item.clone())).collect();
+        Ok(())
+    }
+
+    fn append(&self, _changes: &[(&Path, Option<&Vec<u32>>)]) -> FsCacheResult<()> {
+        match self.fail_appends.load(Relaxed) {
+            true => Err(FsCacheErrorKind::Backend {
+                src: "append failed".to_string(),
+                path: PathBuf::new(),
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+#[test]
+fn update_with_failed_append_is_still_saved() {
+    let backend = FlakyBackend::default();
+    let cache = BaseFsCache::with_backend(Arc::new(ManualOnly), Box::new(backend.clone())).unwrap();
+    cache.insert(PathBuf::from("/a"), vec![1]).unwrap();
+    cache.save().unwrap();
+
+    backend.fail_appends.store(true, Relaxed);
+    let result = cache.update_with(PathBuf::from("/a"), |item| {
+        item.unwrap().push(2);
+        None
+    });
+    assert!(matches!(result, Err(FsCacheErrorKind::Backend { .. })));
+    assert_eq!(cache.get(Path::new("/a")), Some(vec![1, 2]));
+    assert!(cache.is_dirty());
+
+    cache.save().unwrap();
+    assert_eq!(backend.saved.lock().unwrap()[Path::new("/a")], vec![1, 2]);
+}
+
+#[test]
+fn update_with_inserts_replacements() {
+    let backend = FlakyBackend::default();
+    let cache = BaseFsCache::with_backend(Arc::new(ManualOnly), Box::new(backend.clone())).unwrap();
+    cache
+        .update_with(PathBuf::from("/b"), |item| {
+            assert!(item.is_none());
+            Some(vec![3])
+        })
+        .unwrap();
+    cache.update_with(PathBuf::from("/c"), |_| None).unwrap();
+    assert_eq!(cache.keys(), vec![PathBuf::from("/b")]);
+    assert_eq!(cache.pending_modifications(), 1);
+}